@@ -0,0 +1,29 @@
+# Turns the output of `nm -n -C` for the kernel into nasm source for the .ksyms section: a
+# header with a magic number and the number of symbols, one entry per function sorted by
+# address (address, offset and length of the name) and the names, padded to KSYMS_SIZE.
+
+BEGIN {
+    count = 0
+}
+
+$2 ~ /^[tTwW]$/ {
+    name = $0
+    sub(/^[^ ]+ [^ ]+ /, "", name)
+    sub(/::h[0-9a-f]+$/, "", name)
+    # nasm strings can't contain their own quote
+    if (name ~ /"/)
+        next
+    addrs[count] = $1
+    names[count] = name
+    count++
+}
+
+END {
+    print "    dd 0x4d59534b ; KSYM"
+    print "    dd " count
+    for (i = 0; i < count; i++)
+        printf "    dq 0x%s\n    dd name%d - $$, name%d_end - name%d\n", addrs[i], i, i, i
+    for (i = 0; i < count; i++)
+        printf "name%d: db \"%s\"\nname%d_end:\n", i, names[i], i
+    print "    times KSYMS_SIZE - ($ - $$) db 0"
+}
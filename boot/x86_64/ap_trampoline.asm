@@ -0,0 +1,94 @@
+; Entry point for the application processors. The BSP copies everything between
+; _ap_trampoline_start and _ap_trampoline_end to AP_TRAMPOLINE_BASE (it has to be
+; below 1 MiB and page aligned for the startup IPI), fills in the data fields and
+; sends INIT-SIPI-SIPI. The AP then goes real mode -> protected mode -> long mode
+; and calls ap_entry(percpu) in the higher half.
+
+global _ap_trampoline_start
+global _ap_trampoline_end
+
+AP_TRAMPOLINE_BASE equ 0x8000
+
+; physical address of a label once the trampoline is copied to low memory
+%define TR(label) (AP_TRAMPOLINE_BASE + (label - _ap_trampoline_start))
+
+section .rodata
+bits 16
+
+_ap_trampoline_start:
+    jmp short _tr_real_mode
+
+    ; data filled in by the BSP (offsets are mirrored in smp.rs)
+    align 8
+_tr_cr3:
+    dq 0 ; page table that also identity maps the low memory
+_tr_stack:
+    dq 0 ; top of this AP's boot stack
+_tr_entry:
+    dq 0 ; address of ap_entry
+_tr_percpu:
+    dq 0 ; per-CPU area passed to ap_entry
+
+_tr_real_mode:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [TR(_tr_gdt_pointer)]
+
+    ; enable protected mode
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    jmp dword 0x08:TR(_tr_protected_mode)
+
+bits 32
+
+_tr_protected_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    ; enable PAE-flag in cr4 (Physical Address Extension)
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    ; load the page table prepared by the BSP
+    mov eax, [TR(_tr_cr3)]
+    mov cr3, eax
+
+    ; set the long mode bit and System Call Extensions, same as in boot.asm
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, 1
+    or eax, 1 << 8
+    wrmsr
+
+    ; enable paging in the cr0 register
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+    jmp 0x18:TR(_tr_long_mode)
+
+bits 64
+
+_tr_long_mode:
+    mov rsp, [TR(_tr_stack)]
+    mov rdi, [TR(_tr_percpu)]
+    mov rax, [TR(_tr_entry)]
+    call rax ; never returns
+    hlt
+
+    align 8
+_tr_gdt:
+    dq 0 ; zero entry
+    dq 0x00cf9a000000ffff ; 32-bit code segment
+    dq 0x00cf92000000ffff ; 32-bit data segment
+    dq 0x00af9a000000ffff ; 64-bit code segment
+_tr_gdt_pointer:
+    dw $ - _tr_gdt - 1
+    dd TR(_tr_gdt)
+
+_ap_trampoline_end:
@@ -172,6 +172,7 @@ _ua64_mode_entry:
     _ua64_mode_entry_high:
     mov dword [_p3_table], 0
     mov edi, ebx
+    xor ebp, ebp ; the end of the frame pointer chain for backtraces
     jmp _gdt64_code_off:ua64_mode_start
 
 section .rodata
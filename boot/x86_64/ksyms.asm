@@ -0,0 +1,4 @@
+; room for the kernel's symbol table, the Makefile fills it in with the output of
+; boot/ksyms.awk once the kernel is linked, the size is the same so nothing moves
+section .ksyms alloc noexec nowrite progbits align=8
+    times KSYMS_SIZE db 0
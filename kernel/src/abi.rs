@@ -0,0 +1,214 @@
+// Syscall numbers and constants shared between the kernel and userspace.
+// Like errno.rs this file does not depend on the rest of the kernel.
+// Arguments are passed in rdi, rsi, rdx, r10, r8 and r9, the number and the result in rax.
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_BRK: u64 = 2;
+pub const SYS_MMAP: u64 = 3;
+pub const SYS_MUNMAP: u64 = 4;
+pub const SYS_GETPID: u64 = 5;
+pub const SYS_READ: u64 = 6;
+pub const SYS_SPAWN: u64 = 7;
+pub const SYS_WAIT: u64 = 8;
+pub const SYS_GETCWD: u64 = 9;
+pub const SYS_CHDIR: u64 = 10;
+pub const SYS_CLOSE: u64 = 11;
+pub const SYS_DUP: u64 = 12;
+pub const SYS_DUP2: u64 = 13;
+pub const SYS_PIPE: u64 = 14;
+pub const SYS_CHANNEL: u64 = 15;
+pub const SYS_SEND: u64 = 16;
+pub const SYS_RECV: u64 = 17;
+pub const SYS_CALL: u64 = 18;
+pub const SYS_REPLY: u64 = 19;
+pub const SYS_IRQ_BIND: u64 = 20;
+pub const SYS_CONSOLE_INPUT: u64 = 21;
+pub const SYS_SHM_CREATE: u64 = 22;
+pub const SYS_SHM_MAP: u64 = 23;
+pub const SYS_SHM_UNMAP: u64 = 24;
+pub const SYS_KILL: u64 = 25;
+pub const SYS_SIGACTION: u64 = 26;
+pub const SYS_SIGPROCMASK: u64 = 27;
+pub const SYS_SIGRETURN: u64 = 28;
+pub const SYS_DMESG: u64 = 29;
+pub const SYS_OPEN: u64 = 30;
+pub const SYS_LSEEK: u64 = 31;
+pub const SYS_STAT: u64 = 32;
+pub const SYS_FSTAT: u64 = 33;
+pub const SYS_GETDENTS: u64 = 34;
+pub const SYS_MKDIR: u64 = 35;
+pub const SYS_RMDIR: u64 = 36;
+pub const SYS_UNLINK: u64 = 37;
+pub const SYS_CHROOT: u64 = 38;
+pub const SYS_LINK: u64 = 39;
+pub const SYS_SYMLINK: u64 = 40;
+pub const SYS_CHMOD: u64 = 41;
+pub const SYS_TAG_ADD: u64 = 42;
+pub const SYS_TAG_REMOVE: u64 = 43;
+pub const SYS_TAG_LIST: u64 = 44;
+pub const SYS_TAG_QUERY: u64 = 45;
+pub const SYS_PCI_LIST: u64 = 46;
+
+// longest path including the terminating NUL, and the most arguments spawn takes
+pub const PATH_MAX: usize = 4096;
+pub const ARG_MAX: usize = 64;
+
+// mmap protection bits
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+// mmap flags, only private anonymous mappings are supported
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+// most data bytes and pages a channel message can carry
+pub const MSG_MAX: usize = 256;
+pub const MSG_MAX_PAGES: usize = 64;
+
+// describes a message for send, recv, call and reply
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IpcMessage {
+    pub id: u64,       // set by recv if the sender waits for a reply, reply takes it back
+    pub data: u64,     // address of the data
+    pub len: u64,      // bytes to send, bytes received
+    pub capacity: u64, // size of the buffer at data when receiving
+    pub pages: u64,    // page aligned address of pages to move, where they were mapped on receipt
+    pub page_count: u64,
+}
+
+// open flags, the low two bits are the access mode
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_ACCMODE: u64 = 3;
+pub const O_CREAT: u64 = 0x40;
+pub const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+pub const O_DIRECTORY: u64 = 0x10000;
+
+// where lseek counts the offset from
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+// file types in the mode of a Stat, the other bits are the permissions
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+// what stat and fstat store
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub dev: u64, // the mount the file is on
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub atime: u64, // seconds since 1970
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+// getdents fills the buffer with records of a u64 inode number, a u64 offset of the next
+// record, a u16 record length, a u8 type and the NUL-terminated name, 8 byte aligned
+pub const DIRENT_NAME_OFFSET: usize = 19;
+
+// types in directory entries
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+// what pci_list stores for each PCI function
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PciInfo {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub irq_line: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub capabilities: u32, // bit 1 << id for each capability id below 32
+    pub bars: [u64; 6],    // bit 0 set for I/O ports, 0 if not implemented
+    pub bar_sizes: [u64; 6],
+    pub driver: [u8; 16], // NUL-terminated, empty without a driver
+}
+
+// interrupt lines a userspace driver can receive as channel messages
+pub const IRQ_KEYBOARD: u64 = 1;
+
+// signal numbers, a signal sig is bit 1 << sig in masks
+pub const SIGHUP: u32 = 1;
+pub const SIGINT: u32 = 2;
+pub const SIGQUIT: u32 = 3;
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+pub const SIGFPE: u32 = 8;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGPIPE: u32 = 13;
+pub const SIGALRM: u32 = 14;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGURG: u32 = 23;
+pub const SIGWINCH: u32 = 28;
+pub const NSIG: u32 = 32;
+
+// special handlers
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+// sigaction flags, handlers need a restorer that calls sigreturn
+pub const SA_RESTORER: u64 = 0x04000000;
+pub const SA_NODEFER: u64 = 0x40000000;
+
+// sigprocmask operations
+pub const SIG_BLOCK: u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+// what sigaction takes and returns, the handler is called with the signal number and returns
+// to the restorer
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub restorer: u64,
+    pub mask: u64, // signals blocked while the handler runs
+}
+
+// exit code of a task killed by signal sig, like a shell's $?
+pub const fn signal_exit_code(sig: u32) -> i32 {
+    128 + sig as i32
+}
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// auxiliary vector entries passed on the initial stack after argv and envp
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_ENTRY: u64 = 9;
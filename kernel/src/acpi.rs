@@ -0,0 +1,207 @@
+use crate::mem::PhysAddr;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::slice;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// the RSDP lives either in the first KiB of the EBDA or in the BIOS area below 1 MiB
+const EBDA_PTR_ADDR: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_addr: u32,
+    // the fields below are only valid for revision >= 2
+    length: u32,
+    xsdt_addr: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct MadtHeader {
+    header: SdtHeader,
+    lapic_addr: u32,
+    flags: u32,
+}
+
+// one processor as reported by the MADT
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    pub processor_id: u8,
+    pub lapic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub addr: PhysAddr,
+    pub gsi_base: u32,
+}
+
+// CPU and interrupt controller topology read from the MADT
+#[derive(Debug)]
+pub struct CpuTopology {
+    pub lapic_addr: PhysAddr,
+    pub cpus: Vec<CpuInfo>,
+    pub ioapics: Vec<IoApicInfo>,
+}
+
+unsafe fn phys_slice(addr: u64, len: usize) -> &'static [u8] {
+    let virt = PhysAddr::new(addr).to_virt().unwrap();
+    slice::from_raw_parts(virt.addr() as *const u8, len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+unsafe fn scan_for_rsdp(start: u64, end: u64) -> Option<&'static Rsdp> {
+    // the RSDP signature is always 16-byte aligned
+    let mut addr = start;
+    while addr + 20 <= end {
+        let bytes = phys_slice(addr, 20);
+        if &bytes[0..8] == RSDP_SIGNATURE && checksum_ok(bytes) {
+            return Some(PhysAddr::new(addr).to_virt().unwrap().to_ref::<Rsdp>());
+        }
+        addr += 16;
+    }
+    None
+}
+
+unsafe fn find_rsdp() -> Option<&'static Rsdp> {
+    let ebda_segment = *PhysAddr::new(EBDA_PTR_ADDR)
+        .to_virt()
+        .unwrap()
+        .to_ref::<u16>();
+    let ebda = (ebda_segment as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_for_rsdp(ebda, ebda + 0x400) {
+            return Some(rsdp);
+        }
+    }
+    scan_for_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+unsafe fn sdt_at(addr: u64) -> Option<&'static SdtHeader> {
+    let header = PhysAddr::new(addr).to_virt()?.to_ref::<SdtHeader>();
+    if checksum_ok(phys_slice(addr, header.length as usize)) {
+        Some(header)
+    } else {
+        None
+    }
+}
+
+// find an ACPI table by its signature through the XSDT (or RSDT for ACPI 1.0)
+pub unsafe fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    let rsdp = find_rsdp()?;
+    let (root_addr, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_addr != 0 {
+        (rsdp.xsdt_addr, 8)
+    } else {
+        (rsdp.rsdt_addr as u64, 4)
+    };
+    let root = sdt_at(root_addr)?;
+    let entries_len = root.length as usize - size_of::<SdtHeader>();
+    let entries = phys_slice(root_addr + size_of::<SdtHeader>() as u64, entries_len);
+    entries
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            // entries are unaligned 32 or 64-bit physical addresses
+            entry
+                .iter()
+                .rev()
+                .fold(0u64, |addr, b| (addr << 8) | *b as u64)
+        })
+        .filter_map(|addr| sdt_at(addr))
+        .find(|table| &table.signature == signature)
+}
+
+// table-specific data following the SDT header
+pub unsafe fn table_body(table: &'static SdtHeader) -> &'static [u8] {
+    let start = table as *const SdtHeader as *const u8;
+    let len = table.length as usize;
+    &slice::from_raw_parts(start, len)[size_of::<SdtHeader>()..]
+}
+
+// parse the MADT to find all local APICs (one per logical CPU) and I/O APICs
+pub fn cpu_topology() -> Option<CpuTopology> {
+    unsafe {
+        let madt = find_table(MADT_SIGNATURE)?;
+        let madt_header = &*(madt as *const SdtHeader as *const MadtHeader);
+        let mut topology = CpuTopology {
+            lapic_addr: PhysAddr::new(madt_header.lapic_addr as u64),
+            cpus: Vec::new(),
+            ioapics: Vec::new(),
+        };
+        // skip the local APIC address and flags
+        let entries = &table_body(madt)[8..];
+        let mut off = 0;
+        while off + 2 <= entries.len() {
+            let entry_type = entries[off];
+            let entry_len = entries[off + 1] as usize;
+            if entry_len < 2 || off + entry_len > entries.len() {
+                break;
+            }
+            let entry = &entries[off..off + entry_len];
+            match entry_type {
+                // processor local APIC
+                0 => topology.cpus.push(CpuInfo {
+                    processor_id: entry[2],
+                    lapic_id: entry[3],
+                    enabled: entry[4] & 1 != 0,
+                }),
+                // I/O APIC
+                1 => topology.ioapics.push(IoApicInfo {
+                    id: entry[2],
+                    addr: PhysAddr::new(read_u32(&entry[4..8]) as u64),
+                    gsi_base: read_u32(&entry[8..12]),
+                }),
+                // 64-bit local APIC address override
+                5 => {
+                    topology.lapic_addr = PhysAddr::new(read_u64(&entry[4..12]));
+                }
+                _ => {}
+            }
+            off += entry_len;
+        }
+        log::info!(
+            "{} CPUs, {} I/O APICs, LAPIC at {}",
+            topology.cpus.len(),
+            topology.ioapics.len(),
+            topology.lapic_addr
+        );
+        Some(topology)
+    }
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+pub fn read_u64(bytes: &[u8]) -> u64 {
+    read_u32(&bytes[0..4]) as u64 | (read_u32(&bytes[4..8]) as u64) << 32
+}
@@ -0,0 +1,311 @@
+use crate::errno::{Errno, SyscallResult};
+use crate::mem::{
+    self, EmptyFrame, PageTable, PhysAddr, VirtAddr, BIT_PRESENT, BIT_USER, BIT_WRITABLE,
+    FRAME_SIZE,
+};
+use crate::shm::SharedMemory;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+// layout of a user address space: the program and its heap (brk) at the bottom,
+// anonymous mmaps from MMAP_START and the stack right below the kernel with a guard page
+pub const MMAP_START: u64 = 0x80000000;
+pub const USER_STACK_TOP: u64 = mem::USER_END - FRAME_SIZE;
+pub const USER_STACK_SIZE: u64 = 0x10000;
+const MMAP_END: u64 = USER_STACK_TOP - USER_STACK_SIZE - FRAME_SIZE;
+
+fn page_down(addr: u64) -> u64 {
+    addr & !(FRAME_SIZE - 1)
+}
+
+fn page_up(addr: u64) -> u64 {
+    page_down(addr + FRAME_SIZE - 1)
+}
+
+// the userspace memory of a task, every user page is backed by a frame owned by it
+// or by a shared memory region it has mapped
+pub struct AddressSpace {
+    ptable: Box<PageTable>,
+    pages: BTreeMap<u64, Box<EmptyFrame>>, // backing frames by page address
+    shared: BTreeMap<u64, Arc<SharedMemory>>, // mapped shared memory by start address
+    brk_start: u64,
+    brk: u64,
+}
+
+impl AddressSpace {
+    pub unsafe fn new() -> AddressSpace {
+        AddressSpace {
+            ptable: PageTable::new(), // copy over the kernel's page tables
+            pages: BTreeMap::new(),
+            shared: BTreeMap::new(),
+            brk_start: 0,
+            brk: 0,
+        }
+    }
+
+    pub unsafe fn page_table_addr(&self) -> PhysAddr {
+        self.ptable.phys_addr()
+    }
+
+    // back the page at the given address with a zeroed frame, pages that are already there are kept
+    pub unsafe fn map_zeroed(&mut self, page: u64, writable: bool) -> Result<(), Errno> {
+        if page < mem::USER_START || page >= mem::USER_END || page != page_down(page) {
+            return Err(Errno::EINVAL);
+        }
+        if self.pages.contains_key(&page) {
+            return Ok(());
+        }
+        // shared memory mapped there isn't replaced
+        if self.shared_end(page, page + FRAME_SIZE).is_some() {
+            return Err(Errno::ENOMEM);
+        }
+        self.map_frame(page, Box::new([0; FRAME_SIZE as usize]), writable)
+    }
+
+    unsafe fn map_frame(
+        &mut self,
+        page: u64,
+        frame: Box<EmptyFrame>,
+        writable: bool,
+    ) -> Result<(), Errno> {
+        let phys = VirtAddr::new(frame.as_ptr() as u64)
+            .to_phys()
+            .ok_or(Errno::ENOMEM)?
+            .0;
+        let mut options = BIT_PRESENT | BIT_USER;
+        if writable {
+            options |= BIT_WRITABLE;
+        }
+        self.ptable
+            .map_virt_to_phys(VirtAddr::new(page), phys, options);
+        self.pages.insert(page, frame);
+        Ok(())
+    }
+
+    // map every page overlapping [start, start + len)
+    pub unsafe fn map_range(&mut self, start: u64, len: u64, writable: bool) -> Result<(), Errno> {
+        let end = start.checked_add(len).ok_or(Errno::EINVAL)?;
+        let mut page = page_down(start);
+        while page < end {
+            self.map_zeroed(page, writable)?;
+            page += FRAME_SIZE;
+        }
+        Ok(())
+    }
+
+    pub unsafe fn unmap(&mut self, page: u64) {
+        if let Some(frame) = self.pages.remove(&page) {
+            // the frame may only go once no CPU can reach it anymore
+            self.ptable.unmap(VirtAddr::new(page));
+            drop(frame);
+        }
+    }
+
+    // copy into mapped pages through their frames, so it works without this page table being active
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> Result<(), Errno> {
+        let mut done = 0;
+        while done < data.len() {
+            let cur = addr + done as u64;
+            let page_off = (cur - page_down(cur)) as usize;
+            let frame = self.pages.get_mut(&page_down(cur)).ok_or(Errno::EFAULT)?;
+            let count = min(data.len() - done, FRAME_SIZE as usize - page_off);
+            frame[page_off..page_off + count].copy_from_slice(&data[done..done + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    // the heap starts right after the program's segments
+    pub fn init_brk(&mut self, brk_start: u64) {
+        self.brk_start = brk_start;
+        self.brk = brk_start;
+    }
+
+    // move the end of the heap, 0 just returns the current one
+    pub unsafe fn set_brk(&mut self, new_brk: u64) -> SyscallResult {
+        if new_brk == 0 {
+            return Ok(self.brk);
+        }
+        if new_brk < self.brk_start || new_brk > MMAP_START {
+            return Err(Errno::ENOMEM);
+        }
+        let old_end = page_up(self.brk);
+        let new_end = page_up(new_brk);
+        // the heap can't grow into shared memory mapped after it
+        if self.shared_end(old_end, new_end).is_some() {
+            return Err(Errno::ENOMEM);
+        }
+        let mut page = old_end;
+        while page < new_end {
+            self.map_zeroed(page, true)?;
+            page += FRAME_SIZE;
+        }
+        let mut page = new_end;
+        while page < old_end {
+            self.unmap(page);
+            page += FRAME_SIZE;
+        }
+        self.brk = new_brk;
+        Ok(self.brk)
+    }
+
+    // the end of the shared memory region overlapping [start, end)
+    fn shared_end(&self, start: u64, end: u64) -> Option<u64> {
+        // regions don't overlap, so only the last one starting before end can reach into the range
+        self.shared
+            .range(..end)
+            .next_back()
+            .map(|(&addr, region)| addr + region.len())
+            .filter(|&region_end| region_end > start)
+    }
+
+    // the end of the first mapping overlapping [start, end), pages or shared memory
+    fn first_used(&self, start: u64, end: u64) -> Option<u64> {
+        let page = self
+            .pages
+            .range(start..end)
+            .next()
+            .map(|(&page, _)| page + FRAME_SIZE);
+        page.into_iter().chain(self.shared_end(start, end)).max()
+    }
+
+    // find room for len bytes in the mmap area
+    fn find_free(&self, len: u64) -> Result<u64, Errno> {
+        if len == 0 || len > MMAP_END - MMAP_START {
+            return Err(Errno::EINVAL);
+        }
+        let len = page_up(len);
+        let mut start = MMAP_START;
+        while start + len <= MMAP_END {
+            match self.first_used(start, start + len) {
+                Some(used_end) => start = used_end,
+                None => return Ok(start),
+            }
+        }
+        Err(Errno::ENOMEM)
+    }
+
+    // map len bytes of anonymous memory somewhere in the mmap area
+    pub unsafe fn mmap(&mut self, len: u64, writable: bool) -> SyscallResult {
+        let start = self.find_free(len)?;
+        self.map_range(start, len, writable)?;
+        Ok(start)
+    }
+
+    // unmap count pages from start and hand over their frames, e.g. to move them to another task
+    // nothing is unmapped unless all of them are there
+    pub unsafe fn take_pages(
+        &mut self,
+        start: u64,
+        count: u64,
+    ) -> Result<Vec<Box<EmptyFrame>>, Errno> {
+        let end = count
+            .checked_mul(FRAME_SIZE)
+            .and_then(|len| start.checked_add(len))
+            .ok_or(Errno::EINVAL)?;
+        if start != page_down(start) || start < mem::USER_START || end > mem::USER_END {
+            return Err(Errno::EINVAL);
+        }
+        if self.pages.range(start..end).count() as u64 != count {
+            return Err(Errno::EFAULT);
+        }
+        let mut frames = Vec::with_capacity(count as usize);
+        let mut page = start;
+        while page < end {
+            self.ptable.unmap(VirtAddr::new(page));
+            frames.extend(self.pages.remove(&page));
+            page += FRAME_SIZE;
+        }
+        Ok(frames)
+    }
+
+    // map frames taken from an address space next to each other in the mmap area
+    pub unsafe fn map_frames(&mut self, frames: Vec<Box<EmptyFrame>>) -> SyscallResult {
+        let start = self.find_free(frames.len() as u64 * FRAME_SIZE)?;
+        self.map_frames_at(start, frames)?;
+        Ok(start)
+    }
+
+    // put frames back where take_pages got them from
+    pub unsafe fn map_frames_at(
+        &mut self,
+        start: u64,
+        frames: Vec<Box<EmptyFrame>>,
+    ) -> Result<(), Errno> {
+        let mut page = start;
+        for frame in frames {
+            self.map_frame(page, frame, true)?;
+            page += FRAME_SIZE;
+        }
+        Ok(())
+    }
+
+    // map all of a shared memory region at addr, or somewhere in the mmap area if it's 0
+    pub unsafe fn map_shared(
+        &mut self,
+        region: Arc<SharedMemory>,
+        addr: u64,
+        writable: bool,
+    ) -> SyscallResult {
+        let start = match addr {
+            0 => self.find_free(region.len())?,
+            addr if addr != page_down(addr) || addr < mem::USER_START => return Err(Errno::EINVAL),
+            addr => addr,
+        };
+        let end = start.checked_add(region.len()).ok_or(Errno::EINVAL)?;
+        if end > mem::USER_END {
+            return Err(Errno::EINVAL);
+        }
+        if self.first_used(start, end).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let mut options = BIT_PRESENT | BIT_USER;
+        if writable {
+            options |= BIT_WRITABLE;
+        }
+        // all frames are looked up before the first one is mapped, so a failure leaves nothing
+        let mut frames = Vec::with_capacity(region.frames().len());
+        for frame in region.frames() {
+            let phys = VirtAddr::new(frame.as_ptr() as u64)
+                .to_phys()
+                .ok_or(Errno::ENOMEM)?
+                .0;
+            frames.push(phys);
+        }
+        for (i, phys) in frames.into_iter().enumerate() {
+            let page = start + i as u64 * FRAME_SIZE;
+            self.ptable
+                .map_virt_to_phys(VirtAddr::new(page), phys, options);
+        }
+        self.shared.insert(start, region);
+        Ok(start)
+    }
+
+    // unmap the shared memory region mapped at addr, its frames stay as long as others use them
+    pub unsafe fn unmap_shared(&mut self, addr: u64) -> SyscallResult {
+        let region = self.shared.remove(&addr).ok_or(Errno::EINVAL)?;
+        let mut page = addr;
+        while page < addr + region.len() {
+            self.ptable.unmap(VirtAddr::new(page));
+            page += FRAME_SIZE;
+        }
+        drop(region);
+        Ok(0)
+    }
+
+    pub unsafe fn munmap(&mut self, addr: u64, len: u64) -> SyscallResult {
+        let end = addr.checked_add(page_up(len)).ok_or(Errno::EINVAL)?;
+        if addr != page_down(addr) || addr < MMAP_START || end > MMAP_END {
+            return Err(Errno::EINVAL);
+        }
+        let mut page = addr;
+        while page < end {
+            self.unmap(page);
+            page += FRAME_SIZE;
+        }
+        Ok(0)
+    }
+}
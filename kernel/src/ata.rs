@@ -0,0 +1,370 @@
+// Disks on the legacy IDE controller, transferred by PIO: the data goes through the data port a
+// word at a time, and the controller raises IRQ 14 or 15 when a sector is ready or was taken.
+// The primary and secondary channel each have a master and a slave drive, hda to hdd.
+//
+// A task waiting for the interrupt blocks on the wait queue of the channel, which the IRQ handler
+// wakes after setting a flag. While booting there is no task to block and the wait spins.
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::errno::Errno;
+use crate::port::{self, Port};
+use crate::scheduler::{SleepLock, WaitQueue};
+use crate::smp;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+
+// the task file registers, from the I/O base of a channel
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7; // reading it acknowledges the interrupt
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_FLUSH: u8 = 0xe7;
+const CMD_FLUSH_EXT: u8 = 0xea;
+const CMD_IDENTIFY: u8 = 0xec;
+
+const DRIVE_LBA: u8 = 0xe0; // with bit 4 for the slave
+const CONTROL_RESET: u8 = 0x04;
+
+// what a transfer is split into, well below what a command can do
+const MAX_SECTORS: usize = 128;
+const LBA28_SECTORS: u64 = 1 << 28;
+
+// an interrupt that doesn't come within this many ticks, about 2 seconds, means the drive hangs
+const IRQ_TIMEOUT_TICKS: u64 = 36;
+
+struct Channel {
+    io: u16,
+    control: u16,
+    irq: u8,
+    irq_fired: AtomicBool,
+    irq_waiting: WaitQueue,
+    // one command at a time for both drives, they share the registers
+    lock: SleepLock<()>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel {
+        io: 0x1f0,
+        control: 0x3f6,
+        irq: 14,
+        irq_fired: AtomicBool::new(false),
+        irq_waiting: WaitQueue::new(),
+        lock: SleepLock::new(()),
+    },
+    Channel {
+        io: 0x170,
+        control: 0x376,
+        irq: 15,
+        irq_fired: AtomicBool::new(false),
+        irq_waiting: WaitQueue::new(),
+        lock: SleepLock::new(()),
+    },
+];
+
+impl Channel {
+    fn reg(&self, reg: u16) -> Port<u8> {
+        Port::new(self.io + reg)
+    }
+
+    fn data(&self) -> Port<u16> {
+        Port::new(self.io + REG_DATA)
+    }
+
+    // the status without acknowledging an interrupt
+    fn alt_status(&self) -> u8 {
+        Port::<u8>::new(self.control).read()
+    }
+
+    // each read of the alternate status takes about 100ns, the drive needs 400ns after it was
+    // selected or given a command before its status means anything
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&self, slave: bool, lba_high: u8) {
+        self.reg(REG_DRIVE)
+            .write(DRIVE_LBA | (slave as u8) << 4 | lba_high);
+        self.delay();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, Errno> {
+        let until = smp::ticks() + IRQ_TIMEOUT_TICKS;
+        loop {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            if smp::ticks() > until {
+                return Err(Errno::EIO);
+            }
+            spin_loop_hint();
+        }
+    }
+
+    // wait until the drive has a sector for us or room for one
+    fn wait_drq(&self) -> Result<(), Errno> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            log::warn!("ATA {:x}: error {:#x}", self.io, self.reg(REG_ERROR).read());
+            return Err(Errno::EIO);
+        }
+        match status & STATUS_DRQ {
+            0 => Err(Errno::EIO),
+            _ => Ok(()),
+        }
+    }
+
+    // wait for the interrupt that ends a step of a command, then check how it went
+    fn wait_irq(&self) -> Result<(), Errno> {
+        let fired = self.irq_waiting.wait_timeout(IRQ_TIMEOUT_TICKS, || {
+            Some(()).filter(|_| self.irq_fired.swap(false, Ordering::SeqCst))
+        });
+        if fired.is_none() {
+            log::warn!("ATA {:x}: no interrupt", self.io);
+            return Err(Errno::EIO);
+        }
+        let status = self.reg(REG_STATUS).read();
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            log::warn!("ATA {:x}: error {:#x}", self.io, self.reg(REG_ERROR).read());
+            return Err(Errno::EIO);
+        }
+        Ok(())
+    }
+
+    // load the registers for a command on count sectors from lba and issue it
+    fn command(&self, slave: bool, lba48: bool, lba: u64, count: usize, cmd: u8) {
+        self.irq_fired.store(false, Ordering::SeqCst);
+        if lba48 {
+            self.select(slave, 0);
+            // the high bytes go first into the same registers
+            self.reg(REG_SECTOR_COUNT).write((count >> 8) as u8);
+            self.reg(REG_LBA_LOW).write((lba >> 24) as u8);
+            self.reg(REG_LBA_MID).write((lba >> 32) as u8);
+            self.reg(REG_LBA_HIGH).write((lba >> 40) as u8);
+        } else {
+            self.select(slave, (lba >> 24) as u8 & 0x0f);
+        }
+        self.reg(REG_SECTOR_COUNT).write(count as u8);
+        self.reg(REG_LBA_LOW).write(lba as u8);
+        self.reg(REG_LBA_MID).write((lba >> 8) as u8);
+        self.reg(REG_LBA_HIGH).write((lba >> 16) as u8);
+        self.reg(REG_COMMAND).write(cmd);
+        self.delay();
+    }
+
+    fn read_sector(&self, buf: &mut [u8]) {
+        let data = self.data();
+        for word in buf.chunks_mut(2) {
+            word.copy_from_slice(&data.read().to_le_bytes());
+        }
+    }
+
+    fn write_sector(&self, buf: &[u8]) {
+        let data = self.data();
+        for word in buf.chunks(2) {
+            data.write(u16::from_le_bytes([word[0], word[1]]));
+        }
+    }
+
+    // IDENTIFY a drive, None if there is none or it is no ATA disk, e.g. an ATAPI CD-ROM
+    fn identify(&self, slave: bool) -> Option<[u16; 256]> {
+        self.select(slave, 0);
+        for reg in &[REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.reg(*reg).write(0);
+        }
+        self.irq_fired.store(false, Ordering::SeqCst);
+        self.reg(REG_COMMAND).write(CMD_IDENTIFY);
+        self.delay();
+        if self.alt_status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA devices abort with their signature here
+        if self.reg(REG_LBA_MID).read() != 0 || self.reg(REG_LBA_HIGH).read() != 0 {
+            return None;
+        }
+        self.wait_drq().ok()?;
+        let mut words = [0u16; 256];
+        let data = self.data();
+        for word in words.iter_mut() {
+            *word = data.read();
+        }
+        // acknowledge the interrupt IDENTIFY raised
+        self.reg(REG_STATUS).read();
+        self.irq_fired.store(false, Ordering::SeqCst);
+        Some(words)
+    }
+}
+
+pub struct AtaDisk {
+    name: String,
+    channel: &'static Channel,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+}
+
+impl AtaDisk {
+    // start reading or writing count sectors, with LBA48 only for those LBA28 can't reach
+    fn start(&self, lba: u64, count: usize, write: bool) {
+        let lba48 = self.lba48 && lba + count as u64 > LBA28_SECTORS;
+        let cmd = match (write, lba48) {
+            (false, false) => CMD_READ,
+            (false, true) => CMD_READ_EXT,
+            (true, false) => CMD_WRITE,
+            (true, true) => CMD_WRITE_EXT,
+        };
+        self.channel.command(self.slave, lba48, lba, count, cmd);
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check(self, start, buf.len())?;
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = start + (i * MAX_SECTORS) as u64;
+            self.start(lba, chunk.len() / SECTOR_SIZE, false);
+            // every sector raises an interrupt once it can be read
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                channel.wait_irq()?;
+                channel.read_sector(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check(self, start, buf.len())?;
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = start + (i * MAX_SECTORS) as u64;
+            self.start(lba, chunk.len() / SECTOR_SIZE, true);
+            // the first sector is taken without an interrupt, then every sector raises one
+            // once it is written
+            channel.wait_drq()?;
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                channel.write_sector(sector);
+                channel.wait_irq()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        let channel = self.channel;
+        let _lock = channel.lock.lock();
+        channel.irq_fired.store(false, Ordering::SeqCst);
+        channel.select(self.slave, 0);
+        let cmd = if self.lba48 { CMD_FLUSH_EXT } else { CMD_FLUSH };
+        channel.reg(REG_COMMAND).write(cmd);
+        channel.wait_irq()
+    }
+}
+
+// the model string of IDENTIFY, its bytes come swapped in each word
+fn model(words: &[u16; 256]) -> String {
+    let mut model = String::new();
+    for word in &words[27..47] {
+        let [high, low] = word.to_be_bytes();
+        model.push(high as char);
+        model.push(low as char);
+    }
+    String::from(model.trim_end())
+}
+
+// called from the IRQ 14 and 15 handlers
+pub fn handle_irq(channel: usize) {
+    let channel = &CHANNELS[channel];
+    // the waiting transfer reads the status, which acknowledges the interrupt
+    channel.irq_fired.store(true, Ordering::SeqCst);
+    channel.irq_waiting.wake_all();
+}
+
+// find the disks on both channels and register them behind a buffer cache
+// needs interrupts to be enabled
+pub fn init() {
+    for (i, channel) in CHANNELS.iter().enumerate() {
+        // a floating bus reads as all ones, there is no controller
+        if channel.alt_status() == 0xff {
+            continue;
+        }
+        // reset both drives, which also turns interrupts on for them
+        let control: Port<u8> = Port::new(channel.control);
+        control.write(CONTROL_RESET);
+        channel.delay();
+        control.write(0);
+        if channel.wait_not_busy().is_err() {
+            log::warn!("ATA {:x}: stays busy after reset", channel.io);
+            continue;
+        }
+        port::unmask_irq(channel.irq);
+        for &slave in &[false, true] {
+            let words = match channel.identify(slave) {
+                Some(words) => words,
+                None => continue,
+            };
+            // LBA48 if bit 10 of word 83 says so, else what LBA28 reaches
+            let lba48 = words[83] & (1 << 10) != 0;
+            let sectors = if lba48 {
+                words[100..104]
+                    .iter()
+                    .rev()
+                    .fold(0u64, |sectors, &word| sectors << 16 | word as u64)
+            } else {
+                (words[61] as u64) << 16 | words[60] as u64
+            };
+            let letter = (b'a' + (i * 2 + slave as usize) as u8) as char;
+            let mut name = String::from("hd");
+            name.push(letter);
+            log::info!(
+                "{}: {}, {} cylinders, {} heads, {} sectors per track, {} MiB{}",
+                name,
+                model(&words),
+                words[1],
+                words[3],
+                words[6],
+                (sectors * SECTOR_SIZE as u64) >> 20,
+                if lba48 { ", LBA48" } else { "" }
+            );
+            let disk = Arc::new(AtaDisk {
+                name,
+                channel,
+                slave,
+                lba48,
+                sectors,
+            });
+            if let Err(errno) = block::register(block::cached(disk)) {
+                log::warn!("could not register an ATA disk: {}", errno);
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Stack traces for panics and faults in the kernel. The kernel is built with frame pointers, so
+// rbp points at the caller's rbp with the return address right above it. Addresses are shown as
+// function+offset using the symbol table the Makefile writes into the .ksyms section after
+// linking (see boot/ksyms.awk).
+
+use crate::mem::{self, VirtAddr, FRAME_SIZE, VIRT_OFFSET};
+use crate::serial_port;
+use crate::vga_buffer;
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+const KSYMS_MAGIC: u32 = 0x4d59534b; // "KSYM"
+
+// frames printed at most, in case the chain loops
+const MAX_FRAMES: usize = 32;
+
+extern "C" {
+    static _ksymsstart: u8;
+    static _ksymsend: u8;
+}
+
+// an entry of the table, sorted by address, names are relative to the start of the section
+#[repr(C)]
+struct Symbol {
+    addr: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+fn section() -> &'static [u8] {
+    unsafe {
+        let start = &_ksymsstart as *const u8;
+        let end = &_ksymsend as *const u8;
+        slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+// the symbols, empty if the table wasn't filled in
+fn symbols() -> &'static [Symbol] {
+    let section = section();
+    if section.len() < 8 {
+        return &[];
+    }
+    let header = section.as_ptr() as *const u32;
+    let (magic, count) = unsafe { (*header, *header.add(1) as usize) };
+    if magic != KSYMS_MAGIC || 8 + count * size_of::<Symbol>() > section.len() {
+        return &[];
+    }
+    unsafe { slice::from_raw_parts(section.as_ptr().add(8) as *const Symbol, count) }
+}
+
+// the function containing addr and the offset of addr in it
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)> {
+    let symbols = symbols();
+    let i = match symbols.binary_search_by_key(&addr, |symbol| symbol.addr) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let symbol = &symbols[i];
+    let start = symbol.name_offset as usize;
+    let name = section().get(start..start + symbol.name_len as usize)?;
+    Some((str::from_utf8(name).unwrap_or("?"), addr - symbol.addr))
+}
+
+// backtraces go to the screen and the serial port, so they survive a scrolled away screen
+fn print_line(args: fmt::Arguments) {
+    vga_buffer::_print(format_args!("{}\n", args));
+    serial_port::_print(format_args!("{}\n", args));
+}
+
+fn print_frame(depth: usize, addr: u64, lookup_addr: u64) {
+    match lookup(lookup_addr) {
+        Some((name, offset)) => print_line(format_args!(
+            "  #{:<2} {:016x} {}+{:#x}",
+            depth,
+            addr,
+            name,
+            offset + addr - lookup_addr
+        )),
+        None => print_line(format_args!("  #{:<2} {:016x} ?", depth, addr)),
+    }
+}
+
+// a frame can be followed if it is a mapped kernel address
+fn readable_frame(rbp: u64) -> bool {
+    if rbp < VIRT_OFFSET || rbp % 8 != 0 {
+        return false;
+    }
+    let mapped = |addr: u64| unsafe { mem::get_page_table().lookup(VirtAddr::new(addr)) }.is_some();
+    mapped(rbp) && (rbp % FRAME_SIZE <= FRAME_SIZE - 16 || mapped(rbp + 8))
+}
+
+// print the return addresses of the frames starting at rbp
+fn walk(mut rbp: u64, mut depth: usize) {
+    while depth < MAX_FRAMES && readable_frame(rbp) {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        // the first frame of a task or CPU has no return address, userspace isn't followed
+        if ret < VIRT_OFFSET {
+            break;
+        }
+        // the return address can be the start of the next function after a call that doesn't
+        // return, the call itself is one byte before it
+        print_frame(depth, ret, ret - 1);
+        // the stack grows down, so callers' frames are always above
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+        depth += 1;
+    }
+}
+
+// backtrace of an interrupted context, e.g. where a fault happened
+pub fn print_from(rip: u64, rbp: u64) {
+    print_line(format_args!("backtrace:"));
+    print_frame(0, rip, rip);
+    walk(rbp, 1);
+}
+
+// backtrace of the caller
+#[inline(never)]
+pub fn print() {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+    print_line(format_args!("backtrace:"));
+    walk(rbp, 0);
+}
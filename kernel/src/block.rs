@@ -0,0 +1,543 @@
+// Block devices: storage that is read and written in whole sectors. Filesystems on disks sit on
+// top of a BlockDevice, a RamDisk keeps its sectors in memory so they work without any disk.
+//
+// Drivers register their disks, the partitions found on them from a GPT or an MBR become
+// devices of their own. A disk is usually put behind a BufferCache first, which keeps the blocks
+// used last in memory and writes changed ones back later, from a kernel thread every few
+// seconds or when they are evicted.
+
+use crate::errno::Errno;
+use crate::mem::{EmptyFrame, FRAME_SIZE};
+use crate::scheduler::{self, SleepLock, SCHEDULER};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::convert::TryInto;
+use lazy_static::lazy_static;
+use spin::{Mutex, RwLock};
+
+pub const SECTOR_SIZE: usize = 512;
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    // the capacity in sectors
+    fn sector_count(&self) -> u64;
+
+    // buf is a whole number of sectors long, starting at sector start
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno>;
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno>;
+
+    // make sure what was written is stored, for devices with a write cache
+    fn flush(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+// the sectors of a transfer, EINVAL if it isn't whole sectors or goes past the end
+pub fn check(device: &dyn BlockDevice, start: u64, len: usize) -> Result<(), Errno> {
+    let sectors = (len / SECTOR_SIZE) as u64;
+    if len % SECTOR_SIZE != 0
+        || start.checked_add(sectors).ok_or(Errno::EINVAL)? > device.sector_count()
+    {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+const SECTORS_PER_PAGE: u64 = FRAME_SIZE / SECTOR_SIZE as u64;
+
+// a disk in memory, it starts out zeroed
+pub struct RamDisk {
+    name: String,
+    pages: Mutex<Vec<Box<EmptyFrame>>>,
+}
+
+impl RamDisk {
+    pub fn new(name: &str, size: usize) -> RamDisk {
+        let count = (size as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+        let mut pages = Vec::new();
+        for _ in 0..count {
+            pages.push(Box::new([0; FRAME_SIZE as usize]));
+        }
+        RamDisk {
+            name: String::from(name),
+            pages: Mutex::new(pages),
+        }
+    }
+
+    // call f for the part of each page a transfer at sector start covers
+    fn for_pages<F: FnMut(&mut [u8], usize)>(&self, start: u64, len: usize, mut f: F) {
+        let mut pages = self.pages.lock();
+        let mut done = 0;
+        while done < len {
+            let pos = start * SECTOR_SIZE as u64 + done as u64;
+            let offset = (pos % FRAME_SIZE) as usize;
+            let count = min(FRAME_SIZE as usize - offset, len - done);
+            let page = &mut pages[(pos / FRAME_SIZE) as usize];
+            f(&mut page[offset..offset + count], done);
+            done += count;
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.pages.lock().len() as u64 * SECTORS_PER_PAGE
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        check(self, start, buf.len())?;
+        let len = buf.len();
+        self.for_pages(start, len, |page, done| {
+            buf[done..done + page.len()].copy_from_slice(page)
+        });
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno> {
+        check(self, start, buf.len())?;
+        self.for_pages(start, buf.len(), |page, done| {
+            let len = page.len();
+            page.copy_from_slice(&buf[done..done + len])
+        });
+        Ok(())
+    }
+}
+
+// a part of another device
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        check(self, start, buf.len())?;
+        self.device.read_sectors(self.start + start, buf)
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno> {
+        check(self, start, buf.len())?;
+        self.device.write_sectors(self.start + start, buf)
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        self.device.flush()
+    }
+}
+
+const MBR_SIGNATURE: u16 = 0xaa55;
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_GPT: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_MAX_ENTRIES: u32 = 256;
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// the CRC-32 of zlib and Ethernet, which the GPT uses for its header and its entries
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// the partitions of a disk as first sector and sector count, from its GPT if it has one, else
+// from its MBR; a disk with neither has none
+fn partitions(device: &dyn BlockDevice) -> Result<Vec<(u64, u64)>, Errno> {
+    let mut mbr = [0u8; SECTOR_SIZE];
+    device.read_sectors(0, &mut mbr)?;
+    let mut found = Vec::new();
+    if u16::from_le_bytes([mbr[510], mbr[511]]) != MBR_SIGNATURE {
+        return Ok(found);
+    }
+    let entries: Vec<&[u8]> = mbr[MBR_ENTRIES..510].chunks(16).collect();
+    // a volume without a partition table, e.g. FAT, has the same signature but code or zeroes
+    // where the entries would be, only 0x00 and 0x80 are valid boot flags
+    if entries.iter().any(|entry| entry[0] & 0x7f != 0) {
+        return Ok(found);
+    }
+    if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT) {
+        return gpt_partitions(device);
+    }
+    for entry in entries {
+        let (kind, start, count) = (entry[4], u32_at(entry, 8) as u64, u32_at(entry, 12) as u64);
+        if kind == 0 || count == 0 {
+            continue;
+        }
+        // the logical partitions in an extended one aren't looked for
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            log::info!("{}: extended partitions are skipped", device.name());
+            continue;
+        }
+        found.push((start, count));
+    }
+    Ok(found)
+}
+
+fn gpt_partitions(device: &dyn BlockDevice) -> Result<Vec<(u64, u64)>, Errno> {
+    let mut header = [0u8; SECTOR_SIZE];
+    device.read_sectors(1, &mut header)?;
+    if &header[..8] != GPT_SIGNATURE {
+        log::warn!("{}: protective MBR without a GPT", device.name());
+        return Ok(Vec::new());
+    }
+    // the CRC of the header is taken with its own field zeroed
+    let header_size = u32_at(&header, 12) as usize;
+    if header_size < GPT_HEADER_SIZE || header_size > SECTOR_SIZE {
+        log::warn!("{}: GPT header of {} bytes", device.name(), header_size);
+        return Ok(Vec::new());
+    }
+    let header_crc = u32_at(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != header_crc {
+        log::warn!("{}: GPT header with a wrong CRC", device.name());
+        return Ok(Vec::new());
+    }
+    let (entries_at, count, size, entries_crc) = (
+        u64_at(&header, 72),
+        u32_at(&header, 80),
+        u32_at(&header, 84),
+        u32_at(&header, 88),
+    );
+    if size < 128 || size as usize > SECTOR_SIZE || size % 8 != 0 || count > GPT_MAX_ENTRIES {
+        log::warn!(
+            "{}: GPT with {} entries of {} bytes",
+            device.name(),
+            count,
+            size
+        );
+        return Ok(Vec::new());
+    }
+    let len = count as usize * size as usize;
+    let sectors = (len + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let end = entries_at.checked_add(sectors as u64);
+    if end.map_or(true, |end| end > device.sector_count()) {
+        log::warn!("{}: GPT entries past the end of the disk", device.name());
+        return Ok(Vec::new());
+    }
+    let mut entries = Vec::new();
+    entries.resize(sectors * SECTOR_SIZE, 0);
+    device.read_sectors(entries_at, &mut entries)?;
+    if crc32(&entries[..len]) != entries_crc {
+        log::warn!("{}: GPT entries with a wrong CRC", device.name());
+        return Ok(Vec::new());
+    }
+    let found = entries[..len]
+        .chunks(size as usize)
+        // entries with a zero type GUID are unused
+        .filter(|entry| entry[..16].iter().any(|&b| b != 0))
+        .map(|entry| (u64_at(entry, 32), u64_at(entry, 40)))
+        .filter(|&(first, last)| first <= last)
+        .map(|(first, last)| (first, last - first + 1))
+        .collect();
+    Ok(found)
+}
+
+// disks and partitions by name
+lazy_static! {
+    static ref DEVICES: RwLock<Vec<Arc<dyn BlockDevice>>> = RwLock::new(Vec::new());
+}
+
+// add a disk and the partitions found on it, which are named after it with their number, e.g.
+// hda1, or ram0p1 for names that end with a digit
+pub fn register(device: Arc<dyn BlockDevice>) -> Result<(), Errno> {
+    if find(device.name()).is_some() {
+        return Err(Errno::EEXIST);
+    }
+    let sectors = device.sector_count();
+    log::info!(
+        "{}: {} KiB",
+        device.name(),
+        sectors * SECTOR_SIZE as u64 / 1024
+    );
+    let found = partitions(&*device).unwrap_or_else(|errno| {
+        log::warn!(
+            "{}: could not read the partition table: {}",
+            device.name(),
+            errno
+        );
+        Vec::new()
+    });
+    let separator = separator(device.name());
+    let mut devices = DEVICES.write();
+    for (i, (start, count)) in found.into_iter().enumerate() {
+        let name = alloc::format!("{}{}{}", device.name(), separator, i + 1);
+        if start == 0 || start.checked_add(count).map_or(true, |end| end > sectors) {
+            log::warn!("{}: past the end of the disk", name);
+            continue;
+        }
+        log::info!("{}: sectors {} to {}", name, start, start + count - 1);
+        devices.push(Arc::new(Partition {
+            name,
+            device: device.clone(),
+            start,
+            count,
+        }));
+    }
+    devices.push(device);
+    Ok(())
+}
+
+// what goes between the name of a disk and the number of a partition
+fn separator(disk: &str) -> &'static str {
+    match disk.chars().last() {
+        Some(c) if c.is_ascii_digit() => "p",
+        _ => "",
+    }
+}
+
+// remove a disk and its partitions again, along with the buffer caches in front of them, what
+// is still in those caches is dropped
+pub fn unregister(disk: &str) -> Result<(), Errno> {
+    find(disk).ok_or(Errno::ENODEV)?;
+    let prefix = alloc::format!("{}{}", disk, separator(disk));
+    let removed = |name: &str| {
+        name == disk
+            || name.starts_with(&prefix)
+                && name.len() > prefix.len()
+                && name[prefix.len()..].bytes().all(|b| b.is_ascii_digit())
+    };
+    DEVICES.write().retain(|device| !removed(device.name()));
+    CACHES.lock().retain(|cache| !removed(cache.name()));
+    Ok(())
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    let devices = DEVICES.read();
+    devices.iter().find(|device| device.name() == name).cloned()
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
+
+// the cache reads and writes blocks of a page, up to this many per device
+const CACHE_BLOCK_SECTORS: u64 = SECTORS_PER_PAGE;
+const CACHE_BLOCKS: usize = 1024;
+
+// dirty blocks are written back every this many ticks, about 5 seconds
+const FLUSH_TICKS: u64 = 91;
+
+struct CachedBlock {
+    data: Box<EmptyFrame>,
+    dirty: bool,
+    used: u64, // when it was used last, its key in lru
+}
+
+struct CacheState {
+    blocks: BTreeMap<u64, CachedBlock>,
+    lru: BTreeMap<u64, u64>, // block numbers by when they were used last
+    clock: u64,
+}
+
+// a write-back cache in front of a device, what is written is only stored when the cache is
+// flushed or the block is evicted
+pub struct BufferCache {
+    device: Arc<dyn BlockDevice>,
+    // held across the reads and writes of the device, which block
+    state: SleepLock<CacheState>,
+}
+
+// the buffer caches the flush thread writes back
+lazy_static! {
+    static ref CACHES: Mutex<Vec<Arc<BufferCache>>> = Mutex::new(Vec::new());
+}
+
+// put device behind a buffer cache
+pub fn cached(device: Arc<dyn BlockDevice>) -> Arc<dyn BlockDevice> {
+    let cache = Arc::new(BufferCache {
+        device,
+        state: SleepLock::new(CacheState {
+            blocks: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }),
+    });
+    CACHES.lock().push(cache.clone());
+    cache
+}
+
+impl BufferCache {
+    // the bytes of a block, the last one of the device may be short
+    fn block_len(&self, block: u64) -> usize {
+        let start = block * CACHE_BLOCK_SECTORS;
+        min(CACHE_BLOCK_SECTORS, self.device.sector_count() - start) as usize * SECTOR_SIZE
+    }
+
+    // a block in the cache, it is read from the device unless all of it is about to be written
+    fn block<'a>(
+        &self,
+        state: &'a mut CacheState,
+        block: u64,
+        overwrite: bool,
+    ) -> Result<&'a mut CachedBlock, Errno> {
+        state.clock += 1;
+        let now = state.clock;
+        if let Some(cached) = state.blocks.get_mut(&block) {
+            state.lru.remove(&cached.used);
+            state.lru.insert(now, block);
+            cached.used = now;
+            return Ok(state.blocks.get_mut(&block).unwrap());
+        }
+        if state.blocks.len() >= CACHE_BLOCKS {
+            self.evict(state)?;
+        }
+        let mut data = Box::new([0; FRAME_SIZE as usize]);
+        if !overwrite {
+            let len = self.block_len(block);
+            self.device
+                .read_sectors(block * CACHE_BLOCK_SECTORS, &mut data[..len])?;
+        }
+        state.lru.insert(now, block);
+        let cached = CachedBlock {
+            data,
+            dirty: false,
+            used: now,
+        };
+        Ok(state.blocks.entry(block).or_insert(cached))
+    }
+
+    // drop the block used least recently, writing it back first if it changed
+    fn evict(&self, state: &mut CacheState) -> Result<(), Errno> {
+        let (&used, &block) = match state.lru.iter().next() {
+            Some(oldest) => oldest,
+            None => return Ok(()),
+        };
+        if state.blocks[&block].dirty {
+            self.write_block(block, &state.blocks[&block])?;
+        }
+        state.lru.remove(&used);
+        state.blocks.remove(&block);
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, cached: &CachedBlock) -> Result<(), Errno> {
+        let len = self.block_len(block);
+        self.device
+            .write_sectors(block * CACHE_BLOCK_SECTORS, &cached.data[..len])
+    }
+
+    // write back the blocks that changed, returns how many there were
+    pub fn write_back(&self) -> Result<usize, Errno> {
+        let mut state = self.state.lock();
+        let mut count = 0;
+        for (&block, cached) in state.blocks.iter_mut().filter(|(_, cached)| cached.dirty) {
+            self.write_block(block, cached)?;
+            cached.dirty = false;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // call f for the part of each block a transfer at sector start covers, with its offset in the
+    // block, its offset in the transfer and its length
+    fn for_blocks<F: FnMut(&mut CachedBlock, usize, usize, usize)>(
+        &self,
+        start: u64,
+        len: usize,
+        writing: bool,
+        mut f: F,
+    ) -> Result<(), Errno> {
+        check(self, start, len)?;
+        let mut state = self.state.lock();
+        let mut done = 0;
+        while done < len {
+            let sector = start + (done / SECTOR_SIZE) as u64;
+            let block = sector / CACHE_BLOCK_SECTORS;
+            let offset = (sector % CACHE_BLOCK_SECTORS) as usize * SECTOR_SIZE;
+            let count = min(self.block_len(block) - offset, len - done);
+            let overwrite = writing && count == self.block_len(block);
+            let cached = self.block(&mut state, block, overwrite)?;
+            f(cached, offset, done, count);
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BufferCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        let len = buf.len();
+        self.for_blocks(start, len, false, |cached, offset, done, count| {
+            buf[done..done + count].copy_from_slice(&cached.data[offset..offset + count]);
+        })
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno> {
+        self.for_blocks(start, buf.len(), true, |cached, offset, done, count| {
+            cached.data[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+            cached.dirty = true;
+        })
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        self.write_back()?;
+        self.device.flush()
+    }
+}
+
+// write back what changed in every buffer cache
+pub fn sync_all() -> Result<(), Errno> {
+    let caches = CACHES.lock().clone();
+    for cache in caches {
+        if cache.write_back()? != 0 {
+            cache.device.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn flush_thread() {
+    loop {
+        scheduler::sleep(FLUSH_TICKS);
+        if let Err(errno) = sync_all() {
+            log::warn!("writing back the buffer caches failed: {}", errno);
+        }
+    }
+}
+
+pub fn start_flush_thread() {
+    if let Err(errno) = SCHEDULER.spawn_kernel_thread(flush_thread) {
+        log::warn!("no buffer cache flush thread: {}", errno);
+    }
+}
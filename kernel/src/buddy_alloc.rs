@@ -2,7 +2,6 @@ use crate::frame_alloc::FrameSingleAllocator;
 use crate::mem::PhysAddr;
 use crate::mem::VirtAddr;
 use crate::mem::FRAME_SIZE;
-// use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::vec::Vec;
 use core::cmp;
@@ -57,46 +56,76 @@ impl BuddyAllocatorManager {
             match Self::get_mem_area_with_size(frame_alloc, mem_size) {
                 // Success! Found a memory area big enough for our purposes.
                 MemAreaRequest::Success((mem_start, mem_end)) => {
-                    // serial_println!(
-                    //     "* Adding requested mem area to BuddyAlloc: {} to {} ({})",
-                    //     mem_start,
-                    //     mem_end,
-                    //     mem_end.addr() - mem_start.addr()
-                    // );
+                    log::debug!(
+                        "adding requested mem area: {} to {} ({})",
+                        mem_start,
+                        mem_end,
+                        mem_end.addr() - mem_start.addr()
+                    );
                     self.add_memory_area(mem_start, mem_end, block_size);
                     return true;
                 }
                 // Found one or two smaller memory areas instead, insert them and keep looking.
                 MemAreaRequest::SmallerThanReq((mem_start, mem_end), second_area) => {
                     self.add_memory_area(mem_start, mem_end, block_size);
-                    // serial_println!(
-                    //     "* Adding smaller mem area to BuddyAlloc: {} to {} ({})",
-                    //     mem_start,
-                    //     mem_end,
-                    //     mem_end.addr() - mem_start.addr()
-                    // );
+                    log::debug!(
+                        "adding smaller mem area: {} to {} ({})",
+                        mem_start,
+                        mem_end,
+                        mem_end.addr() - mem_start.addr()
+                    );
                     if let Some((mem_start, mem_end)) = second_area {
                         self.add_memory_area(mem_start, mem_end, block_size);
-                        // serial_println!(
-                        //     "* Adding smaller mem area to BuddyAlloc: {} to {} ({})",
-                        //     mem_start,
-                        //     mem_end,
-                        //     mem_end.addr() - mem_start.addr()
-                        // );
+                        log::debug!(
+                            "adding smaller mem area: {} to {} ({})",
+                            mem_start,
+                            mem_end,
+                            mem_end.addr() - mem_start.addr()
+                        );
                     }
                 }
                 // Ran out of memory! Return false.
                 MemAreaRequest::Fail => {
-                    // serial_println!(
-                    //     "! Failed to find mem area big enough for BuddyAlloc: {}",
-                    //     mem_size
-                    // );
+                    log::warn!("failed to find a mem area of {} bytes", mem_size);
                     return false;
                 }
             }
         }
     }
 
+    pub fn alloc_phys(&self, size: usize, align: usize, limit: u64) -> Option<PhysAddr> {
+        // Allocate physical memory that ends at or below limit, for callers like DMA buffers
+        // that need the physical address and not the mapping GlobalAlloc goes through.
+        // The blocks are aligned to their size relative to the start of their allocator,
+        // which is only page aligned, so larger alignments are checked.
+        self.buddy_allocators
+            .read()
+            .iter()
+            .filter_map(|allocator| allocator.try_lock())
+            .filter(|allocator| allocator.end_addr.addr() <= limit)
+            .find_map(|mut allocator| {
+                let phys = allocator.alloc(size, align)?;
+                if phys.addr() % align as u64 != 0 {
+                    allocator.dealloc(phys, size, align);
+                    return None;
+                }
+                log::trace!("allocated {} bytes at {}", size, phys);
+                Some(phys)
+            })
+    }
+
+    pub fn dealloc_phys(&self, addr: PhysAddr, size: usize, align: usize) {
+        // Give back memory from alloc_phys to the allocator whose range contains it.
+        for allocator_mtx in self.buddy_allocators.read().iter() {
+            let mut allocator = allocator_mtx.lock();
+            if allocator.contains(addr) {
+                allocator.dealloc(addr, size, align);
+                return;
+            }
+        }
+        log::warn!("could not de-allocate {}, the memory is lost", addr);
+    }
+
     fn get_mem_area_with_size(
         frame_alloc: &mut dyn FrameSingleAllocator,
         mem_size: u64,
@@ -175,19 +204,15 @@ unsafe impl GlobalAlloc for BuddyAllocatorManager {
                 .read()
                 .iter()
                 .enumerate()
-                .find_map(|(_i, allocator)| {
+                .find_map(|(i, allocator)| {
                     // for each allocator
                     allocator.try_lock().and_then(|mut allocator| {
                         allocator
                             .alloc(layout.size(), layout.align())
                             .map(|allocation| {
                                 // try allocating until one succeeds and return this allocation
-                                // serial_println!(
-                                //     " - BuddyAllocator #{} allocated {} bytes",
-                                //     i,
-                                //     layout.size()
-                                // );
-                                // serial_println!("{}", *allocator);
+                                log::trace!("allocator #{} allocated {} bytes", i, layout.size());
+                                log::trace!("{}", *allocator);
                                 allocation
                             })
                     })
@@ -202,28 +227,21 @@ unsafe impl GlobalAlloc for BuddyAllocatorManager {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let virt_addr = VirtAddr::new(ptr as u64);
         if let Some((phys_addr, _)) = virt_addr.to_phys() {
-            for (_i, allocator_mtx) in self.buddy_allocators.read().iter().enumerate() {
+            for (i, allocator_mtx) in self.buddy_allocators.read().iter().enumerate() {
                 // for each allocator
                 if let Some(mut allocator) = allocator_mtx.try_lock() {
                     // find the one whose memory range contains this address
                     if allocator.contains(phys_addr) {
                         // deallocate using this allocator!
                         allocator.dealloc(phys_addr, layout.size(), layout.align());
-                        // serial_println!(
-                        //     " - BuddyAllocator #{} de-allocated {} bytes",
-                        //     i,
-                        //     layout.size()
-                        // );
-                        // serial_println!("{}", *allocator);
+                        log::trace!("allocator #{} de-allocated {} bytes", i, layout.size());
+                        log::trace!("{}", *allocator);
                         return;
                     }
                 }
             }
         }
-        // serial_println!(
-        //     "! Could not de-allocate virtual address: {} / Memory lost",
-        //     virt_addr
-        // );
+        log::warn!("could not de-allocate {}, the memory is lost", virt_addr);
     }
 }
 
@@ -0,0 +1,270 @@
+// Synchronous message passing between tasks. A channel has two endpoints which live in fd tables
+// like the ends of a pipe, each one sends to the other. Messages carry a few bytes of data and can
+// move whole pages out of the sender's address space into the receiver's. A call sends a message
+// and blocks until the other side replies to it, which is how clients talk to servers.
+
+use crate::errno::Errno;
+use crate::fd::{FileDescription, FileRef};
+use crate::mem::EmptyFrame;
+use crate::scheduler::WaitQueue;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// messages waiting in an endpoint before senders block
+pub const CHANNEL_CAPACITY: usize = 16;
+
+// the ISA interrupt lines
+const IRQ_LINES: usize = 16;
+
+// ids of calls, 0 is a message nobody waits on
+static NEXT_CALL_ID: AtomicU64 = AtomicU64::new(1);
+
+pub struct Message {
+    pub id: u64,
+    pub data: Vec<u8>,
+    pub pages: Vec<Box<EmptyFrame>>, // already unmapped from the sender
+}
+
+impl Message {
+    pub fn new(data: Vec<u8>, pages: Vec<Box<EmptyFrame>>) -> Message {
+        Message { id: 0, data, pages }
+    }
+}
+
+// what was sent to one endpoint
+struct Inbox {
+    messages: VecDeque<Message>,
+    replies: BTreeMap<u64, Message>, // answers to the calls made from this endpoint
+    unanswered: BTreeSet<u64>,       // calls received by this endpoint
+    abandoned: BTreeSet<u64>,        // calls from this endpoint nobody waits for anymore
+}
+
+// inboxes are also filled from interrupt handlers, so they are only locked with interrupts off
+struct Channel {
+    inboxes: [Mutex<Inbox>; 2],
+    closed: [AtomicBool; 2],
+    arrived: [WaitQueue; 2], // receivers and callers waiting on each endpoint
+    room: [WaitQueue; 2],    // senders waiting for room in each inbox
+}
+
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+fn new_inbox() -> Mutex<Inbox> {
+    Mutex::new(Inbox {
+        messages: VecDeque::with_capacity(CHANNEL_CAPACITY),
+        replies: BTreeMap::new(),
+        unanswered: BTreeSet::new(),
+        abandoned: BTreeSet::new(),
+    })
+}
+
+// the two endpoints of a new channel
+pub fn new_channel() -> (FileRef, FileRef) {
+    let channel = Arc::new(Channel {
+        inboxes: [new_inbox(), new_inbox()],
+        closed: [AtomicBool::new(false), AtomicBool::new(false)],
+        arrived: [WaitQueue::new(), WaitQueue::new()],
+        room: [WaitQueue::new(), WaitQueue::new()],
+    });
+    (
+        Arc::new(Endpoint {
+            channel: channel.clone(),
+            side: 0,
+        }),
+        Arc::new(Endpoint { channel, side: 1 }),
+    )
+}
+
+impl Endpoint {
+    fn peer(&self) -> usize {
+        1 - self.side
+    }
+
+    fn peer_closed(&self) -> bool {
+        self.channel.closed[self.peer()].load(Ordering::SeqCst)
+    }
+
+    // blocks while the other endpoint is full, the message is given back if it can't be sent
+    pub fn send(&self, msg: Message) -> Result<(), (Errno, Message)> {
+        let peer = self.peer();
+        let mut msg = Some(msg);
+        let result = self.channel.room[peer].wait_interruptible(|| {
+            if self.peer_closed() {
+                return Some(Err(Errno::EPIPE));
+            }
+            let mut inbox = self.channel.inboxes[peer].lock();
+            if inbox.messages.len() >= CHANNEL_CAPACITY {
+                return None;
+            }
+            inbox.messages.extend(msg.take());
+            Some(Ok(()))
+        });
+        let result = result.and_then(|result| result);
+        self.channel.arrived[peer].wake_all();
+        result.map_err(|errno| (errno, msg.take().unwrap()))
+    }
+
+    // send without blocking, for interrupt handlers, fails if the message doesn't fit
+    pub fn try_send(&self, data: &[u8]) -> bool {
+        let peer = self.peer();
+        let sent = interrupts::without_interrupts(|| {
+            let mut inbox = self.channel.inboxes[peer].lock();
+            if self.peer_closed() || inbox.messages.len() >= CHANNEL_CAPACITY {
+                return false;
+            }
+            inbox
+                .messages
+                .push_back(Message::new(data.to_vec(), Vec::new()));
+            true
+        });
+        if sent {
+            self.channel.arrived[peer].wake_all();
+        }
+        sent
+    }
+
+    // blocks until a message is there, it stays queued if it has more than capacity bytes
+    pub fn recv(&self, capacity: usize) -> Result<Message, Errno> {
+        let side = self.side;
+        let result = self.channel.arrived[side].wait_interruptible(|| {
+            let mut inbox = self.channel.inboxes[side].lock();
+            match inbox.messages.front() {
+                Some(msg) if msg.data.len() > capacity => return Some(Err(Errno::EMSGSIZE)),
+                Some(_) => {}
+                None if self.peer_closed() => return Some(Err(Errno::EPIPE)),
+                None => return None,
+            }
+            let msg = inbox.messages.pop_front().unwrap();
+            if msg.id != 0 {
+                inbox.unanswered.insert(msg.id);
+            }
+            Some(Ok(msg))
+        });
+        self.channel.room[side].wake_all();
+        result.and_then(|result| result)
+    }
+
+    // send the message and block until it is replied to, replies longer than capacity are lost
+    pub fn call(
+        &self,
+        mut msg: Message,
+        capacity: usize,
+    ) -> Result<Message, (Errno, Option<Message>)> {
+        let side = self.side;
+        let id = NEXT_CALL_ID.fetch_add(1, Ordering::SeqCst);
+        msg.id = id;
+        self.send(msg).map_err(|(errno, msg)| (errno, Some(msg)))?;
+        let reply = self.channel.arrived[side].wait_interruptible(|| {
+            let mut inbox = self.channel.inboxes[side].lock();
+            match inbox.replies.remove(&id) {
+                Some(reply) => Some(Ok(reply)),
+                None if self.peer_closed() => Some(Err(Errno::EPIPE)),
+                None => None,
+            }
+        });
+        let reply = reply.and_then(|reply| reply);
+        if reply.is_err() {
+            // a reply that comes in later is dropped instead of waiting forever
+            interrupts::without_interrupts(|| {
+                let mut inbox = self.channel.inboxes[side].lock();
+                if inbox.replies.remove(&id).is_none() {
+                    inbox.abandoned.insert(id);
+                }
+            });
+        }
+        match reply {
+            Ok(reply) if reply.data.len() > capacity => Err((Errno::EMSGSIZE, None)),
+            Ok(reply) => Ok(reply),
+            Err(errno) => Err((errno, None)),
+        }
+    }
+
+    // answer a call received on this endpoint, never blocks as every call has room for its reply
+    pub fn reply(&self, id: u64, mut msg: Message) -> Result<(), (Errno, Message)> {
+        let peer = self.peer();
+        msg.id = id;
+        let result = interrupts::without_interrupts(|| {
+            if !self.channel.inboxes[self.side]
+                .lock()
+                .unanswered
+                .remove(&id)
+            {
+                return Err((Errno::EINVAL, msg));
+            }
+            if self.peer_closed() {
+                return Err((Errno::EPIPE, msg));
+            }
+            let mut inbox = self.channel.inboxes[peer].lock();
+            if !inbox.abandoned.remove(&id) {
+                inbox.replies.insert(id, msg);
+            }
+            Ok(())
+        });
+        if result.is_ok() {
+            self.channel.arrived[peer].wake_all();
+        }
+        result
+    }
+}
+
+impl FileDescription for Endpoint {
+    fn endpoint(&self) -> Option<&Endpoint> {
+        Some(self)
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.channel.closed[self.side].store(true, Ordering::SeqCst);
+        // whoever waits on the other endpoint gets EPIPE now
+        self.channel.arrived[self.peer()].wake_all();
+        self.channel.room[self.side].wake_all();
+    }
+}
+
+lazy_static! {
+    // endpoints userspace drivers bound to interrupt lines
+    static ref IRQ_ENDPOINTS: Mutex<Vec<Option<FileRef>>> = {
+        let mut endpoints = Vec::with_capacity(IRQ_LINES);
+        endpoints.resize(IRQ_LINES, None);
+        Mutex::new(endpoints)
+    };
+}
+
+// send a message to the endpoint's peer on every interrupt of the line, a line stays taken
+// until the driver's endpoint is closed, returns the endpoint it replaces to drop when unlocked
+pub fn bind_irq(irq: usize, file: FileRef) -> Result<Option<FileRef>, Errno> {
+    if irq >= IRQ_LINES || file.endpoint().is_none() {
+        return Err(Errno::EINVAL);
+    }
+    interrupts::without_interrupts(|| {
+        let mut endpoints = IRQ_ENDPOINTS.lock();
+        let bound = endpoints[irq].as_ref().and_then(|file| file.endpoint());
+        if bound.map_or(false, |endpoint| !endpoint.peer_closed()) {
+            return Err(Errno::EBUSY);
+        }
+        Ok(endpoints[irq].replace(file))
+    })
+}
+
+// called from interrupt handlers, false if there is no driver for the line and the kernel has
+// to handle the interrupt itself, messages are lost if the driver can't keep up
+pub fn forward_irq(irq: usize, data: &[u8]) -> bool {
+    let endpoints = IRQ_ENDPOINTS.lock();
+    match endpoints[irq].as_ref().and_then(|file| file.endpoint()) {
+        Some(endpoint) if !endpoint.peer_closed() => {
+            endpoint.try_send(data);
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,70 @@
+// The kernel command line, what follows the kernel in grub.cfg, e.g.
+//   multiboot2 /boot/kernel.bin log=debug init=/bin/sh sched=mlfq mem=512M console=serial tests
+// It is parsed once at boot before anything else is set up and start hands each subsystem its
+// options as it sets it up. Options that aren't understood are logged and otherwise ignored.
+
+use crate::console::ConsoleKind;
+use crate::programs;
+use crate::scheduler::Policy;
+use spin::Once;
+
+pub struct KernelArgs {
+    pub log: Option<&'static str>, // levels for klog, e.g. warn,buddy_alloc=trace
+    pub init: &'static str,        // the first user process
+    pub sched: Policy,
+    pub mem: Option<u64>, // ignore physical memory above this many bytes
+    pub console: ConsoleKind,
+    pub tests: bool, // run the self tests during boot
+}
+
+impl KernelArgs {
+    fn parse(cmdline: &'static str) -> KernelArgs {
+        let mut args = KernelArgs {
+            log: None,
+            init: programs::INIT,
+            sched: Policy::RoundRobin,
+            mem: None,
+            console: ConsoleKind::Vga,
+            tests: false,
+        };
+        for arg in cmdline.split_whitespace() {
+            let mut parts = arg.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap_or(""), parts.next());
+            match (name, value) {
+                ("log", Some(spec)) => args.log = Some(spec),
+                ("init", Some(path)) if path.starts_with('/') => args.init = path,
+                ("sched", Some("rr")) => args.sched = Policy::RoundRobin,
+                ("sched", Some("mlfq")) => args.sched = Policy::Mlfq,
+                ("mem", Some(size)) if parse_size(size).is_some() => args.mem = parse_size(size),
+                ("console", Some("vga")) => args.console = ConsoleKind::Vga,
+                ("console", Some("serial")) => args.console = ConsoleKind::Serial,
+                ("tests", None) => args.tests = true,
+                _ => log::warn!("ignoring unknown kernel argument {}", arg),
+            }
+        }
+        args
+    }
+}
+
+// a number of bytes with an optional K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+static ARGS: Once<KernelArgs> = Once::new();
+
+// parse the command line, only the first call does anything
+pub fn init(cmdline: &'static str) -> &'static KernelArgs {
+    ARGS.call_once(|| KernelArgs::parse(cmdline))
+}
+
+// the arguments the kernel was started with, the defaults if init didn't run yet
+pub fn args() -> &'static KernelArgs {
+    ARGS.call_once(|| KernelArgs::parse(""))
+}
@@ -0,0 +1,125 @@
+// Keyboard input for stdin. The console is line buffered: typed characters are echoed and
+// collected until enter is pressed, only then can the line be read. Output goes to the screen or
+// the serial port, as set by the console option of the command line.
+
+use crate::errno::Errno;
+use crate::fd::FileDescription;
+use crate::scheduler::WaitQueue;
+use crate::serial_port;
+use crate::vga_buffer;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleKind {
+    Vga,
+    Serial,
+}
+
+static ON_SERIAL: AtomicBool = AtomicBool::new(false);
+
+// pick where output goes, the screen until this is called
+pub fn init(kind: ConsoleKind) {
+    ON_SERIAL.store(kind == ConsoleKind::Serial, Ordering::SeqCst);
+}
+
+pub fn _print(args: fmt::Arguments) {
+    if ON_SERIAL.load(Ordering::SeqCst) {
+        serial_port::_print(args);
+    } else {
+        vga_buffer::_print(args);
+    }
+}
+
+macro_rules! console_print {
+    ($($arg:tt)*) => (_print(format_args!($($arg)*)));
+}
+
+const MAX_LINE: usize = 256;
+const BACKSPACE: char = '\x08';
+
+struct Input {
+    line: Vec<u8>,       // the line being typed
+    ready: VecDeque<u8>, // finished lines waiting to be read
+}
+
+lazy_static! {
+    static ref INPUT: Mutex<Input> = Mutex::new(Input {
+        line: Vec::with_capacity(MAX_LINE),
+        ready: VecDeque::new(),
+    });
+}
+
+static INPUT_READY: WaitQueue = WaitQueue::new();
+
+// called from the keyboard interrupt
+pub fn handle_char(c: char) {
+    let mut input = INPUT.lock();
+    match c {
+        '\n' => {
+            console_print!("\n");
+            let line: Vec<u8> = input.line.drain(..).collect();
+            input.ready.extend(line);
+            input.ready.push_back(b'\n');
+            drop(input);
+            INPUT_READY.wake_all();
+        }
+        BACKSPACE => {
+            // drop a whole UTF-8 character
+            while let Some(byte) = input.line.pop() {
+                if byte & 0xc0 != 0x80 {
+                    console_print!("{}", BACKSPACE);
+                    break;
+                }
+            }
+        }
+        c if !c.is_control() && input.line.len() + c.len_utf8() <= MAX_LINE => {
+            let mut buf = [0; 4];
+            input.line.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            console_print!("{}", c);
+        }
+        _ => {}
+    }
+}
+
+// block until a line is there, then read at most up to the end of it
+pub fn read(buf: &mut [u8]) -> Result<usize, Errno> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    INPUT_READY.wait_interruptible(|| {
+        let mut input = INPUT.lock();
+        if input.ready.is_empty() {
+            return None;
+        }
+        let line_len = match input.ready.iter().position(|b| *b == b'\n') {
+            Some(newline) => newline + 1,
+            None => input.ready.len(),
+        };
+        let count = min(buf.len(), line_len);
+        for (dst, src) in buf.iter_mut().zip(input.ready.drain(..count)) {
+            *dst = src;
+        }
+        Some(count)
+    })
+}
+
+// the keyboard and the screen as a file, what fds 0, 1 and 2 start out as
+pub struct Console;
+
+impl FileDescription for Console {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        console_print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
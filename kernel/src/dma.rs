@@ -0,0 +1,190 @@
+// Memory devices read and write by DMA: physically contiguous, zeroed and with a known physical
+// address and alignment. It comes straight from the buddy allocators, from below 4 GiB for
+// devices that can't address more. It is used through the mapping of physical memory below
+// 4 GiB, or through a mapping of its own in the kernel window if it is above that or must not
+// be cached.
+
+use crate::errno::Errno;
+use crate::global_alloc;
+use crate::mem::{
+    get_page_table, PhysAddr, VirtAddr, BIT_HUGE, BIT_NO_CACHE, BIT_PRESENT, BIT_WRITABLE,
+    FRAME_SIZE, KERNEL_WINDOW_END, KERNEL_WINDOW_START,
+};
+use alloc::collections::BTreeMap;
+use core::slice;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// for devices that only take 32 bit addresses
+pub const BELOW_4G: u8 = 1 << 0;
+// mapped with caching disabled
+pub const UNCACHED: u8 = 1 << 1;
+
+const LIMIT_4G: u64 = 1 << 32;
+
+lazy_static! {
+    // the free ranges of the kernel window, by start address to end address
+    static ref WINDOW: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+}
+
+// create the P2 table of the kernel window before there are page tables that copy it
+pub fn init() {
+    unsafe {
+        // a huge entry that isn't present only creates the tables above it
+        get_page_table().map_virt_to_phys(
+            VirtAddr::new(KERNEL_WINDOW_START),
+            PhysAddr::new(0),
+            BIT_HUGE | BIT_WRITABLE,
+        );
+    }
+    WINDOW.lock().insert(KERNEL_WINDOW_START, KERNEL_WINDOW_END);
+}
+
+// size and alignment rounded to whole pages, what is allocated and later freed
+fn round(size: usize, align: usize) -> Result<(usize, usize), Errno> {
+    if size == 0 || !align.is_power_of_two() {
+        return Err(Errno::EINVAL);
+    }
+    let page = FRAME_SIZE as usize;
+    Ok(((size + page - 1) & !(page - 1), align.max(page)))
+}
+
+// first fit of size bytes in the kernel window
+fn window_alloc(size: u64) -> Option<u64> {
+    let mut window = WINDOW.lock();
+    let (start, end) = window
+        .iter()
+        .find(|&(start, end)| end - start >= size)
+        .map(|(&start, &end)| (start, end))?;
+    window.remove(&start);
+    if start + size < end {
+        window.insert(start + size, end);
+    }
+    Some(start)
+}
+
+fn window_free(start: u64, size: u64) {
+    let mut window = WINDOW.lock();
+    let mut end = start + size;
+    let mut start = start;
+    // join it with the free ranges right before and after it
+    if let Some(next_end) = window.remove(&end) {
+        end = next_end;
+    }
+    let before = window
+        .range(..start)
+        .next_back()
+        .map(|(&prev, &prev_end)| (prev, prev_end));
+    if let Some((prev, prev_end)) = before {
+        if prev_end == start {
+            start = prev;
+        }
+    }
+    window.insert(start, end);
+}
+
+// the virtual address to use the memory at
+unsafe fn map(phys: PhysAddr, size: usize, flags: u8) -> Result<VirtAddr, Errno> {
+    if flags & UNCACHED == 0 && phys.addr() + size as u64 <= LIMIT_4G {
+        return phys.to_virt().ok_or(Errno::ENOMEM);
+    }
+    let start = window_alloc(size as u64).ok_or(Errno::ENOMEM)?;
+    let mut options = BIT_PRESENT | BIT_WRITABLE;
+    if flags & UNCACHED != 0 {
+        options |= BIT_NO_CACHE;
+    }
+    let table = get_page_table();
+    for offset in (0..size as u64).step_by(FRAME_SIZE as usize) {
+        table.map_virt_to_phys(VirtAddr::new(start + offset), phys.offset(offset), options);
+    }
+    Ok(VirtAddr::new(start))
+}
+
+// allocate size bytes aligned to align with the options in flags
+pub fn alloc(size: usize, align: usize, flags: u8) -> Result<(VirtAddr, PhysAddr), Errno> {
+    let (size, align) = round(size, align)?;
+    let limit = match flags & BELOW_4G {
+        0 => u64::MAX,
+        _ => LIMIT_4G,
+    };
+    let phys = global_alloc::with_buddy_allocator(|buddy| buddy.alloc_phys(size, align, limit))
+        .flatten()
+        .ok_or(Errno::ENOMEM)?;
+    let virt = match unsafe { map(phys, size, flags) } {
+        Ok(virt) => virt,
+        Err(errno) => {
+            global_alloc::with_buddy_allocator(|buddy| buddy.dealloc_phys(phys, size, align));
+            return Err(errno);
+        }
+    };
+    unsafe { (virt.addr() as *mut u8).write_bytes(0, size) };
+    Ok((virt, phys))
+}
+
+// allocate size bytes aligned to align anywhere in memory, cached since DMA on x86 keeps the
+// caches coherent
+pub fn alloc_coherent(size: usize, align: usize) -> Result<(VirtAddr, PhysAddr), Errno> {
+    alloc(size, align, 0)
+}
+
+// free what alloc or alloc_coherent returned for the same size and alignment
+pub unsafe fn free(virt: VirtAddr, phys: PhysAddr, size: usize, align: usize) {
+    let (size, align) = match round(size, align) {
+        Ok(rounded) => rounded,
+        Err(_) => return,
+    };
+    if virt.addr() >= KERNEL_WINDOW_START {
+        let table = get_page_table();
+        for offset in (0..size as u64).step_by(FRAME_SIZE as usize) {
+            table.unmap(virt.offset(offset));
+        }
+        window_free(virt.addr(), size as u64);
+    }
+    global_alloc::with_buddy_allocator(|buddy| buddy.dealloc_phys(phys, size, align));
+}
+
+// DMA memory that is freed when it is dropped
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+    align: usize,
+}
+
+impl DmaBuffer {
+    pub fn new(size: usize, align: usize, flags: u8) -> Result<DmaBuffer, Errno> {
+        let (virt, phys) = alloc(size, align, flags)?;
+        Ok(DmaBuffer {
+            virt,
+            phys,
+            size,
+            align,
+        })
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.addr() as *const u8, self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.addr() as *mut u8, self.size) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { free(self.virt, self.phys, self.size, self.align) };
+    }
+}
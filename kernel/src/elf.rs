@@ -0,0 +1,175 @@
+// Loader for statically linked ELF64 executables, see user/linker.ld for how they are built.
+
+use crate::abi::{AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use crate::addr_space::{AddressSpace, USER_STACK_SIZE, USER_STACK_TOP};
+use crate::errno::Errno;
+use crate::mem::FRAME_SIZE;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_TYPE_EXEC: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3e;
+const SEGMENT_LOAD: u32 = 1;
+const SEGMENT_FLAG_WRITE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    ident: [u8; 16],
+    elf_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    seg_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+// what the new task needs to know about the program it runs
+pub struct LoadedImage {
+    pub entry: u64,
+    pub brk_start: u64,
+    phdr: u64,
+    phnum: u64,
+}
+
+fn read_struct<T: Copy>(image: &[u8], offset: u64) -> Result<T, Errno> {
+    let end = offset
+        .checked_add(size_of::<T>() as u64)
+        .ok_or(Errno::ENOEXEC)?;
+    if end > image.len() as u64 {
+        return Err(Errno::ENOEXEC);
+    }
+    Ok(unsafe { read_unaligned(image.as_ptr().offset(offset as isize) as *const T) })
+}
+
+// map and fill the PT_LOAD segments of the image into the address space
+pub unsafe fn load(image: &[u8], space: &mut AddressSpace) -> Result<LoadedImage, Errno> {
+    let header: FileHeader = read_struct(image, 0)?;
+    if header.ident[..4] != ELF_MAGIC
+        || header.ident[4] != ELF_CLASS_64
+        || header.ident[5] != ELF_DATA_LSB
+        || header.elf_type != ELF_TYPE_EXEC
+        || header.machine != ELF_MACHINE_X86_64
+        || header.phentsize as usize != size_of::<ProgramHeader>()
+    {
+        return Err(Errno::ENOEXEC);
+    }
+    let mut brk_start = 0;
+    let mut phdr = 0;
+    for i in 0..header.phnum as u64 {
+        let ph: ProgramHeader =
+            read_struct(image, header.phoff + i * size_of::<ProgramHeader>() as u64)?;
+        if ph.seg_type != SEGMENT_LOAD || ph.memsz == 0 {
+            continue;
+        }
+        let file_end = ph.offset.checked_add(ph.filesz).ok_or(Errno::ENOEXEC)?;
+        let mem_end = ph.vaddr.checked_add(ph.memsz).ok_or(Errno::ENOEXEC)?;
+        if ph.filesz > ph.memsz || file_end > image.len() as u64 || mem_end > USER_STACK_TOP {
+            return Err(Errno::ENOEXEC);
+        }
+        space.map_range(ph.vaddr, ph.memsz, ph.flags & SEGMENT_FLAG_WRITE != 0)?;
+        // the rest up to memsz (.bss) stays zeroed
+        space.write(ph.vaddr, &image[ph.offset as usize..file_end as usize])?;
+        // the program headers are usually loaded with the first segment
+        if header.phoff >= ph.offset && header.phoff < file_end {
+            phdr = ph.vaddr + (header.phoff - ph.offset);
+        }
+        if mem_end > brk_start {
+            brk_start = mem_end;
+        }
+    }
+    if brk_start == 0 {
+        return Err(Errno::ENOEXEC);
+    }
+    Ok(LoadedImage {
+        entry: header.entry,
+        brk_start: (brk_start + FRAME_SIZE - 1) & !(FRAME_SIZE - 1),
+        phdr,
+        phnum: header.phnum as u64,
+    })
+}
+
+// map the stack and lay out argc, argv, envp and the auxiliary vector on it as in the
+// System V ABI, the strings go at the very top, returns the initial stack pointer
+pub unsafe fn setup_stack(
+    space: &mut AddressSpace,
+    loaded: &LoadedImage,
+    args: &[&[u8]],
+    env: &[&[u8]],
+) -> Result<u64, Errno> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    space.map_range(stack_bottom, USER_STACK_SIZE, true)?;
+    let mut sp = USER_STACK_TOP;
+    let mut push_strings =
+        |strings: &[&[u8]], space: &mut AddressSpace| -> Result<Vec<u64>, Errno> {
+            let mut ptrs = Vec::with_capacity(strings.len());
+            for string in strings {
+                sp = sp
+                    .checked_sub(string.len() as u64 + 1)
+                    .ok_or(Errno::E2BIG)?;
+                if sp < stack_bottom + FRAME_SIZE {
+                    return Err(Errno::E2BIG);
+                }
+                space.write(sp, string)?;
+                space.write(sp + string.len() as u64, &[0])?;
+                ptrs.push(sp);
+            }
+            Ok(ptrs)
+        };
+    let arg_ptrs = push_strings(args, space)?;
+    let env_ptrs = push_strings(env, space)?;
+    let auxv = [
+        (AT_PHDR, loaded.phdr),
+        (AT_PHENT, size_of::<ProgramHeader>() as u64),
+        (AT_PHNUM, loaded.phnum),
+        (AT_PAGESZ, FRAME_SIZE),
+        (AT_ENTRY, loaded.entry),
+        (AT_NULL, 0),
+    ];
+    let mut words: Vec<u64> = Vec::new();
+    words.push(arg_ptrs.len() as u64);
+    words.extend_from_slice(&arg_ptrs);
+    words.push(0);
+    words.extend_from_slice(&env_ptrs);
+    words.push(0);
+    for (tag, val) in auxv.iter() {
+        words.push(*tag);
+        words.push(*val);
+    }
+    // the stack pointer has to be 16-byte aligned at the entry point
+    let table_size = (words.len() * size_of::<u64>()) as u64;
+    let rsp = (sp - table_size) & !0xf;
+    if rsp < stack_bottom + FRAME_SIZE {
+        return Err(Errno::E2BIG);
+    }
+    let mut bytes = Vec::with_capacity(table_size as usize);
+    for word in words.iter() {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    space.write(rsp, &bytes)?;
+    Ok(rsp)
+}
@@ -0,0 +1,389 @@
+// Handlers for the 32 architectural exceptions. Every exception prints a report with the
+// registers of the faulting context, faults in userspace then become a signal to the task which
+// terminates it unless it handles the signal, faults in the kernel stop the CPU.
+
+use crate::abi::{SIGBUS, SIGFPE, SIGILL, SIGSEGV, SIGTRAP};
+use crate::backtrace;
+use crate::gdbstub;
+use crate::percpu;
+use crate::println;
+use crate::scheduler::{Context, SCHEDULER};
+use crate::signal;
+use crate::uaccess;
+
+const DEBUG: u64 = 1;
+const BREAKPOINT: u64 = 3;
+const DOUBLE_FAULT: u64 = 8;
+const PAGE_FAULT: u64 = 14;
+const MACHINE_CHECK: u64 = 18;
+
+// general purpose registers in the order of scheduler::Context, pushed by exception_entry!,
+// then the vector, the error code (0 for exceptions without one) and what the CPU pushed
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct ExceptionFrame {
+    pub rbp: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionFrame {
+    fn from_user(&self) -> bool {
+        self.cs & 3 != 0
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            rbp: self.rbp,
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            cs: self.cs,
+            rflags: self.rflags,
+            rsp: self.rsp,
+            ss: self.ss,
+        }
+    }
+
+    fn set_context(&mut self, ctx: &Context) {
+        let (vector, error_code) = (self.vector, self.error_code);
+        *self = ExceptionFrame {
+            rbp: ctx.rbp,
+            rax: ctx.rax,
+            rbx: ctx.rbx,
+            rcx: ctx.rcx,
+            rdx: ctx.rdx,
+            rsi: ctx.rsi,
+            rdi: ctx.rdi,
+            r8: ctx.r8,
+            r9: ctx.r9,
+            r10: ctx.r10,
+            r11: ctx.r11,
+            r12: ctx.r12,
+            r13: ctx.r13,
+            r14: ctx.r14,
+            r15: ctx.r15,
+            vector,
+            error_code,
+            rip: ctx.rip,
+            cs: ctx.cs,
+            rflags: ctx.rflags,
+            rsp: ctx.rsp,
+            ss: ctx.ss,
+        };
+    }
+}
+
+// an entry point saving all registers into an ExceptionFrame for handle_exception,
+// which can change them before they are restored
+macro_rules! exception_entry {
+    ($name:ident, $vector:literal) => {
+        exception_entry!(@entry $name, $vector, "push 0 // no error code");
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        exception_entry!(@entry $name, $vector, "");
+    };
+    (@entry $name:ident, $vector:literal, $push_error:literal) => {
+        #[naked]
+        unsafe extern "C" fn $name() {
+            asm!(concat!($push_error, "
+            push {vector}
+            test qword ptr [rsp + 24], 3 // coming from ring 3 we still have the user GS base
+            jz 2f
+            swapgs
+            2:
+            push r15; push r14; push r13; push r12; push r11; push r10; push r9
+            push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp
+            mov rdi, rsp
+            cld
+            call {handler}
+            pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8
+            pop r9; pop r10; pop r11; pop r12; pop r13; pop r14; pop r15
+            add rsp, 16 // vector and error code
+            test qword ptr [rsp + 8], 3
+            jz 2f
+            swapgs
+            2:
+            iretq"),
+            vector = const $vector,
+            handler = sym handle_exception,
+            options(noreturn));
+        }
+    };
+}
+
+exception_entry!(divide_error, 0);
+exception_entry!(debug, 1);
+exception_entry!(nmi, 2);
+exception_entry!(breakpoint, 3);
+exception_entry!(overflow, 4);
+exception_entry!(bound_range, 5);
+exception_entry!(invalid_opcode, 6);
+exception_entry!(device_not_available, 7);
+exception_entry!(double_fault, 8, error_code);
+exception_entry!(coprocessor_segment_overrun, 9);
+exception_entry!(invalid_tss, 10, error_code);
+exception_entry!(segment_not_present, 11, error_code);
+exception_entry!(stack_segment, 12, error_code);
+exception_entry!(general_protection, 13, error_code);
+exception_entry!(page_fault, 14, error_code);
+exception_entry!(reserved_15, 15);
+exception_entry!(x87_floating_point, 16);
+exception_entry!(alignment_check, 17, error_code);
+exception_entry!(machine_check, 18);
+exception_entry!(simd_floating_point, 19);
+exception_entry!(virtualization, 20);
+exception_entry!(control_protection, 21, error_code);
+exception_entry!(reserved_22, 22);
+exception_entry!(reserved_23, 23);
+exception_entry!(reserved_24, 24);
+exception_entry!(reserved_25, 25);
+exception_entry!(reserved_26, 26);
+exception_entry!(reserved_27, 27);
+exception_entry!(hypervisor_injection, 28);
+exception_entry!(vmm_communication, 29, error_code);
+exception_entry!(security, 30, error_code);
+exception_entry!(reserved_31, 31);
+
+// the IDT gates of vectors 0 to 31
+pub static ENTRIES: [unsafe extern "C" fn(); 32] = [
+    divide_error,
+    debug,
+    nmi,
+    breakpoint,
+    overflow,
+    bound_range,
+    invalid_opcode,
+    device_not_available,
+    double_fault,
+    coprocessor_segment_overrun,
+    invalid_tss,
+    segment_not_present,
+    stack_segment,
+    general_protection,
+    page_fault,
+    reserved_15,
+    x87_floating_point,
+    alignment_check,
+    machine_check,
+    simd_floating_point,
+    virtualization,
+    control_protection,
+    reserved_22,
+    reserved_23,
+    reserved_24,
+    reserved_25,
+    reserved_26,
+    reserved_27,
+    hypervisor_injection,
+    vmm_communication,
+    security,
+    reserved_31,
+];
+
+// mnemonic and description of every vector
+const NAMES: [(&str, &str); 32] = [
+    ("#DE", "divide error"),
+    ("#DB", "debug"),
+    ("NMI", "non-maskable interrupt"),
+    ("#BP", "breakpoint"),
+    ("#OF", "overflow"),
+    ("#BR", "bound range exceeded"),
+    ("#UD", "invalid opcode"),
+    ("#NM", "device not available"),
+    ("#DF", "double fault"),
+    ("CSO", "coprocessor segment overrun"),
+    ("#TS", "invalid TSS"),
+    ("#NP", "segment not present"),
+    ("#SS", "stack-segment fault"),
+    ("#GP", "general protection"),
+    ("#PF", "page fault"),
+    ("-", "reserved"),
+    ("#MF", "x87 floating-point"),
+    ("#AC", "alignment check"),
+    ("#MC", "machine check"),
+    ("#XM", "SIMD floating-point"),
+    ("#VE", "virtualization"),
+    ("#CP", "control protection"),
+    ("-", "reserved"),
+    ("-", "reserved"),
+    ("-", "reserved"),
+    ("-", "reserved"),
+    ("-", "reserved"),
+    ("-", "reserved"),
+    ("#HV", "hypervisor injection"),
+    ("#VC", "VMM communication"),
+    ("#SX", "security"),
+    ("-", "reserved"),
+];
+
+// the signal a userspace fault is turned into
+fn signal_for(vector: u64) -> u32 {
+    match vector {
+        0 | 16 | 19 => SIGFPE,
+        6 => SIGILL,
+        1 | 3 => SIGTRAP,
+        11 | 12 | 17 => SIGBUS,
+        _ => SIGSEGV,
+    }
+}
+
+fn read_cr2() -> u64 {
+    let cr2: u64;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2) };
+    cr2
+}
+
+fn read_cr3() -> u64 {
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3) };
+    cr3
+}
+
+// what the error code of the vector says, as far as there is anything to decode
+fn print_error_code(vector: u64, code: u64) {
+    match vector {
+        PAGE_FAULT => {
+            let pick = |bit: u64, set, clear| if code & bit != 0 { set } else { clear };
+            println!(
+                "  error code {:#x}: {} {} from {} mode{}{}{}{}",
+                code,
+                pick(1, "protection violation", "page not present"),
+                pick(2, "on write", "on read"),
+                pick(4, "user", "supervisor"),
+                pick(8, ", reserved bit set", ""),
+                pick(0x10, ", instruction fetch", ""),
+                pick(0x20, ", protection key", ""),
+                pick(0x40, ", shadow stack", ""),
+            );
+        }
+        // these push the selector that caused the fault, or 0 if it wasn't about a selector
+        10..=13 if code != 0 => {
+            println!(
+                "  error code {:#x}: {} index {:#x}{}",
+                code,
+                match (code >> 1) & 3 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                },
+                (code >> 3) & 0x1fff,
+                if code & 1 != 0 {
+                    ", external event"
+                } else {
+                    ""
+                },
+            );
+        }
+        8 | 10..=14 | 17 | 21 | 29 | 30 => println!("  error code {:#x}", code),
+        _ => {}
+    }
+}
+
+fn print_report(frame: &ExceptionFrame) {
+    let (mnemonic, name) = NAMES[frame.vector as usize];
+    let task = percpu::try_current().and_then(|percpu| percpu.cur_task.get());
+    println!(
+        "{} {} (vector {}) in {} mode",
+        mnemonic,
+        name,
+        frame.vector,
+        if frame.from_user() { "user" } else { "kernel" },
+    );
+    match task {
+        Some(pid) => println!("  task {}", pid),
+        None => println!("  no task"),
+    }
+    print_error_code(frame.vector, frame.error_code);
+    println!("  cr2 {:016x} cr3 {:016x}", read_cr2(), read_cr3());
+    println!(
+        "  rip {:016x} cs {:04x} rflags {:016x} rsp {:016x} ss {:04x}",
+        frame.rip, frame.cs, frame.rflags, frame.rsp, frame.ss
+    );
+    println!(
+        "  rax {:016x} rbx {:016x} rcx {:016x} rdx {:016x}",
+        frame.rax, frame.rbx, frame.rcx, frame.rdx
+    );
+    println!(
+        "  rsi {:016x} rdi {:016x} rbp {:016x} r8  {:016x}",
+        frame.rsi, frame.rdi, frame.rbp, frame.r8
+    );
+    println!(
+        "  r9  {:016x} r10 {:016x} r11 {:016x} r12 {:016x}",
+        frame.r9, frame.r10, frame.r11, frame.r12
+    );
+    println!(
+        "  r13 {:016x} r14 {:016x} r15 {:016x}",
+        frame.r13, frame.r14, frame.r15
+    );
+}
+
+// a fault in userspace is the task's problem, it gets a signal it can't ignore
+unsafe fn user_fault(frame: &mut ExceptionFrame, sig: u32) {
+    SCHEDULER.with_current_signals(|signals| signals.force(sig));
+    let mut ctx = frame.context();
+    signal::deliver(&mut ctx);
+    frame.set_context(&ctx);
+}
+
+extern "C" fn handle_exception(frame: &mut ExceptionFrame) {
+    // a fault while copying from or to userspace makes the copy fail instead
+    if frame.vector == PAGE_FAULT && !frame.from_user() {
+        if let Some(fixup) = uaccess::fixup_address(frame.rip) {
+            frame.rip = fixup;
+            return;
+        }
+    }
+    // with the GDB stub running, breakpoints and single steps in the kernel are for GDB
+    let trap = frame.vector == DEBUG || frame.vector == BREAKPOINT;
+    if trap && !frame.from_user() && gdbstub::enabled() {
+        gdbstub::handle(frame);
+        return;
+    }
+    print_report(frame);
+    // a double fault or machine check leaves nothing to go back to, even in userspace
+    if frame.from_user() && frame.vector != DOUBLE_FAULT && frame.vector != MACHINE_CHECK {
+        unsafe { user_fault(frame, signal_for(frame.vector)) };
+        return;
+    }
+    backtrace::print_from(frame.rip, frame.rbp);
+    // an int3 in the kernel is a debugging aid, not an error
+    if frame.vector == BREAKPOINT {
+        return;
+    }
+    println!("kernel halted");
+    loop {
+        unsafe { asm!("cli; hlt") };
+    }
+}
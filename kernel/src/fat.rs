@@ -0,0 +1,1224 @@
+// FAT32, what mkfs.fat makes and every other OS can read, for exchanging files with the host.
+// Long file names are read and written, short 8.3 names are made up as aliases when a name
+// doesn't fit. The sectors of the FAT that are used are cached, changes to them are written to
+// every copy of the FAT when an operation is done. Free clusters are searched for starting
+// after the one found last, which the FSInfo sector remembers across mounts.
+//
+// FAT has no inodes: a file is known by where its directory entry is, the first cluster of the
+// directory and the index of the entry in it, and its size and first cluster are read from that
+// entry every time. The files looked up at the same entry share a flag that unlinking it sets,
+// so that once the entry is reused they don't reach the new file. There are no permissions
+// either, the write bits map to the read-only attribute.
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::errno::Errno;
+use crate::rtc::{self, DateTime};
+use crate::scheduler::SleepLock;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::char;
+use core::cmp::min;
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const BOOT_SIGNATURE: u16 = 0xaa55;
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+// the lowercase flags of the case byte, what Windows uses for names like readme.txt
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+// where the UTF-16 chars of a long name entry are
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME: usize = 255;
+
+const CLUSTER_MASK: u32 = 0x0fff_ffff;
+const CHAIN_END: u32 = 0x0fff_fff8; // and anything above
+const FIRST_CLUSTER: u32 = 2;
+
+// the FAT sectors kept in memory, clean ones are dropped when there are more
+const FAT_CACHE_SECTORS: usize = 64;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn zeroed(len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.resize(len, 0);
+    buf
+}
+
+// FAT keeps local time in two 16 bit fields, the RTC is taken to be in the same zone
+fn fat_time(timestamp: u64) -> (u16, u16) {
+    let time = DateTime::from_timestamp(timestamp);
+    let year = min(time.year.saturating_sub(1980), 127);
+    let date = (year << 9) | (time.month << 5) | time.day;
+    let clock = (time.hours << 11) | (time.minutes << 5) | (time.seconds / 2);
+    (date as u16, clock as u16)
+}
+
+fn timestamp(date: u16, clock: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    let time = DateTime {
+        year: 1980 + (date >> 9) as u64,
+        month: min(max1((date >> 5) as u64 & 0xf), 12),
+        day: max1(date as u64 & 0x1f),
+        hours: (clock >> 11) as u64,
+        minutes: (clock >> 5) as u64 & 0x3f,
+        seconds: (clock as u64 & 0x1f) * 2,
+    };
+    time.timestamp()
+}
+
+fn max1(value: u64) -> u64 {
+    if value == 0 {
+        1
+    } else {
+        value
+    }
+}
+
+// where things are on the volume, in sectors unless it says otherwise
+struct Geometry {
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root: u32, // first cluster of the root directory
+    fsinfo: Option<u64>,
+}
+
+impl Geometry {
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn valid(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster < self.cluster_count + FIRST_CLUSTER
+    }
+}
+
+struct FatSector {
+    data: [u8; SECTOR_SIZE],
+    dirty: bool,
+}
+
+struct State {
+    fat: BTreeMap<u64, FatSector>, // by sector number in the first FAT
+    free_count: u32,               // FSINFO_UNKNOWN if the volume didn't know it
+    next_free: u32,
+    fsinfo_dirty: bool,
+    files: BTreeMap<(u32, usize), Weak<AtomicBool>>, // what the files at a place share
+}
+
+impl State {
+    // the flag of the files at place, set once it is unlinked
+    fn unlinked_flag(&mut self, place: Place) -> Arc<AtomicBool> {
+        let key = (place.dir, place.index);
+        if let Some(flag) = self.files.get(&key).and_then(Weak::upgrade) {
+            return flag;
+        }
+        // forget the places whose files are all gone
+        self.files.retain(|_, flag| flag.strong_count() != 0);
+        let flag = Arc::new(AtomicBool::new(false));
+        self.files.insert(key, Arc::downgrade(&flag));
+        flag
+    }
+
+    fn unlinked(&mut self, place: Place) {
+        let flag = self.files.remove(&(place.dir, place.index));
+        if let Some(flag) = flag.as_ref().and_then(Weak::upgrade) {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    // one operation at a time, they are done with it locked, it is held across device I/O
+    state: SleepLock<State>,
+}
+
+// a short directory entry
+struct Short {
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    ctime: u64,
+    mtime: u64,
+    atime: u64,
+}
+
+impl Short {
+    fn parse(entry: &[u8]) -> Short {
+        let cluster = (u16_at(entry, 20) as u32) << 16 | u16_at(entry, 26) as u32;
+        Short {
+            attr: entry[11],
+            cluster,
+            size: u32_at(entry, 28),
+            ctime: timestamp(u16_at(entry, 16), u16_at(entry, 14)),
+            mtime: timestamp(u16_at(entry, 24), u16_at(entry, 22)),
+            atime: timestamp(u16_at(entry, 18), 0),
+        }
+    }
+}
+
+fn set_cluster(entry: &mut [u8], cluster: u32) {
+    put_u16(entry, 20, (cluster >> 16) as u16);
+    put_u16(entry, 26, cluster as u16);
+}
+
+// mark an entry as modified now
+fn touch(entry: &mut [u8]) {
+    let (date, clock) = fat_time(rtc::now());
+    put_u16(entry, 22, clock);
+    put_u16(entry, 24, date);
+    put_u16(entry, 18, date);
+    entry[11] |= ATTR_ARCHIVE;
+}
+
+// a directory read into memory as a whole
+struct Dir {
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+    dirty: Vec<bool>, // for each cluster
+}
+
+impl Dir {
+    fn slots(&self) -> usize {
+        self.data.len() / ENTRY_SIZE
+    }
+
+    fn slot(&self, index: usize) -> &[u8] {
+        &self.data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+
+    fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        let cluster_size = self.data.len() / self.clusters.len();
+        self.dirty[index * ENTRY_SIZE / cluster_size] = true;
+        &mut self.data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]
+    }
+}
+
+// an entry found in a directory, with its long name if it has one
+struct Found {
+    first: usize, // the first slot of the long name entries, or index without a long name
+    index: usize, // the slot of the short entry
+    name: String,
+    short_name: [u8; 11],
+    entry: Short,
+}
+
+fn checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &c| (sum >> 1 | sum << 7).wrapping_add(c))
+}
+
+// how a short name looks, e.g. README.TXT, or readme.txt with the case flags
+fn display_short(entry: &[u8]) -> String {
+    let case = entry[12];
+    let mut name = String::new();
+    let lower = |c: u8, flag: u8| match case & flag {
+        0 => c as char,
+        _ => (c as char).to_ascii_lowercase(),
+    };
+    for &c in entry[..8].iter().take_while(|&&c| c != b' ') {
+        // a first byte of 0x05 stands for 0xe5, which marks free entries
+        name.push(if c == 0x05 {
+            '\u{e5}'
+        } else {
+            lower(c, CASE_LOWER_BASE)
+        });
+    }
+    if entry[8] != b' ' {
+        name.push('.');
+        for &c in entry[8..11].iter().take_while(|&&c| c != b' ') {
+            name.push(lower(c, CASE_LOWER_EXT));
+        }
+    }
+    name
+}
+
+fn parse_dir(dir: &Dir) -> Vec<Found> {
+    let mut found = Vec::new();
+    // the long name being collected: its first slot, checksum, next order expected and chars
+    let mut long: Option<(usize, u8, u8, Vec<u16>)> = None;
+    for index in 0..dir.slots() {
+        let entry = dir.slot(index);
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attr = entry[11];
+        if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            let order = entry[0] & !LAST_LONG_ENTRY;
+            if entry[0] & LAST_LONG_ENTRY != 0 && order > 0 {
+                let mut chars = Vec::new();
+                chars.resize(order as usize * LONG_NAME_CHARS, 0xffff);
+                long = Some((index, entry[13], order, chars));
+            }
+            if let Some((_, ref sum, ref mut next, ref mut chars)) = long {
+                if order != *next || entry[13] != *sum {
+                    long = None;
+                    continue;
+                }
+                let start = (order as usize - 1) * LONG_NAME_CHARS;
+                for (i, &offset) in LONG_NAME_OFFSETS.iter().enumerate() {
+                    chars[start + i] = u16_at(entry, offset);
+                }
+                *next -= 1;
+            }
+            continue;
+        }
+        if attr & ATTR_VOLUME_ID != 0 {
+            long = None;
+            continue;
+        }
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&entry[..11]);
+        let (first, name) = match long.take() {
+            Some((first, sum, 0, chars)) if sum == checksum(&short_name) => {
+                let len = chars.iter().position(|&c| c == 0).unwrap_or(chars.len());
+                let name = char::decode_utf16(chars[..len].iter().cloned())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (first, name)
+            }
+            _ => (index, display_short(entry)),
+        };
+        if name == "." || name == ".." {
+            continue;
+        }
+        found.push(Found {
+            first,
+            index,
+            name,
+            short_name,
+            entry: Short::parse(entry),
+        });
+    }
+    found
+}
+
+// names are compared like Windows does, ignoring the case of ASCII letters
+fn find<'a>(found: &'a [Found], name: &str) -> Option<&'a Found> {
+    found
+        .iter()
+        .find(|entry| entry.name.eq_ignore_ascii_case(name))
+}
+
+const SHORT_NAME_SPECIALS: &[u8] = b"$%'-_@~`!(){}^#&";
+
+fn short_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIALS.contains(&c) || c >= 0x80
+}
+
+// the short name of a name that is one already, like README.TXT
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let fits = |part: &str, len: usize| {
+        part.len() <= len && part.bytes().all(|c| c < 0x80 && short_char(c))
+    };
+    if base.is_empty() || !fits(base, 8) || !fits(ext, 3) || name.ends_with('.') {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short_name)
+}
+
+// a short name for a long one, BASIS~N.EXT with an N no other entry has
+fn alias(name: &str, found: &[Found]) -> Result<[u8; 11], Errno> {
+    let clean = |part: &str, len: usize| -> Vec<u8> {
+        part.bytes()
+            .filter(|&c| c != b' ' && c != b'.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if c < 0x80 && short_char(c) { c } else { b'_' })
+            .take(len)
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    let mut base = clean(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let ext = clean(ext, 3);
+    for n in 1..1_000_000u32 {
+        let mut tail = Vec::new();
+        let mut value = n;
+        while value != 0 {
+            tail.insert(0, b'0' + (value % 10) as u8);
+            value /= 10;
+        }
+        tail.insert(0, b'~');
+        let keep = min(base.len(), 8 - tail.len());
+        let mut short_name = [b' '; 11];
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(&tail);
+        short_name[8..8 + ext.len()].copy_from_slice(&ext);
+        if !found.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(Errno::ENOSPC)
+}
+
+fn check_name(name: &str) -> Result<(), Errno> {
+    if name.encode_utf16().count() > MAX_NAME {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let invalid = |c: char| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c);
+    if name.chars().any(invalid) || name.ends_with(' ') {
+        return Err(Errno::EINVAL);
+    }
+    Ok(())
+}
+
+// the slots of a new entry: the long name ones if it needs them and the short one
+fn new_entries(name: &str, found: &[Found], attr: u8) -> Result<Vec<[u8; 32]>, Errno> {
+    let exact = exact_short_name(name);
+    let short_name = match exact {
+        Some(short_name) => short_name,
+        None => alias(name, found)?,
+    };
+    let mut slots = Vec::new();
+    if exact.is_none() {
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        if chars.len() % LONG_NAME_CHARS != 0 {
+            chars.push(0);
+        }
+        while chars.len() % LONG_NAME_CHARS != 0 {
+            chars.push(0xffff);
+        }
+        let count = chars.len() / LONG_NAME_CHARS;
+        let sum = checksum(&short_name);
+        for order in (1..=count).rev() {
+            let mut slot = [0u8; 32];
+            slot[0] = order as u8 | if order == count { LAST_LONG_ENTRY } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            let part = &chars[(order - 1) * LONG_NAME_CHARS..order * LONG_NAME_CHARS];
+            for (&c, &offset) in part.iter().zip(LONG_NAME_OFFSETS.iter()) {
+                put_u16(&mut slot, offset, c);
+            }
+            slots.push(slot);
+        }
+    }
+    let mut slot = [0u8; 32];
+    slot[..11].copy_from_slice(&short_name);
+    slot[11] = attr;
+    let (date, clock) = fat_time(rtc::now());
+    put_u16(&mut slot, 14, clock);
+    put_u16(&mut slot, 16, date);
+    put_u16(&mut slot, 18, date);
+    put_u16(&mut slot, 22, clock);
+    put_u16(&mut slot, 24, date);
+    slots.push(slot);
+    Ok(slots)
+}
+
+// the short entries . and .. at the start of a new directory
+fn dot_entries(data: &mut [u8], cluster: u32, parent: u32) {
+    let (date, clock) = fat_time(rtc::now());
+    for (i, &(name, target)) in [
+        (&b".          "[..], cluster),
+        (&b"..         "[..], parent),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let slot = &mut data[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE];
+        slot[..11].copy_from_slice(name);
+        slot[11] = ATTR_DIRECTORY;
+        set_cluster(slot, target);
+        put_u16(slot, 14, clock);
+        put_u16(slot, 16, date);
+        put_u16(slot, 22, clock);
+        put_u16(slot, 24, date);
+    }
+}
+
+impl Volume {
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        let geometry = &self.geometry;
+        geometry.data_start + (cluster - FIRST_CLUSTER) as u64 * geometry.sectors_per_cluster
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> Result<(), Errno> {
+        self.device.read_sectors(self.cluster_sector(cluster), buf)
+    }
+
+    fn write_cluster(&self, cluster: u32, buf: &[u8]) -> Result<(), Errno> {
+        self.device.write_sectors(self.cluster_sector(cluster), buf)
+    }
+
+    fn fat_sector<'a>(
+        &self,
+        state: &'a mut State,
+        sector: u64,
+    ) -> Result<&'a mut FatSector, Errno> {
+        if !state.fat.contains_key(&sector) {
+            if state.fat.len() >= FAT_CACHE_SECTORS {
+                let clean = state
+                    .fat
+                    .iter()
+                    .find(|(_, cached)| !cached.dirty)
+                    .map(|(&sector, _)| sector);
+                if let Some(clean) = clean {
+                    state.fat.remove(&clean);
+                }
+            }
+            let mut data = [0u8; SECTOR_SIZE];
+            self.device
+                .read_sectors(self.geometry.fat_start + sector, &mut data)?;
+            state.fat.insert(sector, FatSector { data, dirty: false });
+        }
+        Ok(state.fat.get_mut(&sector).unwrap())
+    }
+
+    // the entry of a cluster in the FAT, the next cluster of its chain
+    fn next(&self, state: &mut State, cluster: u32) -> Result<u32, Errno> {
+        let offset = cluster as u64 * 4;
+        let sector = self.fat_sector(state, offset / SECTOR_SIZE as u64)?;
+        Ok(u32_at(&sector.data, (offset % SECTOR_SIZE as u64) as usize) & CLUSTER_MASK)
+    }
+
+    fn set_next(&self, state: &mut State, cluster: u32, next: u32) -> Result<(), Errno> {
+        let offset = cluster as u64 * 4;
+        let sector = self.fat_sector(state, offset / SECTOR_SIZE as u64)?;
+        let at = (offset % SECTOR_SIZE as u64) as usize;
+        // the top four bits are reserved and stay as they are
+        let value = u32_at(&sector.data, at) & !CLUSTER_MASK | next & CLUSTER_MASK;
+        put_u32(&mut sector.data, at, value);
+        sector.dirty = true;
+        Ok(())
+    }
+
+    // the clusters of a chain, in order
+    fn chain(&self, state: &mut State, first: u32) -> Result<Vec<u32>, Errno> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 && cluster < CHAIN_END {
+            // a loop or a cluster out of range means the FAT is broken
+            if !self.geometry.valid(cluster)
+                || clusters.len() > self.geometry.cluster_count as usize
+            {
+                return Err(Errno::EIO);
+            }
+            clusters.push(cluster);
+            cluster = self.next(state, cluster)?;
+        }
+        Ok(clusters)
+    }
+
+    // a free cluster filled with zeroes at the end of the chain that ends with prev
+    fn allocate(&self, state: &mut State, prev: Option<u32>) -> Result<u32, Errno> {
+        let count = self.geometry.cluster_count;
+        let start = match state.next_free {
+            next if self.geometry.valid(next) => next - FIRST_CLUSTER,
+            _ => 0,
+        };
+        let mut found = None;
+        for i in 0..count {
+            let cluster = (start + i) % count + FIRST_CLUSTER;
+            if self.next(state, cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(Errno::ENOSPC)?;
+        self.write_cluster(cluster, &zeroed(self.geometry.cluster_size()))?;
+        self.set_next(state, cluster, CLUSTER_MASK)?;
+        if let Some(prev) = prev {
+            self.set_next(state, prev, cluster)?;
+        }
+        if state.free_count != FSINFO_UNKNOWN {
+            state.free_count = state.free_count.saturating_sub(1);
+        }
+        state.next_free = cluster + 1;
+        state.fsinfo_dirty = true;
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), Errno> {
+        for cluster in self.chain(state, first)? {
+            self.set_next(state, cluster, 0)?;
+            if state.free_count != FSINFO_UNKNOWN {
+                state.free_count += 1;
+            }
+        }
+        state.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    // make the chain that starts at first as long as count, new clusters are zeroes;
+    // returns the new first cluster, which is 0 for an empty chain
+    fn resize_chain(&self, state: &mut State, first: u32, count: usize) -> Result<u32, Errno> {
+        let mut clusters = self.chain(state, first)?;
+        if clusters.len() > count {
+            match count {
+                0 => self.free_chain(state, clusters[0])?,
+                _ => {
+                    self.free_chain(state, clusters[count])?;
+                    self.set_next(state, clusters[count - 1], CLUSTER_MASK)?;
+                }
+            }
+            clusters.truncate(count);
+        }
+        let had = clusters.len();
+        while clusters.len() < count {
+            match self.allocate(state, clusters.last().cloned()) {
+                Ok(cluster) => clusters.push(cluster),
+                // give back what was allocated, the chain is as it was
+                Err(errno) if clusters.len() > had => {
+                    self.free_chain(state, clusters[had])?;
+                    if had > 0 {
+                        self.set_next(state, clusters[had - 1], CLUSTER_MASK)?;
+                    }
+                    return Err(errno);
+                }
+                Err(errno) => return Err(errno),
+            }
+        }
+        Ok(clusters.first().cloned().unwrap_or(0))
+    }
+
+    // write the dirty FAT sectors to every copy of the FAT, and the FSInfo sector
+    fn write_back(&self, state: &mut State) -> Result<(), Errno> {
+        let geometry = &self.geometry;
+        for (&sector, cached) in state.fat.iter_mut().filter(|(_, cached)| cached.dirty) {
+            for copy in 0..geometry.fat_count {
+                let at = geometry.fat_start + copy * geometry.fat_sectors + sector;
+                self.device.write_sectors(at, &cached.data)?;
+            }
+            cached.dirty = false;
+        }
+        if let (Some(fsinfo), true) = (geometry.fsinfo, state.fsinfo_dirty) {
+            let mut buf = [0u8; SECTOR_SIZE];
+            self.device.read_sectors(fsinfo, &mut buf)?;
+            put_u32(&mut buf, 488, state.free_count);
+            put_u32(&mut buf, 492, state.next_free);
+            self.device.write_sectors(fsinfo, &buf)?;
+            state.fsinfo_dirty = false;
+        }
+        Ok(())
+    }
+
+    fn load_dir(&self, state: &mut State, first: u32) -> Result<Dir, Errno> {
+        let clusters = self.chain(state, first)?;
+        let cluster_size = self.geometry.cluster_size();
+        let mut data = zeroed(clusters.len() * cluster_size);
+        for (i, &cluster) in clusters.iter().enumerate() {
+            self.read_cluster(cluster, &mut data[i * cluster_size..(i + 1) * cluster_size])?;
+        }
+        let dirty = clusters.iter().map(|_| false).collect();
+        Ok(Dir {
+            clusters,
+            data,
+            dirty,
+        })
+    }
+
+    fn store_dir(&self, dir: &Dir) -> Result<(), Errno> {
+        let cluster_size = self.geometry.cluster_size();
+        for (i, &cluster) in dir.clusters.iter().enumerate() {
+            if dir.dirty[i] {
+                self.write_cluster(cluster, &dir.data[i * cluster_size..(i + 1) * cluster_size])?;
+            }
+        }
+        Ok(())
+    }
+
+    // put slots into a run of free ones, the directory grows if there is none; returns the
+    // index of the last one
+    fn insert(&self, state: &mut State, dir: &mut Dir, slots: &[[u8; 32]]) -> Result<usize, Errno> {
+        let mut run = 0;
+        let mut start = 0;
+        // everything from the end marker on is free, whatever is in it
+        let mut end = dir.slots();
+        for index in 0..dir.slots() {
+            match dir.slot(index)[0] {
+                ENTRY_END if end == dir.slots() => {
+                    end = index;
+                    run += 1;
+                }
+                _ if index > end => run += 1,
+                ENTRY_FREE => run += 1,
+                _ => {
+                    run = 0;
+                    start = index + 1;
+                }
+            }
+            if run == slots.len() {
+                break;
+            }
+        }
+        while run < slots.len() {
+            let cluster = self.allocate(state, dir.clusters.last().cloned())?;
+            dir.clusters.push(cluster);
+            dir.dirty.push(false);
+            let len = dir.data.len() + self.geometry.cluster_size();
+            dir.data.resize(len, 0);
+            run += self.geometry.cluster_size() / ENTRY_SIZE;
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            dir.slot_mut(start + i).copy_from_slice(slot);
+        }
+        let after = start + slots.len();
+        if after > end && after < dir.slots() {
+            dir.slot_mut(after)[0] = ENTRY_END;
+        }
+        Ok(after - 1)
+    }
+
+    // zero the rest of the cluster the end of a file is in, before the file grows past it; other
+    // systems leave whatever was there
+    fn zero_tail(&self, clusters: &[u32], size: u64) -> Result<(), Errno> {
+        let cluster_size = self.geometry.cluster_size();
+        let tail = size as usize % cluster_size;
+        if tail == 0 {
+            return Ok(());
+        }
+        let cluster = *clusters
+            .get(size as usize / cluster_size)
+            .ok_or(Errno::EIO)?;
+        let mut buf = zeroed(cluster_size);
+        self.read_cluster(cluster, &mut buf)?;
+        buf[tail..].iter_mut().for_each(|byte| *byte = 0);
+        self.write_cluster(cluster, &buf)
+    }
+
+    // the short entry at index of the directory that starts at cluster dir, as it is on disk
+    fn entry_location(&self, state: &mut State, place: Place) -> Result<(u32, usize), Errno> {
+        let cluster_size = self.geometry.cluster_size();
+        let clusters = self.chain(state, place.dir)?;
+        let offset = place.index * ENTRY_SIZE;
+        let cluster = *clusters.get(offset / cluster_size).ok_or(Errno::EIO)?;
+        Ok((cluster, offset % cluster_size))
+    }
+
+    fn read_entry(&self, state: &mut State, place: Place) -> Result<Short, Errno> {
+        let (cluster, offset) = self.entry_location(state, place)?;
+        let mut buf = zeroed(self.geometry.cluster_size());
+        self.read_cluster(cluster, &mut buf)?;
+        if buf[offset] == ENTRY_FREE || buf[offset] == ENTRY_END {
+            return Err(Errno::ENOENT);
+        }
+        Ok(Short::parse(&buf[offset..offset + ENTRY_SIZE]))
+    }
+
+    fn update_entry<F: FnOnce(&mut [u8])>(
+        &self,
+        state: &mut State,
+        place: Place,
+        f: F,
+    ) -> Result<(), Errno> {
+        let (cluster, offset) = self.entry_location(state, place)?;
+        let mut buf = zeroed(self.geometry.cluster_size());
+        self.read_cluster(cluster, &mut buf)?;
+        f(&mut buf[offset..offset + ENTRY_SIZE]);
+        self.write_cluster(cluster, &buf)
+    }
+}
+
+// where the short entry of a file or directory is
+#[derive(Clone, Copy)]
+struct Place {
+    dir: u32, // the first cluster of the directory
+    index: usize,
+}
+
+pub struct FatFs(Arc<Volume>);
+
+impl FatFs {
+    // read the boot sector of a FAT32 volume, EINVAL if the device doesn't hold one
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<FatFs, Errno> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot)?;
+        let bytes_per_sector = u16_at(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let total = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            total => total as u64,
+        };
+        // FAT12 and FAT16 have the size of the FAT at 22, FAT32 at 36
+        let fat_sectors = u32_at(&boot, 36) as u64;
+        let fat32 = u16_at(&boot, 22) == 0 && fat_sectors != 0;
+        if u16_at(&boot, 510) != BOOT_SIGNATURE
+            || !fat32
+            || bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || total > device.sector_count()
+        {
+            return Err(Errno::EINVAL);
+        }
+        let data_start = reserved + fat_count * fat_sectors;
+        let clusters = total.checked_sub(data_start).ok_or(Errno::EINVAL)? / sectors_per_cluster;
+        // the FAT has to have an entry for every cluster
+        let cluster_count = min(clusters, fat_sectors * SECTOR_SIZE as u64 / 4 - 2) as u32;
+        let mut geometry = Geometry {
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_count: min(cluster_count, CHAIN_END - FIRST_CLUSTER - 1),
+            root: u32_at(&boot, 44),
+            fsinfo: None,
+        };
+        if !geometry.valid(geometry.root) {
+            return Err(Errno::EINVAL);
+        }
+        let mut state = State {
+            fat: BTreeMap::new(),
+            free_count: FSINFO_UNKNOWN,
+            next_free: FIRST_CLUSTER,
+            fsinfo_dirty: false,
+            files: BTreeMap::new(),
+        };
+        let fsinfo = u16_at(&boot, 48) as u64;
+        if fsinfo != 0 && fsinfo < reserved {
+            let mut buf = [0u8; SECTOR_SIZE];
+            device.read_sectors(fsinfo, &mut buf)?;
+            if u32_at(&buf, 0) == FSINFO_LEAD_SIGNATURE
+                && u32_at(&buf, 484) == FSINFO_STRUCT_SIGNATURE
+            {
+                geometry.fsinfo = Some(fsinfo);
+                state.free_count = u32_at(&buf, 488);
+                state.next_free = u32_at(&buf, 492);
+            }
+        }
+        if state.free_count != FSINFO_UNKNOWN && state.free_count > geometry.cluster_count {
+            state.free_count = FSINFO_UNKNOWN;
+        }
+        Ok(FatFs(Arc::new(Volume {
+            device,
+            geometry,
+            state: SleepLock::new(state),
+        })))
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatDir {
+            volume: self.0.clone(),
+            cluster: self.0.geometry.root,
+            place: None,
+        })
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        self.0.write_back(&mut state)?;
+        self.0.device.flush()
+    }
+}
+
+struct FatDir {
+    volume: Arc<Volume>,
+    cluster: u32,
+    place: Option<Place>, // None for the root
+}
+
+#[derive(Clone)]
+struct FatFile {
+    volume: Arc<Volume>,
+    place: Place,
+    unlinked: Arc<AtomicBool>,
+}
+
+fn perm(attr: u8, directory: bool) -> u32 {
+    let perm = if directory { 0o755 } else { 0o644 };
+    match attr & ATTR_READ_ONLY {
+        0 => perm,
+        _ => perm & !0o222,
+    }
+}
+
+// the write bits of perm are all FAT can keep
+fn set_read_only(volume: &Volume, state: &mut State, place: Place, perm: u32) -> Result<(), Errno> {
+    volume.update_entry(state, place, |entry| match perm & 0o222 {
+        0 => entry[11] |= ATTR_READ_ONLY,
+        _ => entry[11] &= !ATTR_READ_ONLY,
+    })
+}
+
+impl FatDir {
+    fn inode(&self, state: &mut State, found: &Found) -> Arc<dyn Inode> {
+        let place = Place {
+            dir: self.cluster,
+            index: found.index,
+        };
+        match found.entry.attr & ATTR_DIRECTORY {
+            0 => Arc::new(FatFile {
+                volume: self.volume.clone(),
+                place,
+                unlinked: state.unlinked_flag(place),
+            }),
+            _ => Arc::new(FatDir {
+                volume: self.volume.clone(),
+                cluster: found.entry.cluster,
+                place: Some(place),
+            }),
+        }
+    }
+}
+
+impl Inode for FatDir {
+    fn metadata(&self) -> Metadata {
+        let mut state = self.volume.state.lock();
+        let entry = self
+            .place
+            .and_then(|place| self.volume.read_entry(&mut state, place).ok());
+        Metadata {
+            ino: self.cluster as u64,
+            kind: FileType::Directory,
+            perm: perm(entry.as_ref().map_or(0, |entry| entry.attr), true),
+            nlink: 2,
+            size: 0,
+            atime: entry.as_ref().map_or(0, |entry| entry.atime),
+            mtime: entry.as_ref().map_or(0, |entry| entry.mtime),
+            ctime: entry.as_ref().map_or(0, |entry| entry.ctime),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_perm(&self, perm: u32) -> Result<(), Errno> {
+        let place = self.place.ok_or(Errno::EPERM)?;
+        let mut state = self.volume.state.lock();
+        set_read_only(&self.volume, &mut state, place, perm)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.state.lock();
+        let dir = self.volume.load_dir(&mut state, self.cluster)?;
+        let found = parse_dir(&dir);
+        let entry = find(&found, name).ok_or(Errno::ENOENT)?;
+        Ok(self.inode(&mut state, entry))
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>, Errno> {
+        let mut state = self.volume.state.lock();
+        let dir = self.volume.load_dir(&mut state, self.cluster)?;
+        let entries = parse_dir(&dir)
+            .into_iter()
+            .map(|found| {
+                let directory = found.entry.attr & ATTR_DIRECTORY != 0;
+                DirEntry {
+                    ino: if directory {
+                        found.entry.cluster as u64
+                    } else {
+                        (self.cluster as u64) << 32 | found.index as u64
+                    },
+                    kind: if directory {
+                        FileType::Directory
+                    } else {
+                        FileType::Regular
+                    },
+                    name: found.name,
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Errno> {
+        check_name(name)?;
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let mut dir = volume.load_dir(&mut state, self.cluster)?;
+        let found = parse_dir(&dir);
+        if find(&found, name).is_some() {
+            return Err(Errno::EEXIST);
+        }
+        let attr = match kind {
+            FileType::Regular => ATTR_ARCHIVE,
+            FileType::Directory => ATTR_DIRECTORY,
+            _ => return Err(Errno::EINVAL),
+        };
+        let mut slots = new_entries(name, &found, attr)?;
+        let cluster = match kind {
+            FileType::Directory => {
+                let cluster = volume.allocate(&mut state, None)?;
+                let mut data = zeroed(volume.geometry.cluster_size());
+                // .. of a directory in the root is 0
+                let parent = match self.place {
+                    Some(_) => self.cluster,
+                    None => 0,
+                };
+                dot_entries(&mut data, cluster, parent);
+                volume.write_cluster(cluster, &data)?;
+                cluster
+            }
+            _ => 0,
+        };
+        set_cluster(slots.last_mut().unwrap(), cluster);
+        let result = volume
+            .insert(&mut state, &mut dir, &slots)
+            .and_then(|index| volume.store_dir(&dir).map(|_| index));
+        let index = match result {
+            Ok(index) => index,
+            Err(errno) => {
+                if cluster != 0 {
+                    volume.free_chain(&mut state, cluster)?;
+                }
+                volume.write_back(&mut state)?;
+                return Err(errno);
+            }
+        };
+        volume.write_back(&mut state)?;
+        let place = Place {
+            dir: self.cluster,
+            index,
+        };
+        let inode: Arc<dyn Inode> = match kind {
+            FileType::Directory => Arc::new(FatDir {
+                volume: volume.clone(),
+                cluster,
+                place: Some(place),
+            }),
+            _ => Arc::new(FatFile {
+                volume: volume.clone(),
+                place,
+                unlinked: state.unlinked_flag(place),
+            }),
+        };
+        Ok(inode)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    fn link(&self, _name: &str, _target: &dyn Inode) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let mut dir = volume.load_dir(&mut state, self.cluster)?;
+        let found = parse_dir(&dir);
+        let target = find(&found, name).ok_or(Errno::ENOENT)?;
+        let cluster = target.entry.cluster;
+        if target.entry.attr & ATTR_DIRECTORY != 0 {
+            let contents = volume.load_dir(&mut state, cluster)?;
+            if !parse_dir(&contents).is_empty() {
+                return Err(Errno::ENOTEMPTY);
+            }
+        }
+        for index in target.first..=target.index {
+            dir.slot_mut(index)[0] = ENTRY_FREE;
+        }
+        volume.store_dir(&dir)?;
+        state.unlinked(Place {
+            dir: self.cluster,
+            index: target.index,
+        });
+        if cluster != 0 {
+            // the places in a directory that is gone don't come back
+            if target.entry.attr & ATTR_DIRECTORY != 0 {
+                state.files.retain(|&(dir, _), _| dir != cluster);
+            }
+            volume.free_chain(&mut state, cluster)?;
+        }
+        volume.write_back(&mut state)
+    }
+}
+
+impl FatFile {
+    // the entry of the file, EBADF once it was unlinked even if the entry is another file's now
+    fn entry(&self, state: &mut State) -> Result<Short, Errno> {
+        if self.unlinked.load(Ordering::SeqCst) {
+            return Err(Errno::EBADF);
+        }
+        self.volume.read_entry(state, self.place)
+    }
+}
+
+impl Inode for FatFile {
+    fn metadata(&self) -> Metadata {
+        let mut state = self.volume.state.lock();
+        let entry = self.entry(&mut state).ok();
+        Metadata {
+            ino: (self.place.dir as u64) << 32 | self.place.index as u64,
+            kind: FileType::Regular,
+            perm: perm(entry.as_ref().map_or(0, |entry| entry.attr), false),
+            nlink: if entry.is_some() { 1 } else { 0 },
+            size: entry.as_ref().map_or(0, |entry| entry.size as u64),
+            atime: entry.as_ref().map_or(0, |entry| entry.atime),
+            mtime: entry.as_ref().map_or(0, |entry| entry.mtime),
+            ctime: entry.as_ref().map_or(0, |entry| entry.ctime),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_perm(&self, perm: u32) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        self.entry(&mut state)?;
+        set_read_only(&self.volume, &mut state, self.place, perm)
+    }
+
+    fn open(&self) -> Result<Arc<dyn vfs::File>, Errno> {
+        Ok(Arc::new(self.clone()))
+    }
+}
+
+impl vfs::File for FatFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        let entry = self.entry(&mut state)?;
+        if offset >= entry.size as u64 {
+            return Ok(0);
+        }
+        let count = min(buf.len() as u64, entry.size as u64 - offset) as usize;
+        let cluster_size = volume.geometry.cluster_size();
+        let clusters = volume.chain(&mut state, entry.cluster)?;
+        let mut cluster_buf = zeroed(cluster_size);
+        let mut done = 0;
+        while done < count {
+            let pos = offset as usize + done;
+            let cluster = *clusters.get(pos / cluster_size).ok_or(Errno::EIO)?;
+            let start = pos % cluster_size;
+            let len = min(cluster_size - start, count - done);
+            volume.read_cluster(cluster, &mut cluster_buf)?;
+            buf[done..done + len].copy_from_slice(&cluster_buf[start..start + len]);
+            done += len;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        if end > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let volume = &self.volume;
+        let cluster_size = volume.geometry.cluster_size();
+        let mut state = volume.state.lock();
+        let entry = self.entry(&mut state)?;
+        if entry.attr & ATTR_READ_ONLY != 0 {
+            return Err(Errno::EACCES);
+        }
+        let size = entry.size as u64;
+        let needed = (end as usize + cluster_size - 1) / cluster_size;
+        let have = (size as usize + cluster_size - 1) / cluster_size;
+        let first = volume.resize_chain(&mut state, entry.cluster, needed.max(have))?;
+        let clusters = volume.chain(&mut state, first)?;
+        if offset > size {
+            volume.zero_tail(&clusters, size)?;
+        }
+        let mut cluster_buf = zeroed(cluster_size);
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset as usize + done;
+            let cluster = clusters[pos / cluster_size];
+            let start = pos % cluster_size;
+            let len = min(cluster_size - start, buf.len() - done);
+            if len < cluster_size {
+                volume.read_cluster(cluster, &mut cluster_buf)?;
+            }
+            cluster_buf[start..start + len].copy_from_slice(&buf[done..done + len]);
+            volume.write_cluster(cluster, &cluster_buf)?;
+            done += len;
+        }
+        volume.update_entry(&mut state, self.place, |entry| {
+            set_cluster(entry, first);
+            put_u32(entry, 28, end.max(size) as u32);
+            touch(entry);
+        })?;
+        volume.write_back(&mut state)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        if size > u32::MAX as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let volume = &self.volume;
+        let cluster_size = volume.geometry.cluster_size();
+        let mut state = volume.state.lock();
+        let entry = self.entry(&mut state)?;
+        if entry.attr & ATTR_READ_ONLY != 0 {
+            return Err(Errno::EACCES);
+        }
+        let count = (size as usize + cluster_size - 1) / cluster_size;
+        let first = volume.resize_chain(&mut state, entry.cluster, count)?;
+        if size > entry.size as u64 {
+            let clusters = volume.chain(&mut state, first)?;
+            volume.zero_tail(&clusters, entry.size as u64)?;
+        }
+        volume.update_entry(&mut state, self.place, |entry| {
+            set_cluster(entry, first);
+            put_u32(entry, 28, size as u32);
+            touch(entry);
+        })?;
+        volume.write_back(&mut state)
+    }
+}
@@ -0,0 +1,122 @@
+// Per-task file descriptor tables. A descriptor refers to an open file description which can
+// be shared between descriptors (dup) and tasks (spawn), it is closed when the last one goes.
+
+use crate::abi::Stat;
+use crate::channel::Endpoint;
+use crate::console::Console;
+use crate::errno::Errno;
+use crate::shm::SharedMemory;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const MAX_FDS: usize = 64;
+
+// an open file, pipe end or device, operations it doesn't support fail with EBADF
+pub trait FileDescription: Send + Sync {
+    fn read(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    // files opened through the VFS have an offset, whence is one of SEEK_* in abi.rs
+    fn seek(&self, _offset: i64, _whence: u64) -> Result<u64, Errno> {
+        Err(Errno::ESPIPE)
+    }
+
+    fn stat(&self) -> Result<Stat, Errno> {
+        Err(Errno::EBADF)
+    }
+
+    // the directory entries after the ones returned before, as records described in abi.rs
+    fn getdents(&self, _buf: &mut [u8]) -> Result<usize, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // channel endpoints are files too, this is how the IPC syscalls find them
+    fn endpoint(&self) -> Option<&Endpoint> {
+        None
+    }
+
+    fn shared_memory(&self) -> Option<&Arc<SharedMemory>> {
+        None
+    }
+}
+
+pub type FileRef = Arc<dyn FileDescription>;
+
+// descriptions may block or wake tasks when they are dropped, so the ones removed from a table
+// are handed back to the caller to drop once no scheduler locks are held
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<FileRef>>,
+}
+
+impl FdTable {
+    pub fn new() -> FdTable {
+        FdTable { files: Vec::new() }
+    }
+
+    // stdin, stdout and stderr all go to the console
+    pub fn with_console() -> FdTable {
+        let console: FileRef = Arc::new(Console);
+        let mut table = FdTable::new();
+        for _ in 0..3 {
+            table.files.push(Some(console.clone()));
+        }
+        table
+    }
+
+    pub fn get(&self, fd: usize) -> Result<FileRef, Errno> {
+        match self.files.get(fd) {
+            Some(Some(file)) => Ok(file.clone()),
+            _ => Err(Errno::EBADF),
+        }
+    }
+
+    // put the file at the lowest free descriptor
+    pub fn insert(&mut self, file: FileRef) -> Result<usize, Errno> {
+        match self.files.iter().position(|slot| slot.is_none()) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Ok(fd)
+            }
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(Some(file));
+                Ok(self.files.len() - 1)
+            }
+            None => Err(Errno::EMFILE),
+        }
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<FileRef, Errno> {
+        match self.files.get_mut(fd) {
+            Some(slot) => slot.take().ok_or(Errno::EBADF),
+            None => Err(Errno::EBADF),
+        }
+    }
+
+    pub fn dup(&mut self, fd: usize) -> Result<usize, Errno> {
+        let file = self.get(fd)?;
+        self.insert(file)
+    }
+
+    // make new_fd refer to the same file as old_fd, returns what new_fd referred to before
+    pub fn dup2(&mut self, old_fd: usize, new_fd: usize) -> Result<Option<FileRef>, Errno> {
+        let file = self.get(old_fd)?;
+        if new_fd >= MAX_FDS {
+            return Err(Errno::EBADF);
+        }
+        if new_fd >= self.files.len() {
+            self.files.resize(new_fd + 1, None);
+        }
+        Ok(self.files[new_fd].replace(file))
+    }
+
+    // empty the table, see above for why the files are returned
+    pub fn take_all(&mut self) -> Vec<Option<FileRef>> {
+        core::mem::replace(&mut self.files, Vec::new())
+    }
+}
@@ -1,26 +1,32 @@
 use crate::mem::{PhysAddr, VirtAddr, FRAME_SIZE};
-use crate::serial_println;
-use core::cmp::max;
+use core::cmp::{max, min};
 use multiboot2::BootInformation;
 use multiboot2::MemoryAreaIter;
 
 pub static mut BOOTINFO_ALLOCATOR: Option<SimpleAllocator> = None;
 
+// physical ranges that can be kept from being handed out, one per boot module
+const MAX_RESERVED: usize = 16;
+
 pub trait FrameSingleAllocator: Send {
     unsafe fn allocate(&mut self) -> Option<PhysAddr>;
 }
 
 pub struct SimpleAllocator {
     kernel_end_phys: u64, // end address of our kernel sections (don't write before this!)
+    mem_limit: u64,       // memory above this address is never handed out
     mem_areas: MemoryAreaIter, // iter of memory areas
     cur_area: Option<(u64, u64)>, // currently used area's bounds
     next_page: usize,     // next page no. in this area to return
+    reserved: [(u64, u64); MAX_RESERVED], // frame aligned ranges in use before the kernel starts
+    reserved_count: usize,
 }
 
 unsafe impl core::marker::Send for SimpleAllocator {} // shh it's ok pointers are thread-safe
 
 impl SimpleAllocator {
-    pub unsafe fn init(boot_info: &'static BootInformation) {
+    // mem_limit is the mem= kernel argument, to pretend the machine has less memory
+    pub unsafe fn init(boot_info: &'static BootInformation, mem_limit: Option<u64>) {
         let mem_tag = boot_info
             .memory_map_tag()
             .expect("Must have memory map tag");
@@ -29,15 +35,38 @@ impl SimpleAllocator {
         let kernel_end_phys = VirtAddr::new(kernel_end).to_phys().unwrap().0.addr();
         let mut alloc = SimpleAllocator {
             kernel_end_phys,
+            mem_limit: mem_limit.unwrap_or(u64::MAX),
             mem_areas,
             cur_area: None,
             next_page: 0,
+            reserved: [(0, 0); MAX_RESERVED],
+            reserved_count: 0,
         };
         alloc.next_area();
 
         BOOTINFO_ALLOCATOR.replace(alloc);
     }
 
+    // never hand out the frames of [start, end), for memory like the boot modules which stays
+    // in use but isn't below the kernel's end
+    pub fn reserve(&mut self, start: PhysAddr, end: PhysAddr) {
+        if self.reserved_count == MAX_RESERVED {
+            panic!("too many reserved memory ranges");
+        }
+        let start = start.addr() / FRAME_SIZE * FRAME_SIZE;
+        let end = (end.addr() + FRAME_SIZE - 1) / FRAME_SIZE * FRAME_SIZE;
+        self.reserved[self.reserved_count] = (start, end);
+        self.reserved_count += 1;
+    }
+
+    // the end of the reserved range addr is in
+    fn reserved_end(&self, addr: u64) -> Option<u64> {
+        self.reserved[..self.reserved_count]
+            .iter()
+            .find(|&&(start, end)| start <= addr && addr < end)
+            .map(|&(_, end)| end)
+    }
+
     fn next_area(&mut self) {
         self.next_page = 0;
         if let Some(mem_area) = self.mem_areas.next() {
@@ -46,13 +75,13 @@ impl SimpleAllocator {
             let area_len = mem_area.length;
             // start after kernel end
             let mem_start = max(base_addr, self.kernel_end_phys);
-            let mem_end = base_addr + area_len;
+            let mem_end = min(base_addr + area_len, self.mem_limit);
             // memory start addr aligned with page size
             let start_addr = ((mem_start + FRAME_SIZE - 1) / FRAME_SIZE) * FRAME_SIZE;
-            // memory end addr aligned with page size
-            let end_addr = (mem_end / FRAME_SIZE) * FRAME_SIZE;
-            serial_println!(
-                "- FrameAlloc: New area: {:x} to {:x} ({})",
+            // memory end addr aligned with page size, areas above the limit end up empty
+            let end_addr = max((mem_end / FRAME_SIZE) * FRAME_SIZE, start_addr);
+            log::debug!(
+                "new area: {:x} to {:x} ({})",
                 start_addr,
                 end_addr,
                 end_addr - start_addr
@@ -69,6 +98,11 @@ impl FrameSingleAllocator for SimpleAllocator {
         // get current area start and end addr if we still have an area left
         let (start_addr, end_addr) = self.cur_area?;
         let frame = PhysAddr::new(start_addr + (self.next_page as u64 * FRAME_SIZE));
+        if let Some(reserved_end) = self.reserved_end(frame.addr()) {
+            // skip to the first frame after the reserved range
+            self.next_page = ((reserved_end - start_addr) / FRAME_SIZE) as usize;
+            return self.allocate();
+        }
         // return a page from this area
         if frame.addr() + (FRAME_SIZE as u64) < end_addr {
             self.next_page += 1;
@@ -0,0 +1,470 @@
+// A GDB remote serial protocol stub on the second serial port, to debug the kernel with its own
+// view of tasks and memory instead of QEMU's. Built with the gdb feature the kernel stops early
+// in start and waits for GDB (`make debug`, then `target remote :1234` in GDB), breakpoints and
+// single steps then trap into the stub through #BP and #DB. Only the CPU that trapped stops,
+// the others keep running.
+
+use crate::exceptions::ExceptionFrame;
+use crate::mem::{self, VirtAddr, BIT_WRITABLE, FRAME_SIZE};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+
+const COM2: u16 = 0x2f8;
+
+// largest packet in either direction, told to GDB in qSupported
+const PACKET_SIZE: usize = 0x1000;
+
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const RFLAGS_TF: u64 = 0x100;
+
+// GDB's amd64 registers up to gs, the segment registers and eflags are 4 bytes
+const REGISTERS: usize = 24;
+const RIP: usize = 16;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct Stub {
+    port: SerialPort,
+    attached: bool, // GDB resumed us and waits for a stop reply
+    breakpoints: Breakpoints,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+// address and the byte int3 replaced
+struct Breakpoints([Option<(u64, u8)>; MAX_BREAKPOINTS]);
+
+// what to do once a command is handled
+enum Next {
+    Reply,  // send the reply and wait for the next command
+    Resume, // go back to the kernel, GDB waits for the next stop
+    Detach, // send the reply if there is one and go back to the kernel
+}
+
+lazy_static! {
+    static ref STUB: Mutex<Stub> = Mutex::new(Stub {
+        port: unsafe { SerialPort::new(COM2) },
+        attached: false,
+        breakpoints: Breakpoints([None; MAX_BREAKPOINTS]),
+        packet: [0; PACKET_SIZE],
+        reply: Reply {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        },
+    });
+}
+
+// the stub doesn't allocate, it may be stopped in the middle of the allocator
+struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        s.bytes().for_each(|byte| self.push(byte));
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(hex_char(byte >> 4));
+        self.push(hex_char(byte & 0xf));
+    }
+
+    // registers are sent in target byte order
+    fn push_value(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex((value >> (8 * i)) as u8);
+        }
+    }
+}
+
+fn hex_char(nibble: u8) -> u8 {
+    b"0123456789abcdef"[nibble as usize]
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0, |value, &c| Some(value << 4 | hex_digit(c)? as u64))
+}
+
+// bytes given as pairs of hex digits
+fn hex_bytes<'a>(s: &'a [u8]) -> impl Iterator<Item = Option<u8>> + 'a {
+    s.chunks(2).map(|pair| match pair {
+        [high, low] => Some(hex_digit(*high)? << 4 | hex_digit(*low)?),
+        _ => None,
+    })
+}
+
+// a value in target byte order
+fn parse_value(s: &[u8]) -> Option<u64> {
+    hex_bytes(s).enumerate().try_fold(0, |value, (i, byte)| {
+        Some(value | (byte? as u64) << (8 * i))
+    })
+}
+
+// split "a<sep>b" into a and b
+fn split(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|&c| c == sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+// "addr,len" as used by m, M and Z
+fn parse_range(s: &[u8]) -> Option<(u64, u64)> {
+    let (addr, len) = split(s, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+// whether every page of [addr, addr + len) is mapped, and writable if write is set
+fn accessible(addr: u64, len: u64, write: bool) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = addr & !(FRAME_SIZE - 1);
+    while page < end {
+        match unsafe { mem::get_page_table().lookup(VirtAddr::new(page)) } {
+            Some((_, options)) if !write || options & BIT_WRITABLE != 0 => {}
+            _ => return false,
+        }
+        page += FRAME_SIZE;
+    }
+    true
+}
+
+fn register(frame: &mut ExceptionFrame, n: usize) -> Option<&mut u64> {
+    Some(match n {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18 => &mut frame.cs,
+        19 => &mut frame.ss,
+        _ => return None,
+    })
+}
+
+fn register_size(n: usize) -> usize {
+    if n <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+// ds, es, fs and gs aren't used in long mode and read as 0
+fn read_register(frame: &mut ExceptionFrame, n: usize) -> u64 {
+    register(frame, n).map_or(0, |reg| *reg)
+}
+
+// the segments stay what they are, a wrong cs or ss would only fault on the way back
+fn write_register(frame: &mut ExceptionFrame, n: usize, value: u64) {
+    if n == 18 || n == 19 {
+        return;
+    }
+    if let Some(reg) = register(frame, n) {
+        *reg = value;
+    }
+}
+
+impl Breakpoints {
+    fn set(&mut self, addr: u64) -> bool {
+        if self.0.iter().any(|bp| bp.map(|bp| bp.0) == Some(addr)) {
+            return true;
+        }
+        let free = match self.0.iter().position(|bp| bp.is_none()) {
+            Some(free) => free,
+            None => return false,
+        };
+        if !accessible(addr, 1, true) {
+            return false;
+        }
+        unsafe {
+            let byte = addr as *mut u8;
+            self.0[free] = Some((addr, *byte));
+            *byte = INT3;
+        }
+        true
+    }
+
+    fn remove(&mut self, addr: u64) -> bool {
+        for bp in self.0.iter_mut() {
+            match *bp {
+                Some((bp_addr, byte)) if bp_addr == addr => {
+                    unsafe { *(addr as *mut u8) = byte };
+                    *bp = None;
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    fn remove_all(&mut self) {
+        for bp in self.0.iter_mut() {
+            if let Some((addr, byte)) = bp.take() {
+                unsafe { *(addr as *mut u8) = byte };
+            }
+        }
+    }
+}
+
+impl Stub {
+    // wait for a packet with a valid checksum, returns its length
+    fn receive_packet(&mut self) -> usize {
+        'packet: loop {
+            while self.port.receive() != b'$' {}
+            let mut len = 0;
+            let mut checksum = 0u8;
+            loop {
+                let c = self.port.receive();
+                if c == b'#' {
+                    break;
+                }
+                if c == b'$' || len == PACKET_SIZE {
+                    self.port.send(b'-');
+                    continue 'packet;
+                }
+                self.packet[len] = c;
+                len += 1;
+                checksum = checksum.wrapping_add(c);
+            }
+            let high = hex_digit(self.port.receive());
+            let low = hex_digit(self.port.receive());
+            match (high, low) {
+                (Some(high), Some(low)) if high << 4 | low == checksum => {
+                    self.port.send(b'+');
+                    return len;
+                }
+                _ => self.port.send(b'-'),
+            }
+        }
+    }
+
+    // send the reply, again until GDB acknowledges it
+    fn send_reply(&mut self) {
+        let data = &self.reply.data[..self.reply.len];
+        let checksum = data.iter().fold(0u8, |sum, &c| sum.wrapping_add(c));
+        loop {
+            self.port.send(b'$');
+            data.iter().for_each(|&c| self.port.send(c));
+            self.port.send(b'#');
+            self.port.send(hex_char(checksum >> 4));
+            self.port.send(hex_char(checksum & 0xf));
+            if self.port.receive() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn send_stop(&mut self) {
+        self.reply.clear();
+        self.reply.push_str("S05");
+        self.send_reply();
+    }
+}
+
+// handle the packet, filling in reply
+fn command(
+    packet: &[u8],
+    reply: &mut Reply,
+    breakpoints: &mut Breakpoints,
+    frame: &mut ExceptionFrame,
+) -> Next {
+    let (command, args) = match packet.split_first() {
+        Some((&command, args)) => (command, args),
+        None => return Next::Reply,
+    };
+    match command {
+        b'?' => reply.push_str("S05"),
+        b'g' => {
+            for n in 0..REGISTERS {
+                reply.push_value(read_register(frame, n), register_size(n));
+            }
+        }
+        b'G' => {
+            let mut rest = args;
+            for n in 0..REGISTERS {
+                let size = 2 * register_size(n);
+                if rest.len() < size {
+                    break;
+                }
+                if let Some(value) = parse_value(&rest[..size]) {
+                    write_register(frame, n, value);
+                }
+                rest = &rest[size..];
+            }
+            reply.push_str("OK");
+        }
+        b'p' => match parse_hex(args).map(|n| n as usize) {
+            Some(n) if n < REGISTERS => reply.push_value(read_register(frame, n), register_size(n)),
+            _ => reply.push_str("E01"),
+        },
+        b'P' => {
+            let parsed = split(args, b'=')
+                .and_then(|(n, value)| Some((parse_hex(n)? as usize, parse_value(value)?)));
+            match parsed {
+                Some((n, value)) if n < REGISTERS => {
+                    write_register(frame, n, value);
+                    reply.push_str("OK");
+                }
+                _ => reply.push_str("E01"),
+            }
+        }
+        b'm' => match parse_range(args) {
+            Some((addr, len)) if len as usize <= PACKET_SIZE / 2 => {
+                if accessible(addr, len, false) {
+                    for i in 0..len {
+                        reply.push_hex(unsafe { *((addr + i) as *const u8) });
+                    }
+                } else {
+                    reply.push_str("E14");
+                }
+            }
+            _ => reply.push_str("E01"),
+        },
+        b'M' => {
+            let parsed =
+                split(args, b':').and_then(|(range, data)| Some((parse_range(range)?, data)));
+            match parsed {
+                Some(((addr, len), data)) if data.len() as u64 == 2 * len => {
+                    if accessible(addr, len, true) {
+                        for (i, byte) in hex_bytes(data).enumerate() {
+                            unsafe { *((addr + i as u64) as *mut u8) = byte.unwrap_or(0) };
+                        }
+                        reply.push_str("OK");
+                    } else {
+                        reply.push_str("E14");
+                    }
+                }
+                _ => reply.push_str("E01"),
+            }
+        }
+        // only software breakpoints, GDB writes int3 itself if they aren't supported
+        b'Z' | b'z' if args.starts_with(b"0,") => {
+            let done = match parse_range(&args[2..]) {
+                Some((addr, _)) if command == b'Z' => breakpoints.set(addr),
+                Some((addr, _)) => breakpoints.remove(addr),
+                None => false,
+            };
+            reply.push_str(if done { "OK" } else { "E01" });
+        }
+        b'c' | b's' => {
+            if let Some(addr) = parse_hex(args) {
+                frame.rip = addr;
+            }
+            if command == b's' {
+                frame.rflags |= RFLAGS_TF;
+            }
+            return Next::Resume;
+        }
+        b'D' => {
+            breakpoints.remove_all();
+            reply.push_str("OK");
+            return Next::Detach;
+        }
+        // the kernel can't be killed, it just goes on
+        b'k' => {
+            breakpoints.remove_all();
+            return Next::Detach;
+        }
+        b'q' if packet.starts_with(b"qSupported") => {
+            reply.push_str("PacketSize=1000"); // PACKET_SIZE in hex
+        }
+        b'q' if packet == b"qAttached" => reply.push_str("1"),
+        b'H' => reply.push_str("OK"),
+        // an empty reply tells GDB the command isn't supported
+        _ => {}
+    }
+    Next::Reply
+}
+
+// set up the serial port, the stub is only entered once this ran
+pub fn init() {
+    STUB.lock().port.init();
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+// stop here and wait for GDB
+pub fn breakpoint() {
+    unsafe { asm!("int3") };
+}
+
+// called for #BP and #DB in the kernel, talks to GDB until it continues or steps
+pub fn handle(frame: &mut ExceptionFrame) {
+    let mut stub = STUB.lock();
+    let stub = &mut *stub;
+    frame.rflags &= !RFLAGS_TF;
+    if stub.attached {
+        stub.send_stop();
+    }
+    loop {
+        let len = stub.receive_packet();
+        stub.reply.clear();
+        let next = command(
+            &stub.packet[..len],
+            &mut stub.reply,
+            &mut stub.breakpoints,
+            frame,
+        );
+        match next {
+            Next::Reply => stub.send_reply(),
+            Next::Resume => {
+                stub.attached = true;
+                return;
+            }
+            Next::Detach => {
+                // GDB is gone after a kill and wouldn't acknowledge anything
+                if stub.reply.len > 0 {
+                    stub.send_reply();
+                }
+                stub.attached = false;
+                return;
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::println;
-use lazy_static::lazy_static;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use x86_64::instructions::segmentation::{load_ds, set_cs};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{
@@ -10,59 +11,117 @@ use x86_64::{PrivilegeLevel, VirtAddr};
 
 // setup the stacks
 pub const DOUBLE_FAULT_IST_INDEX: u8 = 0;
+pub const SCHEDULER_IST_INDEX: u8 = 1; // used by the timer so that switching tasks never runs on a task's stack
 const STACK_SIZE: usize = 0x2000;
 pub static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 pub static mut USTACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+pub static mut SCHED_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            let s_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let s_end = s_start + STACK_SIZE;
-            s_end
-        };
-        tss.privilege_stack_table[0] = {
-            let s_start = VirtAddr::from_ptr(unsafe { &USTACK });
-            let s_end = s_start + STACK_SIZE;
-            s_end
-        };
-        tss
-    };
+// each CPU has its own TSS (with its own stacks) and therefore its own GDT
+pub struct CpuTables {
+    pub tss: TaskStateSegment,
+    gdt: GlobalDescriptorTable,
+    selectors: [SegmentSelector; 5],
+}
+
+// the BSP loads its tables before we have a heap
+static mut BSP_TABLES: Option<CpuTables> = None;
+
+fn stack_end(stack: *const u8, size: usize) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + size
 }
 
-// global GDT structure, initialized right at kernel startup
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, [SegmentSelector; 5]) = {
-        let mut gdt = GlobalDescriptorTable::new();
+impl CpuTables {
+    fn new(df_stack: VirtAddr, sched_stack: VirtAddr, kernel_stack: VirtAddr) -> CpuTables {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = df_stack;
+        tss.interrupt_stack_table[SCHEDULER_IST_INDEX as usize] = sched_stack;
+        tss.privilege_stack_table[0] = kernel_stack;
+        CpuTables {
+            tss,
+            gdt: GlobalDescriptorTable::new(),
+            selectors: [SegmentSelector(0); 5],
+        }
+    }
+
+    // build the GDT once the TSS is at its final address, the order of the entries
+    // has to stay the same as MSR_STAR relies on it
+    unsafe fn load(&mut self) {
         let data_flags =
             DescriptorFlags::USER_SEGMENT | DescriptorFlags::PRESENT | DescriptorFlags::WRITABLE;
-        let code_selec = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selec = gdt.add_entry(Descriptor::UserSegment(kernel_data_flags.bits()));
-        let tss_selec = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let user_data_selec = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_selec = gdt.add_entry(Descriptor::user_code_segment());
-        (
-            gdt,
-            [code_selec, data_selec, tss_selec, user_data_selec, user_code_selec],
-        )
-    };
+        let tss: &'static TaskStateSegment = &*(&self.tss as *const TaskStateSegment);
+        let code_selec = self.gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selec = self
+            .gdt
+            .add_entry(Descriptor::UserSegment(data_flags.bits()));
+        let tss_selec = self.gdt.add_entry(Descriptor::tss_segment(tss));
+        let user_data_selec = self.gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selec = self.gdt.add_entry(Descriptor::user_code_segment());
+        self.selectors = [
+            code_selec,
+            data_selec,
+            tss_selec,
+            user_data_selec,
+            user_code_selec,
+        ];
+        let gdt: &'static GlobalDescriptorTable = &*(&self.gdt as *const GlobalDescriptorTable);
+        gdt.load();
+        set_cs(self.selectors[0]);
+        load_ds(self.selectors[1]);
+        load_tss(self.selectors[2]);
+    }
+
+    // stack used by the CPU when an interrupt or exception arrives in ring 3
+    pub fn set_kernel_stack(&mut self, stack_top: u64) {
+        self.tss.privilege_stack_table[0] = VirtAddr::new(stack_top);
+    }
 }
 
-// initialize the GDT and stacks
+// initialize the GDT and stacks of the BSP
 pub fn init_gdt() {
-    GDT.0.load();
     let stack = unsafe { &STACK as *const _ };
     let ustack = unsafe { &USTACK as *const _ };
+    let tables = unsafe {
+        BSP_TABLES.replace(CpuTables::new(
+            stack_end(STACK.as_ptr(), STACK_SIZE),
+            stack_end(SCHED_STACK.as_ptr(), STACK_SIZE),
+            stack_end(USTACK.as_ptr(), STACK_SIZE),
+        ));
+        let tables = BSP_TABLES.as_mut().unwrap();
+        tables.load();
+        tables
+    };
     println!(
         " - Loaded GDT: {:p} TSS: {:p} Stack {:p} User stack: {:p} CS segment: {} TSS segment: {}",
-        &GDT.0 as *const _, &*TSS as *const _, stack, ustack, GDT.1[0].0, GDT.1[1].0
+        &tables.gdt as *const _,
+        &tables.tss as *const _,
+        stack,
+        ustack,
+        tables.selectors[0].0,
+        tables.selectors[2].0
     );
+}
+
+pub fn bsp_tables() -> *mut CpuTables {
+    unsafe { BSP_TABLES.as_mut().unwrap() as *mut CpuTables }
+}
+
+// initialize the GDT and stacks of an application processor, everything is on the heap
+pub fn init_ap_gdt() -> *mut CpuTables {
+    let alloc_stack = || {
+        let stack: &'static mut Vec<u8> = Box::leak(Box::new(Vec::with_capacity(STACK_SIZE)));
+        stack_end(stack.as_ptr(), STACK_SIZE)
+    };
+    let tables = Box::leak(Box::new(CpuTables::new(
+        alloc_stack(),
+        alloc_stack(),
+        alloc_stack(),
+    )));
+    let tables_ptr = tables as *mut CpuTables;
     unsafe {
-        set_cs(GDT.1[0]);
-        load_ds(GDT.1[1]);
-        load_tss(GDT.1[2]);
+        tables.load();
     }
+    tables_ptr
 }
 
 // this I don't understand tbh, but it works
@@ -70,9 +129,17 @@ pub fn init_gdt() {
 #[inline(always)]
 pub unsafe fn set_usermode_segs() -> (u16, u16) {
     // set ds and tss, return cs and ds
-    let (mut _cs, mut _ds) = (GDT.1[4], GDT.1[3]);
+    let (cs, ds) = user_segs();
+    load_ds(SegmentSelector(ds));
+    (cs, ds)
+}
+
+// the ring 3 code and data selectors
+// the selectors are the same on every CPU so the BSP's are good enough
+pub unsafe fn user_segs() -> (u16, u16) {
+    let selectors = &BSP_TABLES.as_ref().unwrap().selectors;
+    let (mut _cs, mut _ds) = (selectors[4], selectors[3]);
     _cs.0 |= PrivilegeLevel::Ring3 as u16;
     _ds.0 |= PrivilegeLevel::Ring3 as u16;
-    load_ds(_ds);
     (_cs.0, _ds.0)
-}
\ No newline at end of file
+}
@@ -1,7 +1,6 @@
 use crate::buddy_alloc::BuddyAllocatorManager;
 use crate::frame_alloc::FrameSingleAllocator;
 use crate::mem::{PhysAddr, VirtAddr, FRAME_SIZE};
-use crate::serial_println;
 use alloc::alloc::{GlobalAlloc, Layout};
 use alloc::vec::Vec;
 use core::ptr::null_mut;
@@ -45,7 +44,7 @@ unsafe impl GlobalAlloc for Allocator {
             if let Some(virt) = page.to_virt();
             // return the page
             then {
-                serial_println!(" - GlobalAlloc: Reusing {:x}", virt.addr());
+                log::trace!("reusing {:x}", virt.addr());
                 return virt.to_ref();
             }
         }
@@ -58,7 +57,7 @@ unsafe impl GlobalAlloc for Allocator {
             if let Some(virt) = page.to_virt();
             // return the page
             then {
-                serial_println!(" - GlobalAlloc: Allocated {:x} {}", virt.addr(), layout.size());
+                log::trace!("allocated {:x} {}", virt.addr(), layout.size());
                 return virt.to_ref();
             }
         }
@@ -85,10 +84,15 @@ unsafe impl GlobalAlloc for Allocator {
                 free.push(phys_addr);
             }
         }
-        serial_println!(" - GlobalAlloc: Deallocated {:x}", ptr as u64);
+        log::trace!("deallocated {:x}", ptr as u64);
     }
 }
 
+// run f on the buddy allocators, None while the kernel is still on its first frame allocator
+pub fn with_buddy_allocator<R, F: FnOnce(&BuddyAllocatorManager) -> R>(f: F) -> Option<R> {
+    ALLOCATOR_INFO.strategy.read().as_ref().map(f)
+}
+
 pub fn init_allocator_info(frame_alloc: &'static mut dyn FrameSingleAllocator) {
     // set the frame allocator as our current allocator
     ALLOCATOR_INFO.frame_allocator.lock().replace(frame_alloc);
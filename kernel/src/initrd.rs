@@ -0,0 +1,195 @@
+// Initial ramdisks: archives GRUB loads as boot modules, given with module2 lines in grub.cfg.
+// Both cpio in the newc format (what `find . | cpio -o -H newc` makes) and ustar archives are
+// understood. Their files are copied into the root filesystem by their path relative to the
+// archive's root, e.g. bin/sh in it becomes /bin/sh, the module's memory is left alone.
+
+use crate::abi;
+use crate::errno::Errno;
+use crate::path;
+use crate::vfs::{self, FileType, FsContext};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::str;
+
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const CPIO_MODE_TYPE: u32 = 0o170000;
+const CPIO_MODE_FILE: u32 = 0o100000;
+const CPIO_MODE_DIR: u32 = 0o040000;
+const CPIO_MODE_SYMLINK: u32 = 0o120000;
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+
+const PERM_MASK: u32 = 0o7777;
+
+// an entry of an archive, data is the target of a symlink
+struct Entry {
+    kind: FileType,
+    perm: u32,
+    data: &'static [u8],
+}
+
+fn entry(kind: FileType, perm: u32, data: &'static [u8]) -> Entry {
+    Entry {
+        kind,
+        perm: perm & PERM_MASK,
+        data,
+    }
+}
+
+// a field of a header as a number in the given base
+fn number(field: &[u8], radix: u32) -> Result<usize, Errno> {
+    let digits = str::from_utf8(field).map_err(|_| Errno::EINVAL)?;
+    // tar fields end with NUL or spaces
+    let digits = digits.trim_matches(|c| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_| Errno::EINVAL)
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+fn bytes(archive: &'static [u8], start: usize, len: usize) -> Result<&'static [u8], Errno> {
+    let end = start.checked_add(len).ok_or(Errno::EINVAL)?;
+    archive.get(start..end).ok_or(Errno::EINVAL)
+}
+
+// a NUL padded name
+fn name(field: &'static [u8]) -> Result<&'static str, Errno> {
+    let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..len]).map_err(|_| Errno::EINVAL)
+}
+
+// add an entry and the directories leading to it that the archive doesn't have entries for
+fn add(files: &mut BTreeMap<String, Entry>, name: &str, file: Entry) {
+    let path = path::join("/", name);
+    let mut end = 0;
+    while let Some(slash) = path[end + 1..].find('/') {
+        end += 1 + slash;
+        if !files.contains_key(&path[..end]) {
+            files.insert(
+                String::from(&path[..end]),
+                entry(FileType::Directory, 0o755, &[]),
+            );
+        }
+    }
+    if path != "/" {
+        files.insert(path, file);
+    }
+}
+
+fn load_cpio(archive: &'static [u8], files: &mut BTreeMap<String, Entry>) -> Result<(), Errno> {
+    let mut offset = 0;
+    loop {
+        let header = bytes(archive, offset, CPIO_HEADER_SIZE)?;
+        if &header[..6] != CPIO_MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let mode = number(&header[14..22], 16)? as u32;
+        let file_size = number(&header[54..62], 16)?;
+        let name_size = number(&header[94..102], 16)?;
+        let file_name = name(bytes(archive, offset + CPIO_HEADER_SIZE, name_size)?)?;
+        if file_name == CPIO_TRAILER {
+            return Ok(());
+        }
+        let data_start = align4(offset + CPIO_HEADER_SIZE + name_size);
+        let data = bytes(archive, data_start, file_size)?;
+        let kind = match mode & CPIO_MODE_TYPE {
+            CPIO_MODE_FILE => Some(FileType::Regular),
+            CPIO_MODE_DIR => Some(FileType::Directory),
+            CPIO_MODE_SYMLINK => Some(FileType::Symlink),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            add(files, file_name, entry(kind, mode, data));
+        }
+        offset = align4(data_start + file_size);
+    }
+}
+
+fn load_tar(archive: &'static [u8], files: &mut BTreeMap<String, Entry>) -> Result<(), Errno> {
+    let mut offset = 0;
+    // the archive ends with zeroed blocks, or just stops
+    while offset + TAR_BLOCK_SIZE <= archive.len() {
+        let header = bytes(archive, offset, TAR_BLOCK_SIZE)?;
+        if header.iter().all(|&c| c == 0) {
+            break;
+        }
+        if &header[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()] != TAR_MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let mode = number(&header[100..108], 8)? as u32;
+        let file_size = number(&header[124..136], 8)?;
+        let data = bytes(archive, offset + TAR_BLOCK_SIZE, file_size)?;
+        // old archives leave the type of regular files NUL
+        let kind = match header[156] {
+            b'0' | 0 => Some((FileType::Regular, data)),
+            b'2' => Some((FileType::Symlink, name(&header[157..257])?.as_bytes())),
+            b'5' => Some((FileType::Directory, &[][..])),
+            _ => None,
+        };
+        if let Some((kind, data)) = kind {
+            // long paths are split into a prefix and the name
+            let prefix = name(&header[345..500])?;
+            let file_name = name(&header[..100])?;
+            add(
+                files,
+                &path::join(prefix, file_name),
+                entry(kind, mode, data),
+            );
+        }
+        let blocks = (file_size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE;
+        offset += (1 + blocks) * TAR_BLOCK_SIZE;
+    }
+    Ok(())
+}
+
+// make an entry in the root filesystem, directories that are already there are kept
+fn extract(ctx: &FsContext, path: &str, entry: &Entry) -> Result<(), Errno> {
+    match entry.kind {
+        FileType::Directory => match vfs::mkdir(ctx, path) {
+            Ok(()) | Err(Errno::EEXIST) => {}
+            Err(errno) => return Err(errno),
+        },
+        FileType::Symlink => {
+            let target = str::from_utf8(entry.data).map_err(|_| Errno::EINVAL)?;
+            return vfs::symlink(ctx, target, path);
+        }
+        _ => {
+            let flags = abi::O_WRONLY | abi::O_CREAT | abi::O_TRUNC;
+            let file = vfs::open(ctx, path, flags)?;
+            let mut written = 0;
+            while written < entry.data.len() {
+                written += file.write(&entry.data[written..])?;
+            }
+        }
+    }
+    vfs::chmod(ctx, path, entry.perm)
+}
+
+// copy the files of a boot module into the root filesystem, returns how many there were;
+// nothing is copied if the archive is malformed
+pub fn unpack(archive: &'static [u8]) -> Result<usize, Errno> {
+    let mut files = BTreeMap::new();
+    if archive.starts_with(CPIO_MAGIC) {
+        load_cpio(archive, &mut files)?;
+    } else if archive.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()) == Some(TAR_MAGIC) {
+        load_tar(archive, &mut files)?;
+    } else {
+        return Err(Errno::EINVAL);
+    }
+    // directories sort before what is in them
+    let ctx = FsContext::new();
+    for (path, entry) in files.iter() {
+        if let Err(errno) = extract(&ctx, path, entry) {
+            log::warn!("initrd: could not create {}: {}", path, errno);
+        }
+    }
+    Ok(files.len())
+}
@@ -1,8 +1,16 @@
 // TODO: document further
 
+use crate::abi;
+use crate::ata;
+use crate::channel;
+use crate::console;
+use crate::exceptions;
+use crate::lapic;
+use crate::percpu;
 use crate::port::{end_of_interrupt, Port};
 use crate::scheduler;
-use crate::{print, println};
+use crate::smp;
+use crate::println;
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -33,52 +41,128 @@ macro_rules! irq_fn {
     }
 }
 
-extern "x86-interrupt" fn div_by_zero(sframe: &mut InterruptStackFrame) {
-    println!("division by zero! {:?}", sframe);
-}
-
-extern "x86-interrupt" fn breakpoint(sframe: &mut InterruptStackFrame) {
-    println!("int3 {:?}", sframe);
-}
-
-extern "x86-interrupt" fn page_fault(sframe: &mut InterruptStackFrame, errno: u64) {
-    println!("page fault! error code: {} {:?}", errno, sframe);
-    loop {}
-}
-
-extern "x86-interrupt" fn gpf(sframe: &mut InterruptStackFrame, errno: u64) {
-    println!("GPF! error code: {} {:?}", errno, sframe);
-    loop {}
-}
-
-extern "x86-interrupt" fn double_fault(sframe: &mut InterruptStackFrame, errno: u64) {
-    println!("double fault! error code: {} {:?}", errno, sframe);
-    loop {}
+// runs on the scheduler IST stack of the current CPU
+#[naked]
+unsafe extern "C" fn timer(_sframe: &mut InterruptStackFrame) {
+    // coming from ring 3 we still have the user GS base
+    asm!("test qword ptr [rsp + 8], 3; jz 2f; swapgs; 2:");
+    let ctx = scheduler::get_context();
+    smp::timer_end_of_interrupt();
+    scheduler::wake_sleepers();
+    // tasks are only preempted in userspace, in a syscall they might hold locks
+    if (*ctx).cs & 3 != 0 || percpu::current().cur_task.get().is_none() {
+        // a task running when the tick came has used up its time slice
+        scheduler::SCHEDULER.demote_current();
+        scheduler::SCHEDULER.save_current_context(ctx);
+        scheduler::SCHEDULER.run_next();
+    }
+    // nothing else to run, go back to where we were interrupted
+    scheduler::restore_context(&*ctx);
 }
 
+// a task giving up the CPU in the kernel, e.g. to block, uses the same IST stack as the timer
 #[naked]
-unsafe extern "C" fn timer(_sframe: &mut InterruptStackFrame) {
+unsafe extern "C" fn yield_cpu(_sframe: &mut InterruptStackFrame) {
     let ctx = scheduler::get_context();
     scheduler::SCHEDULER.save_current_context(ctx);
-    end_of_interrupt(32);
     scheduler::SCHEDULER.run_next();
+    scheduler::restore_context(&*ctx);
+}
+
+extern "x86-interrupt" fn tlb_shootdown(_sframe: &mut InterruptStackFrame) {
+    smp::handle_tlb_shootdown();
+    lapic::end_of_interrupt();
 }
 
-// handler for detecting and returning keystrokes
+// spurious interrupts from the local APIC must not be acknowledged
+extern "x86-interrupt" fn spurious(_sframe: &mut InterruptStackFrame) {}
+
+// handler for detecting and returning keystrokes, scancodes go to the keyboard server if
+// there is one and are decoded here otherwise
 irq_fn!(keyboard, 33, || {
     let port: Port<u8> = Port::new(0x60);
     let scan_code = port.read();
+    if channel::forward_irq(abi::IRQ_KEYBOARD as usize, &[scan_code]) {
+        return;
+    }
     let mut kbd = KEYBOARD.lock();
     if let Ok(Some(key_event)) = kbd.add_byte(scan_code) {
         if let Some(key) = kbd.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(char) => print!("{}", char),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
+            // keys without a character (arrows, function keys) are ignored for now
+            if let DecodedKey::Unicode(c) = key {
+                console::handle_char(c);
             }
         }
     }
 });
 
+irq_fn!(ata_primary, 46, || ata::handle_irq(0));
+irq_fn!(ata_secondary, 47, || ata::handle_irq(1));
+
+// vectors handed out to PCI devices for MSI and MSI-X, their messages go straight to the
+// local APIC so the PIC is not involved
+pub const MSI_VECTOR_BASE: u8 = 0x60;
+const MSI_VECTORS: usize = 16;
+
+static MSI_HANDLERS: Mutex<[Option<fn()>; MSI_VECTORS]> = Mutex::new([None; MSI_VECTORS]);
+
+fn handle_msi(index: usize) {
+    let handler = MSI_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler();
+    }
+    lapic::end_of_interrupt();
+}
+
+macro_rules! msi_fn {
+    ($($f: ident = $i: literal),*) => {
+        $(extern "x86-interrupt" fn $f(_sframe: &mut InterruptStackFrame) {
+            handle_msi($i);
+        })*
+        static MSI_ENTRIES: [extern "x86-interrupt" fn(&mut InterruptStackFrame); MSI_VECTORS] =
+            [$($f),*];
+    };
+}
+
+msi_fn!(
+    msi0 = 0,
+    msi1 = 1,
+    msi2 = 2,
+    msi3 = 3,
+    msi4 = 4,
+    msi5 = 5,
+    msi6 = 6,
+    msi7 = 7,
+    msi8 = 8,
+    msi9 = 9,
+    msi10 = 10,
+    msi11 = 11,
+    msi12 = 12,
+    msi13 = 13,
+    msi14 = 14,
+    msi15 = 15
+);
+
+// a free vector whose interrupts call handler, None if they are all taken
+pub fn alloc_msi_vector(handler: fn()) -> Option<u8> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        let index = handlers.iter().position(|slot| slot.is_none())?;
+        handlers[index] = Some(handler);
+        Some(MSI_VECTOR_BASE + index as u8)
+    })
+}
+
+// give back a vector alloc_msi_vector returned
+pub fn free_msi_vector(vector: u8) {
+    let index = vector.wrapping_sub(MSI_VECTOR_BASE) as usize;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(slot) = MSI_HANDLERS.lock().get_mut(index) {
+            *slot = None;
+        }
+    })
+}
+
 // setup the interrupt table with 4 interrupts
 lazy_static! {
     static ref INTERRUPT_TABLE: InterruptDescriptorTable = {
@@ -89,19 +173,39 @@ lazy_static! {
                     IDTEntry::new($e as *const IDTHandler, segmentation::cs(), 0, true, 0);
             };
         }
-        idt_entry!(0, div_by_zero);
-        idt_entry!(3, breakpoint);
-        vectors[8] = IDTEntry::new(
-            double_fault as *const IDTHandler,
+        for (vector, entry) in exceptions::ENTRIES.iter().enumerate() {
+            let ist = match vector {
+                8 => crate::gdt::DOUBLE_FAULT_IST_INDEX + 1,
+                _ => 0,
+            };
+            // int3 may be used from userspace
+            let dpl = if vector == 3 { 3 } else { 0 };
+            vectors[vector] =
+                IDTEntry::new(*entry as *const IDTHandler, segmentation::cs(), ist, true, dpl);
+        }
+        vectors[32] = IDTEntry::new(
+            timer as *const IDTHandler,
             segmentation::cs(),
-            crate::gdt::DOUBLE_FAULT_IST_INDEX + 1,
+            crate::gdt::SCHEDULER_IST_INDEX + 1,
             true,
             0,
         );
-        idt_entry!(13, gpf);
-        idt_entry!(14, page_fault);
-        idt_entry!(32, timer);
         idt_entry!(33, keyboard);
+        idt_entry!(46, ata_primary);
+        idt_entry!(47, ata_secondary);
+        for (i, entry) in MSI_ENTRIES.iter().enumerate() {
+            vectors[MSI_VECTOR_BASE as usize + i] =
+                IDTEntry::new(*entry as *const IDTHandler, segmentation::cs(), 0, true, 0);
+        }
+        vectors[scheduler::YIELD_VECTOR as usize] = IDTEntry::new(
+            yield_cpu as *const IDTHandler,
+            segmentation::cs(),
+            crate::gdt::SCHEDULER_IST_INDEX + 1,
+            true,
+            0,
+        );
+        idt_entry!(0xf0, tlb_shootdown);
+        idt_entry!(0xff, spurious);
         InterruptDescriptorTable(vectors)
     };
 }
@@ -110,11 +214,15 @@ lazy_static! {
 struct InterruptDescriptorTable([IDTEntry; 0x100]);
 
 impl InterruptDescriptorTable {
-    fn load(&'static self) {
-        let idt_ptr = DescriptorTablePointer {
+    fn pointer(&'static self) -> DescriptorTablePointer {
+        DescriptorTablePointer {
             base: self as *const _ as u64,
             limit: (size_of::<Self>() - 1) as u16,
-        };
+        }
+    }
+
+    fn load(&'static self) {
+        let idt_ptr = self.pointer();
         println!(" - Setting up IDT with {} entries", INTERRUPT_TABLE.0.len());
         println!(" - IDT ptr address: {:x}", &idt_ptr as *const _ as u64);
         println!(
@@ -184,4 +292,11 @@ impl IDTEntry {
 
 pub fn setup_idt() {
     INTERRUPT_TABLE.load();
+}
+
+// all CPUs share the same IDT, the APs just need to load it
+pub fn load_idt() {
+    unsafe {
+        lidt(&INTERRUPT_TABLE.pointer());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,194 @@
+// Kernel logging behind the macros of the log crate (log::info! and friends). Records are
+// stamped with the timer tick and kept in a ring buffer that the dmesg syscall reads, they are
+// also written to the serial port and, from info up, to the screen. Which records are kept is
+// set with the log option of the command line in env_logger's syntax, e.g.
+// log=warn,buddy_alloc=trace keeps warnings and errors plus everything the buddy allocator logs.
+
+use crate::serial_port;
+use crate::smp;
+use crate::vga_buffer;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::{Mutex, RwLock};
+use x86_64::instructions::interrupts;
+
+pub const RING_SIZE: usize = 0x10000;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+// less important records only go to the serial port and the ring buffer
+const SCREEN_LEVEL: Level = Level::Info;
+
+// cleared when the console is on the serial port, which gets every record anyway
+static TO_SCREEN: AtomicBool = AtomicBool::new(true);
+
+// targets are module paths, the crate name is left out when matching and printing them
+const CRATE_PREFIX: &str = "diy_os::";
+
+// the last RING_SIZE bytes of log output
+struct Ring {
+    data: [u8; RING_SIZE],
+    written: usize, // bytes written since boot
+}
+
+impl Ring {
+    // copy the newest bytes into buf, a line that doesn't fit completely is left out
+    fn read(&self, buf: &mut [u8]) -> usize {
+        let len = min(min(self.written, RING_SIZE), buf.len());
+        let start = self.written - len;
+        for (i, byte) in buf[..len].iter_mut().enumerate() {
+            *byte = self.data[(start + i) % RING_SIZE];
+        }
+        let cut = start > 0 && self.data[(start - 1) % RING_SIZE] != b'\n';
+        match buf[..len].iter().position(|&c| c == b'\n') {
+            Some(newline) if cut => {
+                buf.copy_within(newline + 1..len, 0);
+                len - newline - 1
+            }
+            _ => len,
+        }
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.written % RING_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+// records come from interrupt handlers too, so the ring is only locked with interrupts off
+static RING: Mutex<Ring> = Mutex::new(Ring {
+    data: [0; RING_SIZE],
+    written: 0,
+});
+
+struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>, // without the crate prefix
+}
+
+impl Filter {
+    // the level of the longest module that contains the target
+    fn level(&self, target: &str) -> LevelFilter {
+        let target = target.trim_start_matches(CRATE_PREFIX);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::")
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, max)
+    }
+}
+
+static FILTER: RwLock<Filter> = RwLock::new(Filter {
+    default: DEFAULT_LEVEL,
+    modules: Vec::new(),
+});
+
+struct KernelLogger;
+
+// a record as it is printed, with the tick it was logged at
+struct Line<'a>(u64, &'a Record<'a>);
+
+impl<'a> fmt::Display for Line<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "[{:>8}] {:<5} {}: {}",
+            self.0,
+            self.1.level(),
+            self.1.target().trim_start_matches(CRATE_PREFIX),
+            self.1.args()
+        )
+    }
+}
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTER.read().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = Line(smp::ticks(), record);
+        interrupts::without_interrupts(|| {
+            let _ = write!(RING.lock(), "{}", line);
+        });
+        serial_port::_print(format_args!("{}", line));
+        if record.level() <= SCREEN_LEVEL && TO_SCREEN.load(Ordering::Relaxed) {
+            vga_buffer::_print(format_args!("{}", line));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// install the logger with the default level, nothing is logged before this
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+// set the levels from the log option, this needs the heap
+pub fn configure(spec: &str) {
+    let mut filter = Filter {
+        default: DEFAULT_LEVEL,
+        modules: Vec::new(),
+    };
+    let mut invalid = Vec::new();
+    for directive in spec.split(',').filter(|directive| !directive.is_empty()) {
+        let mut parts = directive.splitn(2, '=');
+        let (first, level) = (parts.next().unwrap_or(""), parts.next());
+        match level.map(LevelFilter::from_str) {
+            None => match LevelFilter::from_str(first) {
+                Ok(level) => filter.default = level,
+                Err(_) => invalid.push(directive),
+            },
+            Some(Ok(level)) => {
+                let module = first.trim_start_matches(CRATE_PREFIX).to_string();
+                filter.modules.push((module, level));
+            }
+            Some(Err(_)) => invalid.push(directive),
+        }
+    }
+    log::set_max_level(filter.max_level());
+    // the old filter is dropped after unlocking, freeing it may log
+    let old = core::mem::replace(&mut *FILTER.write(), filter);
+    drop(old);
+    for directive in invalid {
+        log::warn!("ignoring invalid log directive {}", directive);
+    }
+}
+
+pub fn set_screen(enabled: bool) {
+    TO_SCREEN.store(enabled, Ordering::Relaxed);
+}
+
+// copy the newest log output into buf, returns its length
+pub fn read(buf: &mut [u8]) -> usize {
+    interrupts::without_interrupts(|| RING.lock().read(buf))
+}
@@ -0,0 +1,95 @@
+use crate::mem::PhysAddr;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// default local APIC base, overridden by the MADT if it says otherwise
+const DEFAULT_LAPIC_BASE: u64 = 0xfee00000;
+
+// local APIC register offsets
+const REG_ID: u64 = 0x20;
+const REG_TPR: u64 = 0x80;
+const REG_EOI: u64 = 0xb0;
+const REG_SPURIOUS: u64 = 0xf0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_TIMER_INIT: u64 = 0x380;
+const REG_TIMER_DIV: u64 = 0x3e0;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIV_16: u32 = 0b0011;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(DEFAULT_LAPIC_BASE);
+
+unsafe fn reg_ptr(reg: u64) -> *mut u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    PhysAddr::new(base + reg).to_virt().unwrap().addr() as *mut u32
+}
+
+fn read(reg: u64) -> u32 {
+    unsafe { read_volatile(reg_ptr(reg)) }
+}
+
+fn write(reg: u64, val: u32) {
+    unsafe { write_volatile(reg_ptr(reg), val) }
+}
+
+pub fn set_base(base: PhysAddr) {
+    LAPIC_BASE.store(base.addr(), Ordering::Relaxed);
+}
+
+// enable the local APIC of the calling CPU and accept all interrupt priorities
+pub fn init() {
+    write(REG_TPR, 0);
+    write(REG_SPURIOUS, SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+pub fn id() -> u32 {
+    read(REG_ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+fn send_icr(dest_lapic_id: u32, cmd: u32) {
+    write(REG_ICR_HIGH, dest_lapic_id << 24);
+    write(REG_ICR_LOW, cmd);
+    // wait for the IPI to be accepted
+    while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+}
+
+pub fn send_init(dest_lapic_id: u32) {
+    send_icr(dest_lapic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+// the AP starts executing in real mode at physical address (vector << 12)
+pub fn send_startup(dest_lapic_id: u32, vector: u8) {
+    send_icr(
+        dest_lapic_id,
+        ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector as u32,
+    );
+}
+
+pub fn send_ipi(dest_lapic_id: u32, vector: u8) {
+    send_icr(dest_lapic_id, ICR_LEVEL_ASSERT | vector as u32);
+}
+
+pub fn broadcast_ipi(vector: u8) {
+    send_icr(0, ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+// fire the given vector periodically on this CPU, used as the scheduler tick on APs
+pub fn start_timer(vector: u8, initial_count: u32) {
+    write(REG_TIMER_DIV, TIMER_DIV_16);
+    write(REG_LVT_TIMER, TIMER_PERIODIC | vector as u32);
+    write(REG_TIMER_INIT, initial_count);
+}
@@ -6,22 +6,58 @@
 #![feature(alloc_error_handler)]
 
 extern crate alloc;
+extern crate log;
 extern crate multiboot2;
 extern crate pc_keyboard;
 extern crate x86_64;
 
+pub mod abi;
+pub mod acpi;
+pub mod addr_space;
+pub mod ata;
+pub mod backtrace;
+pub mod block;
 pub mod buddy_alloc;
+pub mod channel;
+pub mod cmdline;
+pub mod console;
+pub mod dma;
+pub mod elf;
+pub mod errno;
+pub mod fat;
+pub mod fd;
 pub mod frame_alloc;
+pub mod gdbstub;
+pub mod exceptions;
 mod gdt;
 pub mod global_alloc;
+pub mod initrd;
 pub mod interrupts;
+pub mod klog;
+pub mod lapic;
 pub mod mem;
+pub mod multiboot;
+pub mod path;
+pub mod pci;
+pub mod percpu;
+pub mod pipe;
 # pub mod port;
+pub mod programs;
+pub mod rtc;
 # pub mod scheduler;
+pub mod selftest;
 # pub mod serial_port;
+pub mod shm;
+pub mod signal;
+pub mod smp;
 # pub mod syscalls;
-# mod userspace;
+pub mod tag_fs;
+pub mod tmpfs;
+pub mod uaccess;
+pub mod vfs;
 # pub mod vga_buffer;
+pub mod virtio;
+pub mod virtio_blk;
 
 # use gdt::init_gdt;
 # use interrupts::setup_idt;
@@ -31,10 +67,18 @@ pub mod mem;
 # use crate::vga_buffer::set_color;
 # use crate::vga_buffer::Color;
 
+use alloc::string::String;
+use alloc::sync::Arc;
 #[cfg(not(feature = "no-panic-handler"))]
 use core::panic::PanicInfo;
 use multiboot2::BootInformation;
 
+// the size of the RAM disk the tag_fs at /tags is on
+const TAGS_RAMDISK_SIZE: usize = 4 << 20;
+// how much the files of the tmpfs at / and at /tmp may hold
+const ROOT_TMPFS_SIZE: u64 = 256 << 20;
+const TMP_TMPFS_SIZE: u64 = 64 << 20;
+
 #[global_allocator]
 static ALLOCATOR: global_alloc::Allocator = global_alloc::Allocator;
 
@@ -48,6 +92,8 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    serial_println!("{}", info);
+    backtrace::print();
     loop {}
 }
 
@@ -75,8 +121,36 @@ pub extern "C" fn ua64_mode_start() -> ! {
     start(boot_info);
 }
 
+// mount the FAT32 volumes on disks and partitions at /mnt/NAME, e.g. /mnt/hda1
+fn mount_disks() {
+    let ctx = vfs::FsContext::new();
+    for device in block::devices() {
+        let fs = match fat::FatFs::mount(device.clone()) {
+            Ok(fs) => fs,
+            Err(_) => continue,
+        };
+        let mut path = String::from("/mnt/");
+        path.push_str(device.name());
+        let mounted = match vfs::mkdir(&ctx, "/mnt") {
+            Ok(()) | Err(errno::Errno::EEXIST) => vfs::mkdir(&ctx, &path),
+            Err(errno) => Err(errno),
+        };
+        match mounted.and_then(|()| vfs::mount(&path, Arc::new(fs))) {
+            Ok(()) => log::info!("{}: FAT32 at {}", device.name(), path),
+            Err(errno) => log::warn!("{}: could not mount at {}: {}", device.name(), path, errno),
+        }
+    }
+}
+
 pub fn start(boot_info: &'static BootInformation) -> ! {
     cls();
+    klog::init();
+    let args = cmdline::init(multiboot::command_line(boot_info));
+    console::init(args.console);
+    klog::set_screen(args.console == console::ConsoleKind::Vga);
+    unsafe {
+        mem::save_kernel_page_table();
+    }
     init_gdt();
     setup_idt();
     unsafe {
@@ -98,21 +172,91 @@ pub fn start(boot_info: &'static BootInformation) -> ! {
     }
     println!("Kernel end at: {:x}", boot_info.end_address());
     unsafe {
-        frame_alloc::SimpleAllocator::init(boot_info);
-        global_alloc::init_global_alloc(frame_alloc::BOOTINFO_ALLOCATOR.as_mut().unwrap());
+        frame_alloc::SimpleAllocator::init(boot_info, args.mem);
+        let frame_allocator = frame_alloc::BOOTINFO_ALLOCATOR.as_mut().unwrap();
+        // the initrds stay where GRUB loaded them
+        for module in multiboot::modules(boot_info) {
+            let (start, end) = (mem::PhysAddr::new(module.start), mem::PhysAddr::new(module.end));
+            frame_allocator.reserve(start, end);
+        }
+        global_alloc::init_global_alloc(frame_allocator);
+    }
+    dma::init();
+    if let Some(spec) = args.log {
+        klog::configure(spec);
+    }
+    rtc::init();
+    // everything lives in memory until there is a disk filesystem
+    let root = Arc::new(tmpfs::TmpFs::new(0o755, ROOT_TMPFS_SIZE));
+    vfs::mount("/", root).expect("nothing is mounted at / yet");
+    for module in multiboot::modules(boot_info) {
+        let archive = unsafe {
+            let start = mem::PhysAddr::new(module.start).to_virt().unwrap().addr();
+            core::slice::from_raw_parts(start as *const u8, (module.end - module.start) as usize)
+        };
+        match initrd::unpack(archive) {
+            Ok(count) => log::info!("initrd {}: {} files", module.name, count),
+            Err(errno) => log::warn!("initrd {} is no cpio or tar archive: {}", module.name, errno),
+        }
+    }
+    match vfs::mkdir(&vfs::FsContext::new(), "/tmp") {
+        Ok(()) | Err(errno::Errno::EEXIST) => {}
+        Err(errno) => panic!("could not make /tmp: {}", errno),
+    }
+    let tmp = tmpfs::TmpFs::new(0o1777, TMP_TMPFS_SIZE);
+    vfs::mount("/tmp", Arc::new(tmp)).expect("/tmp is a directory");
+    // a tag_fs on a RAM disk at /tags, until there are disks to keep one on
+    let ramdisk = Arc::new(block::RamDisk::new("ram0", TAGS_RAMDISK_SIZE));
+    let tags = tag_fs::format(&*ramdisk)
+        .and_then(|()| block::register(ramdisk.clone()))
+        .and_then(|()| tag_fs::TagFs::mount(ramdisk));
+    let mounted = tags.and_then(|fs| {
+        vfs::mkdir(&vfs::FsContext::new(), "/tags")?;
+        vfs::mount("/tags", Arc::new(fs))
+    });
+    if let Err(errno) = mounted {
+        log::warn!("no tag_fs at /tags: {}", errno);
+    }
+    if cfg!(feature = "gdb") {
+        gdbstub::init();
+        println!("Waiting for GDB on COM2");
+        gdbstub::breakpoint();
     }
     set_color(Color::Green, Color::Black, false);
     init_pics();
-    let userspace_fn_1_in_kernel =
-        mem::VirtAddr::new(userspace::prog1 as *const () as u64);
-    let userspace_fn_2_in_kernel =
-        mem::VirtAddr::new(userspace::prog2 as *const () as u64);
-    unsafe {
-        let sched = &scheduler::SCHEDULER;
-        sched.schedule(userspace_fn_1_in_kernel);
-        sched.schedule(userspace_fn_2_in_kernel);
-        loop {
-            sched.run_next();
+    smp::init();
+    pci::init();
+    // the disk drivers wait for interrupts, so they start once those are on
+    ata::init();
+    pci::register_driver(&virtio_blk::DRIVER);
+    mount_disks();
+    if args.tests && !selftest::run() {
+        println!("Some self tests failed, see dmesg");
+    }
+    scheduler::SCHEDULER.set_policy(args.sched);
+    block::start_flush_thread();
+    for path in programs::SERVERS.iter().chain(&[args.init]) {
+        // init= may name a program that isn't linked in but comes with the initrd
+        let file;
+        let image = match programs::find(path) {
+            Some(image) => image,
+            None => match vfs::read_file(&vfs::FsContext::new(), path) {
+                Ok(data) => {
+                    file = data;
+                    &file[..]
+                }
+                Err(errno) => {
+                    println!("Could not start {}: {}", path, errno);
+                    continue;
+                }
+            },
+        };
+        let argv: [&[u8]; 1] = [path.as_bytes()];
+        if let Err(errno) = unsafe { scheduler::SCHEDULER.spawn(image, &argv) } {
+            println!("Could not start {}: {}", path, errno);
         }
     }
+    unsafe {
+        scheduler::enter_idle();
+    }
 }
@@ -1,9 +1,18 @@
+use crate::smp;
 use alloc::boxed::Box;
 use core::fmt::Display;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-const VIRT_OFFSET: u64 = 0xC0000000;
+pub const VIRT_OFFSET: u64 = 0xC0000000;
 pub const FRAME_SIZE: u64 = 0x1000;
-type EmptyFrame = [u8; FRAME_SIZE as usize];
+// userspace gets everything below the kernel's mappings (except for the null page)
+pub const USER_START: u64 = FRAME_SIZE;
+pub const USER_END: u64 = VIRT_OFFSET;
+// kernel mappings outside of the one of physical memory below 4 GiB, e.g. uncached DMA buffers,
+// they are in the same P2 table in every page table
+pub const KERNEL_WINDOW_START: u64 = 0x1_c000_0000;
+pub const KERNEL_WINDOW_END: u64 = 0x2_0000_0000;
+pub type EmptyFrame = [u8; FRAME_SIZE as usize];
 
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -89,6 +98,26 @@ impl Display for PTEntry {
     }
 }
 
+// physical address of the page table set up at boot, which only has the kernel mappings
+static KERNEL_PT: AtomicU64 = AtomicU64::new(0);
+
+pub unsafe fn save_kernel_page_table() {
+    let p4: u64;
+    asm!("mov rax, cr3", out("rax") p4);
+    KERNEL_PT.store(p4, Ordering::SeqCst);
+}
+
+// switch away from a task's page table, e.g. before it is freed
+pub unsafe fn enable_kernel_page_table() {
+    let p4 = KERNEL_PT.load(Ordering::SeqCst);
+    asm!("mov cr3, rax", in("rax") p4);
+}
+
+// switch to the page table whose P4 is at the given physical address
+pub unsafe fn enable_page_table(p4: PhysAddr) {
+    asm!("mov cr3, rax", in("rax") p4.addr());
+}
+
 pub unsafe fn get_page_table() -> &'static mut PageTable {
     let mut p4: u64;
     asm!("mov rax, cr3", out("rax") p4);
@@ -110,6 +139,7 @@ impl PageTable {
         pt0.entries[4] = cur_pt0.entries[4].clone(); // child PT that is currently in use
         pt0.entries[5] = cur_pt0.entries[5].clone(); // these correspond to the addresses our kernel uses
         pt0.entries[6] = cur_pt0.entries[6].clone(); // plus some more, so that the entire physical memory is mapped
+        pt0.entries[7] = cur_pt0.entries[7].clone(); // and the kernel window
         pt
     }
 
@@ -134,6 +164,40 @@ impl PageTable {
     pub fn get_entry(&mut self, i: usize) -> &mut PTEntry {
         &mut self.entries[i]
     }
+
+    // walk this page table for a virtual address, returning the physical address and the
+    // options that are in effect (user and writable have to be set on every level)
+    pub unsafe fn lookup(&mut self, virt: VirtAddr) -> Option<(PhysAddr, u16)> {
+        let mut options = BIT_PRESENT | BIT_WRITABLE | BIT_USER;
+        let mut pt: &mut PageTable = self;
+        for level in 0..4 {
+            let shift = 39 - 9 * level;
+            let pte = pt.get_entry(((virt.addr() >> shift) & 0b1_1111_1111) as usize);
+            if !pte.get_bit(BIT_PRESENT) {
+                return None;
+            }
+            if !pte.get_bit(BIT_WRITABLE) {
+                options &= !BIT_WRITABLE;
+            }
+            if !pte.get_bit(BIT_USER) {
+                options &= !BIT_USER;
+            }
+            // huge pages end the walk early on the P3 and P2 levels
+            if level == 3 || (level > 0 && pte.get_bit(BIT_HUGE)) {
+                let page_off = virt.addr() & ((1 << shift) - 1);
+                return Some((pte.phys_addr().offset(page_off), options));
+            }
+            pt = pte.next_pt();
+        }
+        None
+    }
+    // remove the mapping of a single page, the frame behind it is left alone
+    pub unsafe fn unmap(&mut self, virt: VirtAddr) {
+        if self.lookup(virt).is_some() {
+            self.map_virt_to_phys(virt, PhysAddr::new(0), 0);
+        }
+    }
+
     pub unsafe fn map_virt_to_phys(
         &mut self,
         virt: VirtAddr,
@@ -188,8 +252,13 @@ impl PageTable {
         }
         let p1_off = (virt.addr() / FRAME_SIZE) & 0b1_1111_1111;
         let pte = pte.next_pt().get_entry(p1_off as usize);
+        let was_present = pte.get_bit(BIT_PRESENT);
         pte.set_phys_addr(phys);
         pte.set_opts(create_options);
+        if was_present {
+            // other CPUs might have the old mapping cached
+            smp::tlb_shootdown(virt);
+        }
         return pte;
     }
 }
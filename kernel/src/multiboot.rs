@@ -0,0 +1,71 @@
+// Tags of the multiboot2 boot information that the multiboot2 crate doesn't parse for us.
+// The information stays where GRUB put it, right before the first frame the frame allocator
+// hands out (see frame_alloc.rs), so references into it live as long as the kernel.
+
+use core::convert::TryInto;
+use core::slice;
+use core::str;
+use multiboot2::BootInformation;
+
+const TAG_END: u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MODULE: u32 = 3;
+
+// the type of each tag and what follows its header
+fn tags(boot_info: &'static BootInformation) -> impl Iterator<Item = (u32, &'static [u8])> {
+    let start = boot_info as *const BootInformation as usize;
+    let total_size = unsafe { *(start as *const u32) } as usize;
+    let end = start + total_size;
+    let mut tag = start + 8;
+    core::iter::from_fn(move || {
+        if tag + 8 > end {
+            return None;
+        }
+        let (typ, size) = unsafe { (*(tag as *const u32), *((tag + 4) as *const u32) as usize) };
+        if typ == TAG_END || size < 8 || tag + size > end {
+            return None;
+        }
+        let data = unsafe { slice::from_raw_parts((tag + 8) as *const u8, size - 8) };
+        // tags start 8 byte aligned
+        tag += (size + 7) & !7;
+        Some((typ, data))
+    })
+}
+
+// a string in a tag, it is NUL-terminated
+fn tag_str(data: &'static [u8]) -> Option<&'static str> {
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    str::from_utf8(&data[..len]).ok()
+}
+
+// what follows the kernel's path in grub.cfg, empty if there is nothing
+pub fn command_line(boot_info: &'static BootInformation) -> &'static str {
+    tags(boot_info)
+        .find(|&(typ, _)| typ == TAG_COMMAND_LINE)
+        .and_then(|(_, data)| tag_str(data))
+        .unwrap_or("")
+}
+
+// a file GRUB loaded next to the kernel for a module2 line in grub.cfg
+pub struct Module {
+    pub start: u64,         // physical address of the first byte
+    pub end: u64,           // and of the byte after the last one
+    pub name: &'static str, // what follows the file's path on its module2 line
+}
+
+fn module(data: &'static [u8]) -> Option<Module> {
+    let start = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let end = u32::from_le_bytes(data.get(4..8)?.try_into().ok()?);
+    Some(Module {
+        start: start as u64,
+        end: end as u64,
+        name: tag_str(&data[8..])?,
+    })
+}
+
+// the modules in the order of their module2 lines
+pub fn modules(boot_info: &'static BootInformation) -> impl Iterator<Item = Module> {
+    tags(boot_info)
+        .filter(|&(typ, _)| typ == TAG_MODULE)
+        .filter_map(|(_, data)| module(data))
+}
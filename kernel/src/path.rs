@@ -0,0 +1,28 @@
+// Path handling that doesn't need a filesystem.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// resolve path relative to the absolute directory cwd, removing "." and ".." components
+pub fn join(cwd: &str, path: &str) -> String {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut parts: Vec<&str> = Vec::new();
+    for part in base.split('/').chain(path.split('/')) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop(); // the parent of / is / itself
+            }
+            part => parts.push(part),
+        }
+    }
+    if parts.is_empty() {
+        return String::from("/");
+    }
+    let mut joined = String::new();
+    for part in parts {
+        joined.push('/');
+        joined.push_str(part);
+    }
+    joined
+}
@@ -0,0 +1,533 @@
+// PCI devices, found by scanning the configuration space of every bus. It is reached through
+// the memory mapped ECAM window the ACPI MCFG table describes, or else through the legacy ports
+// 0xcf8 and 0xcfc, which only reach the first 256 bytes of each function.
+//
+// Drivers register with the vendor and device ids or the classes they handle and are handed
+// each function that matches and has no driver yet.
+
+use crate::abi::PciInfo;
+use crate::acpi;
+use crate::errno::Errno;
+use crate::lapic;
+use crate::mem::PhysAddr;
+use crate::port::{InOut, Port};
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use core::ptr::{read_volatile, write_volatile};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+
+// the configuration space header
+const REG_VENDOR: u16 = 0x00;
+const REG_DEVICE: u16 = 0x02;
+const REG_COMMAND: u16 = 0x04;
+const REG_STATUS: u16 = 0x06;
+const REG_CLASS: u16 = 0x08; // revision, prog if, subclass and class from the low byte up
+const REG_HEADER_TYPE: u16 = 0x0e;
+const REG_BAR0: u16 = 0x10;
+const REG_CAPABILITIES: u16 = 0x34;
+const REG_INTERRUPT: u16 = 0x3c; // line, then pin
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_MSIX: u8 = 0x11;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_CONTROL_MASK_ALL: u16 = 1 << 14;
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+// where a message has to be written to interrupt a local APIC
+const MSI_ADDRESS: u64 = 0xfee0_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// the configuration spaces of a segment, one 4 KiB page for each function of a bus in range
+struct Ecam {
+    base: u64, // where bus 0 would be
+    start_bus: u8,
+    end_bus: u8,
+}
+
+lazy_static! {
+    static ref ECAM: Option<Ecam> = find_ecam();
+    // the address and the data port are written one after the other
+    static ref PORTS: Mutex<()> = Mutex::new(());
+}
+
+fn find_ecam() -> Option<Ecam> {
+    let mcfg = unsafe { acpi::find_table(MCFG_SIGNATURE)? };
+    // 8 reserved bytes, then a base address, segment, start and end bus for each segment
+    let body = unsafe { acpi::table_body(mcfg) };
+    let ecam = body
+        .get(8..)?
+        .chunks_exact(16)
+        .filter(|entry| entry[8] == 0 && entry[9] == 0) // segment 0
+        .map(|entry| Ecam {
+            base: acpi::read_u64(&entry[0..8]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .next()?;
+    log::info!(
+        "PCI: ECAM at {:#x} for buses {} to {}",
+        ecam.base,
+        ecam.start_bus,
+        ecam.end_bus
+    );
+    Some(ecam)
+}
+
+// the mapping of a register in the ECAM window, None if it has to go through the ports
+fn ecam_addr(addr: Address, offset: u16) -> Option<u64> {
+    let ecam = ECAM.as_ref()?;
+    if addr.bus < ecam.start_bus || addr.bus > ecam.end_bus {
+        return None;
+    }
+    let phys = ecam.base
+        + ((addr.bus as u64) << 20 | (addr.device as u64) << 15 | (addr.function as u64) << 12)
+        + offset as u64;
+    unsafe { PhysAddr::new(phys).to_virt() }.map(|virt| virt.addr())
+}
+
+fn select(addr: Address, offset: u16) -> u16 {
+    let address = CONFIG_ENABLE
+        | (addr.bus as u32) << 16
+        | (addr.device as u32) << 11
+        | (addr.function as u32) << 8
+        | (offset & 0xfc) as u32;
+    Port::<u32>::new(CONFIG_ADDRESS).write(address);
+    CONFIG_DATA + (offset & 3)
+}
+
+// read a register of the configuration space, offset is aligned to the size of T
+pub fn read<T: InOut + Copy>(addr: Address, offset: u16) -> T {
+    if let Some(virt) = ecam_addr(addr, offset) {
+        return unsafe { read_volatile(virt as *const T) };
+    }
+    let _ports = PORTS.lock();
+    Port::<T>::new(select(addr, offset)).read()
+}
+
+pub fn write<T: InOut + Copy>(addr: Address, offset: u16, value: T) {
+    if let Some(virt) = ecam_addr(addr, offset) {
+        return unsafe { write_volatile(virt as *mut T, value) };
+    }
+    let _ports = PORTS.lock();
+    Port::<T>::new(select(addr, offset)).write(value)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Bar {
+    None,
+    Io {
+        port: u16,
+        size: u32,
+    },
+    Memory {
+        addr: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub address: Address,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+    pub irq_line: u8,
+    pub irq_pin: u8,
+    pub capabilities: Vec<(u8, u16)>, // ids and offsets, in the order of the list
+}
+
+impl Device {
+    fn probe(address: Address) -> Option<Device> {
+        let vendor: u16 = read(address, REG_VENDOR);
+        if vendor == 0xffff {
+            return None;
+        }
+        let class: u32 = read(address, REG_CLASS);
+        let interrupt: u16 = read(address, REG_INTERRUPT);
+        let mut device = Device {
+            address,
+            vendor,
+            device: read(address, REG_DEVICE),
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+            revision: class as u8,
+            header_type: read(address, REG_HEADER_TYPE),
+            bars: [Bar::None; 6],
+            irq_line: interrupt as u8,
+            irq_pin: (interrupt >> 8) as u8,
+            capabilities: Vec::new(),
+        };
+        device.read_bars();
+        device.read_capabilities();
+        Some(device)
+    }
+
+    // the size of a BAR is found by writing all ones and seeing which bits stick, with decoding
+    // turned off meanwhile so the device doesn't show up at the wrong place
+    fn read_bars(&mut self) {
+        let count = match self.header_type & HEADER_TYPE_MASK {
+            0 => 6,
+            1 => 2, // bridges
+            _ => 0,
+        };
+        let addr = self.address;
+        let command: u16 = read(addr, REG_COMMAND);
+        write(addr, REG_COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let size_of = |reg: u16| -> (u32, u32) {
+            let value: u32 = read(addr, reg);
+            write(addr, reg, 0xffff_ffffu32);
+            let mask: u32 = read(addr, reg);
+            write(addr, reg, value);
+            (value, mask)
+        };
+        let mut i = 0;
+        while i < count {
+            let index = i;
+            let (value, mask) = size_of(REG_BAR0 + index as u16 * 4);
+            i += 1;
+            if value & 1 != 0 {
+                let mask = mask & 0xfffc;
+                if mask != 0 {
+                    self.bars[index] = Bar::Io {
+                        port: (value & !0x3) as u16,
+                        size: (!mask & 0xffff) + 1,
+                    };
+                }
+                continue;
+            }
+            let mut base = (value & !0xf) as u64;
+            let mut mask = (mask & !0xf) as u64 | 0xffff_ffff_0000_0000;
+            // a 64 bit BAR takes the next one for its high half and shows up at its first index
+            if (value >> 1) & 0x3 == 0x2 && i < count {
+                let (high, high_mask) = size_of(REG_BAR0 + i as u16 * 4);
+                base |= (high as u64) << 32;
+                mask = (mask & 0xffff_ffff) | (high_mask as u64) << 32;
+                i += 1;
+            }
+            // no bits stick in a BAR that isn't implemented
+            if mask != 0 && mask != 0xffff_ffff_0000_0000 {
+                self.bars[index] = Bar::Memory {
+                    addr: base,
+                    size: !mask + 1,
+                    prefetchable: value & 0x8 != 0,
+                };
+            }
+        }
+        write(addr, REG_COMMAND, command);
+    }
+
+    fn read_capabilities(&mut self) {
+        let status: u16 = read(self.address, REG_STATUS);
+        if status & STATUS_CAPABILITIES == 0 {
+            return;
+        }
+        let mut offset: u8 = read(self.address, REG_CAPABILITIES);
+        // a broken list could loop, there is room for at most 48 in the header
+        while offset >= 0x40 && self.capabilities.len() < 48 {
+            let offset16 = (offset & 0xfc) as u16;
+            let header: u16 = read(self.address, offset16);
+            self.capabilities.push((header as u8, offset16));
+            offset = (header >> 8) as u8;
+        }
+    }
+
+    // the offset of the first capability with this id
+    pub fn capability(&self, id: u8) -> Option<u16> {
+        self.capabilities
+            .iter()
+            .find(|&&(cap, _)| cap == id)
+            .map(|&(_, offset)| offset)
+    }
+
+    pub fn read<T: InOut + Copy>(&self, offset: u16) -> T {
+        read(self.address, offset)
+    }
+
+    pub fn write<T: InOut + Copy>(&self, offset: u16, value: T) {
+        write(self.address, offset, value)
+    }
+
+    // turn on decoding of its BARs, and DMA if the device is to be a bus master
+    pub fn enable(&self, bus_master: bool) {
+        let mut command: u16 = self.read(REG_COMMAND);
+        command |= COMMAND_IO | COMMAND_MEMORY;
+        if bus_master {
+            command |= COMMAND_BUS_MASTER;
+        }
+        self.write(REG_COMMAND, command);
+    }
+
+    fn disable_intx(&self) {
+        let command: u16 = self.read(REG_COMMAND);
+        self.write(REG_COMMAND, command | COMMAND_INTX_DISABLE);
+    }
+
+    // send the interrupts of the device as vector to the local APIC of this CPU, by MSI
+    pub fn enable_msi(&self, vector: u8) -> Result<(), Errno> {
+        let cap = self.capability(CAP_MSI).ok_or(Errno::ENODEV)?;
+        let control: u16 = self.read(cap + 2);
+        let address = MSI_ADDRESS | (lapic::id() as u64) << 12;
+        self.write(cap + 4, address as u32);
+        // one vector, the device must not use more
+        let control = control & !(0x7 << 4);
+        let data = if control & MSI_CONTROL_64BIT != 0 {
+            self.write(cap + 8, (address >> 32) as u32);
+            cap + 12
+        } else {
+            cap + 8
+        };
+        self.write(data, vector as u16);
+        self.write(cap + 2, control | MSI_CONTROL_ENABLE);
+        self.disable_intx();
+        Ok(())
+    }
+
+    // the entries of the MSI-X table, which is in one of the BARs
+    pub fn msix_entries(&self) -> Result<u16, Errno> {
+        let cap = self.capability(CAP_MSIX).ok_or(Errno::ENODEV)?;
+        let control: u16 = self.read(cap + 2);
+        Ok((control & 0x7ff) + 1)
+    }
+
+    // send the interrupts of an MSI-X table entry as vector to the local APIC of this CPU
+    // the first call turns MSI-X on, the entries that aren't set stay masked
+    pub fn enable_msix(&self, entry: u16, vector: u8) -> Result<(), Errno> {
+        let cap = self.capability(CAP_MSIX).ok_or(Errno::ENODEV)?;
+        if entry >= self.msix_entries()? {
+            return Err(Errno::EINVAL);
+        }
+        let table: u32 = self.read(cap + 4);
+        // BIR 6 and 7 are reserved
+        let base = match self.bars.get((table & 0x7) as usize) {
+            Some(&Bar::Memory { addr, .. }) => addr,
+            _ => return Err(Errno::ENODEV),
+        };
+        let phys = base + (table & !0x7) as u64 + entry as u64 * MSIX_ENTRY_SIZE;
+        // the table is only reached below 4 GiB, where all of physical memory is mapped
+        let virt = unsafe { PhysAddr::new(phys).to_virt() }.ok_or(Errno::ENODEV)?;
+        let slot = virt.addr() as *mut u32;
+        let address = MSI_ADDRESS | (lapic::id() as u64) << 12;
+        let control: u16 = self.read(cap + 2);
+        if control & MSIX_CONTROL_ENABLE == 0 {
+            self.write(
+                cap + 2,
+                control | MSIX_CONTROL_ENABLE | MSIX_CONTROL_MASK_ALL,
+            );
+            self.disable_intx();
+        }
+        self.enable(false);
+        unsafe {
+            write_volatile(slot, address as u32);
+            write_volatile(slot.add(1), (address >> 32) as u32);
+            write_volatile(slot.add(2), vector as u32);
+            write_volatile(slot.add(3), 0); // unmasked
+        }
+        let control: u16 = self.read(cap + 2);
+        self.write(cap + 2, control & !MSIX_CONTROL_MASK_ALL);
+        Ok(())
+    }
+}
+
+// what a driver handles
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl Match {
+    fn matches(&self, device: &Device) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } => device.vendor == vendor && device.device == id,
+            Match::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+pub trait PciDriver: Sync {
+    fn name(&self) -> &'static str;
+
+    fn matches(&self) -> &[Match];
+
+    // set up a device the driver matched, an error leaves it to other drivers
+    fn probe(&self, device: &Device) -> Result<(), Errno>;
+}
+
+struct Slot {
+    device: Device,
+    driver: Option<&'static str>,
+}
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+}
+
+// find the functions of every device on every bus
+pub fn init() {
+    let buses = match *ECAM {
+        Some(ref ecam) => ecam.start_bus..=ecam.end_bus,
+        None => 0..=255,
+    };
+    let mut found = Vec::new();
+    for bus in buses {
+        for device in 0..32 {
+            let address = Address {
+                bus,
+                device,
+                function: 0,
+            };
+            let first = match Device::probe(address) {
+                Some(first) => first,
+                None => continue,
+            };
+            let functions = match first.header_type & HEADER_MULTI_FUNCTION {
+                0 => 1,
+                _ => 8,
+            };
+            found.push(first);
+            for function in 1..functions {
+                let address = Address {
+                    bus,
+                    device,
+                    function,
+                };
+                found.extend(Device::probe(address));
+            }
+        }
+    }
+    for device in found.iter() {
+        log::info!(
+            "PCI {}: {:04x}:{:04x} class {:02x}{:02x}",
+            device.address,
+            device.vendor,
+            device.device,
+            device.class,
+            device.subclass
+        );
+    }
+    let mut devices = DEVICES.lock();
+    devices.extend(found.into_iter().map(|device| Slot {
+        device,
+        driver: None,
+    }));
+}
+
+// probe the driver on every device it matches that has no driver yet
+pub fn register_driver(driver: &'static dyn PciDriver) {
+    let candidates: Vec<Device> = DEVICES
+        .lock()
+        .iter()
+        .filter(|slot| slot.driver.is_none())
+        .filter(|slot| driver.matches().iter().any(|m| m.matches(&slot.device)))
+        .map(|slot| slot.device.clone())
+        .collect();
+    // probing can take a while, the list isn't locked meanwhile
+    for device in candidates {
+        match driver.probe(&device) {
+            Ok(()) => {
+                log::info!("PCI {}: bound to {}", device.address, driver.name());
+                let mut devices = DEVICES.lock();
+                let slot = devices
+                    .iter_mut()
+                    .find(|slot| slot.device.address == device.address);
+                if let Some(slot) = slot {
+                    slot.driver = Some(driver.name());
+                }
+            }
+            Err(errno) => log::warn!(
+                "PCI {}: {} failed: {}",
+                device.address,
+                driver.name(),
+                errno
+            ),
+        }
+    }
+}
+
+pub fn devices() -> Vec<Device> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|slot| slot.device.clone())
+        .collect()
+}
+
+// the devices as the pci_list syscall hands them out
+pub fn list() -> Vec<PciInfo> {
+    let devices = DEVICES.lock();
+    devices
+        .iter()
+        .map(|slot| {
+            let device = &slot.device;
+            let mut info = PciInfo {
+                bus: device.address.bus,
+                device: device.address.device,
+                function: device.address.function,
+                class: device.class,
+                subclass: device.subclass,
+                prog_if: device.prog_if,
+                revision: device.revision,
+                irq_line: device.irq_line,
+                vendor_id: device.vendor,
+                device_id: device.device,
+                ..PciInfo::default()
+            };
+            for &(id, _) in device.capabilities.iter().filter(|&&(id, _)| id < 32) {
+                info.capabilities |= 1 << id;
+            }
+            for (i, bar) in device.bars.iter().enumerate() {
+                let (addr, size) = match *bar {
+                    Bar::None => continue,
+                    Bar::Io { port, size } => (port as u64 | 1, size as u64),
+                    Bar::Memory { addr, size, .. } => (addr, size),
+                };
+                info.bars[i] = addr;
+                info.bar_sizes[i] = size;
+            }
+            let name = slot.driver.unwrap_or("").as_bytes();
+            let len = name.len().min(info.driver.len() - 1);
+            info.driver[..len].copy_from_slice(&name[..len]);
+            info
+        })
+        .collect()
+}
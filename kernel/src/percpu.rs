@@ -0,0 +1,120 @@
+use crate::gdt::CpuTables;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::RwLock;
+use x86_64::registers::model_specific::Msr;
+
+const MSR_GS_BASE: u32 = 0xc0000101;
+const MSR_KERNEL_GS_BASE: u32 = 0xc0000102;
+
+// offsets into PerCpu used by assembly code through the GS segment
+pub const PERCPU_USER_RSP: usize = 0x08;
+pub const PERCPU_KERNEL_STACK: usize = 0x10;
+
+const IDLE_STACK_SIZE: usize = 0x2000;
+
+pub const TLB_FLUSH_NONE: u64 = 0;
+pub const TLB_FLUSH_ALL: u64 = 1;
+
+// data private to one CPU, pointed to by the GS base while in the kernel
+// (and by the kernel GS base while in userspace, swapgs switches between the two)
+#[repr(C)]
+pub struct PerCpu {
+    self_ptr: *const PerCpu, // gs:[0x00] so that we can get a normal pointer back
+    pub user_rsp: Cell<u64>, // gs:[0x08] scratch space for the user stack on syscall
+    pub kernel_stack: Cell<u64>, // gs:[0x10] top of the current task's kernel stack
+    pub cpu_id: usize,       // index of this CPU in our own numbering (BSP is 0)
+    pub lapic_id: u32,       // local APIC id, used as the IPI destination
+    pub cur_task: Cell<Option<usize>>, // pid of the task running on this CPU
+    pub tlb_flush: AtomicU64, // pending TLB shootdown request (address or TLB_FLUSH_ALL)
+    pub online: AtomicBool,  // set once the CPU has finished its initialization
+    pub tables: Cell<Option<*mut CpuTables>>, // this CPU's GDT and TSS
+    pub idle_stack: u64,     // top of the stack used when no task is running
+}
+
+// only the owning CPU touches the Cell fields, other CPUs only use the atomics
+unsafe impl Sync for PerCpu {}
+
+static CPUS: RwLock<Vec<&'static PerCpu>> = RwLock::new(Vec::new());
+
+// allocate the per-CPU area for a CPU, it is leaked as CPUs never go away
+pub fn register_cpu(lapic_id: u32) -> &'static PerCpu {
+    let mut cpus = CPUS.write();
+    let percpu: &'static mut PerCpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        user_rsp: Cell::new(0),
+        kernel_stack: Cell::new(0),
+        cpu_id: cpus.len(),
+        lapic_id,
+        cur_task: Cell::new(None),
+        tlb_flush: AtomicU64::new(TLB_FLUSH_NONE),
+        online: AtomicBool::new(false),
+        tables: Cell::new(None),
+        idle_stack: {
+            let stack: &'static mut Vec<u8> =
+                Box::leak(Box::new(Vec::with_capacity(IDLE_STACK_SIZE)));
+            stack.as_ptr() as u64 + IDLE_STACK_SIZE as u64
+        },
+    }));
+    percpu.self_ptr = percpu as *const PerCpu;
+    cpus.push(percpu);
+    percpu
+}
+
+// point the GS base of the calling CPU to its per-CPU area
+pub unsafe fn load(percpu: &'static PerCpu) {
+    Msr::new(MSR_GS_BASE).write(percpu as *const PerCpu as u64);
+    Msr::new(MSR_KERNEL_GS_BASE).write(0);
+    percpu.online.store(true, Ordering::SeqCst);
+}
+
+// per-CPU data of the calling CPU (only valid while GS holds the kernel base)
+#[inline(always)]
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr);
+        &*ptr
+    }
+}
+
+// like current but None until load ran on this CPU, for code that can run that early
+pub fn try_current() -> Option<&'static PerCpu> {
+    match unsafe { Msr::new(MSR_GS_BASE).read() } {
+        0 => None,
+        _ => Some(current()),
+    }
+}
+
+pub fn cpu(cpu_id: usize) -> Option<&'static PerCpu> {
+    CPUS.read().get(cpu_id).copied()
+}
+
+pub fn by_lapic_id(lapic_id: u32) -> Option<&'static PerCpu> {
+    CPUS.read()
+        .iter()
+        .find(|cpu| cpu.lapic_id == lapic_id)
+        .copied()
+}
+
+pub fn cpu_count() -> usize {
+    CPUS.read().len()
+}
+
+pub fn for_each_online_cpu<F: FnMut(&'static PerCpu)>(mut f: F) {
+    for cpu in CPUS.read().iter() {
+        if cpu.online.load(Ordering::SeqCst) {
+            f(cpu);
+        }
+    }
+}
+
+pub fn online_cpus() -> Vec<&'static PerCpu> {
+    CPUS.read()
+        .iter()
+        .filter(|cpu| cpu.online.load(Ordering::SeqCst))
+        .copied()
+        .collect()
+}
@@ -0,0 +1,113 @@
+// Pipes: a bounded ring buffer between a read end and a write end. Readers block while it is
+// empty and get end of file once the write end is closed, writers block while it is full and
+// get EPIPE once the read end is closed.
+
+use crate::errno::Errno;
+use crate::fd::{FileDescription, FileRef};
+use crate::scheduler::WaitQueue;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const PIPE_SIZE: usize = 0x1000;
+
+struct Pipe {
+    buffer: Mutex<VecDeque<u8>>,
+    reader_closed: AtomicBool,
+    writer_closed: AtomicBool,
+    readable: WaitQueue, // readers waiting for data
+    writable: WaitQueue, // writers waiting for room
+}
+
+pub struct PipeReader(Arc<Pipe>);
+
+pub struct PipeWriter(Arc<Pipe>);
+
+// the read and the write end of a new pipe
+pub fn new_pipe() -> (FileRef, FileRef) {
+    let pipe = Arc::new(Pipe {
+        buffer: Mutex::new(VecDeque::with_capacity(PIPE_SIZE)),
+        reader_closed: AtomicBool::new(false),
+        writer_closed: AtomicBool::new(false),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (
+        Arc::new(PipeReader(pipe.clone())),
+        Arc::new(PipeWriter(pipe)),
+    )
+}
+
+impl FileDescription for PipeReader {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let pipe = &self.0;
+        let count = pipe.readable.wait_interruptible(|| {
+            let mut buffer = pipe.buffer.lock();
+            if buffer.is_empty() {
+                // end of file once nobody can write anymore
+                return match pipe.writer_closed.load(Ordering::SeqCst) {
+                    true => Some(0),
+                    false => None,
+                };
+            }
+            let count = min(buf.len(), buffer.len());
+            for (dst, src) in buf.iter_mut().zip(buffer.drain(..count)) {
+                *dst = src;
+            }
+            Some(count)
+        })?;
+        pipe.writable.wake_all();
+        Ok(count)
+    }
+}
+
+impl FileDescription for PipeWriter {
+    // blocks until everything is written
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        let pipe = &self.0;
+        let mut written = 0;
+        while written < buf.len() {
+            let result = pipe.writable.wait_interruptible(|| {
+                if pipe.reader_closed.load(Ordering::SeqCst) {
+                    return Some(Err(Errno::EPIPE));
+                }
+                let mut buffer = pipe.buffer.lock();
+                let room = PIPE_SIZE - buffer.len();
+                if room == 0 {
+                    return None;
+                }
+                let count = min(room, buf.len() - written);
+                buffer.extend(&buf[written..written + count]);
+                Some(Ok(count))
+            });
+            pipe.readable.wake_all();
+            // a signal or a closed read end after some data was written only ends the write
+            let result = result.and_then(|result| result);
+            match result {
+                Ok(count) => written += count,
+                Err(_) if written > 0 => break,
+                Err(errno) => return Err(errno),
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.0.reader_closed.store(true, Ordering::SeqCst);
+        self.0.writable.wake_all();
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.0.writer_closed.store(true, Ordering::SeqCst);
+        self.0.readable.wake_all();
+    }
+}
@@ -138,6 +138,19 @@ pub fn init_pics() {
     println!(" - Interrupts enabled");
 }
 
+// let an ISA interrupt line through, lines on the slave also need the cascade on IRQ 2
+pub fn unmask_irq(irq: u8) {
+    let (port, line) = match irq {
+        0..=7 => (PIC_MASTER_PORT + 1, irq),
+        _ => {
+            unmask_irq(2);
+            (PIC_SLAVE_PORT + 1, irq - 8)
+        }
+    };
+    let data: Port<u8> = Port::new(port);
+    data.write(data.read() & !(1 << line));
+}
+
 pub fn end_of_interrupt(interrupt_id: u8) {
     if interrupt_id >= PIC_SLAVE_NEW_OFFSET && interrupt_id < PIC_SLAVE_NEW_OFFSET + 8 {
         Port::new(PIC_SLAVE_PORT).write(END_OF_INTERRUPT);
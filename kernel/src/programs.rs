@@ -0,0 +1,50 @@
+// User programs linked into the kernel image by user/programs.asm, they all live in /bin.
+// Others are loaded from the filesystem, e.g. from an initrd.
+
+extern "C" {
+    static _user_sh_start: u8;
+    static _user_sh_end: u8;
+    static _user_hello_start: u8;
+    static _user_hello_end: u8;
+    static _user_kbd_start: u8;
+    static _user_kbd_end: u8;
+    static _user_dmesg_start: u8;
+    static _user_dmesg_end: u8;
+    static _user_ls_start: u8;
+    static _user_ls_end: u8;
+    static _user_cat_start: u8;
+    static _user_cat_end: u8;
+    static _user_tag_start: u8;
+    static _user_tag_end: u8;
+    static _user_lspci_start: u8;
+    static _user_lspci_end: u8;
+}
+
+// the first user process
+pub const INIT: &str = "/bin/sh";
+
+// drivers started before it
+pub const SERVERS: [&str; 1] = ["/bin/kbd"];
+
+unsafe fn embedded(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    let len = end as *const u8 as usize - start as usize;
+    core::slice::from_raw_parts(start, len)
+}
+
+// the ELF image of a program by its absolute path
+pub fn find(path: &str) -> Option<&'static [u8]> {
+    unsafe {
+        match path {
+            "/bin/sh" => Some(embedded(&_user_sh_start, &_user_sh_end)),
+            "/bin/hello" => Some(embedded(&_user_hello_start, &_user_hello_end)),
+            "/bin/kbd" => Some(embedded(&_user_kbd_start, &_user_kbd_end)),
+            "/bin/dmesg" => Some(embedded(&_user_dmesg_start, &_user_dmesg_end)),
+            "/bin/ls" => Some(embedded(&_user_ls_start, &_user_ls_end)),
+            "/bin/cat" => Some(embedded(&_user_cat_start, &_user_cat_end)),
+            "/bin/tag" => Some(embedded(&_user_tag_start, &_user_tag_end)),
+            "/bin/lspci" => Some(embedded(&_user_lspci_start, &_user_lspci_end)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// The CMOS real-time clock. It is read once at boot for the wall clock time, from then on the
+// time moves on with the timer ticks, which come from the PIT at its power-on rate.
+
+use crate::port::Port;
+use crate::smp;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATING: u8 = 0x80;
+const STATUS_B_24_HOURS: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOURS_PM: u8 = 0x80;
+
+// the PIT's input clock and the reload value it starts with
+const PIT_FREQUENCY: u64 = 1193182;
+const PIT_RELOAD: u64 = 0x10000;
+
+// seconds since 1970 when the ticks started
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+fn read_register(reg: u8) -> u8 {
+    Port::<u8>::new(CMOS_ADDRESS).write(reg);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+pub struct DateTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hours: u64,
+    pub minutes: u64,
+    pub seconds: u64,
+}
+
+// the registers as they are, they change while the clock updates itself once a second
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {}
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+    ]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+fn read_clock() -> DateTime {
+    // read until two reads in a row agree, so no update came in between
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    let status = read_register(REG_STATUS_B);
+    let pm = raw[2] & HOURS_PM != 0;
+    raw[2] &= !HOURS_PM;
+    if status & STATUS_B_BINARY == 0 {
+        for value in raw.iter_mut() {
+            *value = from_bcd(*value);
+        }
+    }
+    let mut hours = raw[2] as u64;
+    if status & STATUS_B_24_HOURS == 0 {
+        // 12 is midnight or noon
+        hours = hours % 12 + if pm { 12 } else { 0 };
+    }
+    DateTime {
+        // the century register isn't at the same place everywhere
+        year: 2000 + raw[5] as u64,
+        month: raw[4] as u64,
+        day: raw[3] as u64,
+        hours,
+        minutes: raw[1] as u64,
+        seconds: raw[0] as u64,
+    }
+}
+
+// days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // count years from March so the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl DateTime {
+    // the date and time of seconds since 1970
+    pub fn from_timestamp(timestamp: u64) -> DateTime {
+        // the inverse of days_since_epoch, again with years that start in March
+        let days = timestamp / 86400 + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let month = (month_from_march + 2) % 12 + 1;
+        let seconds = timestamp % 86400;
+        DateTime {
+            year: era * 400 + year_of_era + if month <= 2 { 1 } else { 0 },
+            month,
+            day: day_of_year - (153 * month_from_march + 2) / 5 + 1,
+            hours: seconds / 3600,
+            minutes: seconds / 60 % 60,
+            seconds: seconds % 60,
+        }
+    }
+
+    // seconds since 1970, the date is from then on
+    pub fn timestamp(&self) -> u64 {
+        let days = days_since_epoch(self.year, self.month, self.day);
+        days * 86400 + self.hours * 3600 + self.minutes * 60 + self.seconds
+    }
+}
+
+// read the clock, before the timer is started
+pub fn init() {
+    let time = read_clock();
+    BOOT_TIME.store(time.timestamp(), Ordering::SeqCst);
+    log::info!(
+        "RTC: {}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        time.year,
+        time.month,
+        time.day,
+        time.hours,
+        time.minutes,
+        time.seconds
+    );
+}
+
+// seconds since 1970
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + smp::ticks() * PIT_RELOAD / PIT_FREQUENCY
+}
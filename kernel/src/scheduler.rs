@@ -1,14 +1,30 @@
+use crate::abi::{NSIG, SIGCHLD};
+use crate::addr_space::AddressSpace;
+use crate::elf;
+use crate::errno::Errno;
+use crate::fd::FdTable;
 use crate::gdt;
 use crate::mem;
-use crate::serial_println;
-use alloc::boxed::Box;
+use crate::percpu;
+use crate::signal::{self, SignalState};
+use crate::smp;
+use crate::vfs::FsContext;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::cmp::min;
 use core::fmt::Display;
+use core::mem::take;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
+use x86_64::instructions::{interrupts, segmentation};
 
 // saved register values under context change
-#[derive(Debug, Clone)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct Context {
     pub rbp: u64,
     pub rax: u64,
@@ -45,11 +61,13 @@ pub unsafe fn get_context() -> *const Context {
 }
 
 // reverse get_context
+// when going back to ring 3 the user GS base has to be swapped back in
 #[inline(always)]
 pub unsafe fn restore_context(ctx_ref: &Context) {
     asm!("mov rsp, {};\
     pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8; pop r9;\
-    pop r10; pop r11; pop r12; pop r13; pop r14; pop r15; iretq;",
+    pop r10; pop r11; pop r12; pop r13; pop r14; pop r15;\
+    test qword ptr [rsp + 8], 3; jz 2f; swapgs; 2: iretq;",
     in(reg) ctx_ref);
 }
 
@@ -63,6 +81,7 @@ pub unsafe fn jmp_to_usermode(code: mem::VirtAddr, stack_end: mem::VirtAddr) {
     push 0x200 // rflags (only interrupt bit set)
     push rdx   // code segment
     push rdi   // ret to virtual addr
+    swapgs     // keep the kernel GS base in MSR_KERNEL_GS_BASE while in userspace
     iretq",
     in("rdi") code.addr(), in("rsi") stack_end.addr(), in("dx") cs, in("ax") ds);
 }
@@ -74,131 +93,699 @@ enum TaskState {
     StartingInfo(mem::VirtAddr, mem::VirtAddr),
 }
 
+// blocked tasks are in no run queue, a WaitQueue makes them runnable again
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TaskStatus {
+    Runnable,
+    Blocked,
+}
+
+const KERNEL_STACK_SIZE: usize = 0x4000;
+
+pub const YIELD_VECTOR: u8 = 0x81;
+
 struct Task {
     state: TaskState,
-    ptable: Box<mem::PageTable>,
-    stack_space: Vec<u8>, // container for stack space
+    status: TaskStatus,
+    on_cpu: bool, // running right now, it gets requeued when it is switched away from
+    parent: Option<usize>,
+    fs: FsContext, // root and working directory
+    files: FdTable,
+    signals: SignalState,
+    level: usize, // priority in the run queues, 0 is the highest
+    // locked separately from the task map, syscalls work on it with interrupts enabled
+    space: Arc<Mutex<AddressSpace>>,
+    ptable_addr: mem::PhysAddr, // P4 of the address space, what goes into CR3
+    kernel_stack: Vec<u8>,      // stack used for syscalls and interrupts coming from this task
 }
 
 impl Task {
-    pub fn new(
-        base: mem::VirtAddr,
+    pub unsafe fn new(
+        entry: mem::VirtAddr,
         stack_top: mem::VirtAddr,
-        stack_space: Vec<u8>,
-        ptable: Box<mem::PageTable>,
+        space: AddressSpace,
+        parent: Option<usize>,
+        fs: FsContext,
+        files: FdTable,
     ) -> Task {
         Task {
-            state: TaskState::StartingInfo(base, stack_top),
-            stack_space,
-            ptable,
+            state: TaskState::StartingInfo(entry, stack_top),
+            status: TaskStatus::Runnable,
+            on_cpu: false,
+            parent,
+            fs,
+            files,
+            signals: SignalState::new(),
+            level: 0,
+            ptable_addr: space.page_table_addr(),
+            space: Arc::new(Mutex::new(space)),
+            kernel_stack: Vec::with_capacity(KERNEL_STACK_SIZE),
         }
     }
+
+    fn kernel_stack_top(&self) -> u64 {
+        self.kernel_stack.as_ptr() as u64 + KERNEL_STACK_SIZE as u64
+    }
 }
 
 impl Display for Task {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        unsafe {
-            write!(
-                f,
-                "PT: {}, Context: {:x?}",
-                self.ptable.phys_addr(),
-                self.state
-            )
+        write!(f, "PT: {}, Context: {:x?}", self.ptable_addr, self.state)
+    }
+}
+
+// how tasks waiting to run are ordered, from the sched option of the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin, // each task in turn
+    Mlfq,       // multi-level feedback queue
+}
+
+// priorities of the multi-level feedback queue, round robin only uses the first one
+// a task that is preempted at the end of its time slice goes down a level, one that blocks
+// before keeps its priority, so interactive tasks run before those that keep the CPU busy
+const MLFQ_LEVELS: usize = 4;
+
+// every this many ticks all tasks go back to the highest priority, so that none starves
+const MLFQ_BOOST_TICKS: u64 = 100;
+
+// the tasks waiting to run on one CPU by priority
+struct RunQueue {
+    levels: [VecDeque<usize>; MLFQ_LEVELS],
+}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            levels: Default::default(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.levels.iter().map(|level| level.len()).sum()
+    }
+
+    fn push_back(&mut self, pid: usize, level: usize) {
+        self.levels[level].push_back(pid);
+    }
+
+    // the task of the highest priority that waited the longest
+    fn pop_front(&mut self) -> Option<usize> {
+        self.levels.iter_mut().find_map(|level| level.pop_front())
+    }
+
+    // for other CPUs to steal, the task of the lowest priority that waited the shortest
+    fn pop_back(&mut self) -> Option<usize> {
+        self.levels.iter_mut().rev().find_map(|level| level.pop_back())
+    }
+
+    fn boost(&mut self) {
+        for i in 1..MLFQ_LEVELS {
+            let mut level = take(&mut self.levels[i]);
+            self.levels[0].append(&mut level);
         }
     }
 }
 
 pub struct Scheduler {
-    tasks: Mutex<Vec<Task>>,
-    cur_task: Mutex<Option<usize>>,
+    tasks: Mutex<BTreeMap<usize, Task>>, // all tasks by pid
+    run_queues: RwLock<Vec<Mutex<RunQueue>>>, // pids waiting to run, one queue per CPU
+    levels: AtomicUsize,                 // priority levels in use, 1 for round robin
+    last_boost: AtomicU64,               // tick of the last MLFQ priority boost
+    dead: Mutex<Vec<(usize, Task)>>, // exited tasks and the CPU whose stack might still be theirs
+    zombies: Mutex<BTreeMap<usize, (usize, i32)>>, // exit codes by pid until the parent waits
+    child_exited: WaitQueue,
+    next_pid: AtomicUsize,
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
+        let mut run_queues = Vec::with_capacity(1);
+        run_queues.push(Mutex::new(RunQueue::new())); // the BSP's queue, APs add theirs on startup
         Scheduler {
-            tasks: Mutex::new(Vec::new()),
-            cur_task: Mutex::new(None), // so that next task is 0
-        }
-    }
-
-    // schedule a task
-    pub unsafe fn schedule(&self, fn_addr: mem::VirtAddr) {
-        let fn_phys = fn_addr.to_phys().unwrap().0; // convert to physical address
-        let page_start = (fn_phys.addr() >> 12) << 12; // zero out page offset to get which page we should map
-        let fn_page_offset = fn_phys.addr() - page_start; // offset of function from page start
-        let fn_virt_base = 0x400000; // target virtual address of page
-        let fn_virt = fn_virt_base + fn_page_offset; // target virtual address of function
-        serial_println!(
-            "Mapping {:x} to {:x}",
-            page_start,
-            fn_virt_base
-        );
-        let mut ptable = mem::PageTable::new(); // copy over the kernel's page tables
-        ptable.map_virt_to_phys(
-            mem::VirtAddr::new(fn_virt_base),
-            mem::PhysAddr::new(page_start),
-            mem::BIT_PRESENT | mem::BIT_USER,
-        ); // map the program's code
-        ptable.map_virt_to_phys(
-            mem::VirtAddr::new(fn_virt_base).offset(0x1000),
-            mem::PhysAddr::new(page_start).offset(0x1000),
-            mem::BIT_PRESENT | mem::BIT_USER,
-        ); // also map another page to be sure we got the entire function in
-        let mut stack_space: Vec<u8> = Vec::with_capacity(0x1000); // allocate some memory to use for the stack
-        let stack_space_phys = mem::VirtAddr::new(stack_space.as_mut_ptr() as *const u8 as u64)
-            .to_phys()
-            .unwrap()
-            .0;
-        // take physical address of stack
-        ptable.map_virt_to_phys(
-            mem::VirtAddr::new(0x800000),
-            stack_space_phys,
-            mem::BIT_PRESENT | mem::BIT_WRITABLE | mem::BIT_USER,
-        ); // map the stack memory to 0x800000
+            tasks: Mutex::new(BTreeMap::new()),
+            run_queues: RwLock::new(run_queues),
+            levels: AtomicUsize::new(1),
+            last_boost: AtomicU64::new(0),
+            dead: Mutex::new(Vec::new()),
+            zombies: Mutex::new(BTreeMap::new()),
+            child_exited: WaitQueue::new(),
+            next_pid: AtomicUsize::new(0),
+        }
+    }
+
+    // create the run queue for a newly started CPU
+    pub fn add_cpu(&self) {
+        self.run_queues.write().push(Mutex::new(RunQueue::new()));
+    }
+
+    // called at boot before tasks are started
+    pub fn set_policy(&self, policy: Policy) {
+        let levels = match policy {
+            Policy::RoundRobin => 1,
+            Policy::Mlfq => MLFQ_LEVELS,
+        };
+        self.levels.store(levels, Ordering::SeqCst);
+    }
+
+    // start a new task running the given ELF executable, as a child of the current task
+    // it inherits the root, working directory and open files, the first task gets the console
+    // signal actions start out as the defaults as none of the parent's handlers are there
+    pub unsafe fn spawn(&self, image: &[u8], args: &[&[u8]]) -> Result<usize, Errno> {
+        let mut space = AddressSpace::new();
+        let loaded = elf::load(image, &mut space)?;
+        space.init_brk(loaded.brk_start);
+        let stack_top = elf::setup_stack(&mut space, &loaded, args, &[])?;
+        let parent = percpu::current().cur_task.get();
+        let fs = self.fs_context().unwrap_or_else(FsContext::new);
+        let files = self
+            .with_current_files(|files| files.clone())
+            .unwrap_or_else(FdTable::with_console);
         let task = Task::new(
-            mem::VirtAddr::new(fn_virt),
-            mem::VirtAddr::new(0x801000),
-            stack_space,
-            ptable,
-        ); // create task struct
-        self.tasks.lock().push(task); // push task struct to list of tasks
+            mem::VirtAddr::new(loaded.entry),
+            mem::VirtAddr::new(stack_top),
+            space,
+            parent,
+            fs,
+            files,
+        );
+        let pid = self.next_pid.fetch_add(1, Ordering::SeqCst);
+        log::debug!("spawned task #.{} at {:x}", pid, loaded.entry);
+        interrupts::without_interrupts(|| {
+            self.tasks.lock().insert(pid, task); // add task struct to the map of tasks
+            self.enqueue_least_loaded(pid, 0);
+        });
+        Ok(pid)
+    }
+
+    // start a task that runs entry in the kernel, on its kernel stack, and exits when it returns
+    // it is never preempted, so it has to block or yield to let others run
+    pub fn spawn_kernel_thread(&self, entry: fn()) -> Result<usize, Errno> {
+        let space = unsafe { AddressSpace::new() };
+        let mut task = unsafe {
+            Task::new(
+                mem::VirtAddr::new(0),
+                mem::VirtAddr::new(0),
+                space,
+                None,
+                FsContext::new(),
+                FdTable::new(),
+            )
+        };
+        let ss: u64;
+        unsafe {
+            asm!("mov {}, ss", out(reg) ss);
+        }
+        // as if kernel_thread_start had been called, with entry as its argument
+        let stack_top = (task.kernel_stack_top() & !0xf) - 8;
+        let mut ctx: Context = unsafe { core::mem::zeroed() };
+        ctx.rdi = entry as usize as u64;
+        ctx.rip = kernel_thread_start as usize as u64;
+        ctx.cs = segmentation::cs().0 as u64;
+        ctx.rflags = 0x200;
+        ctx.rsp = stack_top;
+        ctx.ss = ss;
+        task.state = TaskState::SavedContext(ctx);
+        let pid = self.next_pid.fetch_add(1, Ordering::SeqCst);
+        log::debug!("spawned kernel thread #.{}", pid);
+        interrupts::without_interrupts(|| {
+            self.tasks.lock().insert(pid, task);
+            self.enqueue_least_loaded(pid, 0);
+        });
+        Ok(pid)
+    }
+
+    // run f on the address space of the task on this CPU
+    pub fn with_current_space<R, F: FnOnce(&mut AddressSpace) -> R>(&self, f: F) -> Option<R> {
+        let space = interrupts::without_interrupts(|| {
+            let pid = percpu::current().cur_task.get()?;
+            self.tasks.lock().get(&pid).map(|task| task.space.clone())
+        })?;
+        let mut space = space.lock();
+        Some(f(&mut space))
+    }
+
+    // run f on the task of this CPU, keep it short as the task map is locked meanwhile
+    fn with_current<R, F: FnOnce(&mut Task) -> R>(&self, f: F) -> Option<R> {
+        interrupts::without_interrupts(|| {
+            let pid = percpu::current().cur_task.get()?;
+            self.tasks.lock().get_mut(&pid).map(f)
+        })
+    }
+
+    pub fn fs_context(&self) -> Option<FsContext> {
+        self.with_current(|task| task.fs.clone())
+    }
+
+    pub fn set_fs_context(&self, fs: FsContext) {
+        self.with_current(|task| task.fs = fs);
+    }
+
+    // run f on the fd table of the current task, files it removes must be dropped after this
+    // returns, closing a pipe end wakes up the tasks waiting on the other one
+    pub fn with_current_files<R, F: FnOnce(&mut FdTable) -> R>(&self, f: F) -> Option<R> {
+        self.with_current(|task| f(&mut task.files))
+    }
+
+    // run f on the signal state of the current task
+    pub fn with_current_signals<R, F: FnOnce(&mut SignalState) -> R>(&self, f: F) -> Option<R> {
+        self.with_current(|task| f(&mut task.signals))
+    }
+
+    // send sig to the task pid, or to all tasks but the current one if pid is -1
+    // sig 0 only checks that the task exists, blocked tasks are woken up to handle the signal
+    pub fn kill(&self, pid: isize, sig: u32) -> Result<(), Errno> {
+        if sig >= NSIG {
+            return Err(Errno::EINVAL);
+        }
+        let me = percpu::current().cur_task.get();
+        let targets: Vec<usize> = interrupts::without_interrupts(|| {
+            let mut tasks = self.tasks.lock();
+            let targets: Vec<usize> = match pid {
+                -1 => tasks.keys().cloned().filter(|pid| Some(*pid) != me).collect(),
+                pid if pid >= 0 && tasks.contains_key(&(pid as usize)) => {
+                    let mut target = Vec::with_capacity(1);
+                    target.push(pid as usize);
+                    target
+                }
+                _ => return Err(Errno::ESRCH),
+            };
+            if sig != 0 {
+                // raise only refuses signals that aren't valid, sig was checked above so every
+                // target gets it
+                for pid in &targets {
+                    let _ = tasks.get_mut(pid).unwrap().signals.raise(sig);
+                }
+            }
+            Ok(targets)
+        })?;
+        if sig != 0 {
+            for pid in targets {
+                self.wake(pid);
+            }
+        }
+        Ok(())
+    }
+
+    // mark the current task as blocked, it keeps running until it yields
+    // an interruptible task isn't if a signal is pending, checking that under the same lock as
+    // kill raises it means the wakeup can't get lost
+    fn block_current(&self, interruptible: bool) -> Result<Option<usize>, Errno> {
+        let blocked = self.with_current(|task| {
+            if interruptible && task.signals.deliverable() {
+                return Err(Errno::EINTR);
+            }
+            task.status = TaskStatus::Blocked;
+            Ok(())
+        });
+        match blocked {
+            Some(Ok(())) => Ok(percpu::current().cur_task.get()),
+            Some(Err(errno)) => Err(errno),
+            None => Ok(None),
+        }
+    }
+
+    fn wake(&self, pid: usize) {
+        let enqueue = interrupts::without_interrupts(|| {
+            match self.tasks.lock().get_mut(&pid) {
+                Some(task) if task.status == TaskStatus::Blocked => {
+                    task.status = TaskStatus::Runnable;
+                    // a task that hasn't switched away yet is requeued by run_next
+                    Some(task.level).filter(|_| !task.on_cpu)
+                }
+                _ => None,
+            }
+        });
+        if let Some(level) = enqueue {
+            interrupts::without_interrupts(|| self.enqueue_least_loaded(pid, level));
+        }
+    }
+
+    // wait for a child to exit, returning its exit code
+    pub fn wait_child(&self, pid: usize) -> Result<i32, Errno> {
+        let me = percpu::current().cur_task.get().ok_or(Errno::ECHILD)?;
+        self.child_exited.wait_interruptible(|| {
+            let zombie = self.zombies.lock().get(&pid).cloned();
+            if let Some((parent, code)) = zombie {
+                if parent != me {
+                    return Some(Err(Errno::ECHILD));
+                }
+                self.zombies.lock().remove(&pid);
+                return Some(Ok(code));
+            }
+            match self.tasks.lock().get(&pid) {
+                Some(task) if task.parent == Some(me) => None,
+                _ => Some(Err(Errno::ECHILD)),
+            }
+        })?
+    }
+
+    // new tasks go to the CPU with the least work
+    fn enqueue_least_loaded(&self, pid: usize, level: usize) {
+        let queues = self.run_queues.read();
+        let queue = queues
+            .iter()
+            .min_by_key(|queue| queue.lock().len())
+            .unwrap();
+        queue.lock().push_back(pid, level);
+    }
+
+    // take a task from the back of the longest queue of another CPU
+    fn steal(&self, cpu_id: usize) -> Option<usize> {
+        let queues = self.run_queues.read();
+        let (victim, _) = queues
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != cpu_id)
+            .map(|(i, queue)| (i, queue.lock().len()))
+            .max_by_key(|(_, len)| *len)?;
+        let pid = queues[victim].try_lock()?.pop_back();
+        if let Some(pid) = pid {
+            log::debug!("CPU {} stole task #.{} from CPU {}", cpu_id, pid, victim);
+        }
+        pid
+    }
+
+    // the current task used up its time slice, with MLFQ it goes down a level
+    pub fn demote_current(&self) {
+        let lowest = self.levels.load(Ordering::Relaxed) - 1;
+        self.with_current(|task| task.level = min(task.level + 1, lowest));
+    }
+
+    // with MLFQ, move every task back to the highest priority once in a while
+    fn boost_if_due(&self) {
+        let last = self.last_boost.load(Ordering::SeqCst);
+        let now = smp::ticks();
+        if self.levels.load(Ordering::Relaxed) == 1 || now - last < MLFQ_BOOST_TICKS {
+            return;
+        }
+        // only one CPU does it
+        if self
+            .last_boost
+            .compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+        for task in self.tasks.lock().values_mut() {
+            task.level = 0;
+        }
+        for queue in self.run_queues.read().iter() {
+            queue.lock().boost();
+        }
     }
 
     // replace the context of the current task if one exists
     pub unsafe fn save_current_context(&self, ctx_ptr: *const Context) {
-        self.cur_task.lock().map(|cur_task_idx| {
+        percpu::current().cur_task.get().map(|cur_pid| {
             let ctx = (*ctx_ptr).clone();
-            self.tasks.lock()[cur_task_idx].state = TaskState::SavedContext(ctx);
+            if let Some(task) = self.tasks.lock().get_mut(&cur_pid) {
+                task.state = TaskState::SavedContext(ctx);
+            }
         });
     }
 
     // run the next scheduled task, either start it up or restore it if already active
+    // has to be called with interrupts disabled, returns only if there is nothing to run
     pub unsafe fn run_next(&self) {
-        let tasks_len = self.tasks.lock().len();
-        if tasks_len > 0 {
-            let task_state = {
-                let mut cur_task_o = self.cur_task.lock(); // lock the current task
-                let cur_task = cur_task_o.get_or_insert(0);
-                let next_task = (*cur_task + 1) % tasks_len;
-                *cur_task = next_task;
-                let task = &self.tasks.lock()[next_task]; // get the next task
-                serial_println!("Switching to task #.{} ({})", next_task, task);
-                task.ptable.enable();
-                task.state.clone()
+        let cpu = percpu::current();
+        // we are on an interrupt or idle stack here, so tasks that exited on this CPU can go
+        self.dead.lock().retain(|(cpu_id, _)| *cpu_id != cpu.cpu_id);
+        self.boost_if_due();
+        // the task we are leaving goes to the back of our own queue, unless it blocked
+        if let Some(prev) = cpu.cur_task.take() {
+            let requeue = match self.tasks.lock().get_mut(&prev) {
+                Some(task) => {
+                    task.on_cpu = false;
+                    Some(task.level).filter(|_| task.status == TaskStatus::Runnable)
+                }
+                None => None,
             };
-            // continue based on task state
-            match task_state {
-                TaskState::SavedContext(ctx) => {
-                    restore_context(&ctx)
+            if let Some(level) = requeue {
+                self.run_queues.read()[cpu.cpu_id]
+                    .lock()
+                    .push_back(prev, level);
+            }
+        }
+        let local = self.run_queues.read()[cpu.cpu_id].lock().pop_front();
+        let next_task = match local.or_else(|| self.steal(cpu.cpu_id)) {
+            Some(pid) => pid,
+            None => return,
+        };
+        let task_state = {
+            let mut tasks = self.tasks.lock();
+            let task = tasks.get_mut(&next_task).unwrap(); // get the next task
+            task.on_cpu = true;
+            log::trace!(
+                "CPU {}: switching to task #.{} ({})",
+                cpu.cpu_id,
+                next_task,
+                task
+            );
+            mem::enable_page_table(task.ptable_addr);
+            // syscalls and interrupts from ring 3 should land on this task's kernel stack
+            let kernel_stack = task.kernel_stack_top();
+            cpu.kernel_stack.set(kernel_stack);
+            if let Some(tables) = cpu.tables.get() {
+                (*tables).set_kernel_stack(kernel_stack);
+            }
+            task.state.clone()
+        };
+        cpu.cur_task.set(Some(next_task));
+        // continue based on task state
+        match task_state {
+            TaskState::SavedContext(mut ctx) => {
+                // signals are handled on the way back to userspace
+                if ctx.cs & 3 != 0 {
+                    signal::deliver(&mut ctx);
+                }
+                restore_context(&ctx)
+            }
+            TaskState::StartingInfo(base, stack_top) => {
+                jmp_to_usermode(base, stack_top)
+            }
+        }
+    }
+
+    // remove the task running on this CPU, its memory is only freed once we are off its stacks
+    // the exit code is kept until the parent waits for it
+    pub unsafe fn exit_current(&self, code: i32) -> ! {
+        interrupts::disable();
+        let cpu = percpu::current();
+        mem::enable_kernel_page_table();
+        if let Some(pid) = cpu.cur_task.take() {
+            let task = self.tasks.lock().remove(&pid);
+            if let Some(mut task) = task {
+                // close our files right away so that readers of our pipes see the end of them
+                drop(task.files.take_all());
+                log::debug!("CPU {}: task #.{} exited with {}", cpu.cpu_id, pid, code);
+                let mut zombies = self.zombies.lock();
+                // nobody is going to wait for our children anymore
+                let orphans: Vec<usize> = zombies
+                    .iter()
+                    .filter(|(_, (parent, _))| *parent == pid)
+                    .map(|(child, _)| *child)
+                    .collect();
+                for child in orphans {
+                    zombies.remove(&child);
+                }
+                if let Some(parent) = task.parent {
+                    zombies.insert(pid, (parent, code));
+                }
+                drop(zombies);
+                let parent = task.parent;
+                self.dead.lock().push((cpu.cpu_id, task));
+                if let Some(parent) = parent {
+                    let _ = self.kill(parent as isize, SIGCHLD);
+                }
+            }
+        }
+        self.child_exited.wake_all();
+        enter_idle()
+    }
+}
+
+// tasks blocked until something happens, e.g. input arriving or a child exiting
+pub struct WaitQueue {
+    waiting: Mutex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> WaitQueue {
+        WaitQueue {
+            waiting: Mutex::new(Vec::new()),
+        }
+    }
+
+    // block the current task until cond returns something, cond is checked with interrupts
+    // disabled and whoever changes its outcome has to call wake_all afterwards
+    pub fn wait_until<R, F: FnMut() -> Option<R>>(&self, cond: F) -> R {
+        match self.wait(false, false, cond) {
+            Ok(result) => result,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    // like wait_until but a signal ends the wait with EINTR, for waits in syscalls
+    pub fn wait_interruptible<R, F: FnMut() -> Option<R>>(&self, cond: F) -> Result<R, Errno> {
+        self.wait(true, false, cond)
+    }
+
+    // like wait_until but gives up with None after ticks timer ticks, e.g. for a device that
+    // might never interrupt
+    pub fn wait_timeout<R, F: FnMut() -> Option<R>>(&self, ticks: u64, mut cond: F) -> Option<R> {
+        let until = smp::ticks() + ticks;
+        let timed = || match cond() {
+            Some(result) => Some(Some(result)),
+            None => Some(None).filter(|_| smp::ticks() >= until),
+        };
+        match self.wait(false, true, timed) {
+            Ok(result) => result,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    // with timed the task also waits with the sleepers, the timer wakes it up on every tick
+    fn wait<R, F: FnMut() -> Option<R>>(
+        &self,
+        interruptible: bool,
+        timed: bool,
+        mut cond: F,
+    ) -> Result<R, Errno> {
+        loop {
+            let mut blocked = false;
+            let done = interrupts::without_interrupts(|| {
+                // checking and queueing under the lock means no wake_all can slip in between
+                let mut waiting = self.waiting.lock();
+                if let Some(result) = cond() {
+                    return Some(Ok(result));
                 }
-                TaskState::StartingInfo(base, stack_top) => {
-                    jmp_to_usermode(base, stack_top)
+                match SCHEDULER.block_current(interruptible) {
+                    Ok(Some(pid)) => {
+                        waiting.push(pid);
+                        if timed {
+                            SLEEPING.waiting.lock().push(pid);
+                        }
+                        blocked = true;
+                    }
+                    Ok(None) => {}
+                    Err(errno) => return Some(Err(errno)),
                 }
+                None
+            });
+            match done {
+                Some(result) => return result,
+                None if blocked => yield_now(),
+                // there is no task to block while booting, yielding would never come back
+                None => spin_loop_hint(),
             }
         }
     }
+
+    pub fn wake_all(&self) {
+        let pids = interrupts::without_interrupts(|| take(&mut *self.waiting.lock()));
+        for pid in pids {
+            SCHEDULER.wake(pid);
+        }
+    }
+}
+
+// a lock that blocks the tasks waiting for it instead of spinning, so it can be held while
+// blocked, e.g. across a transfer of a device
+pub struct SleepLock<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(data: T) -> SleepLock<T> {
+        SleepLock {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SleepLockGuard<T> {
+        self.waiters
+            .wait_until(|| Some(()).filter(|_| !self.locked.swap(true, Ordering::Acquire)));
+        SleepLockGuard { lock: self }
+    }
+}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<'a, T> Deref for SleepLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SleepLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SleepLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+extern "C" fn kernel_thread_start(entry: usize) -> ! {
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    entry();
+    unsafe { SCHEDULER.exit_current(0) }
+}
+
+// tasks sleeping for some ticks, woken up on every tick to check if their time has come
+static SLEEPING: WaitQueue = WaitQueue::new();
+
+// block the current task for at least ticks timer ticks
+pub fn sleep(ticks: u64) {
+    let until = smp::ticks() + ticks;
+    SLEEPING.wait_until(|| Some(()).filter(|_| smp::ticks() >= until));
+}
+
+// called on every timer tick
+pub fn wake_sleepers() {
+    SLEEPING.wake_all();
+}
+
+// give up the CPU, the task continues here when it is scheduled again
+pub fn yield_now() {
+    unsafe {
+        asm!("int {}", const YIELD_VECTOR);
+    }
+}
+
+// what a CPU runs when it has nothing else to do
+extern "C" fn idle_loop() -> ! {
+    loop {
+        interrupts::disable();
+        unsafe {
+            SCHEDULER.run_next();
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+// move to this CPU's idle stack and start scheduling, whatever stack we were on is abandoned
+pub unsafe fn enter_idle() -> ! {
+    asm!("mov rsp, {}; call {}",
+    in(reg) percpu::current().idle_stack, in(reg) idle_loop as usize,
+    options(noreturn));
 }
 
 lazy_static! {
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
-}
\ No newline at end of file
+}
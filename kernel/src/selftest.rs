@@ -0,0 +1,311 @@
+// Checks of kernel pieces that can run during boot, enabled with `tests` on the kernel command
+// line. They run after the heap is set up and before the first task, results go to the log.
+
+use crate::abi;
+use crate::block::{self, BlockDevice, RamDisk, SECTOR_SIZE};
+use crate::dma::{self, DmaBuffer};
+use crate::errno::Errno;
+use crate::fat::FatFs;
+use crate::mem::BIT_NO_CACHE;
+use crate::path;
+use crate::pipe;
+use crate::tag_fs::{self, TagFs};
+use crate::vfs::{self, FileSystem, FileType, FsContext};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+fn heap() -> Result<(), &'static str> {
+    let boxed = Box::new(0x1234_5678u64);
+    let mut values: Vec<u64> = (0..1000).collect();
+    values.retain(|value| value % 3 == 0);
+    let mut map = BTreeMap::new();
+    for value in values.iter() {
+        map.insert(*value, *boxed + value);
+    }
+    match map.len() == 334 && map[&999] == 0x1234_5678 + 999 {
+        true => Ok(()),
+        false => Err("collections lost values"),
+    }
+}
+
+fn paths() -> Result<(), &'static str> {
+    let cases = [
+        ("/", "bin/sh", "/bin/sh"),
+        ("/home/user", "../../etc/./passwd", "/etc/passwd"),
+        ("/home", "/..//tmp/", "/tmp"),
+        ("/", "..", "/"),
+    ];
+    for &(cwd, relative, expected) in cases.iter() {
+        if path::join(cwd, relative) != expected {
+            return Err("wrong join result");
+        }
+    }
+    Ok(())
+}
+
+fn pipes() -> Result<(), &'static str> {
+    let (reader, writer) = pipe::new_pipe();
+    let message = b"through the pipe";
+    if writer.write(message) != Ok(message.len()) {
+        return Err("write failed");
+    }
+    drop(writer);
+    let mut buf = [0u8; 32];
+    let count = reader.read(&mut buf).map_err(|_| "read failed")?;
+    if &buf[..count] != message {
+        return Err("read other bytes than were written");
+    }
+    match reader.read(&mut buf) {
+        Ok(0) => Ok(()),
+        _ => Err("no end of file once the writer is closed"),
+    }
+}
+
+// a sparse file with a second name in /tmp, everything is removed again
+fn tmpfs() -> Result<(), &'static str> {
+    let ctx = FsContext::new();
+    vfs::mkdir(&ctx, "/tmp/selftest").map_err(|_| "mkdir failed")?;
+    let flags = abi::O_RDWR | abi::O_CREAT | abi::O_EXCL;
+    let file = vfs::open(&ctx, "/tmp/selftest/file", flags).map_err(|_| "create failed")?;
+    file.seek(0x2ffe, abi::SEEK_SET)
+        .map_err(|_| "seek failed")?;
+    if file.write(b"hole") != Ok(4) {
+        return Err("write failed");
+    }
+    let mut buf = [0xffu8; 8];
+    file.seek(0x2ffa, abi::SEEK_SET)
+        .map_err(|_| "seek failed")?;
+    if file.read(&mut buf) != Ok(8) || &buf != b"\0\0\0\0hole" {
+        return Err("read other bytes than were written");
+    }
+    vfs::link(&ctx, "/tmp/selftest/file", "/tmp/selftest/link").map_err(|_| "link failed")?;
+    let stat = vfs::stat(&ctx, "/tmp/selftest/link").map_err(|_| "stat failed")?;
+    if stat.nlink != 2 || stat.size != 0x3002 {
+        return Err("wrong link count or size");
+    }
+    if vfs::rmdir(&ctx, "/tmp/selftest") != Err(Errno::ENOTEMPTY) {
+        return Err("removed a directory that isn't empty");
+    }
+    vfs::unlink(&ctx, "/tmp/selftest/file").map_err(|_| "unlink failed")?;
+    vfs::unlink(&ctx, "/tmp/selftest/link").map_err(|_| "unlink failed")?;
+    vfs::rmdir(&ctx, "/tmp/selftest").map_err(|_| "rmdir failed")
+}
+
+// files and tags on a RAM disk are still there when it is mounted again
+fn tag_fs() -> Result<(), &'static str> {
+    let disk = Arc::new(RamDisk::new("selftest", 0x100000));
+    tag_fs::format(&*disk).map_err(|_| "format failed")?;
+    let fs = TagFs::mount(disk.clone()).map_err(|_| "mount failed")?;
+    let root = fs.root();
+    let music = root
+        .create("music", FileType::Directory)
+        .map_err(|_| "mkdir failed")?;
+    let song = music
+        .create("song", FileType::Regular)
+        .map_err(|_| "create failed")?;
+    let file = song.open().map_err(|_| "open failed")?;
+    // past the direct blocks, so the indirect block is used too
+    if file.write_at(0x10000, b"la la") != Ok(5) {
+        return Err("write failed");
+    }
+    tag_fs::add_tag(&*song, "loud").map_err(|_| "adding a tag failed")?;
+    drop(fs);
+    let fs = TagFs::mount(disk).map_err(|_| "mounting again failed")?;
+    let root = fs.root();
+    let found = tag_fs::query(&*root, &["music", "loud"]).map_err(|_| "query failed")?;
+    if found.len() != 1 || found[0] != "song" {
+        return Err("query found other files");
+    }
+    let song = root.lookup("loud").and_then(|dir| dir.lookup("song"));
+    let song = song.map_err(|_| "no file in the path view")?;
+    let mut buf = [0xffu8; 8];
+    let file = song.open().map_err(|_| "open failed")?;
+    if file.read_at(0xfffd, &mut buf) != Ok(8) || &buf != b"\0\0\0la la" {
+        return Err("read other bytes than were written");
+    }
+    root.unlink("song").map_err(|_| "delete failed")?;
+    match root.unlink("music") {
+        Ok(()) => Ok(()),
+        Err(_) => Err("a tag no file has anymore wasn't deleted"),
+    }
+}
+
+// a FAT32 volume on a RAM disk with a long file name, a file over several clusters that is
+// truncated, and one that is unlinked while open and whose entry another file gets
+fn fat() -> Result<(), &'static str> {
+    let disk = Arc::new(RamDisk::new("selftest", 0x100000));
+    // one sector per cluster, 32 reserved sectors, 2 FATs of 16 sectors and the root at 2
+    let mut boot = [0u8; SECTOR_SIZE];
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1;
+    boot[14..16].copy_from_slice(&32u16.to_le_bytes());
+    boot[16] = 2;
+    boot[32..36].copy_from_slice(&2048u32.to_le_bytes());
+    boot[36..40].copy_from_slice(&16u32.to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+    boot[510..].copy_from_slice(&[0x55, 0xaa]);
+    disk.write_sectors(0, &boot)
+        .map_err(|_| "writing the boot sector failed")?;
+    // the media byte, a reserved entry and the end of the root directory's chain
+    let mut fat = [0u8; SECTOR_SIZE];
+    for (i, entry) in [0x0fff_fff8u32, 0x0fff_ffff, 0x0fff_ffff]
+        .iter()
+        .enumerate()
+    {
+        fat[i * 4..i * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+    }
+    for &sector in &[32, 48] {
+        disk.write_sectors(sector, &fat)
+            .map_err(|_| "writing the FAT failed")?;
+    }
+    let fs = FatFs::mount(disk).map_err(|_| "mount failed")?;
+    let root = fs.root();
+    let name = "A file with a long name.txt";
+    let inode = root
+        .create(name, FileType::Regular)
+        .map_err(|_| "create failed")?;
+    let file = inode.open().map_err(|_| "open failed")?;
+    let mut data = [0u8; 1200];
+    data.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    if file.write_at(100, &data) != Ok(data.len()) {
+        return Err("write failed");
+    }
+    let mut buf = [0xffu8; 1300];
+    if file.read_at(0, &mut buf) != Ok(buf.len())
+        || buf[..100].iter().any(|&b| b != 0)
+        || buf[100..] != data[..]
+    {
+        return Err("read other bytes than were written");
+    }
+    let entries = root.entries().map_err(|_| "listing failed")?;
+    if !entries.iter().any(|entry| entry.name == name) {
+        return Err("the long name isn't listed");
+    }
+    file.truncate(300).map_err(|_| "truncate failed")?;
+    if inode.metadata().size != 300 || file.read_at(0, &mut buf) != Ok(300) {
+        return Err("wrong size after truncating");
+    }
+    // a new file with the same name gets the entry of the unlinked one
+    root.unlink(name).map_err(|_| "unlink failed")?;
+    let other = root
+        .create(name, FileType::Regular)
+        .and_then(|inode| inode.open())
+        .map_err(|_| "creating again failed")?;
+    if other.write_at(0, b"other") != Ok(5) {
+        return Err("write failed");
+    }
+    if file.read_at(0, &mut buf) != Err(Errno::EBADF)
+        || file.write_at(0, b"stale") != Err(Errno::EBADF)
+    {
+        return Err("an unlinked file reached the one in its entry");
+    }
+    if other.read_at(0, &mut buf) != Ok(5) || &buf[..5] != b"other" {
+        return Err("the new file was changed");
+    }
+    root.unlink(name).map_err(|_| "unlink failed")?;
+    match root.entries() {
+        Ok(ref entries) if entries.is_empty() => Ok(()),
+        _ => Err("entries are left"),
+    }
+}
+
+// a partition from an MBR, written through a buffer cache that only changes the disk when flushed
+fn block_cache() -> Result<(), &'static str> {
+    let disk = Arc::new(RamDisk::new("selftest0", 0x100000));
+    let mut mbr = [0u8; SECTOR_SIZE];
+    mbr[446 + 4] = 0x83;
+    mbr[446 + 8..446 + 12].copy_from_slice(&64u32.to_le_bytes());
+    mbr[446 + 12..446 + 16].copy_from_slice(&1000u32.to_le_bytes());
+    mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+    disk.write_sectors(0, &mbr)
+        .map_err(|_| "writing the MBR failed")?;
+    block::register(disk.clone()).map_err(|_| "register failed")?;
+    let result = cached_partition(&*disk);
+    // the disk doesn't stay around for the flush thread
+    block::unregister("selftest0").map_err(|_| "unregister failed")?;
+    result
+}
+
+fn cached_partition(disk: &RamDisk) -> Result<(), &'static str> {
+    let partition = block::find("selftest0p1").ok_or("no partition")?;
+    if partition.sector_count() != 1000 {
+        return Err("partition of the wrong size");
+    }
+    let cache = block::cached(partition);
+    // across two blocks of the cache
+    let mut data = [0u8; 3 * SECTOR_SIZE];
+    data.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    cache.write_sectors(7, &data).map_err(|_| "write failed")?;
+    let mut buf = [0xffu8; 3 * SECTOR_SIZE];
+    cache.read_sectors(7, &mut buf).map_err(|_| "read failed")?;
+    if buf[..] != data[..] {
+        return Err("read other bytes than were written");
+    }
+    disk.read_sectors(64 + 7, &mut buf)
+        .map_err(|_| "read failed")?;
+    if buf.iter().any(|&b| b != 0) {
+        return Err("written before the flush");
+    }
+    cache.flush().map_err(|_| "flush failed")?;
+    disk.read_sectors(64 + 7, &mut buf)
+        .map_err(|_| "read failed")?;
+    match buf[..] == data[..] {
+        true => Ok(()),
+        false => Err("not written by the flush"),
+    }
+}
+
+fn dma_buffers() -> Result<(), &'static str> {
+    let flags = dma::BELOW_4G | dma::UNCACHED;
+    let mut buffer = DmaBuffer::new(0x3001, 0x4000, flags).map_err(|_| "allocation failed")?;
+    let phys = buffer.phys().addr();
+    if phys % 0x4000 != 0 || phys + 0x4000 > 1 << 32 {
+        return Err("misplaced in physical memory");
+    }
+    if buffer.as_slice().iter().any(|&b| b != 0) {
+        return Err("not zeroed");
+    }
+    buffer.as_mut_slice()[0x3000] = 0xa5;
+    let (frame, entry) =
+        unsafe { buffer.virt().offset(0x3000).to_phys() }.ok_or("the last page isn't mapped")?;
+    if frame.addr() != phys + 0x3000 || !entry.get_bit(BIT_NO_CACHE) {
+        return Err("mapped to the wrong frame or cached");
+    }
+    // the mapping is given back, so the next one takes its place again
+    let virt = buffer.virt().addr();
+    drop(buffer);
+    let buffer = DmaBuffer::new(0x1000, 0x1000, flags).map_err(|_| "allocation failed")?;
+    match buffer.virt().addr() == virt {
+        true => Ok(()),
+        false => Err("the mapping wasn't freed"),
+    }
+}
+
+const TESTS: [(&str, fn() -> Result<(), &'static str>); 8] = [
+    ("heap", heap),
+    ("paths", paths),
+    ("pipes", pipes),
+    ("tmpfs", tmpfs),
+    ("tag_fs", tag_fs),
+    ("fat", fat),
+    ("block", block_cache),
+    ("dma", dma_buffers),
+];
+
+// run every test, returns whether they all passed
+pub fn run() -> bool {
+    let mut failed = 0;
+    for (name, test) in TESTS.iter() {
+        match test() {
+            Ok(()) => log::info!("test {} passed", name),
+            Err(reason) => {
+                log::error!("test {} failed: {}", name, reason);
+                failed += 1;
+            }
+        }
+    }
+    log::info!("{} of {} tests passed", TESTS.len() - failed, TESTS.len());
+    failed == 0
+}
@@ -0,0 +1,49 @@
+// Shared memory: a region of frames that can be mapped into several address spaces at once.
+// Regions are reference counted, a task holds one through a handle in its fd table and through
+// every mapping of it, the frames are freed once the last of them is gone.
+
+use crate::errno::Errno;
+use crate::fd::FileDescription;
+use crate::mem::{EmptyFrame, FRAME_SIZE};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+// the largest region shm_create makes
+pub const SHM_MAX: u64 = 0x1000000;
+
+pub struct SharedMemory {
+    frames: Vec<Box<EmptyFrame>>,
+}
+
+impl SharedMemory {
+    // a zeroed region of at least size bytes
+    pub fn new(size: u64) -> Result<Arc<SharedMemory>, Errno> {
+        if size == 0 || size > SHM_MAX {
+            return Err(Errno::EINVAL);
+        }
+        let count = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+        let mut frames = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            frames.push(Box::new([0; FRAME_SIZE as usize]));
+        }
+        Ok(Arc::new(SharedMemory { frames }))
+    }
+
+    pub fn len(&self) -> u64 {
+        self.frames.len() as u64 * FRAME_SIZE
+    }
+
+    pub fn frames(&self) -> &[Box<EmptyFrame>] {
+        &self.frames
+    }
+}
+
+// what shm_create puts into the fd table
+pub struct ShmHandle(pub Arc<SharedMemory>);
+
+impl FileDescription for ShmHandle {
+    fn shared_memory(&self) -> Option<&Arc<SharedMemory>> {
+        Some(&self.0)
+    }
+}
@@ -0,0 +1,223 @@
+// POSIX-style signals. Each task has a mask of pending and of blocked signals and an action per
+// signal. Signals are delivered when a task goes back to userspace, after a syscall, a fault or
+// being switched to, by pushing a SignalFrame onto its user stack and running the handler. The
+// handler returns to a restorer which calls sigreturn to go back to the saved context.
+
+use crate::abi::{
+    signal_exit_code, SigAction, NSIG, SA_NODEFER, SA_RESTORER, SIGCHLD, SIGCONT, SIGKILL, SIGSEGV,
+    SIGURG, SIGWINCH, SIG_BLOCK, SIG_DFL, SIG_IGN, SIG_SETMASK, SIG_UNBLOCK,
+};
+use crate::errno::Errno;
+use crate::gdt;
+use crate::scheduler::{Context, SCHEDULER};
+use crate::uaccess::UserSlice;
+use core::mem::size_of;
+
+// signals that are dropped unless they have a handler, all others terminate the task by default
+const DEFAULT_IGNORED: u64 = 1 << SIGCHLD | 1 << SIGCONT | 1 << SIGURG | 1 << SIGWINCH;
+
+// SIGKILL can't be caught, ignored or blocked
+const UNBLOCKABLE: u64 = 1 << SIGKILL;
+
+// the user stack below rsp that leaf functions may use without moving rsp
+const RED_ZONE: u64 = 128;
+
+// rflags bits userspace may change through sigreturn: the arithmetic flags, trap and direction
+const USER_RFLAGS: u64 = 0x8d5 | 0x100 | 0x400;
+const RFLAGS_IF: u64 = 0x200;
+
+fn bit(sig: u32) -> u64 {
+    1 << sig
+}
+
+fn valid(sig: u32) -> Result<(), Errno> {
+    match sig {
+        1..=31 => Ok(()),
+        _ => Err(Errno::EINVAL),
+    }
+}
+
+pub struct SignalState {
+    pending: u64,
+    blocked: u64,
+    actions: [SigAction; NSIG as usize],
+}
+
+impl SignalState {
+    // every signal with its default action, what a new task starts with
+    pub fn new() -> SignalState {
+        SignalState {
+            pending: 0,
+            blocked: 0,
+            actions: [SigAction::default(); NSIG as usize],
+        }
+    }
+
+    fn ignored(&self, sig: u32) -> bool {
+        match self.actions[sig as usize].handler {
+            SIG_IGN => true,
+            SIG_DFL => DEFAULT_IGNORED & bit(sig) != 0,
+            _ => false,
+        }
+    }
+
+    // mark the signal pending, ignored ones are dropped right away
+    pub fn raise(&mut self, sig: u32) -> Result<(), Errno> {
+        valid(sig)?;
+        if !self.ignored(sig) {
+            self.pending |= bit(sig);
+        }
+        Ok(())
+    }
+
+    // for faults, which can't be ignored or blocked as the task can't go on without handling them
+    pub fn force(&mut self, sig: u32) {
+        if self.actions[sig as usize].handler == SIG_IGN {
+            self.actions[sig as usize].handler = SIG_DFL;
+        }
+        self.blocked &= !bit(sig);
+        self.pending |= bit(sig);
+    }
+
+    // is there a signal that would interrupt a blocking syscall
+    pub fn deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    // take the lowest pending signal that isn't blocked, returns it with its action and the mask
+    // to restore once its handler returns
+    pub fn take_next(&mut self) -> Option<(u32, SigAction, u64)> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let sig = deliverable.trailing_zeros();
+        self.pending &= !bit(sig);
+        let action = self.actions[sig as usize];
+        let old_blocked = self.blocked;
+        if action.handler != SIG_DFL && action.handler != SIG_IGN {
+            self.blocked |= action.mask;
+            if action.flags & SA_NODEFER == 0 {
+                self.blocked |= bit(sig);
+            }
+            self.blocked &= !UNBLOCKABLE;
+        }
+        Some((sig, action, old_blocked))
+    }
+
+    // change the action of sig if new is given, returns the old one
+    pub fn set_action(&mut self, sig: u32, new: Option<SigAction>) -> Result<SigAction, Errno> {
+        valid(sig)?;
+        let old = self.actions[sig as usize];
+        if let Some(new) = new {
+            if sig == SIGKILL {
+                return Err(Errno::EINVAL);
+            }
+            let handled = new.handler != SIG_DFL && new.handler != SIG_IGN;
+            if handled && (new.flags & SA_RESTORER == 0 || new.restorer == 0) {
+                return Err(Errno::EINVAL);
+            }
+            self.actions[sig as usize] = new;
+            // pending signals that are ignored now are dropped
+            if self.ignored(sig) {
+                self.pending &= !bit(sig);
+            }
+        }
+        Ok(old)
+    }
+
+    // change the blocked mask like sigprocmask, returns the old one
+    pub fn set_blocked(&mut self, how: u64, set: u64) -> Result<u64, Errno> {
+        let old = self.blocked;
+        let new = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(Errno::EINVAL),
+        };
+        self.set_blocked_mask(new);
+        Ok(old)
+    }
+
+    // bit 0 is no signal
+    pub fn set_blocked_mask(&mut self, mask: u64) {
+        self.blocked = mask & !UNBLOCKABLE & !1;
+    }
+}
+
+// what deliver pushes onto the user stack, rsp points at restorer when the handler starts
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SignalFrame {
+    restorer: u64, // return address of the handler
+    sig: u64,
+    blocked: u64, // the mask to restore
+    context: Context,
+}
+
+// make ctx, the interrupted user context of the current task, run the handler of the next
+// pending signal, returns whether it does, signals that terminate the task don't return here
+pub unsafe fn deliver(ctx: &mut Context) -> bool {
+    loop {
+        let next = SCHEDULER.with_current_signals(|signals| signals.take_next());
+        let (sig, action, old_blocked) = match next {
+            Some(Some(next)) => next,
+            _ => return false,
+        };
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL if DEFAULT_IGNORED & bit(sig) != 0 => continue,
+            SIG_DFL => SCHEDULER.exit_current(signal_exit_code(sig)),
+            handler => {
+                if push_frame(ctx, sig, handler, action.restorer, old_blocked).is_err() {
+                    // no room for the frame on the stack, nothing left to do for the task
+                    SCHEDULER.exit_current(signal_exit_code(SIGSEGV));
+                }
+                return true;
+            }
+        }
+    }
+}
+
+fn push_frame(
+    ctx: &mut Context,
+    sig: u32,
+    handler: u64,
+    restorer: u64,
+    blocked: u64,
+) -> Result<(), Errno> {
+    // as if the handler was called: the return address at rsp and rsp + 8 aligned to 16 bytes
+    let addr = ctx
+        .rsp
+        .checked_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+        .ok_or(Errno::EFAULT)?
+        & !0xf;
+    let addr = addr - 8;
+    let frame = SignalFrame {
+        restorer,
+        sig: sig as u64,
+        blocked,
+        context: *ctx,
+    };
+    UserSlice::<SignalFrame>::new(addr, 1)?.write(0, frame)?;
+    ctx.rip = handler;
+    ctx.rsp = addr;
+    ctx.rdi = sig as u64;
+    ctx.rflags &= !(0x100 | 0x400); // no single stepping into the handler, direction upwards
+    Ok(())
+}
+
+// the context saved by deliver, rsp is the user stack pointer when the restorer called
+// sigreturn, right after the handler returned to it
+pub unsafe fn restore(rsp: u64) -> Result<Context, Errno> {
+    let addr = rsp.checked_sub(8).ok_or(Errno::EFAULT)?;
+    let frame = UserSlice::<SignalFrame>::new(addr, 1)?.read(0)?;
+    SCHEDULER.with_current_signals(|signals| signals.set_blocked_mask(frame.blocked));
+    // userspace may have changed the frame, so it can't return to ring 0 or take over IOPL
+    let mut ctx = frame.context;
+    let (cs, ss) = gdt::user_segs();
+    ctx.cs = cs as u64;
+    ctx.ss = ss as u64;
+    ctx.rflags = (ctx.rflags & USER_RFLAGS) | RFLAGS_IF;
+    Ok(ctx)
+}
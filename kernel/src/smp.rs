@@ -0,0 +1,220 @@
+use crate::acpi;
+use crate::gdt;
+use crate::interrupts;
+use crate::lapic;
+use crate::mem;
+use crate::percpu::{self, PerCpu, TLB_FLUSH_ALL, TLB_FLUSH_NONE};
+use crate::port::Port;
+use crate::scheduler::{self, SCHEDULER};
+use crate::syscalls;
+use crate::println;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr::{copy_nonoverlapping, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+
+// where the real-mode trampoline is copied to, has to match ap_trampoline.asm
+const AP_TRAMPOLINE_BASE: u64 = 0x8000;
+const TRAMPOLINE_CR3: u64 = 0x08;
+const TRAMPOLINE_STACK: u64 = 0x10;
+const TRAMPOLINE_ENTRY: u64 = 0x18;
+const TRAMPOLINE_PERCPU: u64 = 0x20;
+
+const AP_STACK_SIZE: usize = 0x4000;
+const AP_TIMER_COUNT: u32 = 0x100000;
+
+pub const TIMER_VECTOR: u8 = 32;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xf0;
+
+// set once the APs have been started, before that TLB changes only need a local flush
+static SMP_STARTED: AtomicBool = AtomicBool::new(false);
+
+// timer interrupts on the BSP since they were enabled
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    static _ap_trampoline_start: u8;
+    static _ap_trampoline_end: u8;
+}
+
+// port 0x80 is unused, each write to it takes roughly a microsecond
+fn io_delay_us(us: u64) {
+    let port: Port<u8> = Port::new(0x80);
+    for _ in 0..us {
+        port.write(0);
+    }
+}
+
+// a copy of the current page table which also identity maps the first GiB,
+// as the APs enable paging while executing from low memory
+unsafe fn ap_page_table() -> &'static mem::PageTable {
+    let ptable = Box::leak(mem::PageTable::new());
+    let p3 = ptable.get_entry(0).next_pt();
+    *p3.get_entry(0) = *p3.get_entry(3);
+    ptable
+}
+
+unsafe fn copy_trampoline() {
+    let start = &_ap_trampoline_start as *const u8;
+    let len = &_ap_trampoline_end as *const u8 as usize - start as usize;
+    let dest = mem::PhysAddr::new(AP_TRAMPOLINE_BASE).to_virt().unwrap();
+    copy_nonoverlapping(start, dest.addr() as *mut u8, len);
+}
+
+unsafe fn set_trampoline_field(offset: u64, val: u64) {
+    let field = mem::PhysAddr::new(AP_TRAMPOLINE_BASE + offset)
+        .to_virt()
+        .unwrap();
+    write_volatile(field.addr() as *mut u64, val);
+}
+
+// bring up the other CPUs listed in the MADT, each of them ends up in the scheduler loop
+pub fn init() {
+    let topology = acpi::cpu_topology();
+    if let Some(ref topology) = topology {
+        lapic::set_base(topology.lapic_addr);
+    }
+    lapic::init();
+    let bsp = percpu::register_cpu(lapic::id());
+    bsp.tables.set(Some(gdt::bsp_tables()));
+    unsafe {
+        percpu::load(bsp);
+    }
+    let topology = match topology {
+        Some(topology) => topology,
+        None => {
+            println!(" - No MADT found, running on a single CPU");
+            return;
+        }
+    };
+    unsafe {
+        copy_trampoline();
+        let cr3 = ap_page_table().phys_addr().addr();
+        for cpu in topology.cpus.iter() {
+            if cpu.enabled && cpu.lapic_id as u32 != bsp.lapic_id {
+                start_ap(cpu.lapic_id as u32, cr3);
+            }
+        }
+    }
+    SMP_STARTED.store(true, Ordering::SeqCst);
+    println!(" - {} CPUs online", percpu::online_cpus().len());
+}
+
+// INIT-SIPI-SIPI sequence from the Intel MP specification
+unsafe fn start_ap(lapic_id: u32, cr3: u64) {
+    let percpu = percpu::register_cpu(lapic_id);
+    SCHEDULER.add_cpu();
+    let stack: &'static mut Vec<u8> = Box::leak(Box::new(Vec::with_capacity(AP_STACK_SIZE)));
+    let stack_top = stack.as_ptr() as u64 + AP_STACK_SIZE as u64;
+    set_trampoline_field(TRAMPOLINE_CR3, cr3);
+    set_trampoline_field(TRAMPOLINE_STACK, stack_top);
+    set_trampoline_field(TRAMPOLINE_ENTRY, ap_entry as *const () as u64);
+    set_trampoline_field(TRAMPOLINE_PERCPU, percpu as *const PerCpu as u64);
+    let vector = (AP_TRAMPOLINE_BASE >> 12) as u8;
+    lapic::send_init(lapic_id);
+    io_delay_us(10000);
+    for _ in 0..2 {
+        lapic::send_startup(lapic_id, vector);
+        io_delay_us(200);
+    }
+    // give the AP 100ms to show up
+    for _ in 0..1000 {
+        if percpu.online.load(Ordering::SeqCst) {
+            log::info!(
+                "CPU {} (LAPIC {}) is online",
+                percpu.cpu_id,
+                lapic_id
+            );
+            return;
+        }
+        io_delay_us(100);
+    }
+    println!(" - CPU with LAPIC id {} did not start", lapic_id);
+}
+
+extern "C" fn ap_entry(percpu: &'static PerCpu) -> ! {
+    percpu.tables.set(Some(gdt::init_ap_gdt()));
+    interrupts::load_idt();
+    unsafe {
+        syscalls::init_syscalls();
+    }
+    lapic::init();
+    unsafe {
+        percpu::load(percpu);
+    }
+    lapic::start_timer(TIMER_VECTOR, AP_TIMER_COUNT);
+    unsafe { scheduler::enter_idle() }
+}
+
+// the scheduler tick comes from the PIC on the BSP and from the local APIC timer on APs
+pub fn timer_end_of_interrupt() {
+    if percpu::current().cpu_id == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        crate::port::end_of_interrupt(TIMER_VECTOR);
+    } else {
+        lapic::end_of_interrupt();
+    }
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn flush_local(request: u64) {
+    if request == TLB_FLUSH_ALL {
+        tlb::flush_all();
+    } else {
+        tlb::flush(x86_64::VirtAddr::new(request));
+    }
+}
+
+// invalidate a page on every CPU after its mapping changed, waiting until all of them are done
+pub fn tlb_shootdown(virt: mem::VirtAddr) {
+    let page = virt.addr() & !(mem::FRAME_SIZE - 1);
+    flush_local(page);
+    if !SMP_STARTED.load(Ordering::SeqCst) {
+        return;
+    }
+    let me = lapic::id();
+    percpu::for_each_online_cpu(|cpu| {
+        if cpu.lapic_id != me {
+            // merge with a request that is still pending by flushing everything
+            if cpu
+                .tlb_flush
+                .compare_exchange(TLB_FLUSH_NONE, page, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                cpu.tlb_flush.store(TLB_FLUSH_ALL, Ordering::SeqCst);
+            }
+            lapic::send_ipi(cpu.lapic_id, TLB_SHOOTDOWN_VECTOR);
+        }
+    });
+    percpu::for_each_online_cpu(|cpu| {
+        while cpu.tlb_flush.load(Ordering::SeqCst) != TLB_FLUSH_NONE {
+            // another CPU might be waiting on us at the same time with interrupts off
+            handle_tlb_shootdown();
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+// called from the TLB shootdown IPI, finds this CPU through its LAPIC id as GS
+// might still hold the user base
+pub fn handle_tlb_shootdown() {
+    if let Some(cpu) = percpu::by_lapic_id(lapic::id()) {
+        let request = cpu.tlb_flush.load(Ordering::SeqCst);
+        if request != TLB_FLUSH_NONE {
+            flush_local(request);
+            // only acknowledge once flushed, if another request came in meanwhile flush everything
+            if cpu
+                .tlb_flush
+                .compare_exchange(request, TLB_FLUSH_NONE, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                tlb::flush_all();
+                cpu.tlb_flush.store(TLB_FLUSH_NONE, Ordering::SeqCst);
+            }
+        }
+    }
+}
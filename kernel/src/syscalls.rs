@@ -1,18 +1,42 @@
+use crate::abi::{self, IpcMessage, PciInfo, SigAction, Stat};
+use crate::channel::{self, Message};
+use crate::console;
+use crate::errno::{self, Errno, SyscallResult};
+use crate::fd::FileRef;
+use crate::gdt;
+use crate::klog;
+use crate::path;
+use crate::pci;
+use crate::percpu::{self, PERCPU_KERNEL_STACK, PERCPU_USER_RSP};
+use crate::pipe;
 use crate::println;
+use crate::programs;
+use crate::scheduler::{self, Context, SCHEDULER};
+use crate::shm::{SharedMemory, ShmHandle};
+use crate::signal;
+use crate::tag_fs;
+use crate::uaccess::{self, UserSlice};
+use crate::vfs::{self, FsContext};
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::cmp::min;
+use x86_64::instructions::interrupts;
 
 // register for address of syscall handler
 const MSR_STAR: usize = 0xc0000081;
 const MSR_LSTAR: usize = 0xc0000082;
 const MSR_FMASK: usize = 0xc0000084;
 
+// rflags bits cleared on syscall: trap, interrupt, direction and alignment check
+const SYSCALL_RFLAGS_MASK: u64 = 0x100 | 0x200 | 0x400 | 0x40000;
+
 pub unsafe fn init_syscalls() {
     let handler_addr = handle_syscall as *const () as u64;
-    // clear Interrupt flag on syscall with AMD's MSR_FSTAR register
+    // clear the flags above on syscall with AMD's MSR_FMASK register
     asm!("\
     xor rdx, rdx
-    mov rax, 0x200
-    wrmsr", in("rcx") MSR_FMASK, out("rdx") _);
+    wrmsr", in("rax") SYSCALL_RFLAGS_MASK, in("rcx") MSR_FMASK, out("rdx") _);
     // write handler address to AMD's MSR_LSTAR register
     asm!("\
     mov rdx, rax
@@ -25,88 +49,826 @@ pub unsafe fn init_syscalls() {
     wrmsr", in("rcx") MSR_STAR, out("rax") _, out("rdx") _);
 }
 
-#[inline(never)]
-fn sys0(a: u64, b: u64, c: u64, d: u64) -> i64 {
-    println!("sys0 {:x} {:x} {:x} {:x}", a, b, c, d);
-    123
+// user registers as pushed by handle_syscall, in reverse order of the pushes
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct SyscallFrame {
+    pub rax: u64, // syscall number, return value on the way out
+    pub rdi: u64, // arguments
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64, // callee-saved registers of the task
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rcx: u64, // user rip, used by sysretq
+    pub r11: u64, // user rflags, used by sysretq
+    pub rsp: u64, // user rsp
 }
 
-#[inline(never)]
-fn sys1(a: u64, b: u64, c: u64, d: u64) -> i64 {
-    println!("sys1 {:x} {:x} {:x} {:x}", a, b, c, d);
-    456
+impl SyscallFrame {
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+
+    // the user context sysretq returns to
+    pub fn context(&self) -> Context {
+        let (cs, ss) = unsafe { gdt::user_segs() };
+        Context {
+            rbp: self.rbp,
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rcx,
+            cs: cs as u64,
+            rflags: self.r11,
+            rsp: self.rsp,
+            ss: ss as u64,
+        }
+    }
+
+    // return to ctx instead, sysretq can't restore rcx and r11 as it puts rip and rflags there
+    // so this is only good for starting signal handlers, which don't care about them
+    pub fn set_context(&mut self, ctx: &Context) {
+        self.rbp = ctx.rbp;
+        self.rax = ctx.rax;
+        self.rbx = ctx.rbx;
+        self.rdx = ctx.rdx;
+        self.rsi = ctx.rsi;
+        self.rdi = ctx.rdi;
+        self.r8 = ctx.r8;
+        self.r9 = ctx.r9;
+        self.r10 = ctx.r10;
+        self.r12 = ctx.r12;
+        self.r13 = ctx.r13;
+        self.r14 = ctx.r14;
+        self.r15 = ctx.r15;
+        self.rcx = ctx.rip;
+        self.r11 = ctx.rflags;
+        self.rsp = ctx.rsp;
+    }
 }
 
-#[naked]
-fn handle_syscall() {
-    unsafe {
-        asm!("\
-        push rcx // backup registers for sysretq
-        push r11
-        push rbp // save callee-saved registers
-        push rbx
-        push r12
-        push r13
-        push r14
-        push r15
-        mov rbp, rsp // save rsp
-        sub rsp, 0x400 // make some room in the stack
-        push rax // backup syscall params while we get some stack space
-        push rdi
-        push rsi
-        push rdx
-        push r10"
-        );
+pub type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+// syscalls by number, see abi.rs
+static SYSCALL_TABLE: [SyscallHandler; 47] = [
+    sys_exit,          // SYS_EXIT
+    sys_write,         // SYS_WRITE
+    sys_brk,           // SYS_BRK
+    sys_mmap,          // SYS_MMAP
+    sys_munmap,        // SYS_MUNMAP
+    sys_getpid,        // SYS_GETPID
+    sys_read,          // SYS_READ
+    sys_spawn,         // SYS_SPAWN
+    sys_wait,          // SYS_WAIT
+    sys_getcwd,        // SYS_GETCWD
+    sys_chdir,         // SYS_CHDIR
+    sys_close,         // SYS_CLOSE
+    sys_dup,           // SYS_DUP
+    sys_dup2,          // SYS_DUP2
+    sys_pipe,          // SYS_PIPE
+    sys_channel,       // SYS_CHANNEL
+    sys_send,          // SYS_SEND
+    sys_recv,          // SYS_RECV
+    sys_call,          // SYS_CALL
+    sys_reply,         // SYS_REPLY
+    sys_irq_bind,      // SYS_IRQ_BIND
+    sys_console_input, // SYS_CONSOLE_INPUT
+    sys_shm_create,    // SYS_SHM_CREATE
+    sys_shm_map,       // SYS_SHM_MAP
+    sys_shm_unmap,     // SYS_SHM_UNMAP
+    sys_kill,          // SYS_KILL
+    sys_sigaction,     // SYS_SIGACTION
+    sys_sigprocmask,   // SYS_SIGPROCMASK
+    sys_sigreturn,     // SYS_SIGRETURN
+    sys_dmesg,         // SYS_DMESG
+    sys_open,          // SYS_OPEN
+    sys_lseek,         // SYS_LSEEK
+    sys_stat,          // SYS_STAT
+    sys_fstat,         // SYS_FSTAT
+    sys_getdents,      // SYS_GETDENTS
+    sys_mkdir,         // SYS_MKDIR
+    sys_rmdir,         // SYS_RMDIR
+    sys_unlink,        // SYS_UNLINK
+    sys_chroot,        // SYS_CHROOT
+    sys_link,          // SYS_LINK
+    sys_symlink,       // SYS_SYMLINK
+    sys_chmod,         // SYS_CHMOD
+    sys_tag_add,       // SYS_TAG_ADD
+    sys_tag_remove,    // SYS_TAG_REMOVE
+    sys_tag_list,      // SYS_TAG_LIST
+    sys_tag_query,     // SYS_TAG_QUERY
+    sys_pci_list,      // SYS_PCI_LIST
+];
+
+// data is copied between the task and its files in chunks of this size
+const IO_CHUNK: usize = 512;
+
+// exit code of tasks the kernel had to kill
+const EXIT_KILLED: i32 = -1;
+
+// most bytes of directory entries getdents returns at once
+const DIRENT_CHUNK: usize = 0x1000;
+
+fn user_path(addr: u64) -> Result<String, Errno> {
+    let bytes = uaccess::strncpy_from_user(addr, abi::PATH_MAX)?;
+    if bytes.len() >= abi::PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
     }
-    let syscall_stack: Vec<u8> = Vec::with_capacity(0x10000);
-    let stack_ptr = syscall_stack.as_ptr();
-    unsafe {
-        asm!("\
-        pop r10 // restore syscall params to their registers
-        pop rdx
-        pop rsi
-        pop rdi
-        pop rax
-        mov rsp, rbx // move our stack to the newly allocated one
-        sti // enable interrupts",
-        inout("rbx") stack_ptr => _);
-    }
-    let syscall: u64;
-    let arg0: u64;
-    let arg1: u64;
-    let arg2: u64;
-    let arg3: u64;
-    unsafe {
-        // move the syscall arguments from registers to variables
-        asm!("nop",
-        out("rax") syscall, out("rdi") arg0, out("rsi") arg1, out("rdx") arg2, out("r10") arg3);
-    }
-    let retval: i64 = match syscall {
-        0x0 => sys0(arg0, arg1, arg2, arg3),
-        0x1 => sys1(arg0, arg1, arg2, arg3),
-        _ => -1,
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+fn current_fs() -> Result<FsContext, Errno> {
+    SCHEDULER.fs_context().ok_or(Errno::ESRCH)
+}
+
+fn current_file(fd: u64) -> Result<FileRef, Errno> {
+    SCHEDULER
+        .with_current_files(|files| files.get(fd as usize))
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    unsafe { SCHEDULER.exit_current(frame.rdi as i32) }
+}
+
+// write(fd, buf, len) may block, e.g. on a full pipe, writing to a pipe nobody reads raises SIGPIPE
+fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buf, len, _, _, _] = frame.args();
+    let result = write(fd, buf, len);
+    if result == Err(Errno::EPIPE) {
+        let pid = percpu::current().cur_task.get().ok_or(Errno::ESRCH)?;
+        SCHEDULER.kill(pid as isize, abi::SIGPIPE)?;
+    }
+    result
+}
+
+fn write(fd: u64, buf: u64, len: u64) -> SyscallResult {
+    let file = current_file(fd)?;
+    uaccess::check_range(buf, len as usize, false)?;
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut written = 0;
+    while written < len {
+        let count = min(chunk.len() as u64, len - written) as usize;
+        uaccess::copy_from_user(&mut chunk[..count], buf + written)?;
+        // report what got written before an error, the error comes again on the next write
+        match file.write(&chunk[..count]) {
+            Ok(done) if done < count => {
+                written += done as u64;
+                break;
+            }
+            Ok(done) => written += done as u64,
+            Err(_) if written > 0 => break,
+            Err(errno) => return Err(errno),
+        }
+    }
+    Ok(written)
+}
+
+fn sys_brk(frame: &mut SyscallFrame) -> SyscallResult {
+    let addr = frame.rdi;
+    SCHEDULER
+        .with_current_space(|space| unsafe { space.set_brk(addr) })
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+// only private anonymous mappings, the address hint, fd and offset are ignored
+fn sys_mmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [_, len, prot, flags, _, _] = frame.args();
+    if flags & abi::MAP_ANONYMOUS == 0 || flags & abi::MAP_PRIVATE == 0 {
+        return Err(Errno::EINVAL);
+    }
+    SCHEDULER
+        .with_current_space(|space| unsafe { space.mmap(len, prot & abi::PROT_WRITE != 0) })
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+fn sys_munmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let [addr, len, _, _, _, _] = frame.args();
+    SCHEDULER
+        .with_current_space(|space| unsafe { space.munmap(addr, len) })
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    match percpu::current().cur_task.get() {
+        Some(pid) => Ok(pid as u64),
+        None => Err(Errno::ESRCH),
+    }
+}
+
+// read(fd, buf, len) blocks until there is something to read, returns 0 at the end of a file
+fn sys_read(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buf, len, _, _, _] = frame.args();
+    let file = current_file(fd)?;
+    uaccess::check_range(buf, len as usize, true)?;
+    let mut chunk = [0u8; IO_CHUNK];
+    let count = file.read(&mut chunk[..min(chunk.len() as u64, len) as usize])?;
+    uaccess::copy_to_user(buf, &chunk[..count])?;
+    Ok(count as u64)
+}
+
+// spawn(path, argv, argc) starts a program as a child, returning its pid
+// the filesystem is searched first, then the programs linked into the kernel, which tasks that
+// chrooted can't reach
+fn sys_spawn(frame: &mut SyscallFrame) -> SyscallResult {
+    let [path, argv, argc, _, _, _] = frame.args();
+    let fs = current_fs()?;
+    let path = user_path(path)?;
+    let file;
+    let image = match vfs::read_file(&fs, &path) {
+        Ok(contents) => {
+            file = contents;
+            &file[..]
+        }
+        Err(Errno::ENOENT) if fs.root == "/" => {
+            programs::find(&path::join(&fs.cwd, &path)).ok_or(Errno::ENOENT)?
+        }
+        Err(errno) => return Err(errno),
     };
-    unsafe {
-        asm!("\
-        mov rbx, {} // save return value into rbx so that it's maintained through free
-        cli",
-        in(reg) retval // disable interrupts while restoring the stack
-        );
+    if argc as usize > abi::ARG_MAX {
+        return Err(Errno::E2BIG);
+    }
+    let mut args = Vec::with_capacity(argc as usize);
+    for arg in UserSlice::<u64>::new(argv, argc as usize)?.read_all()? {
+        args.push(uaccess::strncpy_from_user(arg, abi::PATH_MAX)?);
+    }
+    let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_slice()).collect();
+    let pid = unsafe { SCHEDULER.spawn(image, &args)? };
+    Ok(pid as u64)
+}
+
+// wait(pid, status) blocks until the child exits, its exit code is stored in status if not NULL
+fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult {
+    let [pid, status, _, _, _, _] = frame.args();
+    let status = if status != 0 {
+        Some(UserSlice::<i32>::new(status, 1)?)
+    } else {
+        None
+    };
+    let code = SCHEDULER.wait_child(pid as usize)?;
+    if let Some(status) = status {
+        status.write(0, code)?;
+    }
+    Ok(pid)
+}
+
+// getcwd(buf, size) stores the NUL-terminated working directory, returns its length
+fn sys_getcwd(frame: &mut SyscallFrame) -> SyscallResult {
+    let [buf, size, _, _, _, _] = frame.args();
+    let mut cwd = current_fs()?.cwd.into_bytes();
+    let len = cwd.len();
+    if len + 1 > size as usize {
+        return Err(Errno::ERANGE);
+    }
+    cwd.push(0);
+    uaccess::copy_to_user(buf, &cwd)?;
+    Ok(len as u64)
+}
+
+fn sys_chdir(frame: &mut SyscallFrame) -> SyscallResult {
+    let mut fs = current_fs()?;
+    fs.cwd = vfs::chdir(&fs, &user_path(frame.rdi)?)?;
+    SCHEDULER.set_fs_context(fs);
+    Ok(0)
+}
+
+// link(old_path, new_path) gives the file at old_path a second name
+fn sys_link(frame: &mut SyscallFrame) -> SyscallResult {
+    let (old_path, new_path) = (user_path(frame.rdi)?, user_path(frame.rsi)?);
+    vfs::link(&current_fs()?, &old_path, &new_path)?;
+    Ok(0)
+}
+
+// symlink(target, path), the target isn't looked at until the link is followed
+fn sys_symlink(frame: &mut SyscallFrame) -> SyscallResult {
+    let (target, path) = (user_path(frame.rdi)?, user_path(frame.rsi)?);
+    vfs::symlink(&current_fs()?, &target, &path)?;
+    Ok(0)
+}
+
+// chmod(path, perm)
+fn sys_chmod(frame: &mut SyscallFrame) -> SyscallResult {
+    vfs::chmod(&current_fs()?, &user_path(frame.rdi)?, frame.rsi as u32)?;
+    Ok(0)
+}
+
+fn sys_close(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.rdi as usize;
+    let file = SCHEDULER
+        .with_current_files(|files| files.close(fd))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    // only drop it once the task map is unlocked, see fd.rs
+    drop(file);
+    Ok(0)
+}
+
+// dup(fd) returns the lowest free fd, referring to the same file as fd
+fn sys_dup(frame: &mut SyscallFrame) -> SyscallResult {
+    let fd = frame.rdi as usize;
+    let new_fd = SCHEDULER
+        .with_current_files(|files| files.dup(fd))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    Ok(new_fd as u64)
+}
+
+// dup2(old_fd, new_fd) closes new_fd if it's open and makes it refer to old_fd's file
+fn sys_dup2(frame: &mut SyscallFrame) -> SyscallResult {
+    let [old_fd, new_fd, _, _, _, _] = frame.args();
+    let replaced = SCHEDULER
+        .with_current_files(|files| files.dup2(old_fd as usize, new_fd as usize))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    drop(replaced);
+    Ok(new_fd)
+}
+
+// put both ends of a pipe or channel into the fd table and store their fds in the user's fds
+fn insert_pair(fds: u64, (first, second): (FileRef, FileRef)) -> SyscallResult {
+    let fds = UserSlice::<i32>::new(fds, 2)?;
+    // the table only gets clones, so nothing is dropped while it is locked
+    let (first_fd, second_fd) = SCHEDULER
+        .with_current_files(|files| {
+            let first_fd = files.insert(first.clone())?;
+            match files.insert(second.clone()) {
+                Ok(second_fd) => Ok((first_fd, second_fd)),
+                Err(errno) => {
+                    let _ = files.close(first_fd);
+                    Err(errno)
+                }
+            }
+        })
+        .unwrap_or(Err(Errno::ESRCH))?;
+    fds.write(0, first_fd as i32)?;
+    fds.write(1, second_fd as i32)?;
+    Ok(0)
+}
+
+// pipe(fds) stores the fd of the read end in fds[0] and of the write end in fds[1]
+fn sys_pipe(frame: &mut SyscallFrame) -> SyscallResult {
+    insert_pair(frame.rdi, pipe::new_pipe())
+}
+
+// channel(fds) stores the fds of the two endpoints of a new channel in fds[0] and fds[1]
+fn sys_channel(frame: &mut SyscallFrame) -> SyscallResult {
+    insert_pair(frame.rdi, channel::new_channel())
+}
+
+// copy the data of a message described by the user and take its pages out of the address space
+fn message_from_user(desc: &IpcMessage) -> Result<Message, Errno> {
+    if desc.len as usize > abi::MSG_MAX || desc.page_count as usize > abi::MSG_MAX_PAGES {
+        return Err(Errno::EMSGSIZE);
     }
-    drop(syscall_stack); // we can now drop the syscall temp stack
+    let mut data = Vec::new();
+    if desc.len > 0 {
+        data.resize(desc.len as usize, 0);
+        uaccess::copy_from_user(&mut data, desc.data)?;
+    }
+    let pages = match desc.page_count {
+        0 => Vec::new(),
+        count => SCHEDULER
+            .with_current_space(|space| unsafe { space.take_pages(desc.pages, count) })
+            .unwrap_or(Err(Errno::ESRCH))?,
+    };
+    Ok(Message::new(data, pages))
+}
+
+// a message that could not be sent gives its pages back to the sender
+fn return_pages(desc: &IpcMessage, msg: Message) {
+    if !msg.pages.is_empty() {
+        let _ = SCHEDULER
+            .with_current_space(|space| unsafe { space.map_frames_at(desc.pages, msg.pages) });
+    }
+}
+
+// map the pages of a received message and fill in the user's description of it
+fn message_to_user(
+    msg: Message,
+    mut desc: IpcMessage,
+    user: &UserSlice<IpcMessage>,
+) -> SyscallResult {
+    if !msg.data.is_empty() {
+        uaccess::copy_to_user(desc.data, &msg.data)?;
+    }
+    desc.id = msg.id;
+    desc.len = msg.data.len() as u64;
+    desc.page_count = msg.pages.len() as u64;
+    desc.pages = match msg.pages.len() {
+        0 => 0,
+        _ => SCHEDULER
+            .with_current_space(|space| unsafe { space.map_frames(msg.pages) })
+            .unwrap_or(Err(Errno::ESRCH))?,
+    };
+    user.write(0, desc)?;
+    Ok(0)
+}
+
+fn current_endpoint(fd: u64) -> Result<FileRef, Errno> {
+    let file = current_file(fd)?;
+    match file.endpoint() {
+        Some(_) => Ok(file),
+        None => Err(Errno::EBADF),
+    }
+}
+
+// send(fd, msg) blocks while the other endpoint has CHANNEL_CAPACITY messages waiting
+fn sys_send(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, msg, _, _, _, _] = frame.args();
+    let file = current_endpoint(fd)?;
+    let desc = UserSlice::<IpcMessage>::new(msg, 1)?.read(0)?;
+    let msg = message_from_user(&desc)?;
+    match file.endpoint().unwrap().send(msg) {
+        Ok(()) => Ok(0),
+        Err((errno, msg)) => {
+            return_pages(&desc, msg);
+            Err(errno)
+        }
+    }
+}
+
+// recv(fd, msg) blocks until a message arrives, its data goes to the buffer msg describes
+fn sys_recv(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, msg, _, _, _, _] = frame.args();
+    let file = current_endpoint(fd)?;
+    let user = UserSlice::<IpcMessage>::new(msg, 1)?;
+    let desc = user.read(0)?;
+    if desc.capacity > 0 {
+        uaccess::check_range(desc.data, desc.capacity as usize, true)?;
+    }
+    let msg = file.endpoint().unwrap().recv(desc.capacity as usize)?;
+    message_to_user(msg, desc, &user)
+}
+
+// call(fd, msg) sends msg and blocks until the reply arrives, which is stored in msg like for recv
+fn sys_call(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, msg, _, _, _, _] = frame.args();
+    let file = current_endpoint(fd)?;
+    let user = UserSlice::<IpcMessage>::new(msg, 1)?;
+    let desc = user.read(0)?;
+    if desc.capacity > 0 {
+        uaccess::check_range(desc.data, desc.capacity as usize, true)?;
+    }
+    let msg = message_from_user(&desc)?;
+    match file.endpoint().unwrap().call(msg, desc.capacity as usize) {
+        Ok(reply) => message_to_user(reply, desc, &user),
+        Err((errno, msg)) => {
+            if let Some(msg) = msg {
+                return_pages(&desc, msg);
+            }
+            Err(errno)
+        }
+    }
+}
+
+// reply(fd, msg) answers the call with the id msg.id received on fd
+fn sys_reply(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, msg, _, _, _, _] = frame.args();
+    let file = current_endpoint(fd)?;
+    let desc = UserSlice::<IpcMessage>::new(msg, 1)?.read(0)?;
+    let msg = message_from_user(&desc)?;
+    match file.endpoint().unwrap().reply(desc.id, msg) {
+        Ok(()) => Ok(0),
+        Err((errno, msg)) => {
+            return_pages(&desc, msg);
+            Err(errno)
+        }
+    }
+}
+
+// irq_bind(irq, fd) makes the kernel send what it reads on each interrupt of the line to the
+// other endpoint of fd, only the keyboard can be handled by userspace so far
+fn sys_irq_bind(frame: &mut SyscallFrame) -> SyscallResult {
+    let [irq, fd, _, _, _, _] = frame.args();
+    if irq != abi::IRQ_KEYBOARD {
+        return Err(Errno::EINVAL);
+    }
+    let replaced = channel::bind_irq(irq as usize, current_endpoint(fd)?)?;
+    drop(replaced);
+    Ok(0)
+}
+
+// console_input(buf, len) types text into the console, this is how the keyboard server
+// passes on what it decoded
+fn sys_console_input(frame: &mut SyscallFrame) -> SyscallResult {
+    let [buf, len, _, _, _, _] = frame.args();
+    if len as usize > abi::MSG_MAX {
+        return Err(Errno::EINVAL);
+    }
+    let mut input = Vec::new();
+    input.resize(len as usize, 0);
+    uaccess::copy_from_user(&mut input, buf)?;
+    // the keyboard interrupt takes the same locks when there is no keyboard server
+    interrupts::without_interrupts(|| {
+        for c in String::from_utf8_lossy(&input).chars() {
+            console::handle_char(c);
+        }
+    });
+    Ok(len)
+}
+
+// shm_create(size) makes a zeroed shared memory region and returns a handle to it, children
+// inherit the handle and can map the same region
+fn sys_shm_create(frame: &mut SyscallFrame) -> SyscallResult {
+    let region = SharedMemory::new(frame.rdi)?;
+    let handle: FileRef = Arc::new(ShmHandle(region));
+    let fd = SCHEDULER
+        .with_current_files(|files| files.insert(handle))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    Ok(fd as u64)
+}
+
+// shm_map(fd, addr, prot) maps all of the region at addr, or wherever there is room if it's 0
+fn sys_shm_map(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, addr, prot, _, _, _] = frame.args();
+    let region = current_file(fd)?
+        .shared_memory()
+        .cloned()
+        .ok_or(Errno::EBADF)?;
+    SCHEDULER
+        .with_current_space(|space| unsafe {
+            space.map_shared(region, addr, prot & abi::PROT_WRITE != 0)
+        })
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+// shm_unmap(addr) unmaps the region shm_map returned addr for
+fn sys_shm_unmap(frame: &mut SyscallFrame) -> SyscallResult {
+    let addr = frame.rdi;
+    SCHEDULER
+        .with_current_space(|space| unsafe { space.unmap_shared(addr) })
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+// kill(pid, sig) sends sig to the task pid, or to every other task if pid is -1
+fn sys_kill(frame: &mut SyscallFrame) -> SyscallResult {
+    let [pid, sig, _, _, _, _] = frame.args();
+    SCHEDULER.kill(pid as i64 as isize, sig as u32)?;
+    Ok(0)
+}
+
+// sigaction(sig, act, old) sets the action for sig unless act is NULL, and stores the previous
+// one in old unless that is NULL
+fn sys_sigaction(frame: &mut SyscallFrame) -> SyscallResult {
+    let [sig, act, old, _, _, _] = frame.args();
+    let act = match act {
+        0 => None,
+        act => Some(UserSlice::<SigAction>::new(act, 1)?.read(0)?),
+    };
+    let old = match old {
+        0 => None,
+        old => Some(UserSlice::<SigAction>::new(old, 1)?),
+    };
+    let previous = SCHEDULER
+        .with_current_signals(|signals| signals.set_action(sig as u32, act))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    if let Some(old) = old {
+        old.write(0, previous)?;
+    }
+    Ok(0)
+}
+
+// sigprocmask(how, set) changes the blocked signals and returns the old mask
+fn sys_sigprocmask(frame: &mut SyscallFrame) -> SyscallResult {
+    let [how, set, _, _, _, _] = frame.args();
+    SCHEDULER
+        .with_current_signals(|signals| signals.set_blocked(how, set))
+        .unwrap_or(Err(Errno::ESRCH))
+}
+
+// sigreturn() goes back to the context a signal handler interrupted, it doesn't return
+// the whole context is restored with iretq as sysretq would lose rcx and r11
+fn sys_sigreturn(frame: &mut SyscallFrame) -> SyscallResult {
     unsafe {
-        asm!("\
-        mov rax, rbx // restore syscall return value from rbx to rax
-        mov rsp, rbp // restore rsp from rbp
-        pop r15 // restore callee-saved registers
-        pop r14
-        pop r13
-        pop r12
-        pop rbx
-        pop rbp // restore stack and registers for sysretq
-        pop r11
-        pop rcx
-        sysretq // back to userland",
-        options(noreturn));
+        let mut ctx = match signal::restore(frame.rsp) {
+            Ok(ctx) if is_canonical(ctx.rip) => ctx,
+            _ => {
+                // the frame is broken, there is nowhere to return to
+                SCHEDULER.with_current_signals(|signals| signals.force(abi::SIGSEGV));
+                frame.context()
+            }
+        };
+        // signals the restored mask unblocks are handled right away
+        signal::deliver(&mut ctx);
+        interrupts::disable();
+        scheduler::restore_context(&ctx);
+    }
+    unreachable!()
+}
+
+// dmesg(buf, len) copies the newest kernel log records into buf, returns how many bytes it copied
+fn sys_dmesg(frame: &mut SyscallFrame) -> SyscallResult {
+    let [buf, len, _, _, _, _] = frame.args();
+    let mut data = Vec::new();
+    data.resize(min(len as usize, klog::RING_SIZE), 0);
+    let len = klog::read(&mut data);
+    uaccess::copy_to_user(buf, &data[..len])?;
+    Ok(len as u64)
+}
+
+// open(path, flags) returns the lowest free fd for the file, flags are the O_* of abi.rs
+fn sys_open(frame: &mut SyscallFrame) -> SyscallResult {
+    let [path, flags, _, _, _, _] = frame.args();
+    let file = vfs::open(&current_fs()?, &user_path(path)?, flags)?;
+    // the table only gets a clone, so nothing is dropped while it is locked
+    let fd = SCHEDULER
+        .with_current_files(|files| files.insert(file.clone()))
+        .unwrap_or(Err(Errno::ESRCH))?;
+    Ok(fd as u64)
+}
+
+// lseek(fd, offset, whence) returns the new offset
+fn sys_lseek(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, offset, whence, _, _, _] = frame.args();
+    current_file(fd)?.seek(offset as i64, whence)
+}
+
+// stat(path, buf) stores a Stat of the file in buf
+fn sys_stat(frame: &mut SyscallFrame) -> SyscallResult {
+    let [path, buf, _, _, _, _] = frame.args();
+    let buf = UserSlice::<Stat>::new(buf, 1)?;
+    buf.write(0, vfs::stat(&current_fs()?, &user_path(path)?)?)?;
+    Ok(0)
+}
+
+fn sys_fstat(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buf, _, _, _, _] = frame.args();
+    let buf = UserSlice::<Stat>::new(buf, 1)?;
+    buf.write(0, current_file(fd)?.stat()?)?;
+    Ok(0)
+}
+
+// getdents(fd, buf, len) stores the next entries of the directory, returns 0 after the last
+fn sys_getdents(frame: &mut SyscallFrame) -> SyscallResult {
+    let [fd, buf, len, _, _, _] = frame.args();
+    let file = current_file(fd)?;
+    uaccess::check_range(buf, len as usize, true)?;
+    let mut data = Vec::new();
+    data.resize(min(len as usize, DIRENT_CHUNK), 0);
+    let len = file.getdents(&mut data)?;
+    uaccess::copy_to_user(buf, &data[..len])?;
+    Ok(len as u64)
+}
+
+fn sys_mkdir(frame: &mut SyscallFrame) -> SyscallResult {
+    vfs::mkdir(&current_fs()?, &user_path(frame.rdi)?)?;
+    Ok(0)
+}
+
+fn sys_rmdir(frame: &mut SyscallFrame) -> SyscallResult {
+    vfs::rmdir(&current_fs()?, &user_path(frame.rdi)?)?;
+    Ok(0)
+}
+
+fn sys_unlink(frame: &mut SyscallFrame) -> SyscallResult {
+    vfs::unlink(&current_fs()?, &user_path(frame.rdi)?)?;
+    Ok(0)
+}
+
+// chroot(path) makes path the root of the task and its working directory
+fn sys_chroot(frame: &mut SyscallFrame) -> SyscallResult {
+    let fs = vfs::chroot(&current_fs()?, &user_path(frame.rdi)?)?;
+    SCHEDULER.set_fs_context(fs);
+    Ok(0)
+}
+
+// tag_add(path, tag) gives a file of a tag_fs a tag, making the tag if it is new
+fn sys_tag_add(frame: &mut SyscallFrame) -> SyscallResult {
+    let inode = vfs::inode(&current_fs()?, &user_path(frame.rdi)?, true)?;
+    tag_fs::add_tag(&*inode, &user_path(frame.rsi)?)?;
+    Ok(0)
+}
+
+// tag_remove(path, tag)
+fn sys_tag_remove(frame: &mut SyscallFrame) -> SyscallResult {
+    let inode = vfs::inode(&current_fs()?, &user_path(frame.rdi)?, true)?;
+    tag_fs::remove_tag(&*inode, &user_path(frame.rsi)?)?;
+    Ok(0)
+}
+
+// names one after the other, each ending with a NUL, ERANGE if they don't fit into len
+fn copy_names(names: &[String], buf: u64, len: u64) -> SyscallResult {
+    let mut data = Vec::new();
+    for name in names {
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+    }
+    if data.len() as u64 > len {
+        return Err(Errno::ERANGE);
     }
+    uaccess::copy_to_user(buf, &data)?;
+    Ok(data.len() as u64)
+}
+
+// tag_list(path, buf, len) gets the tags of a file, or of the whole tag_fs for a directory
+fn sys_tag_list(frame: &mut SyscallFrame) -> SyscallResult {
+    let inode = vfs::inode(&current_fs()?, &user_path(frame.rdi)?, false)?;
+    copy_names(&tag_fs::tags(&*inode)?, frame.rsi, frame.rdx)
+}
+
+// tag_query(dir, tags, buf, len) gets the files in dir that have all the tags, separated by /
+fn sys_tag_query(frame: &mut SyscallFrame) -> SyscallResult {
+    let inode = vfs::inode(&current_fs()?, &user_path(frame.rdi)?, false)?;
+    let tags = user_path(frame.rsi)?;
+    let tags: Vec<&str> = tags.split('/').filter(|tag| !tag.is_empty()).collect();
+    copy_names(&tag_fs::query(&*inode, &tags)?, frame.rdx, frame.r10)
+}
+
+// pci_list(buf, count) stores up to count PCI functions and returns how many there are
+fn sys_pci_list(frame: &mut SyscallFrame) -> SyscallResult {
+    let [buf, count, _, _, _, _] = frame.args();
+    let devices = pci::list();
+    UserSlice::<PciInfo>::new(buf, count as usize)?.write_from(&devices)?;
+    Ok(devices.len() as u64)
+}
+
+// bits 63..47 have to be copies of bit 47
+fn is_canonical(addr: u64) -> bool {
+    (((addr << 16) as i64) >> 16) as u64 == addr
+}
+
+extern "C" fn dispatch_syscall(frame: &mut SyscallFrame) {
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = errno::encode(result) as u64;
+    // handle signals that came in during the syscall or were raised by it
+    let mut ctx = frame.context();
+    if unsafe { signal::deliver(&mut ctx) } {
+        frame.set_context(&ctx);
+    }
+    // sysretq to a non-canonical rip faults in ring 0 with the user's stack, don't let it happen
+    if !is_canonical(frame.rcx) {
+        println!(
+            "syscall: non-canonical return address {:x}, killing task",
+            frame.rcx
+        );
+        unsafe { SCHEDULER.exit_current(EXIT_KILLED) }
+    }
+}
+
+#[naked]
+unsafe extern "C" fn handle_syscall() {
+    asm!("\
+    swapgs // switch to the kernel GS base
+    mov gs:[{user_rsp}], rsp // never trust (or use) the user's stack
+    mov rsp, gs:[{kernel_stack}] // switch to the current task's kernel stack
+    push qword ptr gs:[{user_rsp}] // build the SyscallFrame
+    push r11
+    push rcx
+    push r15
+    push r14
+    push r13
+    push r12
+    push rbp
+    push rbx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp // pass the frame to the dispatcher
+    sti // enable interrupts, we're on our own stack now
+    call {dispatch}
+    cli // disable interrupts while restoring the user state
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rbx
+    pop rbp
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop rcx
+    pop r11
+    pop rsp // back to the user stack
+    swapgs // restore the user GS base
+    sysretq // back to userland",
+    user_rsp = const PERCPU_USER_RSP,
+    kernel_stack = const PERCPU_KERNEL_STACK,
+    dispatch = sym dispatch_syscall,
+    options(noreturn));
 }
@@ -0,0 +1,1077 @@
+// A filesystem that finds files by their tags rather than by a single path. Every file has a name
+// that is unique in the filesystem and a set of tags, the tag syscalls change them and find the
+// files that have all of some tags.
+//
+// For ordinary tools there is a path-like view where directories are sets of tags: /a/b holds
+// the files that have both a and b, and as subdirectories the tags that narrow that down further
+// (the root has all of them). A file made in /a/b gets the tags a and b, mkdir makes a new tag,
+// linking /a/f to /b/f adds b to f, unlinking /a/b/f takes b away and unlinking a file at the
+// root deletes it. rmdir deletes a tag that no file has anymore.
+//
+// On disk, in blocks of BLOCK_SIZE: the superblock, a bitmap of the blocks in use, the inode
+// table, the tag table and then the data blocks. Files have DIRECT_BLOCKS block numbers in their
+// inode and one indirect block, 0 is a hole. All metadata is read when mounting and kept in
+// memory, every change to it is written back before the operation returns.
+
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::errno::Errno;
+use crate::rtc;
+use crate::scheduler::SleepLock;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::{max, min};
+use core::convert::TryInto;
+use core::mem::take;
+use core::str;
+
+const BLOCK_SIZE: usize = 4096;
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+const MAGIC: &[u8; 8] = b"tagfs\0\0\x01";
+
+const INODE_SIZE: usize = 256;
+const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+const TAG_SIZE: usize = 64;
+const TAGS_PER_BLOCK: usize = BLOCK_SIZE / TAG_SIZE;
+const TAG_COUNT: usize = 16 * TAGS_PER_BLOCK;
+
+const DIRECT_BLOCKS: usize = 12;
+const MAX_BLOCKS: usize = DIRECT_BLOCKS + BLOCK_SIZE / 4;
+const MAX_NAME: usize = 96;
+const MAX_TAG_NAME: usize = TAG_SIZE - 4;
+const MAX_FILE_TAGS: usize = 32;
+
+const KIND_FILE: u8 = 1;
+
+// where the parts of the filesystem are, in blocks, kept in the superblock
+#[derive(Clone, Copy)]
+struct Layout {
+    blocks: u32,
+    bitmap_start: u32,
+    inode_start: u32,
+    inode_count: u32,
+    tag_start: u32,
+    tag_count: u32,
+    data_start: u32,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn blocks_for(count: usize, per_block: usize) -> u32 {
+    ((count + per_block - 1) / per_block) as u32
+}
+
+impl Layout {
+    // the layout format gives a device of this many blocks, a quarter of them can be files
+    fn new(blocks: u32) -> Result<Layout, Errno> {
+        let bitmap_blocks = blocks_for(blocks as usize, BLOCK_SIZE * 8);
+        let inode_count = max(blocks as usize / 4, INODES_PER_BLOCK);
+        let inode_blocks = blocks_for(inode_count, INODES_PER_BLOCK);
+        let tag_blocks = blocks_for(TAG_COUNT, TAGS_PER_BLOCK);
+        let layout = Layout {
+            blocks,
+            bitmap_start: 1,
+            inode_start: 1 + bitmap_blocks,
+            inode_count: inode_blocks * INODES_PER_BLOCK as u32,
+            tag_start: 1 + bitmap_blocks + inode_blocks,
+            tag_count: TAG_COUNT as u32,
+            data_start: 1 + bitmap_blocks + inode_blocks + tag_blocks,
+        };
+        match layout.data_start < blocks {
+            true => Ok(layout),
+            false => Err(Errno::ENOSPC),
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Result<Layout, Errno> {
+        if &buf[..8] != MAGIC {
+            return Err(Errno::EINVAL);
+        }
+        let layout = Layout {
+            blocks: u32_at(buf, 8),
+            bitmap_start: u32_at(buf, 12),
+            inode_start: u32_at(buf, 16),
+            inode_count: u32_at(buf, 20),
+            tag_start: u32_at(buf, 24),
+            tag_count: u32_at(buf, 28),
+            data_start: u32_at(buf, 32),
+        };
+        // every part has to come before the next one
+        let ends = [
+            (
+                layout.bitmap_start,
+                blocks_for(layout.blocks as usize, BLOCK_SIZE * 8),
+            ),
+            (
+                layout.inode_start,
+                blocks_for(layout.inode_count as usize, INODES_PER_BLOCK),
+            ),
+            (
+                layout.tag_start,
+                blocks_for(layout.tag_count as usize, TAGS_PER_BLOCK),
+            ),
+        ];
+        let mut end = 1;
+        for &(start, count) in ends.iter() {
+            if start < end {
+                return Err(Errno::EINVAL);
+            }
+            end = start.checked_add(count).ok_or(Errno::EINVAL)?;
+        }
+        if layout.data_start < end || layout.data_start > layout.blocks || layout.tag_count > 0xffff
+        {
+            return Err(Errno::EINVAL);
+        }
+        Ok(layout)
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(MAGIC);
+        let fields = [
+            self.blocks,
+            self.bitmap_start,
+            self.inode_start,
+            self.inode_count,
+            self.tag_start,
+            self.tag_count,
+            self.data_start,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            buf[8 + i * 4..12 + i * 4].copy_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    fn bitmap_end(&self) -> u32 {
+        self.inode_start
+    }
+
+    fn inode_end(&self) -> u32 {
+        self.inode_start + blocks_for(self.inode_count as usize, INODES_PER_BLOCK)
+    }
+
+    fn tag_end(&self) -> u32 {
+        self.tag_start + blocks_for(self.tag_count as usize, TAGS_PER_BLOCK)
+    }
+}
+
+// a file as it is kept in memory
+struct Node {
+    serial: u64, // tells apart files that had the same inode one after the other
+    perm: u32,
+    size: u64,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    name: String,
+    tags: Vec<u16>, // tag ids, their index in the tag table plus one
+    blocks: Vec<u32>,
+    indirect: u32,
+}
+
+// the layout of an inode:
+// 0 kind, 1 tag count, 2 perm u16, 4 name length, 8 size, 16 atime, 24 mtime, 32 ctime,
+// 40 direct blocks u32, 88 indirect block u32, 96 tags u16, 160 name
+fn decode_inode(buf: &[u8], serial: u64) -> Result<Option<Node>, Errno> {
+    if buf[0] != KIND_FILE {
+        return Ok(None);
+    }
+    let tag_count = buf[1] as usize;
+    let name_len = buf[4] as usize;
+    if tag_count > MAX_FILE_TAGS || name_len > MAX_NAME {
+        return Err(Errno::EINVAL);
+    }
+    let name = str::from_utf8(&buf[160..160 + name_len]).map_err(|_| Errno::EINVAL)?;
+    let mut blocks = Vec::new();
+    for i in 0..DIRECT_BLOCKS {
+        blocks.push(u32_at(buf, 40 + i * 4));
+    }
+    let mut tags = Vec::new();
+    for i in 0..tag_count {
+        tags.push(u16_at(buf, 96 + i * 2));
+    }
+    Ok(Some(Node {
+        serial,
+        perm: u16_at(buf, 2) as u32,
+        size: u64_at(buf, 8),
+        atime: u64_at(buf, 16),
+        mtime: u64_at(buf, 24),
+        ctime: u64_at(buf, 32),
+        name: String::from(name),
+        tags,
+        blocks,
+        indirect: u32_at(buf, 88),
+    }))
+}
+
+fn encode_inode(node: &Option<Node>, buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = 0;
+    }
+    let node = match node {
+        Some(node) => node,
+        None => return,
+    };
+    buf[0] = KIND_FILE;
+    buf[1] = node.tags.len() as u8;
+    buf[2..4].copy_from_slice(&(node.perm as u16).to_le_bytes());
+    buf[4] = node.name.len() as u8;
+    buf[8..16].copy_from_slice(&node.size.to_le_bytes());
+    buf[16..24].copy_from_slice(&node.atime.to_le_bytes());
+    buf[24..32].copy_from_slice(&node.mtime.to_le_bytes());
+    buf[32..40].copy_from_slice(&node.ctime.to_le_bytes());
+    for (i, block) in node.blocks.iter().take(DIRECT_BLOCKS).enumerate() {
+        buf[40 + i * 4..44 + i * 4].copy_from_slice(&block.to_le_bytes());
+    }
+    buf[88..92].copy_from_slice(&node.indirect.to_le_bytes());
+    for (i, tag) in node.tags.iter().enumerate() {
+        buf[96 + i * 2..98 + i * 2].copy_from_slice(&tag.to_le_bytes());
+    }
+    buf[160..160 + node.name.len()].copy_from_slice(node.name.as_bytes());
+}
+
+// the metadata of a mounted filesystem
+struct State {
+    layout: Layout,
+    bitmap: Vec<u8>,
+    nodes: Vec<Option<Node>>,
+    tags: Vec<Option<String>>,
+    dirty: BTreeSet<u32>, // metadata blocks that differ from the disk
+    next_serial: u64,
+}
+
+impl State {
+    fn node(&self, index: usize, serial: u64) -> Result<&Node, Errno> {
+        match self.nodes[index] {
+            Some(ref node) if node.serial == serial => Ok(node),
+            _ => Err(Errno::ENOENT),
+        }
+    }
+
+    fn node_mut(&mut self, index: usize, serial: u64) -> Result<&mut Node, Errno> {
+        match self.nodes[index] {
+            Some(ref mut node) if node.serial == serial => Ok(node),
+            _ => Err(Errno::ENOENT),
+        }
+    }
+
+    fn tag_id(&self, name: &str) -> Option<u16> {
+        let index = self
+            .tags
+            .iter()
+            .position(|tag| tag.as_ref().map(|tag| tag.as_str()) == Some(name))?;
+        Some(index as u16 + 1)
+    }
+
+    fn tag_name(&self, id: u16) -> &str {
+        self.tags[id as usize - 1]
+            .as_ref()
+            .map_or("", |name| name.as_str())
+    }
+
+    fn file_named(&self, name: &str) -> Option<usize> {
+        self.nodes
+            .iter()
+            .position(|node| node.as_ref().map(|node| node.name.as_str()) == Some(name))
+    }
+
+    // the files that have all of the tags
+    fn tagged<'a>(&'a self, tags: &'a [u16]) -> impl Iterator<Item = (usize, &'a Node)> + 'a {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(index, node)| node.as_ref().map(|node| (index, node)))
+            .filter(move |(_, node)| tags.iter().all(|tag| node.tags.contains(tag)))
+    }
+
+    fn touch_node(&mut self, index: usize) {
+        let block = self.layout.inode_start + (index / INODES_PER_BLOCK) as u32;
+        self.dirty.insert(block);
+    }
+
+    fn touch_tag(&mut self, id: u16) {
+        let block = self.layout.tag_start + ((id as usize - 1) / TAGS_PER_BLOCK) as u32;
+        self.dirty.insert(block);
+    }
+
+    fn allocate_block(&mut self) -> Result<u32, Errno> {
+        let start = self.layout.data_start;
+        let block = (start..self.layout.blocks)
+            .find(|&block| self.bitmap[block as usize / 8] & (1 << (block % 8)) == 0)
+            .ok_or(Errno::ENOSPC)?;
+        self.bitmap[block as usize / 8] |= 1 << (block % 8);
+        self.dirty
+            .insert(self.layout.bitmap_start + block / (BLOCK_SIZE as u32 * 8));
+        Ok(block)
+    }
+
+    fn free_block(&mut self, block: u32) {
+        self.bitmap[block as usize / 8] &= !(1 << (block % 8));
+        self.dirty
+            .insert(self.layout.bitmap_start + block / (BLOCK_SIZE as u32 * 8));
+    }
+
+    // the number of the block at slot in a file, allocating it if it is a hole; returns whether
+    // it is new, so the caller knows it holds garbage
+    fn file_block(&mut self, index: usize, slot: usize) -> Result<(u32, bool), Errno> {
+        let node = self.nodes[index].as_ref().unwrap();
+        if let Some(&block) = node.blocks.get(slot).filter(|&&block| block != 0) {
+            return Ok((block, false));
+        }
+        let needs_indirect = slot >= DIRECT_BLOCKS && node.indirect == 0;
+        let block = self.allocate_block()?;
+        if needs_indirect {
+            match self.allocate_block() {
+                Ok(indirect) => self.nodes[index].as_mut().unwrap().indirect = indirect,
+                Err(errno) => {
+                    self.free_block(block);
+                    return Err(errno);
+                }
+            }
+        }
+        let node = self.nodes[index].as_mut().unwrap();
+        if node.blocks.len() <= slot {
+            node.blocks.resize(slot + 1, 0);
+        }
+        node.blocks[slot] = block;
+        let indirect = node.indirect;
+        self.touch_node(index);
+        if slot >= DIRECT_BLOCKS {
+            self.dirty.insert(indirect);
+        }
+        Ok((block, true))
+    }
+
+    // turn slots of a file back into holes
+    fn release<I: Iterator<Item = (usize, u32)>>(&mut self, index: usize, slots: I) {
+        for (slot, block) in slots {
+            self.nodes[index].as_mut().unwrap().blocks[slot] = 0;
+            self.free_block(block);
+        }
+        let len = self.nodes[index].as_ref().unwrap().blocks.len();
+        self.free_blocks_from(index, len);
+    }
+
+    // drop the blocks of a file from slot on
+    fn free_blocks_from(&mut self, index: usize, slot: usize) {
+        let node = self.nodes[index].as_mut().unwrap();
+        let mut freed = Vec::new();
+        if node.blocks.len() > slot {
+            freed.extend(node.blocks.drain(slot..).filter(|&block| block != 0));
+        }
+        while node.blocks.last() == Some(&0) {
+            node.blocks.pop();
+        }
+        if node.blocks.len() <= DIRECT_BLOCKS && node.indirect != 0 {
+            freed.push(node.indirect);
+            node.indirect = 0;
+        } else if node.indirect != 0 {
+            let indirect = node.indirect;
+            self.dirty.insert(indirect);
+        }
+        for block in freed {
+            self.free_block(block);
+        }
+        self.touch_node(index);
+    }
+
+    // what a metadata block holds now, None for an indirect block that was freed
+    fn render(&self, block: u32) -> Option<Vec<u8>> {
+        let layout = &self.layout;
+        let mut buf = Vec::new();
+        buf.resize(BLOCK_SIZE, 0);
+        if block == 0 {
+            layout.encode(&mut buf);
+        } else if block < layout.bitmap_end() {
+            let start = (block - layout.bitmap_start) as usize * BLOCK_SIZE;
+            let end = min(start + BLOCK_SIZE, self.bitmap.len());
+            buf[..end - start].copy_from_slice(&self.bitmap[start..end]);
+        } else if block < layout.inode_end() {
+            let first = (block - layout.inode_start) as usize * INODES_PER_BLOCK;
+            for (i, chunk) in buf.chunks_mut(INODE_SIZE).enumerate() {
+                encode_inode(&self.nodes[first + i], chunk);
+            }
+        } else if block < layout.tag_end() {
+            let first = (block - layout.tag_start) as usize * TAGS_PER_BLOCK;
+            for (i, chunk) in buf.chunks_mut(TAG_SIZE).enumerate() {
+                if let Some(ref name) = self.tags[first + i] {
+                    chunk[0] = name.len() as u8;
+                    chunk[4..4 + name.len()].copy_from_slice(name.as_bytes());
+                }
+            }
+        } else {
+            let node = self
+                .nodes
+                .iter()
+                .flatten()
+                .find(|node| node.indirect == block)?;
+            let slots = node.blocks.iter().skip(DIRECT_BLOCKS);
+            for (i, slot) in slots.enumerate() {
+                buf[i * 4..i * 4 + 4].copy_from_slice(&slot.to_le_bytes());
+            }
+        }
+        Some(buf)
+    }
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    mounted: u64,
+    // held across the reads and writes of the device, which block
+    state: SleepLock<State>,
+}
+
+impl Volume {
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<(), Errno> {
+        self.device
+            .read_sectors(block as u64 * SECTORS_PER_BLOCK, buf)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), Errno> {
+        self.device
+            .write_sectors(block as u64 * SECTORS_PER_BLOCK, buf)
+    }
+
+    // write back the metadata that changed, the state stays locked so the writes of two
+    // operations can't overtake each other
+    fn write_back(&self, state: &mut State) -> Result<(), Errno> {
+        for block in take(&mut state.dirty) {
+            if let Some(buf) = state.render(block) {
+                self.write_block(block, &buf)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// make an empty tag_fs on a device, whatever was on it is lost
+pub fn format(device: &dyn BlockDevice) -> Result<(), Errno> {
+    let blocks = min(device.sector_count() / SECTORS_PER_BLOCK, u32::MAX as u64) as u32;
+    let layout = Layout::new(blocks)?;
+    let mut bitmap = Vec::new();
+    bitmap.resize(blocks as usize / 8 + 1, 0);
+    for block in 0..layout.data_start {
+        bitmap[block as usize / 8] |= 1 << (block % 8);
+    }
+    let mut nodes = Vec::new();
+    nodes.resize_with(layout.inode_count as usize, || None);
+    let mut tags = Vec::new();
+    tags.resize(layout.tag_count as usize, None);
+    let state = State {
+        layout,
+        bitmap,
+        nodes,
+        tags,
+        dirty: BTreeSet::new(),
+        next_serial: 0,
+    };
+    // the superblock goes last, so a format that fails halfway leaves no tag_fs behind
+    for block in (1..layout.data_start).chain(0..1) {
+        let buf = state.render(block).unwrap();
+        device.write_sectors(block as u64 * SECTORS_PER_BLOCK, &buf)?;
+    }
+    device.flush()
+}
+
+pub struct TagFs(Arc<Volume>);
+
+impl TagFs {
+    // read the metadata of the tag_fs on a device, EINVAL if there is none
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<TagFs, Errno> {
+        let mut buf = Vec::new();
+        buf.resize(BLOCK_SIZE, 0);
+        device.read_sectors(0, &mut buf)?;
+        let layout = Layout::decode(&buf)?;
+        if layout.blocks as u64 * SECTORS_PER_BLOCK > device.sector_count() {
+            return Err(Errno::EINVAL);
+        }
+        let read =
+            |block: u32, buf: &mut [u8]| device.read_sectors(block as u64 * SECTORS_PER_BLOCK, buf);
+        let mut bitmap = Vec::new();
+        for block in layout.bitmap_start..layout.bitmap_end() {
+            read(block, &mut buf)?;
+            bitmap.extend_from_slice(&buf);
+        }
+        let mut nodes = Vec::new();
+        for block in layout.inode_start..layout.inode_end() {
+            read(block, &mut buf)?;
+            for chunk in buf.chunks(INODE_SIZE) {
+                if nodes.len() < layout.inode_count as usize {
+                    nodes.push(decode_inode(chunk, nodes.len() as u64)?);
+                }
+            }
+        }
+        // numbers out of range would make a corrupted filesystem crash the kernel
+        let valid = |block: u32| block == 0 || (layout.data_start..layout.blocks).contains(&block);
+        for node in nodes.iter_mut().flatten() {
+            let tags_valid = node
+                .tags
+                .iter()
+                .all(|&tag| tag != 0 && tag as u32 <= layout.tag_count);
+            if !tags_valid || !valid(node.indirect) {
+                return Err(Errno::EINVAL);
+            }
+            if node.indirect != 0 {
+                read(node.indirect, &mut buf)?;
+                for chunk in buf.chunks(4) {
+                    node.blocks.push(u32_at(chunk, 0));
+                }
+            }
+            while node.blocks.last() == Some(&0) {
+                node.blocks.pop();
+            }
+            if !node.blocks.iter().all(|&block| valid(block)) {
+                return Err(Errno::EINVAL);
+            }
+        }
+        let mut tags = Vec::new();
+        for block in layout.tag_start..layout.tag_end() {
+            read(block, &mut buf)?;
+            for chunk in buf.chunks(TAG_SIZE) {
+                let len = min(chunk[0] as usize, MAX_TAG_NAME);
+                let name = str::from_utf8(&chunk[4..4 + len]).map_err(|_| Errno::EINVAL)?;
+                tags.push(if len == 0 {
+                    None
+                } else {
+                    Some(String::from(name))
+                });
+            }
+        }
+        tags.truncate(layout.tag_count as usize);
+        let next_serial = nodes.len() as u64;
+        let state = State {
+            layout,
+            bitmap,
+            nodes,
+            tags,
+            dirty: BTreeSet::new(),
+            next_serial,
+        };
+        Ok(TagFs(Arc::new(Volume {
+            device,
+            mounted: rtc::now(),
+            state: SleepLock::new(state),
+        })))
+    }
+}
+
+impl FileSystem for TagFs {
+    fn name(&self) -> &'static str {
+        "tag_fs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(TagDir {
+            volume: self.0.clone(),
+            tags: Vec::new(),
+        })
+    }
+
+    fn sync(&self) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        self.0.write_back(&mut state)?;
+        self.0.device.flush()
+    }
+}
+
+// a directory of the path view, the files that have all of its tags
+struct TagDir {
+    volume: Arc<Volume>,
+    tags: Vec<u16>, // in the order of the path
+}
+
+#[derive(Clone)]
+struct TagFile {
+    volume: Arc<Volume>,
+    index: usize,
+    serial: u64,
+}
+
+impl TagDir {
+    fn subdir(&self, tag: u16) -> Arc<dyn Inode> {
+        let mut tags = self.tags.clone();
+        tags.push(tag);
+        Arc::new(TagDir {
+            volume: self.volume.clone(),
+            tags,
+        })
+    }
+
+    fn file(&self, index: usize, serial: u64) -> Arc<dyn Inode> {
+        Arc::new(TagFile {
+            volume: self.volume.clone(),
+            index,
+            serial,
+        })
+    }
+}
+
+// names are unique among both the files and the tags of a filesystem
+fn check_name(state: &State, name: &str, max_len: usize) -> Result<(), Errno> {
+    if name.len() > max_len {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    if state.tag_id(name).is_some() || state.file_named(name).is_some() {
+        return Err(Errno::EEXIST);
+    }
+    Ok(())
+}
+
+fn add_tags(state: &mut State, index: usize, tags: &[u16]) -> Result<(), Errno> {
+    let node = state.nodes[index].as_mut().unwrap();
+    for &tag in tags {
+        if !node.tags.contains(&tag) {
+            if node.tags.len() == MAX_FILE_TAGS {
+                return Err(Errno::ENOSPC);
+            }
+            node.tags.push(tag);
+        }
+    }
+    node.ctime = rtc::now();
+    state.touch_node(index);
+    Ok(())
+}
+
+fn new_tag(state: &mut State, name: &str) -> Result<u16, Errno> {
+    check_name(state, name, MAX_TAG_NAME)?;
+    let index = state
+        .tags
+        .iter()
+        .position(Option::is_none)
+        .ok_or(Errno::ENOSPC)?;
+    state.tags[index] = Some(String::from(name));
+    let id = index as u16 + 1;
+    state.touch_tag(id);
+    Ok(id)
+}
+
+impl Inode for TagDir {
+    fn metadata(&self) -> Metadata {
+        let state = self.volume.state.lock();
+        let layout = &state.layout;
+        let ino = match self.tags.last() {
+            Some(&tag) => layout.inode_count as u64 + tag as u64,
+            None => layout.inode_count as u64 + layout.tag_count as u64 + 1,
+        };
+        Metadata {
+            ino,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            size: 0,
+            atime: self.volume.mounted,
+            mtime: self.volume.mounted,
+            ctime: self.volume.mounted,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_perm(&self, _perm: u32) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        let state = self.volume.state.lock();
+        if let Some(tag) = state.tag_id(name) {
+            return match self.tags.contains(&tag) {
+                true => Err(Errno::ENOENT),
+                false => Ok(self.subdir(tag)),
+            };
+        }
+        let (index, node) = state
+            .tagged(&self.tags)
+            .find(|(_, node)| node.name == name)
+            .ok_or(Errno::ENOENT)?;
+        Ok(self.file(index, node.serial))
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>, Errno> {
+        let state = self.volume.state.lock();
+        let layout = &state.layout;
+        // the tags of the files here that narrow them down, every tag at the root
+        let mut subdirs: BTreeSet<u16> = BTreeSet::new();
+        let mut files = BTreeMap::new();
+        for (index, node) in state.tagged(&self.tags) {
+            subdirs.extend(
+                node.tags
+                    .iter()
+                    .filter(|tag| !self.tags.contains(tag))
+                    .cloned(),
+            );
+            files.insert(node.name.as_str(), index);
+        }
+        if self.tags.is_empty() {
+            subdirs.extend(
+                (1..=state.tags.len() as u16).filter(|&id| state.tags[id as usize - 1].is_some()),
+            );
+        }
+        let mut entries = Vec::new();
+        for tag in subdirs {
+            entries.push(DirEntry {
+                ino: layout.inode_count as u64 + tag as u64,
+                kind: FileType::Directory,
+                name: String::from(state.tag_name(tag)),
+            });
+        }
+        for (name, index) in files {
+            entries.push(DirEntry {
+                ino: index as u64 + 1,
+                kind: FileType::Regular,
+                name: String::from(name),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Errno> {
+        let mut state = self.volume.state.lock();
+        let inode = match kind {
+            FileType::Directory => {
+                let tag = new_tag(&mut state, name)?;
+                self.subdir(tag)
+            }
+            FileType::Regular => {
+                check_name(&state, name, MAX_NAME)?;
+                let index = state
+                    .nodes
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(Errno::ENOSPC)?;
+                let serial = state.next_serial;
+                state.next_serial += 1;
+                let now = rtc::now();
+                state.nodes[index] = Some(Node {
+                    serial,
+                    perm: 0o644,
+                    size: 0,
+                    atime: now,
+                    mtime: now,
+                    ctime: now,
+                    name: String::from(name),
+                    tags: self.tags.clone(),
+                    blocks: Vec::new(),
+                    indirect: 0,
+                });
+                state.touch_node(index);
+                self.file(index, serial)
+            }
+            _ => return Err(Errno::EINVAL),
+        };
+        self.volume.write_back(&mut state)?;
+        Ok(inode)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<(), Errno> {
+        Err(Errno::EPERM)
+    }
+
+    // the file keeps its name, it only gets the tags of this directory
+    fn link(&self, name: &str, target: &dyn Inode) -> Result<(), Errno> {
+        let target = target
+            .as_any()
+            .downcast_ref::<TagFile>()
+            .ok_or(Errno::EXDEV)?;
+        if !Arc::ptr_eq(&target.volume, &self.volume) {
+            return Err(Errno::EXDEV);
+        }
+        let mut state = self.volume.state.lock();
+        if state.node(target.index, target.serial)?.name != name {
+            return Err(Errno::EINVAL);
+        }
+        add_tags(&mut state, target.index, &self.tags)?;
+        self.volume.write_back(&mut state)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        if let Some(tag) = state.tag_id(name) {
+            if state.tagged(&[tag]).next().is_some() {
+                return Err(Errno::ENOTEMPTY);
+            }
+            state.tags[tag as usize - 1] = None;
+            state.touch_tag(tag);
+            return self.volume.write_back(&mut state);
+        }
+        let (index, _) = state
+            .tagged(&self.tags)
+            .find(|(_, node)| node.name == name)
+            .ok_or(Errno::ENOENT)?;
+        match self.tags.last() {
+            Some(tag) => {
+                let node = state.nodes[index].as_mut().unwrap();
+                node.tags.retain(|other| other != tag);
+                node.ctime = rtc::now();
+                state.touch_node(index);
+            }
+            None => {
+                state.free_blocks_from(index, 0);
+                state.nodes[index] = None;
+            }
+        }
+        self.volume.write_back(&mut state)
+    }
+}
+
+impl Inode for TagFile {
+    fn metadata(&self) -> Metadata {
+        let state = self.volume.state.lock();
+        let node = state.node(self.index, self.serial).ok();
+        Metadata {
+            ino: self.index as u64 + 1,
+            kind: FileType::Regular,
+            perm: node.map_or(0, |node| node.perm),
+            nlink: if node.is_some() { 1 } else { 0 },
+            size: node.map_or(0, |node| node.size),
+            atime: node.map_or(0, |node| node.atime),
+            mtime: node.map_or(0, |node| node.mtime),
+            ctime: node.map_or(0, |node| node.ctime),
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_perm(&self, perm: u32) -> Result<(), Errno> {
+        let mut state = self.volume.state.lock();
+        let node = state.node_mut(self.index, self.serial)?;
+        node.perm = perm;
+        node.ctime = rtc::now();
+        state.touch_node(self.index);
+        self.volume.write_back(&mut state)
+    }
+
+    fn open(&self) -> Result<Arc<dyn vfs::File>, Errno> {
+        Ok(Arc::new(self.clone()))
+    }
+}
+
+impl vfs::File for TagFile {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        // the state stays locked while reading, a truncate could give the blocks to another file
+        let state = self.volume.state.lock();
+        let node = state.node(self.index, self.serial)?;
+        if offset >= node.size {
+            return Ok(0);
+        }
+        let count = min(buf.len() as u64, node.size - offset) as usize;
+        let first = (offset / BLOCK_SIZE as u64) as usize;
+        let last = ((offset + count as u64 - 1) / BLOCK_SIZE as u64) as usize;
+        let blocks: Vec<u32> = (first..=last)
+            .map(|slot| node.blocks.get(slot).cloned().unwrap_or(0))
+            .collect();
+        let mut block_buf = Vec::new();
+        block_buf.resize(BLOCK_SIZE, 0);
+        let mut done = 0;
+        for block in blocks {
+            let start = ((offset + done as u64) % BLOCK_SIZE as u64) as usize;
+            let len = min(BLOCK_SIZE - start, count - done);
+            let dst = &mut buf[done..done + len];
+            if block == 0 {
+                for byte in dst.iter_mut() {
+                    *byte = 0;
+                }
+            } else {
+                self.volume.read_block(block, &mut block_buf)?;
+                dst.copy_from_slice(&block_buf[start..start + len]);
+            }
+            done += len;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        if end > (MAX_BLOCKS * BLOCK_SIZE) as u64 {
+            return Err(Errno::EFBIG);
+        }
+        // allocate the blocks and grow the file, then write the data with the state still locked
+        // so that the blocks stay the file's
+        let first = (offset / BLOCK_SIZE as u64) as usize;
+        let last = ((end - 1) / BLOCK_SIZE as u64) as usize;
+        let mut blocks = Vec::new();
+        let mut state = self.volume.state.lock();
+        state.node(self.index, self.serial)?;
+        for slot in first..=last {
+            match state.file_block(self.index, slot) {
+                Ok(block) => blocks.push(block),
+                Err(errno) => {
+                    // give back the blocks this write got, nothing was written to them
+                    let new = blocks.iter().zip(first..).filter(|&(&(_, new), _)| new);
+                    state.release(self.index, new.map(|(&(block, _), slot)| (slot, block)));
+                    self.volume.write_back(&mut state)?;
+                    return Err(errno);
+                }
+            }
+        }
+        let node = state.node_mut(self.index, self.serial)?;
+        node.size = max(node.size, end);
+        node.mtime = rtc::now();
+        node.ctime = node.mtime;
+        state.touch_node(self.index);
+        self.volume.write_back(&mut state)?;
+        let mut block_buf = Vec::new();
+        block_buf.resize(BLOCK_SIZE, 0);
+        let mut done = 0;
+        for (block, new) in blocks {
+            let start = ((offset + done as u64) % BLOCK_SIZE as u64) as usize;
+            let len = min(BLOCK_SIZE - start, buf.len() - done);
+            if len < BLOCK_SIZE {
+                // the rest of the block stays as it is, or zeroes if the block is new
+                match new {
+                    true => block_buf.iter_mut().for_each(|byte| *byte = 0),
+                    false => self.volume.read_block(block, &mut block_buf)?,
+                }
+            }
+            block_buf[start..start + len].copy_from_slice(&buf[done..done + len]);
+            self.volume.write_block(block, &block_buf)?;
+            done += len;
+        }
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), Errno> {
+        if size > (MAX_BLOCKS * BLOCK_SIZE) as u64 {
+            return Err(Errno::EFBIG);
+        }
+        let mut state = self.volume.state.lock();
+        let node = state.node(self.index, self.serial)?;
+        // the end of the last block that is kept reads as zeroes when the file grows again
+        let tail = (size % BLOCK_SIZE as u64) as usize;
+        let slot = (size / BLOCK_SIZE as u64) as usize;
+        match node.blocks.get(slot).cloned() {
+            Some(block) if tail != 0 && block != 0 && size < node.size => {
+                let mut block_buf = Vec::new();
+                block_buf.resize(BLOCK_SIZE, 0);
+                self.volume.read_block(block, &mut block_buf)?;
+                block_buf[tail..].iter_mut().for_each(|byte| *byte = 0);
+                self.volume.write_block(block, &block_buf)?;
+            }
+            _ => {}
+        }
+        let kept = (size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        state.free_blocks_from(self.index, kept);
+        let node = state.node_mut(self.index, self.serial)?;
+        node.size = size;
+        node.mtime = rtc::now();
+        node.ctime = node.mtime;
+        self.volume.write_back(&mut state)
+    }
+}
+
+// what the tag syscalls work on, a path to a file or directory of a tag_fs
+enum Target<'a> {
+    Dir(&'a TagDir),
+    File(&'a TagFile),
+}
+
+fn target(inode: &dyn Inode) -> Result<Target, Errno> {
+    let any = inode.as_any();
+    if let Some(dir) = any.downcast_ref::<TagDir>() {
+        return Ok(Target::Dir(dir));
+    }
+    any.downcast_ref::<TagFile>()
+        .map(Target::File)
+        .ok_or(Errno::EINVAL)
+}
+
+fn file(inode: &dyn Inode) -> Result<&TagFile, Errno> {
+    match target(inode)? {
+        Target::File(file) => Ok(file),
+        Target::Dir(_) => Err(Errno::EISDIR),
+    }
+}
+
+// give a file a tag, which is made if the filesystem doesn't have it yet
+pub fn add_tag(inode: &dyn Inode, tag: &str) -> Result<(), Errno> {
+    let file = file(inode)?;
+    if tag.is_empty() || tag.contains('/') {
+        return Err(Errno::EINVAL);
+    }
+    let mut state = file.volume.state.lock();
+    state.node(file.index, file.serial)?;
+    let (id, created) = match state.tag_id(tag) {
+        Some(id) => (id, false),
+        None => (new_tag(&mut state, tag)?, true),
+    };
+    let result = add_tags(&mut state, file.index, &[id]);
+    // a tag made for a file that couldn't take it isn't kept
+    if result.is_err() && created {
+        state.tags[id as usize - 1] = None;
+        state.touch_tag(id);
+    }
+    file.volume.write_back(&mut state)?;
+    result
+}
+
+pub fn remove_tag(inode: &dyn Inode, tag: &str) -> Result<(), Errno> {
+    let file = file(inode)?;
+    let mut state = file.volume.state.lock();
+    let id = state.tag_id(tag).ok_or(Errno::ENOENT)?;
+    let node = state.node_mut(file.index, file.serial)?;
+    if !node.tags.contains(&id) {
+        return Err(Errno::ENOENT);
+    }
+    node.tags.retain(|&other| other != id);
+    node.ctime = rtc::now();
+    state.touch_node(file.index);
+    file.volume.write_back(&mut state)
+}
+
+// the tags of a file, or for a directory all tags of its filesystem
+pub fn tags(inode: &dyn Inode) -> Result<Vec<String>, Errno> {
+    let (volume, ids) = match target(inode)? {
+        Target::File(file) => {
+            let state = file.volume.state.lock();
+            let ids = state.node(file.index, file.serial)?.tags.clone();
+            (&file.volume, Some(ids))
+        }
+        Target::Dir(dir) => (&dir.volume, None),
+    };
+    let state = volume.state.lock();
+    let names: Vec<String> = match ids {
+        Some(ids) => ids
+            .iter()
+            .map(|&id| String::from(state.tag_name(id)))
+            .collect(),
+        None => state.tags.iter().flatten().cloned().collect(),
+    };
+    Ok(names)
+}
+
+// the names of the files that have all the tags and those of the directory
+pub fn query(inode: &dyn Inode, tags: &[&str]) -> Result<Vec<String>, Errno> {
+    let dir = match target(inode)? {
+        Target::Dir(dir) => dir,
+        Target::File(_) => return Err(Errno::ENOTDIR),
+    };
+    let state = dir.volume.state.lock();
+    let mut ids = dir.tags.clone();
+    for tag in tags {
+        ids.push(state.tag_id(tag).ok_or(Errno::ENOENT)?);
+    }
+    let mut names: Vec<String> = state
+        .tagged(&ids)
+        .map(|(_, node)| node.name.clone())
+        .collect();
+    names.sort();
+    Ok(names)
+}
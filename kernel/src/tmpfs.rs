@@ -0,0 +1,393 @@
+// A filesystem kept in memory. The contents of files are stored sparsely in page-sized blocks the
+// heap gets from the buddy allocator, pages that were never written read as zeroes. It is the
+// root, with the initrd unpacked into it, until there is a disk filesystem, and another one is
+// mounted at /tmp. Like size= of tmpfs on Linux each one has a limit on the pages its files hold,
+// so filling it ends in ENOSPC rather than in a kernel without heap.
+
+use crate::errno::Errno;
+use crate::mem::{EmptyFrame, FRAME_SIZE};
+use crate::rtc;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata};
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::cmp::{max, min};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// inode numbers are unique across all tmpfs instances
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+enum Content {
+    File {
+        size: u64,
+        pages: BTreeMap<u64, Box<EmptyFrame>>, // by page number in the file
+    },
+    Dir(BTreeMap<String, Arc<Node>>),
+    Symlink(String),
+}
+
+struct State {
+    perm: u32,
+    nlink: u32, // directories count their own . and the .. of subdirectories
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    content: Content,
+}
+
+struct Node {
+    ino: u64,
+    kind: FileType,
+    usage: Arc<Usage>, // of the filesystem the node is in
+    state: Mutex<State>,
+}
+
+// the pages the files of a filesystem hold and how many they may
+struct Usage {
+    pages: AtomicU64,
+    limit: u64,
+}
+
+impl Usage {
+    // a zeroed page for the contents of a file, ENOSPC if the filesystem is full or the heap has
+    // no room left
+    fn new_page(&self) -> Result<Box<EmptyFrame>, Errno> {
+        if self.pages.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            self.release(1);
+            return Err(Errno::ENOSPC);
+        }
+        let page = unsafe { alloc_zeroed(Layout::new::<EmptyFrame>()) as *mut EmptyFrame };
+        if page.is_null() {
+            self.release(1);
+            return Err(Errno::ENOSPC);
+        }
+        Ok(unsafe { Box::from_raw(page) })
+    }
+
+    fn release(&self, count: usize) {
+        self.pages.fetch_sub(count as u64, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Content::File { ref pages, .. } = self.state.lock().content {
+            self.usage.release(pages.len());
+        }
+    }
+}
+
+// a node as the VFS sees it, both the inode and the file that opening it gives
+#[derive(Clone)]
+struct TmpInode(Arc<Node>);
+
+pub struct TmpFs {
+    root: Arc<Node>,
+}
+
+fn new_node(usage: &Arc<Usage>, kind: FileType, perm: u32, content: Content) -> Arc<Node> {
+    let now = rtc::now();
+    Arc::new(Node {
+        ino: NEXT_INO.fetch_add(1, Ordering::SeqCst),
+        kind,
+        usage: usage.clone(),
+        state: Mutex::new(State {
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            atime: now,
+            mtime: now,
+            ctime: now,
+            content,
+        }),
+    })
+}
+
+fn inode(node: &Arc<Node>) -> Arc<dyn Inode> {
+    Arc::new(TmpInode(node.clone()))
+}
+
+impl TmpFs {
+    // an empty filesystem whose files hold at most size bytes, perm are the permissions of the
+    // root directory
+    pub fn new(perm: u32, size: u64) -> TmpFs {
+        let usage = Arc::new(Usage {
+            pages: AtomicU64::new(0),
+            limit: size / FRAME_SIZE,
+        });
+        TmpFs {
+            root: new_node(
+                &usage,
+                FileType::Directory,
+                perm,
+                Content::Dir(BTreeMap::new()),
+            ),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        inode(&self.root)
+    }
+}
+
+impl TmpInode {
+    // add a node to this directory
+    fn insert(&self, name: &str, node: Arc<Node>) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        let subdir = node.kind == FileType::Directory;
+        let entries = match state.content {
+            Content::Dir(ref mut entries) => entries,
+            _ => return Err(Errno::ENOTDIR),
+        };
+        if entries.contains_key(name) {
+            return Err(Errno::EEXIST);
+        }
+        entries.insert(String::from(name), node);
+        if subdir {
+            state.nlink += 1;
+        }
+        state.mtime = rtc::now();
+        state.ctime = state.mtime;
+        Ok(())
+    }
+}
+
+impl Inode for TmpInode {
+    fn metadata(&self) -> Metadata {
+        let state = self.0.state.lock();
+        let size = match state.content {
+            Content::File { size, .. } => size,
+            Content::Dir(ref entries) => entries.len() as u64,
+            Content::Symlink(ref target) => target.len() as u64,
+        };
+        Metadata {
+            ino: self.0.ino,
+            kind: self.0.kind,
+            perm: state.perm,
+            nlink: state.nlink,
+            size,
+            atime: state.atime,
+            mtime: state.mtime,
+            ctime: state.ctime,
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_perm(&self, perm: u32) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        state.perm = perm;
+        state.ctime = rtc::now();
+        Ok(())
+    }
+
+    fn open(&self) -> Result<Arc<dyn vfs::File>, Errno> {
+        Ok(Arc::new(self.clone()))
+    }
+
+    fn readlink(&self) -> Result<String, Errno> {
+        match self.0.state.lock().content {
+            Content::Symlink(ref target) => Ok(target.clone()),
+            _ => Err(Errno::EINVAL),
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        match self.0.state.lock().content {
+            Content::Dir(ref entries) => entries.get(name).map(inode).ok_or(Errno::ENOENT),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>, Errno> {
+        let mut state = self.0.state.lock();
+        state.atime = rtc::now();
+        match state.content {
+            Content::Dir(ref entries) => Ok(entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    ino: node.ino,
+                    kind: node.kind,
+                    name: name.clone(),
+                })
+                .collect()),
+            _ => Err(Errno::ENOTDIR),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, Errno> {
+        let node = match kind {
+            FileType::Regular => {
+                let content = Content::File {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                };
+                new_node(&self.0.usage, kind, 0o644, content)
+            }
+            FileType::Directory => {
+                new_node(&self.0.usage, kind, 0o755, Content::Dir(BTreeMap::new()))
+            }
+            _ => return Err(Errno::EINVAL),
+        };
+        self.insert(name, node.clone())?;
+        Ok(inode(&node))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<(), Errno> {
+        let content = Content::Symlink(String::from(target));
+        let node = new_node(&self.0.usage, FileType::Symlink, 0o777, content);
+        self.insert(name, node)
+    }
+
+    fn link(&self, name: &str, target: &dyn Inode) -> Result<(), Errno> {
+        let target = target.as_any().downcast_ref::<TmpInode>();
+        let node = target.ok_or(Errno::EXDEV)?.0.clone();
+        self.insert(name, node.clone())?;
+        let mut state = node.state.lock();
+        state.nlink += 1;
+        state.ctime = rtc::now();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), Errno> {
+        let mut state = self.0.state.lock();
+        let removed = match state.content {
+            Content::Dir(ref mut entries) => {
+                let node = entries.get(name).ok_or(Errno::ENOENT)?;
+                let mut node_state = node.state.lock();
+                if let Content::Dir(ref children) = node_state.content {
+                    if !children.is_empty() {
+                        return Err(Errno::ENOTEMPTY);
+                    }
+                    node_state.nlink = 0;
+                } else {
+                    node_state.nlink -= 1;
+                }
+                node_state.ctime = rtc::now();
+                drop(node_state);
+                entries.remove(name).unwrap()
+            }
+            _ => return Err(Errno::ENOTDIR),
+        };
+        if removed.kind == FileType::Directory {
+            state.nlink -= 1;
+        }
+        state.mtime = rtc::now();
+        state.ctime = state.mtime;
+        // open files keep the node and its pages until they are closed
+        drop(state);
+        drop(removed);
+        Ok(())
+    }
+}
+
+impl vfs::File for TmpInode {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+        let mut state = self.0.state.lock();
+        state.atime = rtc::now();
+        let (size, pages) = match state.content {
+            Content::File {
+                ref size,
+                ref pages,
+            } => (*size, pages),
+            _ => return Err(Errno::EISDIR),
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = min(buf.len() as u64, size - offset) as usize;
+        let mut done = 0;
+        while done < count {
+            let pos = offset + done as u64;
+            let start = (pos % FRAME_SIZE) as usize;
+            let len = min(FRAME_SIZE as usize - start, count - done);
+            let dst = &mut buf[done..done + len];
+            match pages.get(&(pos / FRAME_SIZE)) {
+                Some(page) => dst.copy_from_slice(&page[start..start + len]),
+                None => {
+                    for byte in dst.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+            }
+            done += len;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, Errno> {
+        offset.checked_add(buf.len() as u64).ok_or(Errno::EFBIG)?;
+        let mut state = self.0.state.lock();
+        let (size, pages) = match state.content {
+            Content::File {
+                ref mut size,
+                ref mut pages,
+            } => (size, pages),
+            _ => return Err(Errno::EISDIR),
+        };
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let start = (pos % FRAME_SIZE) as usize;
+            let len = min(FRAME_SIZE as usize - start, buf.len() - done);
+            let index = pos / FRAME_SIZE;
+            if !pages.contains_key(&index) {
+                match self.0.usage.new_page() {
+                    Ok(page) => {
+                        pages.insert(index, page);
+                    }
+                    // what was written so far stays, the write is short
+                    Err(errno) if done == 0 => return Err(errno),
+                    Err(_) => break,
+                }
+            }
+            let page = pages.get_mut(&index).unwrap();
+            page[start..start + len].copy_from_slice(&buf[done..done + len]);
+            done += len;
+        }
+        *size = max(*size, offset + done as u64);
+        state.mtime = rtc::now();
+        state.ctime = state.mtime;
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), Errno> {
+        let end = new_size.checked_add(FRAME_SIZE - 1).ok_or(Errno::EFBIG)?;
+        let mut state = self.0.state.lock();
+        match state.content {
+            Content::File {
+                ref mut size,
+                ref mut pages,
+            } => {
+                // drop the pages past the end and zero the rest of the last one
+                let kept = end / FRAME_SIZE;
+                let dropped = pages.split_off(&kept);
+                self.0.usage.release(dropped.len());
+                if new_size % FRAME_SIZE != 0 {
+                    if let Some(page) = pages.get_mut(&(new_size / FRAME_SIZE)) {
+                        for byte in page[(new_size % FRAME_SIZE) as usize..].iter_mut() {
+                            *byte = 0;
+                        }
+                    }
+                }
+                *size = new_size;
+            }
+            _ => return Err(Errno::EISDIR),
+        }
+        state.mtime = rtc::now();
+        state.ctime = state.mtime;
+        Ok(())
+    }
+}
@@ -0,0 +1,191 @@
+// Access to userspace memory from syscalls. Every range is checked against the current
+// (i.e. the calling task's) page table before it is touched, and page faults taken while
+// copying are turned into an error by the page fault handler instead of being fatal.
+
+use crate::errno::Errno;
+use crate::mem::{self, VirtAddr, BIT_USER, BIT_WRITABLE, FRAME_SIZE};
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+// a user pointer was invalid or faulted while being accessed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Efault;
+
+impl From<Efault> for Errno {
+    fn from(_: Efault) -> Errno {
+        Errno::EFAULT
+    }
+}
+
+extern "C" {
+    static uaccess_copy_start: u8;
+    static uaccess_copy_end: u8;
+    static uaccess_copy_fixup: u8;
+}
+
+// rep movsb with a landing pad for faults, returns 0 on success and 1 if it faulted
+#[naked]
+#[inline(never)]
+unsafe extern "C" fn copy_user_raw(_dst: *mut u8, _src: *const u8, _len: usize) -> u64 {
+    asm!("\
+    .global uaccess_copy_start
+    .global uaccess_copy_end
+    .global uaccess_copy_fixup
+    mov rcx, rdx // rdi and rsi are already the destination and source
+    uaccess_copy_start:
+    rep movsb
+    uaccess_copy_end:
+    xor eax, eax
+    ret
+    uaccess_copy_fixup:
+    mov eax, 1
+    ret",
+    options(noreturn));
+}
+
+// called by the page fault handler, gives the address to continue at if the fault came from a copy
+pub fn fixup_address(rip: u64) -> Option<u64> {
+    unsafe {
+        let start = &uaccess_copy_start as *const u8 as u64;
+        let end = &uaccess_copy_end as *const u8 as u64;
+        if rip >= start && rip < end {
+            Some(&uaccess_copy_fixup as *const u8 as u64)
+        } else {
+            None
+        }
+    }
+}
+
+// check that [addr, addr + len) lies in userspace and that every page of it is mapped for the user
+pub fn check_range(addr: u64, len: usize, write: bool) -> Result<(), Efault> {
+    let end = addr.checked_add(len as u64).ok_or(Efault)?;
+    if addr < mem::USER_START || end > mem::USER_END {
+        return Err(Efault);
+    }
+    let mut page = addr & !(FRAME_SIZE - 1);
+    while page < end {
+        let (_, options) =
+            unsafe { mem::get_page_table().lookup(VirtAddr::new(page)) }.ok_or(Efault)?;
+        if options & BIT_USER == 0 || (write && options & BIT_WRITABLE == 0) {
+            return Err(Efault);
+        }
+        page += FRAME_SIZE;
+    }
+    Ok(())
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Efault> {
+    check_range(src, dst.len(), false)?;
+    match unsafe { copy_user_raw(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Efault> {
+    check_range(dst, src.len(), true)?;
+    match unsafe { copy_user_raw(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(Efault),
+    }
+}
+
+// copy a NUL-terminated string of at most max_len bytes (without the NUL),
+// the string is cut off if it is longer than that
+pub fn strncpy_from_user(src: u64, max_len: usize) -> Result<Vec<u8>, Efault> {
+    let mut string = Vec::new();
+    let mut buf = [0u8; 256];
+    let mut addr = src;
+    while string.len() < max_len {
+        // never read past the current page, the next one might not be mapped
+        let page_left = (FRAME_SIZE - (addr & (FRAME_SIZE - 1))) as usize;
+        let chunk = min(min(page_left, buf.len()), max_len - string.len());
+        copy_from_user(&mut buf[..chunk], addr)?;
+        match buf[..chunk].iter().position(|b| *b == 0) {
+            Some(nul) => {
+                string.extend_from_slice(&buf[..nul]);
+                return Ok(string);
+            }
+            None => string.extend_from_slice(&buf[..chunk]),
+        }
+        addr += chunk as u64;
+    }
+    Ok(string)
+}
+
+// a checked array of T in userspace, T should be plain data without invalid bit patterns
+pub struct UserSlice<T: Copy> {
+    addr: u64,
+    len: usize,
+    _t: PhantomData<T>,
+}
+
+impl<T: Copy> UserSlice<T> {
+    pub fn new(addr: u64, len: usize) -> Result<UserSlice<T>, Efault> {
+        let bytes = len.checked_mul(size_of::<T>()).ok_or(Efault)?;
+        let end = addr.checked_add(bytes as u64).ok_or(Efault)?;
+        if addr < mem::USER_START || end > mem::USER_END {
+            return Err(Efault);
+        }
+        Ok(UserSlice {
+            addr,
+            len,
+            _t: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    fn byte_len(&self) -> usize {
+        self.len * size_of::<T>()
+    }
+
+    pub fn read(&self, idx: usize) -> Result<T, Efault> {
+        if idx >= self.len {
+            return Err(Efault);
+        }
+        let mut val = core::mem::MaybeUninit::<T>::uninit();
+        let dst =
+            unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        copy_from_user(dst, self.addr + (idx * size_of::<T>()) as u64)?;
+        Ok(unsafe { val.assume_init() })
+    }
+
+    pub fn read_all(&self) -> Result<Vec<T>, Efault> {
+        let mut vals: Vec<T> = Vec::with_capacity(self.len);
+        unsafe {
+            let dst =
+                core::slice::from_raw_parts_mut(vals.as_mut_ptr() as *mut u8, self.byte_len());
+            copy_from_user(dst, self.addr)?;
+            vals.set_len(self.len);
+        }
+        Ok(vals)
+    }
+
+    pub fn write(&self, idx: usize, val: T) -> Result<(), Efault> {
+        if idx >= self.len {
+            return Err(Efault);
+        }
+        let src =
+            unsafe { core::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr + (idx * size_of::<T>()) as u64, src)
+    }
+
+    // write as much of vals as fits, returning how many elements were written
+    pub fn write_from(&self, vals: &[T]) -> Result<usize, Efault> {
+        let count = min(vals.len(), self.len);
+        let src = unsafe {
+            core::slice::from_raw_parts(vals.as_ptr() as *const u8, count * size_of::<T>())
+        };
+        copy_to_user(self.addr, src)?;
+        Ok(count)
+    }
+}
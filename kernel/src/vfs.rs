@@ -0,0 +1,660 @@
+// The virtual filesystem: filesystems are mounted at directories and paths are walked across
+// them. A filesystem hands out an Inode for each of its files, directories and symlinks, and
+// opening a regular file gets a File to read and write it at offsets. What userspace holds a
+// descriptor for is an OpenFile, which keeps the offset and the flags it was opened with.
+//
+// Every task has a root, the directory / is for it, and a working directory. Both are paths
+// without symlinks in them, so getcwd has the working directory at hand and .. goes back up the
+// directories walked, also across mount points.
+
+use crate::abi::{self, Stat};
+use crate::errno::Errno;
+use crate::fd::{FileDescription, FileRef};
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use lazy_static::lazy_static;
+use spin::{Mutex, RwLock};
+
+// symlinks followed while resolving one path before giving up with ELOOP
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+}
+
+impl FileType {
+    fn mode(self) -> u32 {
+        match self {
+            FileType::Regular => abi::S_IFREG,
+            FileType::Directory => abi::S_IFDIR,
+            FileType::Symlink => abi::S_IFLNK,
+            FileType::CharDevice => abi::S_IFCHR,
+        }
+    }
+
+    fn dirent_type(self) -> u8 {
+        match self {
+            FileType::Regular => abi::DT_REG,
+            FileType::Directory => abi::DT_DIR,
+            FileType::Symlink => abi::DT_LNK,
+            FileType::CharDevice => abi::DT_CHR,
+        }
+    }
+}
+
+pub struct Metadata {
+    pub ino: u64,
+    pub kind: FileType,
+    pub perm: u32, // permission bits, nothing checks them yet
+    pub nlink: u32,
+    pub size: u64,
+    pub atime: u64, // seconds since 1970
+    pub mtime: u64,
+    pub ctime: u64,
+}
+
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+// a file, directory or symlink of a filesystem, the VFS only calls the operations that fit the
+// type of the inode, the ones that change a directory fail like on a read-only filesystem
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    // for a filesystem to get back its own type of inode, e.g. the target of a hard link
+    fn as_any(&self) -> &dyn Any;
+
+    fn set_perm(&self, _perm: u32) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    // regular files and devices
+    fn open(&self) -> Result<Arc<dyn File>, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    // symlinks
+    fn readlink(&self) -> Result<String, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    // directories, lookup fails with ENOENT if there is no entry with the name
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>, Errno> {
+        Err(Errno::ENOTDIR)
+    }
+
+    // a new empty file or directory, the name isn't taken yet
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    // a new entry for an inode of the same filesystem that isn't a directory
+    fn link(&self, _name: &str, _target: &dyn Inode) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+
+    // remove an entry, directories only when they are empty
+    fn unlink(&self, _name: &str) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+// the data of an open regular file or device
+pub trait File: Send + Sync {
+    // returns 0 at the end of the file
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, Errno>;
+
+    // writing past the end makes the file larger
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, Errno> {
+        Err(Errno::EROFS)
+    }
+
+    // new bytes are zeroes
+    fn truncate(&self, _size: u64) -> Result<(), Errno> {
+        Err(Errno::EROFS)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // write back what is cached, for filesystems on disks
+    fn sync(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+struct Mount {
+    path: String, // seen from the root of tasks that didn't chroot
+    fs: Arc<dyn FileSystem>,
+}
+
+lazy_static! {
+    // the first filesystem is mounted at /, the index of a mount is the dev in a Stat
+    static ref MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+}
+
+// where the paths of a task start from
+#[derive(Clone)]
+pub struct FsContext {
+    pub root: String, // seen from the root of all mounts
+    pub cwd: String,  // seen from root
+}
+
+impl FsContext {
+    // what the first task and the kernel itself use
+    pub fn new() -> FsContext {
+        FsContext {
+            root: String::from("/"),
+            cwd: String::from("/"),
+        }
+    }
+}
+
+// a directory passed on a walk, or where it ended
+#[derive(Clone)]
+struct Step {
+    name: String,
+    inode: Arc<dyn Inode>,
+    mount: usize,
+}
+
+struct Walk {
+    // a copy of the mount table, so no lock is held while filesystems are busy
+    mounts: Vec<(String, Arc<dyn FileSystem>)>,
+    steps: Vec<Step>, // starting with /
+    floor: usize,     // steps up to the task's root, .. doesn't go above it
+    symlinks: usize,
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/').filter(|part| !part.is_empty())
+}
+
+fn is_dir(inode: &dyn Inode) -> bool {
+    inode.metadata().kind == FileType::Directory
+}
+
+impl Walk {
+    // a walk that is at the root of all mounts
+    fn new() -> Result<Walk, Errno> {
+        let mounts: Vec<_> = MOUNTS
+            .read()
+            .iter()
+            .map(|mount| (mount.path.clone(), mount.fs.clone()))
+            .collect();
+        let root = mounts.first().ok_or(Errno::ENOENT)?.1.root();
+        let mut steps = Vec::new();
+        steps.push(Step {
+            name: String::new(),
+            inode: root,
+            mount: 0,
+        });
+        Ok(Walk {
+            mounts,
+            steps,
+            floor: 1,
+            symlinks: 0,
+        })
+    }
+
+    fn top(&self) -> &Step {
+        self.steps.last().unwrap()
+    }
+
+    fn path_from(&self, start: usize) -> String {
+        if start >= self.steps.len() {
+            return String::from("/");
+        }
+        let mut path = String::new();
+        for step in &self.steps[start..] {
+            path.push('/');
+            path.push_str(&step.name);
+        }
+        path
+    }
+
+    // where the walk is, seen from the root of all mounts
+    fn global_path(&self) -> String {
+        self.path_from(1)
+    }
+
+    // and from the task's root
+    fn path(&self) -> String {
+        self.path_from(self.floor)
+    }
+
+    fn writable(&self) -> Result<(), Errno> {
+        match self.mounts[self.top().mount].1.read_only() {
+            true => Err(Errno::EROFS),
+            false => Ok(()),
+        }
+    }
+
+    // the mount with the root at the global path, if any
+    fn mount_at(&self, path: &str) -> Option<usize> {
+        self.mounts.iter().position(|(at, _)| at == path)
+    }
+
+    // go to the child of the directory the walk is at, into what is mounted there if anything
+    fn child(&self, name: &str) -> Result<Step, Errno> {
+        let top = self.top();
+        if !is_dir(&*top.inode) {
+            return Err(Errno::ENOTDIR);
+        }
+        let mut path = self.global_path();
+        if path != "/" {
+            path.push('/');
+        }
+        path.push_str(name);
+        let step = match self.mount_at(&path) {
+            Some(mount) => Step {
+                name: name.to_string(),
+                inode: self.mounts[mount].1.root(),
+                mount,
+            },
+            None => Step {
+                name: name.to_string(),
+                inode: top.inode.lookup(name)?,
+                mount: top.mount,
+            },
+        };
+        Ok(step)
+    }
+
+    // walk along path, symlinks are followed unless the last component is one and follow_last
+    // is false, then the walk ends at the symlink itself
+    fn walk(&mut self, path: &str, follow_last: bool) -> Result<(), Errno> {
+        if path.starts_with('/') {
+            self.steps.truncate(self.floor);
+        }
+        let mut queue: VecDeque<String> = components(path).map(String::from).collect();
+        while let Some(name) = queue.pop_front() {
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if self.steps.len() > self.floor {
+                        self.steps.pop();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            let step = self.child(&name)?;
+            let last = queue.is_empty();
+            if step.inode.metadata().kind == FileType::Symlink && (follow_last || !last) {
+                self.symlinks += 1;
+                if self.symlinks > MAX_SYMLINKS {
+                    return Err(Errno::ELOOP);
+                }
+                // continue with the target in place of the symlink
+                let target = step.inode.readlink()?;
+                if target.starts_with('/') {
+                    self.steps.truncate(self.floor);
+                }
+                for part in components(&target).rev() {
+                    queue.push_front(String::from(part));
+                }
+                continue;
+            }
+            self.steps.push(step);
+        }
+        Ok(())
+    }
+}
+
+// walk to where path leads for a task
+fn lookup(ctx: &FsContext, path: &str, follow_last: bool) -> Result<Walk, Errno> {
+    if path.is_empty() {
+        return Err(Errno::ENOENT);
+    }
+    let mut walk = Walk::new()?;
+    walk.walk(&ctx.root, true)?;
+    walk.floor = walk.steps.len();
+    if !path.starts_with('/') {
+        walk.walk(&ctx.cwd, true)?;
+    }
+    walk.walk(path, follow_last)?;
+    Ok(walk)
+}
+
+// walk to the directory the last component of path is in, returns the walk and that component
+fn lookup_parent(ctx: &FsContext, path: &str) -> Result<(Walk, String), Errno> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rfind('/') {
+        Some(slash) => (&path[..slash + 1], &path[slash + 1..]),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Errno::EINVAL);
+    }
+    let walk = lookup(ctx, dir, true)?;
+    if !is_dir(&*walk.top().inode) {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok((walk, name.to_string()))
+}
+
+// make a new file or directory at path, returns the walk that ends at it
+fn create(ctx: &FsContext, path: &str, kind: FileType) -> Result<Walk, Errno> {
+    let (mut walk, name) = lookup_parent(ctx, path)?;
+    match walk.child(&name) {
+        Ok(_) => return Err(Errno::EEXIST),
+        Err(Errno::ENOENT) => {}
+        Err(errno) => return Err(errno),
+    }
+    walk.writable()?;
+    let top = walk.top();
+    let step = Step {
+        inode: top.inode.create(&name, kind)?,
+        mount: top.mount,
+        name,
+    };
+    walk.steps.push(step);
+    Ok(walk)
+}
+
+fn stat_of(inode: &dyn Inode, mount: usize) -> Stat {
+    let metadata = inode.metadata();
+    Stat {
+        dev: mount as u64,
+        ino: metadata.ino,
+        mode: metadata.kind.mode() | metadata.perm,
+        nlink: metadata.nlink,
+        size: metadata.size,
+        atime: metadata.atime,
+        mtime: metadata.mtime,
+        ctime: metadata.ctime,
+    }
+}
+
+// mount fs at the directory path, the first filesystem mounted has to go to /
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), Errno> {
+    let path = if MOUNTS.read().is_empty() {
+        if path != "/" {
+            return Err(Errno::ENOENT);
+        }
+        String::from(path)
+    } else {
+        let walk = lookup(&FsContext::new(), path, true)?;
+        if !is_dir(&*walk.top().inode) {
+            return Err(Errno::ENOTDIR);
+        }
+        walk.global_path()
+    };
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(Errno::EBUSY);
+    }
+    log::info!("mounted {} at {}", fs.name(), path);
+    mounts.push(Mount { path, fs });
+    Ok(())
+}
+
+// open a file or directory with the O_* flags of abi.rs
+pub fn open(ctx: &FsContext, path: &str, flags: u64) -> Result<FileRef, Errno> {
+    let access = flags & abi::O_ACCMODE;
+    if access == abi::O_ACCMODE {
+        return Err(Errno::EINVAL);
+    }
+    let creating = flags & abi::O_CREAT != 0;
+    let walk = match lookup(ctx, path, true) {
+        Ok(_) if creating && flags & abi::O_EXCL != 0 => return Err(Errno::EEXIST),
+        Ok(walk) => walk,
+        Err(Errno::ENOENT) if creating => create(ctx, path, FileType::Regular)?,
+        Err(errno) => return Err(errno),
+    };
+    let writing = access != abi::O_RDONLY;
+    let top = walk.top();
+    let file = match top.inode.metadata().kind {
+        FileType::Directory if writing => return Err(Errno::EISDIR),
+        FileType::Directory => None,
+        _ if flags & abi::O_DIRECTORY != 0 => return Err(Errno::ENOTDIR),
+        _ => {
+            if writing {
+                walk.writable()?;
+            }
+            let file = top.inode.open()?;
+            if writing && flags & abi::O_TRUNC != 0 {
+                file.truncate(0)?;
+            }
+            Some(file)
+        }
+    };
+    Ok(Arc::new(OpenFile {
+        inode: top.inode.clone(),
+        file,
+        mount: top.mount,
+        flags,
+        offset: Mutex::new(0),
+    }))
+}
+
+pub fn stat(ctx: &FsContext, path: &str) -> Result<Stat, Errno> {
+    let walk = lookup(ctx, path, true)?;
+    let top = walk.top();
+    Ok(stat_of(&*top.inode, top.mount))
+}
+
+// the inode at path, for syscalls that only some filesystems have
+pub fn inode(ctx: &FsContext, path: &str, writing: bool) -> Result<Arc<dyn Inode>, Errno> {
+    let walk = lookup(ctx, path, true)?;
+    if writing {
+        walk.writable()?;
+    }
+    Ok(walk.top().inode.clone())
+}
+
+// change the permission bits
+pub fn chmod(ctx: &FsContext, path: &str, perm: u32) -> Result<(), Errno> {
+    let walk = lookup(ctx, path, true)?;
+    walk.writable()?;
+    walk.top().inode.set_perm(perm & 0o7777)
+}
+
+// make new_path another name for the file at old_path
+pub fn link(ctx: &FsContext, old_path: &str, new_path: &str) -> Result<(), Errno> {
+    let target = lookup(ctx, old_path, false)?;
+    let (walk, name) = lookup_parent(ctx, new_path)?;
+    match walk.child(&name) {
+        Ok(_) => return Err(Errno::EEXIST),
+        Err(Errno::ENOENT) => {}
+        Err(errno) => return Err(errno),
+    }
+    let target = target.top();
+    if is_dir(&*target.inode) {
+        return Err(Errno::EPERM);
+    }
+    if target.mount != walk.top().mount {
+        return Err(Errno::EXDEV);
+    }
+    walk.writable()?;
+    walk.top().inode.link(&name, &*target.inode)
+}
+
+// make a symlink at path that points to target
+pub fn symlink(ctx: &FsContext, target: &str, path: &str) -> Result<(), Errno> {
+    let (walk, name) = lookup_parent(ctx, path)?;
+    match walk.child(&name) {
+        Ok(_) => return Err(Errno::EEXIST),
+        Err(Errno::ENOENT) => {}
+        Err(errno) => return Err(errno),
+    }
+    walk.writable()?;
+    walk.top().inode.symlink(&name, target)
+}
+
+pub fn mkdir(ctx: &FsContext, path: &str) -> Result<(), Errno> {
+    create(ctx, path, FileType::Directory).map(|_| ())
+}
+
+// remove a file or symlink, or with dir an empty directory
+fn remove(ctx: &FsContext, path: &str, dir: bool) -> Result<(), Errno> {
+    let (walk, name) = lookup_parent(ctx, path)?;
+    let target = walk.child(&name)?;
+    match (is_dir(&*target.inode), dir) {
+        (true, false) => return Err(Errno::EISDIR),
+        (false, true) => return Err(Errno::ENOTDIR),
+        _ => {}
+    }
+    if target.mount != walk.top().mount {
+        return Err(Errno::EBUSY);
+    }
+    walk.writable()?;
+    walk.top().inode.unlink(&name)
+}
+
+pub fn unlink(ctx: &FsContext, path: &str) -> Result<(), Errno> {
+    remove(ctx, path, false)
+}
+
+pub fn rmdir(ctx: &FsContext, path: &str) -> Result<(), Errno> {
+    remove(ctx, path, true)
+}
+
+// the working directory after changing to path
+pub fn chdir(ctx: &FsContext, path: &str) -> Result<String, Errno> {
+    let walk = lookup(ctx, path, true)?;
+    if !is_dir(&*walk.top().inode) {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(walk.path())
+}
+
+// the context after making path the root, the working directory moves there too
+pub fn chroot(ctx: &FsContext, path: &str) -> Result<FsContext, Errno> {
+    let walk = lookup(ctx, path, true)?;
+    if !is_dir(&*walk.top().inode) {
+        return Err(Errno::ENOTDIR);
+    }
+    Ok(FsContext {
+        root: walk.global_path(),
+        cwd: String::from("/"),
+    })
+}
+
+// the whole contents of a regular file, e.g. a program to run
+pub fn read_file(ctx: &FsContext, path: &str) -> Result<Vec<u8>, Errno> {
+    let file = open(ctx, path, abi::O_RDONLY)?;
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 512];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(data),
+            count => data.extend_from_slice(&chunk[..count]),
+        }
+    }
+}
+
+// an open file or directory, for directories the offset counts entries
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    file: Option<Arc<dyn File>>, // None for directories
+    mount: usize,
+    flags: u64,
+    offset: Mutex<u64>,
+}
+
+impl FileDescription for OpenFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.flags & abi::O_ACCMODE == abi::O_WRONLY {
+            return Err(Errno::EBADF);
+        }
+        let file = self.file.as_ref().ok_or(Errno::EISDIR)?;
+        // filesystems may block, so the offset isn't kept locked meanwhile
+        let offset = *self.offset.lock();
+        let count = file.read_at(offset, buf)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize, Errno> {
+        if self.flags & abi::O_ACCMODE == abi::O_RDONLY {
+            return Err(Errno::EBADF);
+        }
+        let file = self.file.as_ref().ok_or(Errno::EISDIR)?;
+        let offset = match self.flags & abi::O_APPEND {
+            0 => *self.offset.lock(),
+            _ => self.inode.metadata().size,
+        };
+        let count = file.write_at(offset, buf)?;
+        *self.offset.lock() = offset + count as u64;
+        Ok(count)
+    }
+
+    fn seek(&self, offset: i64, whence: u64) -> Result<u64, Errno> {
+        let base = match whence {
+            abi::SEEK_SET => 0,
+            abi::SEEK_CUR => *self.offset.lock(),
+            abi::SEEK_END => self.inode.metadata().size,
+            _ => return Err(Errno::EINVAL),
+        };
+        let offset = (base as i64)
+            .checked_add(offset)
+            .filter(|&offset| offset >= 0)
+            .ok_or(Errno::EINVAL)?;
+        *self.offset.lock() = offset as u64;
+        Ok(offset as u64)
+    }
+
+    fn stat(&self) -> Result<Stat, Errno> {
+        Ok(stat_of(&*self.inode, self.mount))
+    }
+
+    fn getdents(&self, buf: &mut [u8]) -> Result<usize, Errno> {
+        if self.file.is_some() {
+            return Err(Errno::ENOTDIR);
+        }
+        let entries = self.inode.entries()?;
+        let mut next = *self.offset.lock() as usize;
+        let mut len = 0;
+        for entry in entries.iter().skip(next) {
+            let name = entry.name.as_bytes();
+            let record_len = (abi::DIRENT_NAME_OFFSET + name.len() + 1 + 7) & !7;
+            if len + record_len > buf.len() {
+                if len == 0 {
+                    return Err(Errno::EINVAL);
+                }
+                break;
+            }
+            next += 1;
+            let record = &mut buf[len..len + record_len];
+            record[0..8].copy_from_slice(&entry.ino.to_le_bytes());
+            record[8..16].copy_from_slice(&(next as u64).to_le_bytes());
+            record[16..18].copy_from_slice(&(record_len as u16).to_le_bytes());
+            record[18] = entry.kind.dirent_type();
+            let name_end = abi::DIRENT_NAME_OFFSET + name.len();
+            record[abi::DIRENT_NAME_OFFSET..name_end].copy_from_slice(name);
+            for byte in &mut record[name_end..] {
+                *byte = 0;
+            }
+            len += record_len;
+        }
+        *self.offset.lock() = next as u64;
+        Ok(len)
+    }
+}
@@ -102,6 +102,13 @@ impl ScreenWriter {
     pub fn write(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            0x08 => {
+                // backspace, only within the current line
+                if self.col > 0 {
+                    self.col -= 1;
+                    self.buffer.chars[self.row][self.col].chr = b' ';
+                }
+            }
             b => {
                 let fg_u4 = self.fg_color as u8 & 0b1111;
                 let bg_u3 = self.bg_color as u8 & 0b1111;
@@ -0,0 +1,354 @@
+// Virtio devices on PCI, the version 1.0 ("modern") interface: vendor capabilities point into
+// the BARs at the common configuration, where features are negotiated and queues set up, the
+// registers to notify a queue and the configuration of the device type.
+//
+// A Virtqueue is a split ring: a table of descriptors of buffers, a ring where the driver puts
+// the chains of descriptors it hands to the device and one where the device returns them once
+// it is done. All three are in memory the device reads and writes by DMA.
+
+use crate::dma::DmaBuffer;
+use crate::errno::Errno;
+use crate::mem::{PhysAddr, FRAME_SIZE};
+use crate::pci::{self, Bar};
+use core::mem::size_of;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, spin_loop_hint, Ordering};
+
+pub const VENDOR: u16 = 0x1af4;
+
+// where the structures are, in the cfg_type of a vendor capability
+const CAP_COMMON: u8 = 1;
+const CAP_NOTIFY: u8 = 2;
+const CAP_DEVICE: u8 = 4;
+
+// the common configuration
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0;
+const COMMON_DEVICE_FEATURE: u64 = 4;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 8;
+const COMMON_DRIVER_FEATURE: u64 = 12;
+const COMMON_STATUS: u64 = 20;
+const COMMON_CONFIG_GENERATION: u64 = 21;
+const COMMON_QUEUE_SELECT: u64 = 22;
+const COMMON_QUEUE_SIZE: u64 = 24;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 26;
+const COMMON_QUEUE_ENABLE: u64 = 28;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 30;
+const COMMON_QUEUE_DESC: u64 = 32;
+const COMMON_QUEUE_DRIVER: u64 = 40;
+const COMMON_QUEUE_DEVICE: u64 = 48;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+// devices that only speak the legacy interface don't offer it
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const NO_VECTOR: u16 = 0xffff;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// a reset that takes longer than this many polls of the status means the device is broken
+const RESET_SPINS: usize = 1_000_000;
+
+// the registers of a device, mapped from its BARs
+pub struct Transport {
+    common: u64,
+    notify: u64,
+    notify_multiplier: u32,
+    device: u64,
+}
+
+// where a structure a vendor capability describes is mapped
+fn map_cap(device: &pci::Device, cap: u16) -> Result<u64, Errno> {
+    let bar: u8 = device.read(cap + 4);
+    let offset: u32 = device.read(cap + 8);
+    let base = match device.bars.get(bar as usize) {
+        Some(&Bar::Memory { addr, .. }) => addr,
+        _ => return Err(Errno::ENODEV),
+    };
+    // BARs are only reached below 4 GiB, where all of physical memory is mapped
+    let virt = unsafe { PhysAddr::new(base + offset as u64).to_virt() };
+    virt.map(|virt| virt.addr()).ok_or(Errno::ENODEV)
+}
+
+impl Transport {
+    pub fn new(device: &pci::Device) -> Result<Transport, Errno> {
+        let (mut common, mut notify, mut config) = (None, None, None);
+        let mut notify_multiplier = 0;
+        let caps = device
+            .capabilities
+            .iter()
+            .filter(|&&(id, _)| id == pci::CAP_VENDOR);
+        // the first of each type is the one to use
+        for &(_, cap) in caps {
+            let kind: u8 = device.read(cap + 3);
+            match kind {
+                CAP_COMMON if common.is_none() => common = Some(map_cap(device, cap)?),
+                CAP_NOTIFY if notify.is_none() => {
+                    notify = Some(map_cap(device, cap)?);
+                    notify_multiplier = device.read(cap + 16);
+                }
+                CAP_DEVICE if config.is_none() => config = Some(map_cap(device, cap)?),
+                _ => {}
+            }
+        }
+        device.enable(true);
+        Ok(Transport {
+            common: common.ok_or(Errno::ENODEV)?,
+            notify: notify.ok_or(Errno::ENODEV)?,
+            notify_multiplier,
+            device: config.ok_or(Errno::ENODEV)?,
+        })
+    }
+
+    fn read<T>(&self, offset: u64) -> T {
+        unsafe { read_volatile((self.common + offset) as *const T) }
+    }
+
+    fn write<T>(&self, offset: u64, value: T) {
+        unsafe { write_volatile((self.common + offset) as *mut T, value) }
+    }
+
+    fn set_status(&self, bits: u8) {
+        let status: u8 = self.read(COMMON_STATUS);
+        self.write(COMMON_STATUS, status | bits);
+    }
+
+    // the device forgets its features and queues and stops using their memory once this returns
+    pub fn reset(&self) -> Result<(), Errno> {
+        self.write(COMMON_STATUS, 0u8);
+        let mut spins = 0;
+        while self.read::<u8>(COMMON_STATUS) != 0 {
+            spins += 1;
+            if spins == RESET_SPINS {
+                return Err(Errno::EIO);
+            }
+            spin_loop_hint();
+        }
+        Ok(())
+    }
+
+    // reset the device and tell it we know how to drive it, with the features of wanted it has
+    // too, which are returned
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, Errno> {
+        self.reset()?;
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+        let mut offered = 0;
+        for half in 0..2u32 {
+            self.write(COMMON_DEVICE_FEATURE_SELECT, half);
+            offered |= (self.read::<u32>(COMMON_DEVICE_FEATURE) as u64) << (half * 32);
+        }
+        let features = offered & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);
+        }
+        for half in 0..2u32 {
+            self.write(COMMON_DRIVER_FEATURE_SELECT, half);
+            self.write(COMMON_DRIVER_FEATURE, (features >> (half * 32)) as u32);
+        }
+        self.set_status(STATUS_FEATURES_OK);
+        // the device clears it again if it can't work with those
+        if self.read::<u8>(COMMON_STATUS) & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(Errno::ENODEV);
+        }
+        Ok(features)
+    }
+
+    // set up queue index with at most size entries, its interrupts go to an MSI-X table entry
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        size: u16,
+        msix_entry: Option<u16>,
+    ) -> Result<Virtqueue, Errno> {
+        self.write(COMMON_QUEUE_SELECT, index);
+        let max: u16 = self.read(COMMON_QUEUE_SIZE);
+        if max == 0 {
+            return Err(Errno::ENODEV);
+        }
+        // the size of a split ring is a power of 2
+        let mut size = size.min(max);
+        while !size.is_power_of_two() {
+            size &= size - 1;
+        }
+        let vector = msix_entry.unwrap_or(NO_VECTOR);
+        self.write(COMMON_QUEUE_MSIX_VECTOR, vector);
+        // it reads back as NO_VECTOR if the device couldn't take it
+        if self.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) != vector {
+            return Err(Errno::ENOSPC);
+        }
+        let notify_off: u16 = self.read(COMMON_QUEUE_NOTIFY_OFF);
+        let notify = self.notify + notify_off as u64 * self.notify_multiplier as u64;
+        let queue = Virtqueue::new(index, size, notify)?;
+        self.write(COMMON_QUEUE_SIZE, size);
+        self.write(COMMON_QUEUE_DESC, queue.memory.phys().addr());
+        self.write(
+            COMMON_QUEUE_DRIVER,
+            queue.memory.phys().addr() + queue.avail,
+        );
+        self.write(COMMON_QUEUE_DEVICE, queue.memory.phys().addr() + queue.used);
+        self.write(COMMON_QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    // the device can be used once it is told that the driver is done setting it up
+    pub fn driver_ok(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    pub fn fail(&self) {
+        self.set_status(STATUS_FAILED);
+    }
+
+    // read the configuration of the device type, again if it changed in the middle
+    pub fn config<T>(&self, offset: u64) -> T {
+        loop {
+            let generation: u8 = self.read(COMMON_CONFIG_GENERATION);
+            let value = unsafe { read_volatile((self.device + offset) as *const T) };
+            if self.read::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// a buffer of a request, writable ones are filled by the device
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    // where the rings are in memory, the descriptors are at the start
+    avail: u64,
+    used: u64,
+    notify: u64,
+    free_head: u16,
+    free_count: u16,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify: u64) -> Result<Virtqueue, Errno> {
+        // flags, idx, a ring of size entries and an event index
+        let avail = size as u64 * size_of::<Descriptor>() as u64;
+        let used = (avail + 6 + 2 * size as u64 + 3) & !3;
+        let memory = DmaBuffer::new(
+            (used + 6 + 8 * size as u64) as usize,
+            FRAME_SIZE as usize,
+            0,
+        )?;
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            avail,
+            used,
+            notify,
+            free_head: 0,
+            free_count: size,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        // every descriptor is free, chained to the next
+        for i in 0..size {
+            unsafe { (*queue.desc(i)).next = i + 1 };
+        }
+        Ok(queue)
+    }
+
+    fn desc(&self, i: u16) -> *mut Descriptor {
+        (self.memory.virt().addr() as *mut Descriptor).wrapping_add(i as usize)
+    }
+
+    fn ring<T>(&self, offset: u64) -> *mut T {
+        (self.memory.virt().addr() + offset) as *mut T
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    // hand a chain of buffers to the device, returns the id it comes back with from pop_used
+    pub fn add(&mut self, buffers: &[Buffer]) -> Result<u16, Errno> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return Err(Errno::ENOSPC);
+        }
+        let head = self.free_head;
+        let mut last = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = unsafe { &mut *self.desc(self.free_head) };
+            last = self.free_head;
+            self.free_head = desc.next;
+            desc.addr = buffer.addr.addr();
+            desc.len = buffer.len;
+            desc.flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+        }
+        unsafe { (*self.desc(last)).next = self.free_head };
+        self.free_count -= buffers.len() as u16;
+        // the entry has to be there before the device sees the index move
+        let slot = self.avail + 4 + 2 * (self.avail_idx % self.size) as u64;
+        unsafe { write_volatile(self.ring::<u16>(slot), head) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.ring::<u16>(self.avail + 2), self.avail_idx) };
+        Ok(head)
+    }
+
+    // tell the device there is something new in the ring
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.notify as *mut u16, self.index) };
+    }
+
+    // a chain the device is done with: its id and how many bytes were written to it
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let idx = unsafe { read_volatile(self.ring::<u16>(self.used + 2)) };
+        if idx == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = self.used + 4 + 8 * (self.last_used % self.size) as u64;
+        let id = unsafe { read_volatile(self.ring::<u32>(slot)) } as u16;
+        let len = unsafe { read_volatile(self.ring::<u32>(slot + 4)) };
+        self.last_used = self.last_used.wrapping_add(1);
+        // put the chain back on the free list
+        let mut last = id;
+        let mut count = 1;
+        loop {
+            let desc = unsafe { &*self.desc(last) };
+            if desc.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            last = desc.next;
+            count += 1;
+        }
+        unsafe { (*self.desc(last)).next = self.free_head };
+        self.free_head = id;
+        self.free_count += count;
+        Some((id, len))
+    }
+}
@@ -0,0 +1,268 @@
+// Virtio block devices, what QEMU attaches with `-drive if=virtio`: vda, vdb and so on.
+//
+// A request is a chain of a header saying what to do with which sector, the data and a status
+// byte the device writes at the end. They go through a buffer in DMA memory one at a time, the
+// device raises an MSI-X interrupt once it put the chain into the used ring. Like for ATA the
+// task that sent it blocks until the interrupt wakes it up, and spins while booting. A request
+// that never completes might still be written by the device later, so the device is reset and
+// not used anymore after that.
+
+use crate::block::{self, BlockDevice, SECTOR_SIZE};
+use crate::dma::DmaBuffer;
+use crate::errno::Errno;
+use crate::interrupts;
+use crate::mem::FRAME_SIZE;
+use crate::pci::{self, Match, PciDriver};
+use crate::scheduler::{SleepLock, WaitQueue};
+use crate::virtio::{self, Buffer, Transport, Virtqueue};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::RwLock;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// the device configuration
+const CONFIG_CAPACITY: u64 = 0;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+// there is only one request at a time, its chain takes 3 descriptors
+const QUEUE_SIZE: u16 = 8;
+
+// the DMA buffer: the header and the status in the first page, then the data
+const HEADER_OFFSET: u64 = 0;
+const STATUS_OFFSET: u64 = 16;
+const DATA_OFFSET: u64 = FRAME_SIZE;
+const MAX_SECTORS: usize = 128;
+
+// a request that isn't done after this many ticks, about 2 seconds, means the device hangs
+const IRQ_TIMEOUT_TICKS: u64 = 36;
+
+static MATCHES: [Match; 2] = [
+    // transitional devices also have the legacy interface, which isn't used
+    Match::Id {
+        vendor: virtio::VENDOR,
+        device: 0x1001,
+    },
+    Match::Id {
+        vendor: virtio::VENDOR,
+        device: 0x1042,
+    },
+];
+
+struct Queue {
+    queue: Virtqueue,
+    buffer: DmaBuffer,
+}
+
+pub struct VirtioBlk {
+    name: String,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    transport: Transport,
+    failed: AtomicBool, // after a request timed out
+    // held while the request is waited for
+    queue: SleepLock<Queue>,
+    irq_waiting: WaitQueue,
+}
+
+lazy_static! {
+    // the disks the interrupt handler wakes up
+    static ref DISKS: RwLock<Vec<Arc<VirtioBlk>>> = RwLock::new(Vec::new());
+}
+
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+impl VirtioBlk {
+    // send a request on count sectors from sector, with the data in the buffer, and wait for it
+    fn request(
+        &self,
+        queue: &mut Queue,
+        kind: u32,
+        sector: u64,
+        count: usize,
+    ) -> Result<(), Errno> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err(Errno::EIO);
+        }
+        let base = queue.buffer.virt().addr();
+        let phys = queue.buffer.phys();
+        unsafe {
+            write_volatile((base + HEADER_OFFSET) as *mut u32, kind);
+            write_volatile((base + HEADER_OFFSET + 4) as *mut u32, 0);
+            write_volatile((base + HEADER_OFFSET + 8) as *mut u64, sector);
+            write_volatile((base + STATUS_OFFSET) as *mut u8, 0xff);
+        }
+        let mut chain = Vec::with_capacity(3);
+        chain.push(Buffer {
+            addr: phys.offset(HEADER_OFFSET),
+            len: 16,
+            writable: false,
+        });
+        if count != 0 {
+            chain.push(Buffer {
+                addr: phys.offset(DATA_OFFSET),
+                len: (count * SECTOR_SIZE) as u32,
+                writable: kind == T_IN,
+            });
+        }
+        chain.push(Buffer {
+            addr: phys.offset(STATUS_OFFSET),
+            len: 1,
+            writable: true,
+        });
+        let id = queue.queue.add(&chain)?;
+        queue.queue.notify();
+        // the interrupt might be for another disk, the request is done once it is in the ring
+        let ring = &mut queue.queue;
+        let done = self
+            .irq_waiting
+            .wait_timeout(IRQ_TIMEOUT_TICKS, || match ring.pop_used() {
+                Some((used, _)) if used == id => Some(()),
+                Some(_) => {
+                    log::warn!("{}: unexpected chain in the used ring", self.name);
+                    None
+                }
+                None => None,
+            });
+        if done.is_none() {
+            log::warn!("{}: no interrupt, the device isn't used anymore", self.name);
+            self.failed.store(true, Ordering::SeqCst);
+            // the buffer and the descriptors stay the device's unless the reset takes
+            let _ = self.transport.reset();
+            self.transport.fail();
+            return Err(Errno::EIO);
+        }
+        match unsafe { read_volatile((base + STATUS_OFFSET) as *const u8) } {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(Errno::EINVAL),
+            status => {
+                log::warn!("{}: error {} on sector {}", self.name, status, sector);
+                Err(Errno::EIO)
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) -> Result<(), Errno> {
+        block::check(self, start, buf.len())?;
+        let mut queue = self.queue.lock();
+        for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let sector = start + (i * MAX_SECTORS) as u64;
+            self.request(&mut queue, T_IN, sector, chunk.len() / SECTOR_SIZE)?;
+            let data = &queue.buffer.as_slice()[DATA_OFFSET as usize..];
+            chunk.copy_from_slice(&data[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) -> Result<(), Errno> {
+        block::check(self, start, buf.len())?;
+        if self.read_only {
+            return Err(Errno::EROFS);
+        }
+        let mut queue = self.queue.lock();
+        for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let sector = start + (i * MAX_SECTORS) as u64;
+            let data = &mut queue.buffer.as_mut_slice()[DATA_OFFSET as usize..];
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.request(&mut queue, T_OUT, sector, chunk.len() / SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Errno> {
+        // without a write cache there is nothing to flush
+        if !self.can_flush {
+            return Ok(());
+        }
+        let mut queue = self.queue.lock();
+        self.request(&mut queue, T_FLUSH, 0, 0)
+    }
+}
+
+// called from the MSI-X vectors of the disks
+fn handle_irq() {
+    for disk in DISKS.read().iter() {
+        disk.irq_waiting.wake_all();
+    }
+}
+
+pub struct Driver;
+
+pub static DRIVER: Driver = Driver;
+
+impl PciDriver for Driver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn matches(&self) -> &[Match] {
+        &MATCHES
+    }
+
+    fn probe(&self, device: &pci::Device) -> Result<(), Errno> {
+        let transport = Transport::new(device)?;
+        // the interrupts of the queue come through the first entry of the MSI-X table
+        device.msix_entries()?;
+        let size = DATA_OFFSET as usize + MAX_SECTORS * SECTOR_SIZE;
+        let buffer = DmaBuffer::new(size, FRAME_SIZE as usize, 0)?;
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let setup = || -> Result<Virtqueue, Errno> {
+            let queue = transport.setup_queue(0, QUEUE_SIZE, Some(0))?;
+            let vector = interrupts::alloc_msi_vector(handle_irq).ok_or(Errno::EBUSY)?;
+            if let Err(errno) = device.enable_msix(0, vector) {
+                interrupts::free_msi_vector(vector);
+                return Err(errno);
+            }
+            Ok(queue)
+        };
+        let queue = setup().map_err(|errno| {
+            transport.fail();
+            errno
+        })?;
+        let mut name = String::from("vd");
+        name.push((b'a' + NEXT_INDEX.fetch_add(1, Ordering::SeqCst) as u8) as char);
+        let disk = Arc::new(VirtioBlk {
+            name,
+            sectors: transport.config(CONFIG_CAPACITY),
+            read_only: features & F_RO != 0,
+            can_flush: features & F_FLUSH != 0,
+            transport,
+            failed: AtomicBool::new(false),
+            queue: SleepLock::new(Queue { queue, buffer }),
+            irq_waiting: WaitQueue::new(),
+        });
+        // the handler takes the lock in interrupts
+        without_interrupts(|| DISKS.write().push(disk.clone()));
+        disk.transport.driver_ok();
+        log::info!(
+            "{}: virtio block device, {} MiB{}",
+            disk.name,
+            (disk.sectors * SECTOR_SIZE as u64) >> 20,
+            if disk.read_only { ", read-only" } else { "" }
+        );
+        block::register(block::cached(disk))
+    }
+}
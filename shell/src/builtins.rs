@@ -1,11 +1,10 @@
 use super::RustShellOutput;
-use std::str::FromStr;
-
-use std::fs::File;
-use std::io::BufReader;
-use std::io::prelude::*;
-use std::path::Path;
-use std::env;
+use super::sys;
+use core::str::FromStr;
+#[cfg(feature = "diy-os")]
+use alloc::string::String;
+#[cfg(feature = "diy-os")]
+use alloc::vec::Vec;
 
 pub enum RustShellBuiltin {
     Echo,
@@ -36,37 +35,36 @@ pub fn builtin_echo(args : &Vec<String>) -> Result<RustShellOutput, RustShellOut
     })
 }
 
-// BUG: If rush_history doesn't exist yet, runtime panic.
 pub fn builtin_history(_ : &Vec<String>) -> Result<RustShellOutput, RustShellOutput> {
+    match sys::read_history() {
+        Ok(lines) => {
+            let o : Vec<String> = lines.iter()
+                .enumerate()
+                .map(|x| format!("{:3} {}", (x.0 + 1), x.1))
+                .collect();
 
-    let f = File::open("rush_history").expect("rush_history not found");
-    let reader = BufReader::new(f);
-    let lines = reader.lines();
-
-    let o = lines
-        .enumerate()
-        .map(|x| format!("{:3} {}", (x.0 + 1), x.1.unwrap()));
-
-    println!("{:?}", o);
-
-    Ok(RustShellOutput {
-        code: Some(0),
-        stdout: String::from("").into_bytes(),
-        stderr: String::from("").into_bytes(),
-    })
+            Ok(RustShellOutput {
+                code: Some(0),
+                stdout: o.join("\n").into_bytes(),
+                stderr: String::from("").into_bytes(),
+            })
+        },
+        Err(e) => Ok(RustShellOutput {
+            code: Some(1),
+            stdout: String::from("").into_bytes(),
+            stderr: e.into_bytes(),
+        })
+    }
 }
 
-// FIXME: This doesn't actually do anything. Lol.
 pub fn builtin_cd(args : &Vec<String>) -> Result<RustShellOutput, RustShellOutput> {
-    warn!("Not yet implemented");
     // let new_dir = args.peekable().peek().map_or("/", |x| *x);
     let input = match args.get(0) {
-        Some(i) => i,
+        Some(i) => i.as_str(),
         _ => "./"
     };
 
-    let root = Path::new(input);
-    match env::set_current_dir(&root) {
+    match sys::set_current_dir(input) {
         Ok(_) => Ok(RustShellOutput {
             code: Some(0),
             stdout: String::from("").into_bytes(),
@@ -75,23 +73,23 @@ pub fn builtin_cd(args : &Vec<String>) -> Result<RustShellOutput, RustShellOutpu
         Err(e) => Ok(RustShellOutput {
             code: Some(1),
             stdout: String::from("").into_bytes(),
-            stderr: e.to_string().into_bytes(),
+            stderr: e.into_bytes(),
         })
     }
 }
 
 pub fn builtin_pwd(_ : &Vec<String>) -> Result<RustShellOutput, RustShellOutput> {
-    match env::current_dir() {
+    match sys::current_dir() {
         Ok(r) => Ok(RustShellOutput {
             code: Some(0),
-            stdout: String::from(r.to_str().unwrap()).into_bytes(),
+            stdout: r.into_bytes(),
             stderr: String::from("").into_bytes(),
         }),
         Err(e) => Ok(
             RustShellOutput {
                 code: Some(1),
                 stdout: String::from("").into_bytes(),
-                stderr: String::from(e.to_string()).into_bytes(),
+                stderr: e.into_bytes(),
             })
     }
 }
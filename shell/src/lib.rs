@@ -1,7 +1,27 @@
-use std::io::{self};
-use std::fmt;
+#![cfg_attr(feature = "diy-os", no_std)]
+
+#[cfg(feature = "diy-os")]
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate log;
+#[cfg(feature = "diy-os")]
+extern crate spin;
+#[cfg(feature = "diy-os")]
+#[macro_use]
+extern crate user;
+
+#[cfg(feature = "diy-os")]
+use alloc::string::String;
+#[cfg(feature = "diy-os")]
+use alloc::vec::Vec;
+use core::fmt;
 
 pub mod builtins;
+// what the shell needs from the OS it runs on
+#[cfg_attr(not(feature = "diy-os"), path = "sys_host.rs")]
+#[cfg_attr(feature = "diy-os", path = "sys_os.rs")]
+mod sys;
 pub mod utils;
 use self::utils::*;
 
@@ -29,7 +49,7 @@ impl RustShellCommand {
     // TODO: Test for interactive / non-interactive sessions
     fn read() -> RustShellCommand {
         let mut command = String::new();
-        io::stdin().read_line(&mut command)
+        sys::read_line(&mut command)
             .expect("Failed to read in command");
         debug!("Raw input: {:?}", command);
 
@@ -0,0 +1,23 @@
+#![cfg_attr(feature = "diy-os", no_std)]
+#![cfg_attr(feature = "diy-os", no_main)]
+
+extern crate rust_shell;
+#[cfg(feature = "diy-os")]
+extern crate user;
+
+use rust_shell::RustShellCommand;
+
+#[cfg(not(feature = "diy-os"))]
+fn main() {
+    RustShellCommand::loop_interactive();
+}
+
+// the kernel starts the shell as its first process
+#[cfg(feature = "diy-os")]
+#[no_mangle]
+pub fn main() -> i32 {
+    // Ctrl+C is meant for the programs the shell runs
+    let _ = user::signal::ignore(user::abi::SIGINT);
+    RustShellCommand::loop_interactive();
+    0
+}
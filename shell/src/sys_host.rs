@@ -0,0 +1,66 @@
+use super::RustShellCommand;
+use super::RustShellOutput;
+use std::io::{self,BufRead,BufReader,Write};
+use std::process::{Command};
+use std::fs::{File,OpenOptions};
+use std::path::Path;
+use std::env;
+
+pub fn read_line(line: &mut String) -> Result<usize, String> {
+    io::stdin().read_line(line).map_err(|e| e.to_string())
+}
+
+pub fn flush_stdout() {
+    io::stdout().flush().unwrap();
+}
+
+pub fn current_dir() -> Result<String, String> {
+    match env::current_dir() {
+        Ok(r) => Ok(String::from(r.to_str().unwrap())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn set_current_dir(path: &str) -> Result<(), String> {
+    env::set_current_dir(Path::new(path)).map_err(|e| e.to_string())
+}
+
+// TODO: Figure out an intentional error code
+pub fn append_to_history(c: String) {
+    let file = OpenOptions::new().append(true).create(true).open("rush_history");
+    match file {
+        Ok(_) => {writeln!(file.unwrap(), "{}", format!("{}", c))},
+        _ => Err(io::Error::from_raw_os_error(1))
+    };
+}
+
+pub fn read_history() -> Result<Vec<String>, String> {
+    let f = File::open("rush_history").map_err(|e| e.to_string())?;
+    BufReader::new(f).lines()
+        .map(|line| line.map_err(|e| e.to_string()))
+        .collect()
+}
+
+pub fn execute_binary(c : &RustShellCommand) -> Result<RustShellOutput, RustShellOutput> {
+    // TODO: Maybe pipe stdout and stderr so print() will handle all i/o
+    // Figure out how to make vim continue working
+    //  .stdout(Stdio::piped())
+    //  .stderr(Stdio::piped())
+    let child = Command::new(&c.command)
+        .args(&c.args)
+        .spawn();
+
+    match child {
+        Ok(process) => {
+          let output = process.wait_with_output().unwrap();
+          Ok(RustShellOutput{code: output.status.code(), stdout: output.stdout, stderr: output.stderr})
+        },
+        Err(e) => {
+            Err(RustShellOutput {
+                code: e.raw_os_error(),
+                stdout: String::from("").into_bytes(),
+                stderr: String::from(format!("rush: {}: command not found", &c.command)).into_bytes(),
+            })
+        },
+    }
+}
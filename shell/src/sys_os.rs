@@ -0,0 +1,67 @@
+use super::RustShellCommand;
+use super::RustShellOutput;
+use alloc::string::{String,ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+use user::abi::PATH_MAX;
+use user::errno::Errno;
+use user::{io,syscall};
+
+// there's no filesystem to keep the history in yet
+static HISTORY: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn read_line(line: &mut String) -> Result<usize, String> {
+    io::read_line(line).map_err(|e| e.to_string())
+}
+
+// every print is a write syscall already
+pub fn flush_stdout() {
+}
+
+pub fn current_dir() -> Result<String, String> {
+    let mut buf = vec![0u8; PATH_MAX];
+    syscall::getcwd(&mut buf)
+        .map(|dir| String::from(dir))
+        .map_err(|e| e.to_string())
+}
+
+pub fn set_current_dir(path: &str) -> Result<(), String> {
+    syscall::chdir(path).map_err(|e| e.to_string())
+}
+
+pub fn append_to_history(c: String) {
+    HISTORY.lock().push(c);
+}
+
+pub fn read_history() -> Result<Vec<String>, String> {
+    Ok(HISTORY.lock().clone())
+}
+
+// commands without a slash are looked up in /bin, the program writes to the console
+// itself so there is no output to pass on
+pub fn execute_binary(c : &RustShellCommand) -> Result<RustShellOutput, RustShellOutput> {
+    let path = match c.command.contains('/') {
+        true => c.command.clone(),
+        false => format!("/bin/{}", c.command),
+    };
+    let mut args: Vec<&str> = vec![&c.command];
+    args.extend(c.args.iter().map(|a| a.as_str()));
+
+    match syscall::spawn(&path, &args).and_then(syscall::wait) {
+        Ok(code) => Ok(RustShellOutput {
+            code: Some(code),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }),
+        Err(Errno::ENOENT) => Err(RustShellOutput {
+            code: Some(Errno::ENOENT.code() as i32),
+            stdout: Vec::new(),
+            stderr: format!("rush: {}: command not found", &c.command).into_bytes(),
+        }),
+        Err(e) => Err(RustShellOutput {
+            code: Some(e.code() as i32),
+            stdout: Vec::new(),
+            stderr: format!("rush: {}: {}", &c.command, e).into_bytes(),
+        }),
+    }
+}
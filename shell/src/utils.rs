@@ -1,19 +1,17 @@
 use super::RustShellCommand;
 use super::RustShellOutput;
-use std::io::{self,Write};
 use super::builtins::*;
-use std::str::FromStr;
-use std::process::{Command};
-use std::fs::{OpenOptions};
-use std::env;
+use super::sys;
+use core::str::FromStr;
+#[cfg(feature = "diy-os")]
+use alloc::borrow::ToOwned;
+#[cfg(feature = "diy-os")]
+use alloc::string::{String,ToString};
+#[cfg(feature = "diy-os")]
+use alloc::vec::Vec;
 
-// TODO: Figure out an intentional error code
 pub fn append_to_history(c: String) {
-    let file = OpenOptions::new().append(true).create(true).open("rush_history");
-    match file {
-        Ok(_) => {writeln!(file.unwrap(), "{}", format!("{}", c))},
-        _ => Err(io::Error::from_raw_os_error(1))
-    };
+    sys::append_to_history(c);
 }
 
 pub fn tokenize_command(c : String) -> RustShellCommand {
@@ -28,8 +26,8 @@ pub fn tokenize_command(c : String) -> RustShellCommand {
 
 // TODO: Read prompt from an environment variable
 pub fn print_prompt() {
-    print!("{0}$", env::current_dir().unwrap().to_str().unwrap());
-    io::stdout().flush().unwrap();
+    print!("{0}$", sys::current_dir().unwrap());
+    sys::flush_stdout();
 }
 
 pub fn process_command(c : &RustShellCommand) -> Result<RustShellOutput, RustShellOutput> {
@@ -49,34 +47,8 @@ pub fn process_command(c : &RustShellCommand) -> Result<RustShellOutput, RustShe
                         stderr: String::from("").into_bytes(),
                     })
                 },
-                false => execute_binary(&c),
+                false => sys::execute_binary(&c),
             }
         },
     }
 }
-
-fn execute_binary(c : &RustShellCommand) -> Result<RustShellOutput, RustShellOutput> {
-    // TODO: Maybe pipe stdout and stderr so print() will handle all i/o
-    // Figure out how to make vim continue working
-    //  .stdout(Stdio::piped())
-    //  .stderr(Stdio::piped())
-    let child = Command::new(&c.command)
-        .args(&c.args)
-        .spawn();
-
-    match child {
-        Ok(process) => {
-          let output = process.wait_with_output().unwrap();
-          Ok(RustShellOutput{code: output.status.code(), stdout: output.stdout, stderr: output.stderr})
-        },
-        Err(e) => {
-            eprintln!("DEBUG: Exit code: {:?}", e.raw_os_error());
-            Err(RustShellOutput {
-                code: e.raw_os_error(),
-                stdout: String::from("").into_bytes(),
-                stderr: String::from(format!("rush: {}: command not found", &c.command)).into_bytes(),
-            })
-        },
-    }
-}
-
@@ -0,0 +1,59 @@
+; the user programs the kernel can start, see kernel/src/programs.rs
+; they are built before the kernel and included from the cargo target directory
+
+section .rodata
+align 16
+global _user_hello_start
+global _user_hello_end
+_user_hello_start:
+    incbin "target/x86_64-rust_os/release/hello"
+_user_hello_end:
+
+align 16
+global _user_sh_start
+global _user_sh_end
+_user_sh_start:
+    incbin "target/x86_64-rust_os/release/sh"
+_user_sh_end:
+
+align 16
+global _user_kbd_start
+global _user_kbd_end
+_user_kbd_start:
+    incbin "target/x86_64-rust_os/release/kbd"
+_user_kbd_end:
+
+align 16
+global _user_dmesg_start
+global _user_dmesg_end
+_user_dmesg_start:
+    incbin "target/x86_64-rust_os/release/dmesg"
+_user_dmesg_end:
+
+align 16
+global _user_ls_start
+global _user_ls_end
+_user_ls_start:
+    incbin "target/x86_64-rust_os/release/ls"
+_user_ls_end:
+
+align 16
+global _user_cat_start
+global _user_cat_end
+_user_cat_start:
+    incbin "target/x86_64-rust_os/release/cat"
+_user_cat_end:
+
+align 16
+global _user_tag_start
+global _user_tag_end
+_user_tag_start:
+    incbin "target/x86_64-rust_os/release/tag"
+_user_tag_end:
+
+align 16
+global _user_lspci_start
+global _user_lspci_end
+_user_lspci_start:
+    incbin "target/x86_64-rust_os/release/lspci"
+_user_lspci_end:
@@ -0,0 +1,42 @@
+// Writes the files given to stdout one after the other.
+
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+
+use user::abi::{O_RDONLY, STDOUT};
+use user::errno::Errno;
+use user::{env, syscall};
+
+fn copy(fd: u64) -> Result<(), Errno> {
+    let mut buf = [0u8; 512];
+    loop {
+        let count = syscall::read(fd, &mut buf)?;
+        if count == 0 {
+            return Ok(());
+        }
+        let mut written = 0;
+        while written < count {
+            written += syscall::write(STDOUT, &buf[written..count])?;
+        }
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut code = 0;
+    for path in env::args().skip(1) {
+        let result = syscall::open(path, O_RDONLY).and_then(|fd| {
+            let result = copy(fd);
+            let _ = syscall::close(fd);
+            result
+        });
+        if let Err(errno) = result {
+            eprintln!("cat: {}: {}", path, errno);
+            code = 1;
+        }
+    }
+    code
+}
@@ -0,0 +1,36 @@
+// Prints the kernel log kept in the kernel's ring buffer.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate user;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use user::abi::STDOUT;
+use user::io::FdWriter;
+use user::syscall;
+
+// the size of the kernel's ring buffer
+const LOG_SIZE: usize = 0x10000;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut buf = Vec::new();
+    buf.resize(LOG_SIZE, 0);
+    let len = match syscall::dmesg(&mut buf) {
+        Ok(len) => len,
+        Err(errno) => {
+            eprintln!("dmesg: {}", errno);
+            return 1;
+        }
+    };
+    let log = String::from_utf8_lossy(&buf[..len]);
+    match FdWriter(STDOUT).write_str(&log) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
@@ -0,0 +1,30 @@
+// Prints its arguments and tries out both kinds of heap allocations.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate user;
+
+use alloc::vec::Vec;
+use user::{env, syscall};
+
+#[no_mangle]
+pub fn main() -> i32 {
+    println!("Hello from userspace, this is task #.{}", syscall::getpid());
+    for (i, arg) in env::args().enumerate() {
+        println!("argv[{}] = {}", i, arg);
+    }
+    // small blocks come from brk
+    let squares: Vec<u64> = (0..100).map(|i| i * i).collect();
+    // anything larger than 64KiB is mmapped
+    let mut big: Vec<u8> = Vec::new();
+    big.resize(1 << 20, 1);
+    println!(
+        "sum of squares: {}, sum of a 1MiB buffer: {}",
+        squares.iter().sum::<u64>(),
+        big.iter().map(|b| *b as u64).sum::<u64>()
+    );
+    0
+}
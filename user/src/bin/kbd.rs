@@ -0,0 +1,62 @@
+// The keyboard driver. The kernel forwards scancodes from the keyboard interrupt as messages on a
+// channel, they are decoded here and the characters typed into the console. Ctrl+C sends SIGINT
+// to every other task, there are no process groups to pick the foreground ones yet.
+
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate user;
+extern crate pc_keyboard;
+
+use pc_keyboard::{layouts, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use user::abi::{IRQ_KEYBOARD, SIGINT};
+use user::syscall;
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let (server, irq) = match syscall::channel() {
+        Ok(endpoints) => endpoints,
+        Err(errno) => {
+            eprintln!("kbd: cannot create a channel: {}", errno);
+            return 1;
+        }
+    };
+    if let Err(errno) = syscall::irq_bind(IRQ_KEYBOARD, irq) {
+        eprintln!("kbd: cannot bind the keyboard interrupt: {}", errno);
+        return 1;
+    }
+    // the kernel keeps its own reference to the bound endpoint
+    let _ = syscall::close(irq);
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1);
+    let mut ctrl = false;
+    let mut scancodes = [0u8; 16];
+    loop {
+        let msg = match syscall::recv(server, &mut scancodes) {
+            Ok(msg) => msg,
+            Err(errno) => {
+                eprintln!("kbd: cannot receive scancodes: {}", errno);
+                return 1;
+            }
+        };
+        for &scancode in &scancodes[..msg.len as usize] {
+            if let Ok(Some(event)) = keyboard.add_byte(scancode) {
+                if event.code == KeyCode::ControlLeft || event.code == KeyCode::ControlRight {
+                    ctrl = event.state == KeyState::Down;
+                }
+                // keys without a character (arrows, function keys) are ignored for now
+                match keyboard.process_keyevent(event) {
+                    Some(DecodedKey::Unicode(c)) if ctrl && (c == 'c' || c == '\u{3}') => {
+                        println!("^C");
+                        let _ = syscall::kill(-1, SIGINT);
+                    }
+                    Some(DecodedKey::Unicode(c)) => {
+                        let mut buf = [0; 4];
+                        let _ = syscall::console_input(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
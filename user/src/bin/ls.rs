@@ -0,0 +1,71 @@
+// Lists directories, or the files given, with their type and size.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate user;
+
+use alloc::format;
+use user::abi::{Stat, S_IFDIR, S_IFLNK, S_IFMT};
+use user::{env, fs, syscall};
+
+fn kind(stat: &Stat) -> char {
+    match stat.mode & S_IFMT {
+        S_IFDIR => 'd',
+        S_IFLNK => 'l',
+        _ => '-',
+    }
+}
+
+fn show(path: &str, name: &str) {
+    match syscall::stat(path) {
+        Ok(stat) => println!("{} {:8} {}", kind(&stat), stat.size, name),
+        Err(errno) => eprintln!("ls: {}: {}", path, errno),
+    }
+}
+
+fn list(path: &str) -> bool {
+    let stat = match syscall::stat(path) {
+        Ok(stat) => stat,
+        Err(errno) => {
+            eprintln!("ls: {}: {}", path, errno);
+            return false;
+        }
+    };
+    if stat.mode & S_IFMT != S_IFDIR {
+        show(path, path);
+        return true;
+    }
+    match fs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                let full = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+                show(&full, &entry.name);
+            }
+            true
+        }
+        Err(errno) => {
+            eprintln!("ls: {}: {}", path, errno);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let mut ok = true;
+    let mut listed = false;
+    for path in env::args().skip(1) {
+        ok &= list(path);
+        listed = true;
+    }
+    if !listed {
+        ok = list(".");
+    }
+    match ok {
+        true => 0,
+        false => 1,
+    }
+}
@@ -0,0 +1,134 @@
+// Lists the PCI devices the kernel found, with their class, ids and driver. With -v also the
+// BARs and capabilities.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate user;
+
+use alloc::vec::Vec;
+use core::str;
+use user::abi::PciInfo;
+use user::{env, syscall};
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
+
+fn capability_name(id: u32) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x05 => "MSI",
+        0x09 => "Vendor Specific",
+        0x10 => "Express",
+        0x11 => "MSI-X",
+        _ => "Unknown",
+    }
+}
+
+fn show(info: &PciInfo, verbose: bool) {
+    print!(
+        "{:02x}:{:02x}.{} {} [{:02x}{:02x}]: {:04x}:{:04x}",
+        info.bus,
+        info.device,
+        info.function,
+        class_name(info.class, info.subclass),
+        info.class,
+        info.subclass,
+        info.vendor_id,
+        info.device_id
+    );
+    if info.revision != 0 {
+        print!(" (rev {:02x})", info.revision);
+    }
+    println!();
+    if !verbose {
+        return;
+    }
+    if info.irq_line != 0 && info.irq_line != 0xff {
+        println!("\tIRQ {}", info.irq_line);
+    }
+    for (i, (&bar, &size)) in info.bars.iter().zip(info.bar_sizes.iter()).enumerate() {
+        match bar {
+            0 => {}
+            bar if bar & 1 != 0 => {
+                println!("\tBAR {}: I/O ports at {:x} [size={}]", i, bar & !1, size)
+            }
+            bar => println!("\tBAR {}: Memory at {:x} [size={:#x}]", i, bar, size),
+        }
+    }
+    for id in (0..32).filter(|id| info.capabilities & 1 << id != 0) {
+        println!("\tCapability [{:02x}] {}", id, capability_name(id));
+    }
+    let len = info
+        .driver
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(info.driver.len());
+    if len != 0 {
+        println!(
+            "\tKernel driver in use: {}",
+            str::from_utf8(&info.driver[..len]).unwrap_or("?")
+        );
+    }
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let verbose = match env::args().nth(1) {
+        None => false,
+        Some("-v") => true,
+        Some(_) => {
+            eprintln!("usage: lspci [-v]");
+            return 2;
+        }
+    };
+    // ask again with room for all of them if there are more than fit
+    let mut devices = Vec::new();
+    devices.resize(32, PciInfo::default());
+    loop {
+        match syscall::pci_list(&mut devices) {
+            Ok(count) if count > devices.len() => devices.resize(count, PciInfo::default()),
+            Ok(count) => {
+                devices.truncate(count);
+                break;
+            }
+            Err(errno) => {
+                eprintln!("lspci: {}", errno);
+                return 1;
+            }
+        }
+    }
+    for info in devices.iter() {
+        show(info, verbose);
+    }
+    0
+}
@@ -0,0 +1,72 @@
+// Tags of files on a tag_fs:
+//   tag add FILE TAG...   tag rm FILE TAG...   tag ls PATH   tag find DIR TAG...
+// ls shows the tags of a file, or every tag for a directory, find the files that have all tags.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate user;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use user::errno::Errno;
+use user::{env, syscall};
+
+// call get with larger buffers until the names fit, then print them one per line
+fn print_names<F: Fn(&mut [u8]) -> Result<usize, Errno>>(get: F) -> Result<(), Errno> {
+    let mut buf = Vec::new();
+    buf.resize(1024, 0);
+    let len = loop {
+        match get(&mut buf[..]) {
+            Err(Errno::ERANGE) if buf.len() < 0x10000 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            result => break result?,
+        }
+    };
+    for name in buf[..len]
+        .split(|&c| c == 0)
+        .filter(|name| !name.is_empty())
+    {
+        println!("{}", String::from_utf8_lossy(name));
+    }
+    Ok(())
+}
+
+// None if the arguments make no sense
+fn run(args: &[&str]) -> Option<Result<(), Errno>> {
+    let result = match args {
+        ["add", file, tags @ ..] if !tags.is_empty() => {
+            tags.iter().try_for_each(|tag| syscall::tag_add(file, tag))
+        }
+        ["rm", file, tags @ ..] if !tags.is_empty() => tags
+            .iter()
+            .try_for_each(|tag| syscall::tag_remove(file, tag)),
+        ["ls", path] => print_names(|buf| syscall::tag_list(path, buf)),
+        ["find", dir, tags @ ..] => {
+            let tags = tags.join("/");
+            print_names(|buf| syscall::tag_query(dir, &tags, buf))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+#[no_mangle]
+pub fn main() -> i32 {
+    let args: Vec<&str> = env::args().skip(1).collect();
+    match run(&args) {
+        Some(Ok(())) => 0,
+        Some(Err(errno)) => {
+            eprintln!("tag: {}", errno);
+            1
+        }
+        None => {
+            eprintln!("usage: tag add|rm FILE TAG... | tag ls PATH | tag find DIR TAG...");
+            2
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// The arguments, environment and auxiliary vector the kernel puts on the initial stack:
+// argc, argv[0..argc], NULL, envp..., NULL, (tag, value)... pairs ending with AT_NULL
+
+use crate::abi::AT_NULL;
+use core::ptr::null;
+use core::{slice, str};
+
+static mut ARGC: usize = 0;
+static mut ARGV: *const *const u8 = null();
+static mut ENVC: usize = 0;
+static mut ENVP: *const *const u8 = null();
+static mut AUXV: *const u64 = null();
+
+pub unsafe fn init(stack: *const u64) {
+    ARGC = *stack as usize;
+    ARGV = stack.offset(1) as *const *const u8;
+    ENVP = ARGV.add(ARGC + 1);
+    let mut envc = 0;
+    while !(*ENVP.add(envc)).is_null() {
+        envc += 1;
+    }
+    ENVC = envc;
+    AUXV = ENVP.add(envc + 1) as *const u64;
+}
+
+// strings that aren't valid UTF-8 come out empty
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    str::from_utf8(slice::from_raw_parts(ptr, len)).unwrap_or("")
+}
+
+// iterator over one of the NULL-terminated string arrays
+pub struct Strings {
+    ptrs: *const *const u8,
+    len: usize,
+    next: usize,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        if self.next >= self.len {
+            return None;
+        }
+        let string = unsafe { c_str(*self.ptrs.add(self.next)) };
+        self.next += 1;
+        Some(string)
+    }
+}
+
+pub fn args() -> Strings {
+    unsafe {
+        Strings {
+            ptrs: ARGV,
+            len: ARGC,
+            next: 0,
+        }
+    }
+}
+
+// environment variables as "NAME=value" strings
+pub fn vars() -> Strings {
+    unsafe {
+        Strings {
+            ptrs: ENVP,
+            len: ENVC,
+            next: 0,
+        }
+    }
+}
+
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find_map(|var| {
+        let mut parts = var.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(val)) if key == name => Some(val),
+            _ => None,
+        }
+    })
+}
+
+// look up an entry of the auxiliary vector, e.g. abi::AT_PAGESZ
+pub fn auxv(tag: u64) -> Option<u64> {
+    unsafe {
+        let mut entry = AUXV;
+        while !entry.is_null() && *entry != AT_NULL {
+            if *entry == tag {
+                return Some(*entry.add(1));
+            }
+            entry = entry.add(2);
+        }
+    }
+    None
+}
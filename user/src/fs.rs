@@ -0,0 +1,48 @@
+// Reading directories, getdents hands out the raw records described in abi.rs.
+
+use crate::abi::{DIRENT_NAME_OFFSET, O_DIRECTORY, O_RDONLY};
+use crate::errno::Errno;
+use crate::syscall;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: u8, // one of DT_* in abi.rs
+    pub name: String,
+}
+
+// the records getdents stored at the start of buf
+fn parse(buf: &[u8], entries: &mut Vec<DirEntry>) {
+    let mut offset = 0;
+    while offset + DIRENT_NAME_OFFSET <= buf.len() {
+        let record = &buf[offset..];
+        let ino = u64::from_le_bytes(record[0..8].try_into().unwrap());
+        let len = u16::from_le_bytes(record[16..18].try_into().unwrap()) as usize;
+        let name = &record[DIRENT_NAME_OFFSET..len];
+        let name_len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        entries.push(DirEntry {
+            ino,
+            kind: record[18],
+            name: String::from_utf8_lossy(&name[..name_len]).into_owned(),
+        });
+        offset += len;
+    }
+}
+
+// the entries of the directory at path
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, Errno> {
+    let fd = syscall::open(path, O_RDONLY | O_DIRECTORY)?;
+    let mut entries = Vec::new();
+    let mut buf = [0u8; 1024];
+    let result = loop {
+        match syscall::getdents(fd, &mut buf) {
+            Ok(0) => break Ok(entries),
+            Ok(len) => parse(&buf[..len], &mut entries),
+            Err(errno) => break Err(errno),
+        }
+    };
+    let _ = syscall::close(fd);
+    result
+}
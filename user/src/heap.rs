@@ -0,0 +1,123 @@
+// The global allocator: small blocks come from power-of-two free lists carved out of the
+// brk heap, large ones get an anonymous mmap of their own.
+
+use crate::abi::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use crate::syscall;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp::max;
+use core::ptr::null_mut;
+use spin::Mutex;
+
+const PAGE_SIZE: usize = 0x1000;
+const MIN_BLOCK: usize = 16;
+const NUM_CLASSES: usize = 13; // 16 bytes up to 64KiB
+const MAX_BLOCK: usize = MIN_BLOCK << (NUM_CLASSES - 1);
+const BRK_GROWTH: usize = 0x10000; // grow the heap by at least this much at once
+
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Heap {
+    free: [*mut FreeBlock; NUM_CLASSES], // free blocks of each size class
+    top: usize,                          // start of the part of the heap never handed out
+    end: usize,                          // current brk
+}
+
+unsafe impl Send for Heap {}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// blocks are aligned to their size, so the alignment only matters if it's larger than the size
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = max(max(layout.size(), layout.align()), MIN_BLOCK).next_power_of_two();
+    if size > MAX_BLOCK {
+        return None;
+    }
+    Some((size.trailing_zeros() - MIN_BLOCK.trailing_zeros()) as usize)
+}
+
+impl Heap {
+    unsafe fn alloc(&mut self, class: usize) -> *mut u8 {
+        let block = self.free[class];
+        if !block.is_null() {
+            self.free[class] = (*block).next;
+            return block as *mut u8;
+        }
+        if self.end == 0 {
+            match syscall::brk(0) {
+                Ok(start) => {
+                    self.top = start as usize;
+                    self.end = start as usize;
+                }
+                Err(_) => return null_mut(),
+            }
+        }
+        // whatever is skipped for alignment is lost, blocks of one size tend to come in runs
+        let size = MIN_BLOCK << class;
+        let start = align_up(self.top, size);
+        if start + size > self.end {
+            let new_end = align_up(max(start + size, self.end + BRK_GROWTH), PAGE_SIZE);
+            match syscall::brk(new_end as u64) {
+                Ok(end) => self.end = end as usize,
+                Err(_) => return null_mut(),
+            }
+        }
+        self.top = start + size;
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, class: usize) {
+        let block = ptr as *mut FreeBlock;
+        (*block).next = self.free[class];
+        self.free[class] = block;
+    }
+}
+
+pub struct Allocator {
+    heap: Mutex<Heap>,
+}
+
+impl Allocator {
+    pub const fn new() -> Allocator {
+        Allocator {
+            heap: Mutex::new(Heap {
+                free: [null_mut(); NUM_CLASSES],
+                top: 0,
+                end: 0,
+            }),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(class) = size_class(&layout) {
+            return self.heap.lock().alloc(class);
+        }
+        // mmap only gives page alignment
+        if layout.align() > PAGE_SIZE {
+            return null_mut();
+        }
+        syscall::mmap(
+            null_mut(),
+            layout.size(),
+            PROT_READ | PROT_WRITE,
+            MAP_PRIVATE | MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+        .unwrap_or(null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class(&layout) {
+            Some(class) => self.heap.lock().dealloc(ptr, class),
+            None => {
+                let _ = syscall::munmap(ptr, layout.size());
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use crate::abi::{STDERR, STDIN, STDOUT};
+use crate::errno::Errno;
+use crate::syscall;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::io::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
+// formats straight into the write syscall on a file descriptor
+pub struct FdWriter(pub u64);
+
+impl Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut buf = s.as_bytes();
+        while !buf.is_empty() {
+            match syscall::write(self.0, buf) {
+                Ok(0) | Err(_) => return Err(fmt::Error),
+                Ok(written) => buf = &buf[written..],
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn _print(args: fmt::Arguments) {
+    let _ = FdWriter(STDOUT).write_fmt(args);
+}
+
+pub fn _eprint(args: fmt::Arguments) {
+    let _ = FdWriter(STDERR).write_fmt(args);
+}
+
+// append a line from stdin including the newline, returns 0 at the end of input
+pub fn read_line(line: &mut String) -> Result<usize, Errno> {
+    let mut bytes = Vec::new();
+    let mut buf = [0u8; 128];
+    loop {
+        let count = syscall::read(STDIN, &mut buf)?;
+        bytes.extend_from_slice(&buf[..count]);
+        if count == 0 || buf[count - 1] == b'\n' {
+            break;
+        }
+    }
+    line.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(bytes.len())
+}
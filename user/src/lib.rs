@@ -0,0 +1,43 @@
+// Runtime for userspace programs: the entry point, syscall wrappers, a heap and printing.
+// A program is a no_std, no_main binary that links this crate and defines
+//     #[no_mangle] pub fn main() -> i32
+// its return value becomes the exit code.
+
+#![no_std]
+#![feature(asm)]
+#![feature(naked_functions)]
+#![feature(alloc_error_handler)]
+
+extern crate alloc;
+extern crate spin;
+
+#[macro_use]
+pub mod io;
+
+// the ABI definitions are shared with the kernel
+#[path = "../../kernel/src/abi.rs"]
+pub mod abi;
+pub mod env;
+#[path = "../../kernel/src/errno.rs"]
+pub mod errno;
+pub mod fs;
+pub mod heap;
+pub mod signal;
+mod start;
+pub mod syscall;
+
+use core::panic::PanicInfo;
+
+#[global_allocator]
+static ALLOCATOR: heap::Allocator = heap::Allocator::new();
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!("allocation error: {:?}", layout)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    syscall::exit(101)
+}
@@ -0,0 +1,38 @@
+// Signal handlers. The kernel calls a handler with the signal number, when it returns it lands
+// in restore which asks the kernel to continue wherever the signal interrupted the program.
+
+use crate::abi::{SigAction, SA_RESTORER, SIG_DFL, SIG_IGN, SYS_SIGRETURN};
+use crate::errno::Errno;
+use crate::syscall;
+
+pub type Handler = extern "C" fn(u32);
+
+fn set_action(sig: u32, handler: u64) -> Result<(), Errno> {
+    let action = SigAction {
+        handler,
+        flags: SA_RESTORER,
+        restorer: restore as usize as u64,
+        mask: 0,
+    };
+    syscall::sigaction(sig, Some(&action)).map(|_| ())
+}
+
+// call handler whenever sig arrives, sig is blocked while it runs
+pub fn set_handler(sig: u32, handler: Handler) -> Result<(), Errno> {
+    set_action(sig, handler as usize as u64)
+}
+
+pub fn ignore(sig: u32) -> Result<(), Errno> {
+    set_action(sig, SIG_IGN)
+}
+
+// back to what the kernel does by default, mostly terminating the program
+pub fn reset(sig: u32) -> Result<(), Errno> {
+    set_action(sig, SIG_DFL)
+}
+
+// the handler returns here with rsp right above the frame the kernel pushed
+#[naked]
+unsafe extern "C" fn restore() {
+    asm!("mov rax, {}; syscall", const SYS_SIGRETURN, options(noreturn));
+}
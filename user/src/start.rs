@@ -0,0 +1,27 @@
+use crate::env;
+use crate::syscall;
+
+extern "Rust" {
+    fn main() -> i32;
+}
+
+// the kernel starts us with rsp pointing at argc, see setup_stack in kernel/src/elf.rs
+#[naked]
+#[no_mangle]
+pub unsafe extern "C" fn _start() -> ! {
+    asm!("\
+    mov rdi, rsp // argc, argv, envp and auxv start here
+    xor rbp, rbp // end of the frame pointer chain
+    and rsp, -16
+    call {}",
+    sym start_rust,
+    options(noreturn));
+}
+
+extern "C" fn start_rust(stack: *const u64) -> ! {
+    unsafe {
+        env::init(stack);
+    }
+    let code = unsafe { main() };
+    syscall::exit(code)
+}
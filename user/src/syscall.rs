@@ -0,0 +1,410 @@
+// Raw syscalls and typed wrappers around them, the numbers are in abi.rs.
+
+use crate::abi::*;
+use crate::errno::{decode, Errno};
+use alloc::vec::Vec;
+use core::cmp::min;
+
+// arguments in rdi, rsi, rdx, r10, r8, r9, the syscall instruction overwrites rcx and r11
+#[inline(always)]
+pub unsafe fn syscall6(n: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64, a6: u64) -> i64 {
+    let ret: i64;
+    asm!("syscall",
+    inlateout("rax") n as i64 => ret,
+    in("rdi") a1, in("rsi") a2, in("rdx") a3, in("r10") a4, in("r8") a5, in("r9") a6,
+    out("rcx") _, out("r11") _,
+    options(nostack));
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: u64, a1: u64, a2: u64, a3: u64) -> i64 {
+    syscall6(n, a1, a2, a3, 0, 0, 0)
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: u64, a1: u64) -> i64 {
+    syscall6(n, a1, 0, 0, 0, 0, 0)
+}
+
+#[inline(always)]
+pub unsafe fn syscall0(n: u64) -> i64 {
+    syscall6(n, 0, 0, 0, 0, 0, 0)
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe {
+        syscall1(SYS_EXIT, code as u64);
+    }
+    // exit doesn't return, but don't trust that with a panic that could loop back here
+    loop {}
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(SYS_WRITE, fd, buf.as_ptr() as u64, buf.len() as u64) };
+    decode(ret).map(|written| written as usize)
+}
+
+// move the end of the heap, 0 returns the current end
+// unsafe as shrinking it frees memory the allocator may still be using
+pub unsafe fn brk(addr: u64) -> Result<u64, Errno> {
+    decode(syscall1(SYS_BRK, addr))
+}
+
+pub unsafe fn mmap(
+    addr: *mut u8,
+    len: usize,
+    prot: u64,
+    flags: u64,
+    fd: i64,
+    offset: u64,
+) -> Result<*mut u8, Errno> {
+    let ret = syscall6(
+        SYS_MMAP,
+        addr as u64,
+        len as u64,
+        prot,
+        flags,
+        fd as u64,
+        offset,
+    );
+    decode(ret).map(|addr| addr as *mut u8)
+}
+
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<(), Errno> {
+    decode(syscall3(SYS_MUNMAP, addr as u64, len as u64, 0)).map(|_| ())
+}
+
+pub fn getpid() -> usize {
+    let ret = unsafe { syscall0(SYS_GETPID) };
+    decode(ret).unwrap_or(0) as usize
+}
+
+// blocks until input is available
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    decode(ret).map(|read| read as usize)
+}
+
+fn c_string(string: &str) -> Vec<u8> {
+    let mut c_string = Vec::with_capacity(string.len() + 1);
+    c_string.extend_from_slice(string.as_bytes());
+    c_string.push(0);
+    c_string
+}
+
+// start the program at path as a child, args are passed on as its argv
+pub fn spawn(path: &str, args: &[&str]) -> Result<usize, Errno> {
+    let path = c_string(path);
+    let args: Vec<Vec<u8>> = args.iter().map(|arg| c_string(arg)).collect();
+    let argv: Vec<u64> = args.iter().map(|arg| arg.as_ptr() as u64).collect();
+    let ret = unsafe {
+        syscall3(
+            SYS_SPAWN,
+            path.as_ptr() as u64,
+            argv.as_ptr() as u64,
+            argv.len() as u64,
+        )
+    };
+    decode(ret).map(|pid| pid as usize)
+}
+
+// wait for a child to exit and return its exit code
+pub fn wait(pid: usize) -> Result<i32, Errno> {
+    let mut status: i32 = 0;
+    let ret = unsafe { syscall3(SYS_WAIT, pid as u64, &mut status as *mut i32 as u64, 0) };
+    decode(ret).map(|_| status)
+}
+
+pub fn getcwd(buf: &mut [u8]) -> Result<&str, Errno> {
+    let ret = unsafe { syscall3(SYS_GETCWD, buf.as_mut_ptr() as u64, buf.len() as u64, 0) };
+    let len = decode(ret)? as usize;
+    core::str::from_utf8(&buf[..len]).map_err(|_| Errno::EINVAL)
+}
+
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    let path = c_string(path);
+    decode(unsafe { syscall1(SYS_CHDIR, path.as_ptr() as u64) }).map(|_| ())
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    decode(unsafe { syscall1(SYS_CLOSE, fd) }).map(|_| ())
+}
+
+// a new fd referring to the same file as fd
+pub fn dup(fd: u64) -> Result<u64, Errno> {
+    decode(unsafe { syscall1(SYS_DUP, fd) })
+}
+
+// make new_fd refer to the file of old_fd, closing what it referred to before
+pub fn dup2(old_fd: u64, new_fd: u64) -> Result<u64, Errno> {
+    decode(unsafe { syscall3(SYS_DUP2, old_fd, new_fd, 0) })
+}
+
+// returns the read and the write end of a new pipe
+pub fn pipe() -> Result<(u64, u64), Errno> {
+    let mut fds: [i32; 2] = [-1, -1];
+    decode(unsafe { syscall1(SYS_PIPE, fds.as_mut_ptr() as u64) })?;
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+// returns the two endpoints of a new channel
+pub fn channel() -> Result<(u64, u64), Errno> {
+    let mut fds: [i32; 2] = [-1, -1];
+    decode(unsafe { syscall1(SYS_CHANNEL, fds.as_mut_ptr() as u64) })?;
+    Ok((fds[0] as u64, fds[1] as u64))
+}
+
+fn message(data: &[u8]) -> IpcMessage {
+    IpcMessage {
+        data: data.as_ptr() as u64,
+        len: data.len() as u64,
+        ..IpcMessage::default()
+    }
+}
+
+unsafe fn syscall_msg(n: u64, fd: u64, msg: &mut IpcMessage) -> Result<u64, Errno> {
+    decode(syscall3(n, fd, msg as *mut IpcMessage as u64, 0))
+}
+
+// blocks while the other endpoint has too many messages waiting
+pub fn send(fd: u64, data: &[u8]) -> Result<(), Errno> {
+    unsafe { syscall_msg(SYS_SEND, fd, &mut message(data)) }.map(|_| ())
+}
+
+// move page_count pages at pages along with the data, they are unmapped from this task
+pub unsafe fn send_pages(
+    fd: u64,
+    data: &[u8],
+    pages: *mut u8,
+    page_count: usize,
+) -> Result<(), Errno> {
+    let mut msg = IpcMessage {
+        pages: pages as u64,
+        page_count: page_count as u64,
+        ..message(data)
+    };
+    syscall_msg(SYS_SEND, fd, &mut msg).map(|_| ())
+}
+
+// blocks until a message arrives, its data goes to buf and the returned description says how
+// much of it there is, where its pages were mapped and the id to reply to
+pub fn recv(fd: u64, buf: &mut [u8]) -> Result<IpcMessage, Errno> {
+    let mut msg = IpcMessage {
+        capacity: buf.len() as u64,
+        ..message(buf)
+    };
+    unsafe { syscall_msg(SYS_RECV, fd, &mut msg) }?;
+    Ok(msg)
+}
+
+// send the first len bytes of buf and block until the other side replies, the reply
+// overwrites buf and is described like for recv
+pub fn call(fd: u64, buf: &mut [u8], len: usize) -> Result<IpcMessage, Errno> {
+    let mut msg = IpcMessage {
+        len: min(len, buf.len()) as u64,
+        capacity: buf.len() as u64,
+        ..message(buf)
+    };
+    unsafe { syscall_msg(SYS_CALL, fd, &mut msg) }?;
+    Ok(msg)
+}
+
+// answer the call with the given id
+pub fn reply(fd: u64, id: u64, data: &[u8]) -> Result<(), Errno> {
+    let mut msg = IpcMessage {
+        id,
+        ..message(data)
+    };
+    unsafe { syscall_msg(SYS_REPLY, fd, &mut msg) }.map(|_| ())
+}
+
+// the kernel sends the data of each interrupt on the line to the other endpoint of fd
+pub fn irq_bind(irq: u64, fd: u64) -> Result<(), Errno> {
+    decode(unsafe { syscall3(SYS_IRQ_BIND, irq, fd, 0) }).map(|_| ())
+}
+
+// type text into the console as if it came from the keyboard
+pub fn console_input(text: &[u8]) -> Result<(), Errno> {
+    let ret = unsafe {
+        syscall3(
+            SYS_CONSOLE_INPUT,
+            text.as_ptr() as u64,
+            text.len() as u64,
+            0,
+        )
+    };
+    decode(ret).map(|_| ())
+}
+
+// a handle to a new zeroed shared memory region of at least size bytes
+pub fn shm_create(size: usize) -> Result<u64, Errno> {
+    decode(unsafe { syscall1(SYS_SHM_CREATE, size as u64) })
+}
+
+// map the region of the handle at addr, or wherever there is room if it's null
+pub unsafe fn shm_map(fd: u64, addr: *mut u8, prot: u64) -> Result<*mut u8, Errno> {
+    decode(syscall3(SYS_SHM_MAP, fd, addr as u64, prot)).map(|addr| addr as *mut u8)
+}
+
+pub unsafe fn shm_unmap(addr: *mut u8) -> Result<(), Errno> {
+    decode(syscall1(SYS_SHM_UNMAP, addr as u64)).map(|_| ())
+}
+
+// send sig to the task pid, or to every other task if pid is -1
+pub fn kill(pid: isize, sig: u32) -> Result<(), Errno> {
+    decode(unsafe { syscall3(SYS_KILL, pid as u64, sig as u64, 0) }).map(|_| ())
+}
+
+// set the action for sig if one is given, returns the previous one
+pub fn sigaction(sig: u32, action: Option<&SigAction>) -> Result<SigAction, Errno> {
+    let mut old = SigAction::default();
+    let action = action.map_or(0, |action| action as *const SigAction as u64);
+    let ret = unsafe {
+        syscall3(
+            SYS_SIGACTION,
+            sig as u64,
+            action,
+            &mut old as *mut SigAction as u64,
+        )
+    };
+    decode(ret).map(|_| old)
+}
+
+// block, unblock or set the blocked signals depending on how, returns the old mask
+pub fn sigprocmask(how: u64, set: u64) -> Result<u64, Errno> {
+    decode(unsafe { syscall3(SYS_SIGPROCMASK, how, set, 0) })
+}
+
+// the newest kernel log output that fits into buf
+pub fn dmesg(buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(SYS_DMESG, buf.as_mut_ptr() as u64, buf.len() as u64, 0) };
+    decode(ret).map(|len| len as usize)
+}
+
+// open a file or directory, flags are O_* from abi.rs
+pub fn open(path: &str, flags: u64) -> Result<u64, Errno> {
+    let path = c_string(path);
+    decode(unsafe { syscall3(SYS_OPEN, path.as_ptr() as u64, flags, 0) })
+}
+
+// move the offset of fd, returns the new one
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> Result<u64, Errno> {
+    decode(unsafe { syscall3(SYS_LSEEK, fd, offset as u64, whence) })
+}
+
+pub fn stat(path: &str) -> Result<Stat, Errno> {
+    let path = c_string(path);
+    let mut stat = Stat::default();
+    let ret = unsafe {
+        syscall3(
+            SYS_STAT,
+            path.as_ptr() as u64,
+            &mut stat as *mut Stat as u64,
+            0,
+        )
+    };
+    decode(ret).map(|_| stat)
+}
+
+pub fn fstat(fd: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    let ret = unsafe { syscall3(SYS_FSTAT, fd, &mut stat as *mut Stat as u64, 0) };
+    decode(ret).map(|_| stat)
+}
+
+// the next entries of the directory fd as records described in abi.rs, 0 after the last
+pub fn getdents(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(SYS_GETDENTS, fd, buf.as_mut_ptr() as u64, buf.len() as u64) };
+    decode(ret).map(|len| len as usize)
+}
+
+fn path_syscall(n: u64, path: &str) -> Result<(), Errno> {
+    let path = c_string(path);
+    decode(unsafe { syscall1(n, path.as_ptr() as u64) }).map(|_| ())
+}
+
+pub fn mkdir(path: &str) -> Result<(), Errno> {
+    path_syscall(SYS_MKDIR, path)
+}
+
+// remove an empty directory
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    path_syscall(SYS_RMDIR, path)
+}
+
+// remove anything but a directory
+pub fn unlink(path: &str) -> Result<(), Errno> {
+    path_syscall(SYS_UNLINK, path)
+}
+
+// make path the root directory and the working directory
+pub fn chroot(path: &str) -> Result<(), Errno> {
+    path_syscall(SYS_CHROOT, path)
+}
+
+fn two_path_syscall(n: u64, first: &str, second: &str) -> Result<(), Errno> {
+    let (first, second) = (c_string(first), c_string(second));
+    let ret = unsafe { syscall3(n, first.as_ptr() as u64, second.as_ptr() as u64, 0) };
+    decode(ret).map(|_| ())
+}
+
+// another name for the file at old_path
+pub fn link(old_path: &str, new_path: &str) -> Result<(), Errno> {
+    two_path_syscall(SYS_LINK, old_path, new_path)
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), Errno> {
+    two_path_syscall(SYS_SYMLINK, target, path)
+}
+
+pub fn chmod(path: &str, perm: u32) -> Result<(), Errno> {
+    let path = c_string(path);
+    decode(unsafe { syscall3(SYS_CHMOD, path.as_ptr() as u64, perm as u64, 0) }).map(|_| ())
+}
+
+// give a file on a tag_fs a tag
+pub fn tag_add(path: &str, tag: &str) -> Result<(), Errno> {
+    two_path_syscall(SYS_TAG_ADD, path, tag)
+}
+
+pub fn tag_remove(path: &str, tag: &str) -> Result<(), Errno> {
+    two_path_syscall(SYS_TAG_REMOVE, path, tag)
+}
+
+// the tags of a file, or all tags for a directory, each name ends with a NUL
+pub fn tag_list(path: &str, buf: &mut [u8]) -> Result<usize, Errno> {
+    let path = c_string(path);
+    let ret = unsafe {
+        syscall3(
+            SYS_TAG_LIST,
+            path.as_ptr() as u64,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+        )
+    };
+    decode(ret).map(|len| len as usize)
+}
+
+// the names of the files in dir that have all the tags, which are separated by /
+pub fn tag_query(dir: &str, tags: &str, buf: &mut [u8]) -> Result<usize, Errno> {
+    let (dir, tags) = (c_string(dir), c_string(tags));
+    let ret = unsafe {
+        syscall6(
+            SYS_TAG_QUERY,
+            dir.as_ptr() as u64,
+            tags.as_ptr() as u64,
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+            0,
+            0,
+        )
+    };
+    decode(ret).map(|len| len as usize)
+}
+
+// the PCI functions that fit into buf, returns how many there are in all
+pub fn pci_list(buf: &mut [PciInfo]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall3(SYS_PCI_LIST, buf.as_mut_ptr() as u64, buf.len() as u64, 0) };
+    decode(ret).map(|count| count as usize)
+}